@@ -71,6 +71,9 @@
             nativeBuildInputs = [ pkgs.pkg-config pkgs.rustPlatform.bindgenHook ];
             buildInputs = [ pkgs.dbus.dev pkgs.systemdLibs.dev ];
 
+            # Reported by the agent's /version endpoint.
+            NIXLESS_AGENT_GIT_COMMIT = self.rev or self.dirtyRev or "unknown";
+
             meta = {
               description = "nixless-agent";
               mainProgram = "nixless-agent";
@@ -1,11 +1,15 @@
 mod signing;
+mod store_path;
 
 pub use signing::*;
+pub use store_path::*;
+
+const NIX32_ALPHABET: &str = "0123456789abcdfghijklmnpqrsvwxyz";
 
 /// https://github.com/NixOS/nix/blob/c0b6907ccdaf3d3911cfdb2ff2d000e1683997c7/src/libutil/hash.cc#L90
-/// To go from nix32 to u8, follow this: https://github.com/NixOS/nix/blob/c0b6907ccdaf3d3911cfdb2ff2d000e1683997c7/src/libutil/hash.cc#L231
+/// To go from nix32 to u8, see `from_nix32()`.
 pub fn to_nix32(slice: &[u8]) -> String {
-    let alphabet = "0123456789abcdfghijklmnpqrsvwxyz";
+    let alphabet = NIX32_ALPHABET;
     let b32len = (slice.len() * 8 - 1) / 5 + 1;
 
     let mut res = String::with_capacity(b32len);
@@ -27,3 +31,84 @@ pub fn to_nix32(slice: &[u8]) -> String {
 
     res
 }
+
+/// The inverse of `to_nix32()`. Returns `None` if `s` has characters outside the nix32 alphabet, or if it encodes more bits than fit in the decoded bytes.
+/// https://github.com/NixOS/nix/blob/c0b6907ccdaf3d3911cfdb2ff2d000e1683997c7/src/libutil/hash.cc#L231
+pub fn from_nix32(s: &str) -> Option<Vec<u8>> {
+    let len = s.len();
+    let mut res = vec![0u8; len * 5 / 8];
+
+    for (n, c) in s.bytes().rev().enumerate() {
+        let digit = NIX32_ALPHABET.bytes().position(|a| a == c)? as u16;
+        let b = n * 5;
+        let i = b / 8;
+        let j = b % 8;
+
+        if i >= res.len() {
+            // Only happens for lengths that don't map to whole bytes, in which case the extra bits must be zero.
+            if digit != 0 {
+                return None;
+            }
+            continue;
+        }
+
+        res[i] |= (digit << j) as u8;
+
+        if i < res.len() - 1 {
+            res[i + 1] |= (digit >> (8 - j)) as u8;
+        } else if (digit >> (8 - j)) != 0 {
+            return None;
+        }
+    }
+
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sha256 of the empty string.
+    const EMPTY_SHA256: [u8; 32] = [
+        0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9,
+        0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52,
+        0xb8, 0x55,
+    ];
+    // What `nix-hash --type sha256 --to-base32` gives for the hash above.
+    const EMPTY_SHA256_NIX32: &str = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+
+    #[test]
+    fn nix32_matches_nix() {
+        assert_eq!(to_nix32(&EMPTY_SHA256), EMPTY_SHA256_NIX32);
+        assert_eq!(
+            from_nix32(EMPTY_SHA256_NIX32).as_deref(),
+            Some(&EMPTY_SHA256[..])
+        );
+    }
+
+    #[test]
+    fn nix32_round_trips() {
+        // 20 bytes is the length of store path hashes, and 32 the length of sha256 hashes.
+        for len in [1, 20, 32, 64] {
+            let bytes: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+            assert_eq!(from_nix32(&to_nix32(&bytes)), Some(bytes));
+        }
+    }
+
+    #[test]
+    fn from_nix32_rejects_characters_outside_the_alphabet() {
+        for c in ['e', 'o', 'u', 't', 'A', '-'] {
+            let mut s = EMPTY_SHA256_NIX32.to_string();
+            s.replace_range(10..11, &c.to_string());
+            assert_eq!(from_nix32(&s), None, "accepted {c:?}");
+        }
+    }
+
+    #[test]
+    fn from_nix32_rejects_extra_bits() {
+        // 52 characters hold 260 bits, so the first character can only use the lowest of its 5 bits.
+        let mut s = EMPTY_SHA256_NIX32.to_string();
+        s.replace_range(0..1, "z");
+        assert_eq!(from_nix32(&s), None);
+    }
+}
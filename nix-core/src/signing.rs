@@ -1,6 +1,9 @@
 use std::collections::HashMap;
 
-use base64::{engine::general_purpose::STANDARD, Engine};
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine,
+};
 use ed25519_dalek::{
     ed25519::signature::SignerMut, Signature, SigningKey, Verifier, VerifyingKey, KEYPAIR_LENGTH,
     PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH,
@@ -13,10 +16,14 @@ pub enum PublicKeyError {
     PublicKeyTooShort,
     #[error("the data for the signature isn't long enough!")]
     SignatureTooShort,
+    #[error("the signature has {0} bytes instead of the 64 it should have!")]
+    InvalidSignatureLength(usize),
     #[error("the public key string is in an unexpected format!")]
     UnexpectedFormat,
     #[error("unable to decode data for key")]
     UnableToDecode(#[from] base64::DecodeSliceError),
+    #[error("unable to decode the signature")]
+    UnableToDecodeSignature(#[from] base64::DecodeError),
     #[error("unable to read public key data")]
     UnableToReadKey(#[from] ed25519_dalek::SignatureError),
     #[error("this key already exists in the keychain!")]
@@ -91,6 +98,12 @@ impl NixStylePrivateKey {
         Ok(STANDARD.encode::<[u8; 64]>(signature.into()))
     }
 
+    /// Signs `data` and returns the signature in the same `<name>:<base64str>` format Nix uses for signatures in NAR infos, so the verifier knows which key to check it against.
+    pub fn sign_to_nix_format(&mut self, data: &[u8]) -> Result<String, PrivateKeyError> {
+        let signature = self.sign_to_base64(data)?;
+        Ok(format!("{}:{}", self.name, signature))
+    }
+
     pub fn public_key_nix_format(&self) -> String {
         let pk = self.key.verifying_key();
         let pk_encoded = STANDARD.encode(pk.as_bytes());
@@ -128,6 +141,11 @@ impl PublicKeychain {
         }
     }
 
+    pub fn has_key(&self, key_name: &str) -> bool {
+        self.keys.contains_key(key_name)
+    }
+
+    /// Returns an error if the signature is malformed (not valid base64, or not exactly `SIGNATURE_LENGTH` bytes once decoded), and `Ok(false)` if it's well-formed but doesn't verify `data`, or if we don't know the key. Callers that only care whether the data is trustworthy should treat both as a failed verification.
     pub fn verify(
         &self,
         key_name: &str,
@@ -142,26 +160,191 @@ impl PublicKeychain {
         }
     }
 
+    /// Same contract as `verify`, but checks the signature against every key in the keychain.
     pub fn verify_any(&self, data: &[u8], signature_base64: &[u8]) -> Result<bool, PublicKeyError> {
+        Ok(self.verify_any_named(data, signature_base64)?.is_some())
+    }
+
+    /// Same as `verify_any`, but returns the name of the key that verified the signature, so callers know who authorised the data.
+    pub fn verify_any_named(
+        &self,
+        data: &[u8],
+        signature_base64: &[u8],
+    ) -> Result<Option<&str>, PublicKeyError> {
         let signature = signature_from_base64(signature_base64)?;
 
         for key in self.keys.values() {
             if key.key.verify(data, &signature).is_ok() {
-                return Ok(true);
+                return Ok(Some(&key.name));
             }
         }
 
-        Ok(false)
+        Ok(None)
     }
 }
 
+/// Signing tools don't all agree on how to encode signatures, so we ignore whitespace and trailing padding, and accept both the standard and the URL-safe base64 alphabets.
 fn signature_from_base64(data: &[u8]) -> Result<Signature, PublicKeyError> {
-    let mut signature_bytes = [0u8; SIGNATURE_LENGTH];
-    let bytes_written = STANDARD.decode_slice(data, &mut signature_bytes)?;
+    let mut normalised: Vec<u8> = data
+        .iter()
+        .filter(|b| !b.is_ascii_whitespace())
+        .map(|b| match b {
+            b'-' => b'+',
+            b'_' => b'/',
+            b => *b,
+        })
+        .collect();
+    // Padding anywhere else means the signature is malformed, so we'll leave it there for the decoder to refuse.
+    while normalised.last() == Some(&b'=') {
+        normalised.pop();
+    }
+    let signature_bytes = STANDARD_NO_PAD.decode(normalised)?;
 
-    if bytes_written < signature_bytes.len() {
+    if signature_bytes.len() < SIGNATURE_LENGTH {
         return Err(PublicKeyError::SignatureTooShort);
     }
 
+    let signature_bytes: [u8; SIGNATURE_LENGTH] = signature_bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| PublicKeyError::InvalidSignatureLength(bytes.len()))?;
+
     Ok(Signature::from_bytes(&signature_bytes))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"1;/nix/store/0c0fbflsgmvl9j3ag6p0h2ja1bxmd5ii-hello-2.12.1;sha256:0m7yh1nb5k6xnbghl3d5yjsjpbgw4njzd42kdrrhqbnhvy6psc6y;226560;";
+
+    fn private_key(name: &str, seed: u8) -> NixStylePrivateKey {
+        let key = SigningKey::from_bytes(&[seed; 32]);
+        NixStylePrivateKey::from_nix_format(&format!(
+            "{}:{}",
+            name,
+            STANDARD.encode(key.to_keypair_bytes())
+        ))
+        .unwrap()
+    }
+
+    fn keychain_with(private_key: &NixStylePrivateKey) -> PublicKeychain {
+        let mut keychain = PublicKeychain::new();
+        keychain
+            .add_key(
+                NixStylePublicKey::from_nix_format(&private_key.public_key_nix_format()).unwrap(),
+            )
+            .unwrap();
+        keychain
+    }
+
+    #[test]
+    fn verifies_valid_signature() {
+        let mut signer = private_key("test-1", 1);
+        let keychain = keychain_with(&signer);
+        let signature = signer.sign_to_base64(DATA).unwrap();
+
+        assert!(keychain
+            .verify("test-1", DATA, signature.as_bytes())
+            .unwrap());
+        assert_eq!(
+            keychain
+                .verify_any_named(DATA, signature.as_bytes())
+                .unwrap(),
+            Some("test-1")
+        );
+    }
+
+    #[test]
+    fn rejects_signature_from_wrong_key() {
+        let mut signer = private_key("test-1", 1);
+        let keychain = keychain_with(&private_key("test-1", 2));
+        let signature = signer.sign_to_base64(DATA).unwrap();
+
+        assert!(!keychain
+            .verify("test-1", DATA, signature.as_bytes())
+            .unwrap());
+        assert!(!keychain.verify_any(DATA, signature.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn rejects_signature_of_other_data() {
+        let mut signer = private_key("test-1", 1);
+        let keychain = keychain_with(&signer);
+        let signature = signer.sign_to_base64(b"something else").unwrap();
+
+        assert!(!keychain.verify_any(DATA, signature.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn rejects_truncated_signature() {
+        let mut signer = private_key("test-1", 1);
+        let keychain = keychain_with(&signer);
+        let signature = signer.sign_to_base64(DATA).unwrap();
+        // Drops the padding and the 2 characters before it, which encode the last byte.
+        let truncated = &signature[..signature.len() - 4];
+
+        assert!(matches!(
+            keychain.verify("test-1", DATA, truncated.as_bytes()),
+            Err(PublicKeyError::SignatureTooShort)
+        ));
+        assert!(matches!(
+            keychain.verify_any(DATA, truncated.as_bytes()),
+            Err(PublicKeyError::SignatureTooShort)
+        ));
+    }
+
+    fn signature_bytes() -> [u8; SIGNATURE_LENGTH] {
+        private_key("test-1", 1).key.sign(DATA).into()
+    }
+
+    #[test]
+    fn signature_from_base64_accepts_common_encodings() {
+        let bytes = signature_bytes();
+        let standard = STANDARD.encode(bytes);
+        let url_safe = base64::engine::general_purpose::URL_SAFE.encode(bytes);
+        let unpadded = STANDARD_NO_PAD.encode(bytes);
+        let split = format!("{}\n {}", &standard[..40], &standard[40..]);
+
+        for encoded in [standard, url_safe, unpadded, split] {
+            let signature = signature_from_base64(encoded.as_bytes())
+                .unwrap_or_else(|err| panic!("refused {encoded:?}: {err}"));
+            assert_eq!(signature.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn signature_from_base64_rejects_padding_in_the_middle() {
+        let standard = STANDARD.encode(signature_bytes());
+        let padded_in_middle = format!("{}={}", &standard[..40], &standard[40..]);
+
+        assert!(matches!(
+            signature_from_base64(padded_in_middle.as_bytes()),
+            Err(PublicKeyError::UnableToDecodeSignature(_))
+        ));
+    }
+
+    #[test]
+    fn signature_from_base64_rejects_wrong_lengths() {
+        let bytes = signature_bytes();
+
+        assert!(matches!(
+            signature_from_base64(STANDARD.encode(&bytes[..63]).as_bytes()),
+            Err(PublicKeyError::SignatureTooShort)
+        ));
+        assert!(matches!(
+            signature_from_base64(STANDARD.encode([&bytes[..], &[0]].concat()).as_bytes()),
+            Err(PublicKeyError::InvalidSignatureLength(65))
+        ));
+    }
+
+    #[test]
+    fn signature_from_base64_rejects_invalid_characters() {
+        let standard = STANDARD.encode(signature_bytes());
+        let invalid = format!("{}!{}", &standard[..40], &standard[41..]);
+
+        assert!(matches!(
+            signature_from_base64(invalid.as_bytes()),
+            Err(PublicKeyError::UnableToDecodeSignature(_))
+        ));
+    }
+}
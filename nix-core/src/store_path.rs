@@ -0,0 +1,145 @@
+use std::fmt::Display;
+
+use thiserror::Error;
+
+use crate::from_nix32;
+
+/// Length of the hash part of a store path, in nix32 characters.
+pub const STORE_PATH_HASH_LENGTH: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum StorePathError {
+    #[error("the store path {0:?} isn't in the format <hash>-<name>!")]
+    UnexpectedFormat(String),
+    #[error("the hash of the store path {0:?} doesn't have the expected length!")]
+    InvalidHashLength(String),
+    #[error("the hash of the store path {0:?} isn't valid nix32!")]
+    InvalidHash(String),
+    #[error("the name of the store path {0:?} contains characters Nix doesn't allow!")]
+    InvalidName(String),
+}
+
+/// The name of an entry in the Nix store (which is what the agent calls a package id), e.g. `<hash>-hello-2.12.1`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StorePath {
+    hash: String,
+    name: String,
+}
+
+impl StorePath {
+    /// Expects only the name of the entry, without the store directory in front of it.
+    pub fn from_package_id(package_id: &str) -> Result<Self, StorePathError> {
+        let Some((hash, name)) = package_id.split_once('-') else {
+            return Err(StorePathError::UnexpectedFormat(package_id.to_string()));
+        };
+
+        if name.is_empty() {
+            return Err(StorePathError::UnexpectedFormat(package_id.to_string()));
+        }
+
+        if hash.len() != STORE_PATH_HASH_LENGTH {
+            return Err(StorePathError::InvalidHashLength(package_id.to_string()));
+        }
+
+        if from_nix32(hash).is_none() {
+            return Err(StorePathError::InvalidHash(package_id.to_string()));
+        }
+
+        // Same rules Nix uses when it creates a store path: only some ASCII characters, and the name can't start with a dot.
+        if name.starts_with('.')
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-._?=".contains(c))
+        {
+            return Err(StorePathError::InvalidName(package_id.to_string()));
+        }
+
+        Ok(Self {
+            hash: hash.to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    /// In nix32, the same way it shows up in the store path and in narinfo URLs.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Display for StorePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.hash, self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0c0fbflsgmvl9j3ag6p0h2ja1bxmd5ii";
+
+    #[test]
+    fn parses_package_id() {
+        let package_id = format!("{HASH}-hello-2.12.1");
+        let store_path = StorePath::from_package_id(&package_id).unwrap();
+
+        assert_eq!(store_path.hash(), HASH);
+        assert_eq!(store_path.name(), "hello-2.12.1");
+        assert_eq!(store_path.to_string(), package_id);
+    }
+
+    #[test]
+    fn accepts_all_allowed_name_characters() {
+        assert!(StorePath::from_package_id(&format!("{HASH}-a+-._?=Z9")).is_ok());
+    }
+
+    #[test]
+    fn rejects_missing_name() {
+        assert!(matches!(
+            StorePath::from_package_id(HASH),
+            Err(StorePathError::UnexpectedFormat(_))
+        ));
+        assert!(matches!(
+            StorePath::from_package_id(".links"),
+            Err(StorePathError::UnexpectedFormat(_))
+        ));
+        assert!(matches!(
+            StorePath::from_package_id(&format!("{HASH}-")),
+            Err(StorePathError::UnexpectedFormat(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_hash_length() {
+        assert!(matches!(
+            StorePath::from_package_id(&format!("{}-hello", &HASH[1..])),
+            Err(StorePathError::InvalidHashLength(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_hash() {
+        // `e` isn't part of the nix32 alphabet.
+        assert!(matches!(
+            StorePath::from_package_id("0c0fbflsgmvl9j3ag6p0h2ja1bxmd5ie-hello"),
+            Err(StorePathError::InvalidHash(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_name() {
+        for name in [".hidden", "with space", "with/slash", "ünicode"] {
+            assert!(
+                matches!(
+                    StorePath::from_package_id(&format!("{HASH}-{name}")),
+                    Err(StorePathError::InvalidName(_))
+                ),
+                "accepted {name:?}"
+            );
+        }
+    }
+}
@@ -1,26 +1,33 @@
 use std::{collections::HashSet, ops::Deref, path::PathBuf};
 
-use anyhow::anyhow;
 use derive_builder::Builder;
+use nix_core::StorePath;
 use tokio::{
     sync::{mpsc, oneshot},
     task::JoinHandle,
 };
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tokio_stream::StreamExt;
 use tracing::instrument;
 
-use crate::path_utils::remove_readonly_path;
+use super::{actor_input_stream, ActorInputStream, DEFAULT_ACTOR_CHANNEL_CAPACITY};
+use crate::{
+    error::{send_response, AgentError, AgentResult},
+    path_utils::remove_readonly_path,
+};
 
 #[derive(Builder)]
 pub struct Deleter {
     nix_store_dir: PathBuf,
     nar_info_cache_dir: PathBuf,
+    #[builder(default = "DEFAULT_ACTOR_CHANNEL_CAPACITY")]
+    channel_capacity: usize,
 }
 
 pub enum DeleterRequest {
     DeletePackages {
         package_ids: HashSet<String>,
-        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+        live_package_ids: HashSet<String>,
+        resp_tx: oneshot::Sender<AgentResult<()>>,
     },
     Shutdown,
 }
@@ -29,6 +36,7 @@ pub enum DeleterRequest {
 pub struct StartedDeleter {
     task: JoinHandle<anyhow::Result<()>>,
     input: StartedDeleterInput,
+    shutdown_tx: oneshot::Sender<()>,
 }
 
 #[derive(Clone, Debug)]
@@ -42,7 +50,8 @@ impl StartedDeleter {
     }
 
     pub async fn shutdown(self) -> anyhow::Result<()> {
-        self.input.input_tx.send(DeleterRequest::Shutdown).await?;
+        // If the task already stopped, waiting for it tells us why.
+        let _ = self.shutdown_tx.send(());
         self.task.await?
     }
 }
@@ -56,17 +65,26 @@ impl Deref for StartedDeleter {
 }
 
 impl StartedDeleterInput {
-    pub async fn delete_packages(&self, package_ids: HashSet<String>) -> anyhow::Result<()> {
+    /// Deletes the given packages, except for any of them that are also in `live_package_ids`.
+    pub async fn delete_packages(
+        &self,
+        package_ids: HashSet<String>,
+        live_package_ids: HashSet<String>,
+    ) -> AgentResult<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
 
         self.input_tx
             .send(DeleterRequest::DeletePackages {
                 package_ids,
+                live_package_ids,
                 resp_tx,
             })
-            .await?;
+            .await
+            .map_err(|_| AgentError::channel_closed("deleter"))?;
 
-        resp_rx.await?
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("deleter"))?
     }
 }
 
@@ -76,17 +94,20 @@ impl Deleter {
     }
 
     pub fn start(self) -> StartedDeleter {
-        let (input_tx, input_rx) = mpsc::channel(10);
+        let (input_tx, input_rx) = mpsc::channel(self.channel_capacity);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let input_stream = actor_input_stream(input_rx, shutdown_rx, |()| DeleterRequest::Shutdown);
 
         let task = tokio::spawn(deleter_task(
             self.nix_store_dir,
             self.nar_info_cache_dir,
-            input_rx,
+            input_stream,
         ));
 
         StartedDeleter {
             task,
             input: StartedDeleterInput { input_tx },
+            shutdown_tx,
         }
     }
 }
@@ -95,10 +116,8 @@ impl Deleter {
 async fn deleter_task(
     nix_store_dir: PathBuf,
     nar_info_cache_dir: PathBuf,
-    input_rx: mpsc::Receiver<DeleterRequest>,
+    mut input_stream: ActorInputStream<DeleterRequest>,
 ) -> anyhow::Result<()> {
-    let mut input_stream = ReceiverStream::new(input_rx);
-
     tracing::info!("Deleter will now enter its main loop.");
 
     while let Some(req) = input_stream.next().await {
@@ -109,6 +128,7 @@ async fn deleter_task(
             }
             DeleterRequest::DeletePackages {
                 package_ids,
+                live_package_ids,
                 resp_tx,
             } => {
                 let nix_store_dir_clone = nix_store_dir.clone();
@@ -116,16 +136,37 @@ async fn deleter_task(
                 // Enclosed in a new task so we can easily catch any errors.
                 let delete_task = tokio::spawn(async move {
                     for package_id in package_ids {
+                        // This is only a safety net: the packages we're asked to delete should never be live, but deleting a live package could break the running system.
+                        if live_package_ids.contains(&package_id) {
+                            tracing::warn!(
+                                package_id,
+                                "Refusing to delete a package that is still part of a tracked configuration."
+                            );
+                            continue;
+                        }
+
+                        // Something in the store that isn't a valid store path wasn't put there by Nix or by us (e.g. Nix's `.links` directory), so it's safer to leave it alone.
+                        let store_path = match StorePath::from_package_id(&package_id) {
+                            Ok(store_path) => store_path,
+                            Err(err) => {
+                                tracing::warn!(
+                                    ?err,
+                                    package_id,
+                                    "Refusing to delete something that isn't a valid store path."
+                                );
+                                continue;
+                            }
+                        };
+
                         let package_path = nix_store_dir_clone.join(&package_id);
 
                         if !package_path.exists() {
                             continue;
                         }
 
-                        let cached_nar_info_path = package_id
-                            .split_once("-")
-                            .map(|(hash, _name)| nar_info_cache_dir_clone.join(hash))
-                            .filter(|p| p.exists());
+                        let cached_nar_info_path =
+                            Some(nar_info_cache_dir_clone.join(store_path.hash()))
+                                .filter(|p| p.exists());
 
                         if let Some(cached_nar_info_path) = cached_nar_info_path {
                             let res = tokio::join!(
@@ -141,10 +182,8 @@ async fn deleter_task(
                     Ok(())
                 });
 
-                let res = delete_task.await?;
-                resp_tx
-                    .send(res)
-                    .map_err(|_| anyhow!("channel closed before we could send the response"))?;
+                let res = delete_task.await?.map_err(AgentError::Deletion);
+                send_response(resp_tx, res, "deleter");
             }
         }
     }
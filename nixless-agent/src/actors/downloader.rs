@@ -1,29 +1,48 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    fmt,
+    future::Future,
     ops::Deref,
+    os::fd::AsRawFd,
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use anyhow::{anyhow, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
 use derive_builder::Builder;
 use futures::StreamExt;
 use narinfo::{NarInfo, NixCacheInfo};
-use nix_core::{to_nix32, NixStylePublicKey, PublicKeychain};
-use reqwest::header::{HeaderMap, HeaderValue};
+use nix::{
+    errno::Errno,
+    fcntl::{fallocate, FallocateFlags},
+};
+use nix_core::{to_nix32, NixStylePublicKey, PublicKeychain, StorePath};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use tokio::{
     fs::File,
-    io::{AsyncWriteExt, BufWriter},
-    sync::{mpsc, oneshot},
+    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
+    sync::{mpsc, oneshot, watch},
     task::JoinHandle,
 };
-use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::io::{InspectWriter, StreamReader};
 use tracing::instrument;
 use xz_decoder::XZDecoder;
 
+use super::{actor_input_stream, ActorInputStream, DEFAULT_ACTOR_CHANNEL_CAPACITY};
 use crate::{
-    fingerprint::Fingerprint, owned_nar_info::OwnedNarInfo, path_utils::collect_nix_store_packages,
+    content_address::verify_content_address,
+    error::{send_response, AgentError, AgentResult},
+    fingerprint::Fingerprint,
+    limited_writer::LimitedWriter,
+    metrics, netrc,
+    owned_nar_info::OwnedNarInfo,
+    path_utils::collect_nix_store_packages,
 };
 
 #[derive(Builder)]
@@ -32,23 +51,190 @@ pub struct Downloader {
     temp_download_path: PathBuf,
     cache_url: String,
     cache_auth_token: Option<String>,
+    #[builder(default)]
+    cache_auth_scheme: CacheAuthScheme,
+    cache_netrc_file: Option<PathBuf>,
     cache_public_key: Option<String>,
+    #[builder(default)]
+    cache_proxy: Option<CacheProxy>,
+    cache_connection_settings: CacheConnectionSettings,
+    /// Added to the `User-Agent` header sent to the cache, after the agent's name and version.
+    #[builder(default)]
+    cache_user_agent_suffix: Option<String>,
     max_parallel_nar_downloads: usize,
+    max_nar_info_size: u64,
+    nar_download_timeout: Duration,
     nar_info_cache_dir: PathBuf,
+    preallocate_nar_files: bool,
+    /// Set when the agent will resume a configuration switch that may still need the NARs already in the temporary download directory. Otherwise, anything in there is left over from interrupted downloads and is removed when the downloader starts.
+    #[builder(default)]
+    keep_temp_downloads: bool,
+    #[builder(default = "DEFAULT_ACTOR_CHANNEL_CAPACITY")]
+    channel_capacity: usize,
 }
 
 pub enum DownloaderRequest {
     DownloadPackages {
         package_ids: HashSet<String>,
-        resp_tx: oneshot::Sender<anyhow::Result<Vec<NarDownloadResult>>>,
+        /// Sent in the `X-Request-Id` header of every request to the cache made for these packages, so the cache can tell which requests belong together.
+        request_id: String,
+        /// The span the request was made in, so the span of each download becomes part of it (e.g. part of the switch the packages are being downloaded for).
+        span: tracing::Span,
+        resp_tx: oneshot::Sender<AgentResult<Vec<NarDownloadResult>>>,
+    },
+    /// Replaces the settings used to trust and authenticate with the binary cache. Takes effect for the next download request.
+    Reload {
+        cache_auth_token: Option<String>,
+        cache_auth_scheme: CacheAuthScheme,
+        cache_netrc_file: Option<PathBuf>,
+        cache_public_key: Option<String>,
+        cache_proxy: Option<CacheProxy>,
+        resp_tx: oneshot::Sender<AgentResult<()>>,
     },
     Shutdown,
 }
 
+/// How the cache authorization token is sent to the cache. Parsed from:
+/// - `bearer`: sends `Authorization: bearer <token>`, with the lowercase scheme the agent always used.
+/// - `basic`: the token must be `<username>:<password>`, which gets encoded and sent with basic authentication.
+/// - `header:<name>`: sends the token as-is in the header with the given name.
+/// - Any other word is used as the scheme in `Authorization: <scheme> <token>`, e.g. `Bearer` or `Token`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CacheAuthScheme {
+    Scheme(String),
+    Basic,
+    Header(HeaderName),
+}
+
+impl Default for CacheAuthScheme {
+    fn default() -> Self {
+        Self::Scheme("bearer".to_string())
+    }
+}
+
+impl CacheAuthScheme {
+    fn header(&self, token: &str) -> anyhow::Result<(HeaderName, HeaderValue)> {
+        let (header_name, value) = match self {
+            Self::Scheme(scheme) => (AUTHORIZATION, format!("{} {}", scheme, token)),
+            Self::Basic => {
+                if !token.contains(':') {
+                    return Err(anyhow!("the cache authorization token must be in the format <username>:<password> to be used with basic authentication"));
+                }
+                (AUTHORIZATION, format!("Basic {}", STANDARD.encode(token)))
+            }
+            Self::Header(header_name) => (header_name.clone(), token.to_string()),
+        };
+
+        let mut header_value = HeaderValue::from_str(&value)
+            .context("the cache authorization token can't be sent in a header")?;
+        header_value.set_sensitive(true);
+        Ok((header_name, header_value))
+    }
+}
+
+impl FromStr for CacheAuthScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "basic" {
+            return Ok(Self::Basic);
+        }
+
+        if let Some(header_name) = s.strip_prefix("header:") {
+            return HeaderName::from_str(header_name)
+                .map(Self::Header)
+                .map_err(|_| anyhow!("invalid header name: {}", header_name));
+        }
+
+        // Schemes are HTTP tokens, which can't be empty or have spaces or separators in them.
+        if s.is_empty()
+            || !s
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+        {
+            return Err(anyhow!(
+                "expected bearer, basic, header:<name> or another authorization scheme, got {}",
+                s
+            ));
+        }
+
+        Ok(Self::Scheme(s.to_string()))
+    }
+}
+
+impl fmt::Display for CacheAuthScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Scheme(scheme) => write!(f, "{}", scheme),
+            Self::Basic => write!(f, "basic"),
+            Self::Header(header_name) => write!(f, "header:{}", header_name),
+        }
+    }
+}
+
+/// An HTTP(S) proxy that every request to the binary cache goes through.
+#[derive(Clone, Debug)]
+pub struct CacheProxy {
+    pub url: String,
+    /// In the format "<username>:<password>".
+    pub credentials: Option<String>,
+}
+
+/// Which HTTP version to use when talking to the binary cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheHttpVersion {
+    /// Negotiate HTTP/2 (through ALPN) with caches over HTTPS, falling back to HTTP/1.1 if the cache doesn't support it. Caches over plain HTTP always get HTTP/1.1.
+    #[default]
+    Auto,
+    /// Always use HTTP/1.1.
+    Http1,
+    /// Use HTTP/2 right away without negotiating it, which also works over plain HTTP. There's no fallback, so every request fails if the cache doesn't support HTTP/2.
+    Http2PriorKnowledge,
+}
+
+/// The oldest TLS version accepted when talking to the binary cache over HTTPS. Older versions aren't supported by the TLS library we use at all.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ValueEnum)]
+pub enum CacheTlsVersion {
+    #[value(name = "1.2")]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// How connections to the binary cache are made and reused.
+#[derive(Clone, Debug)]
+pub struct CacheConnectionSettings {
+    pub http_version: CacheHttpVersion,
+    pub pool_max_idle_per_host: usize,
+    /// `None` disables keep-alives.
+    pub keep_alive_interval: Option<Duration>,
+    /// PEM file with CA certificates to trust on top of the usual ones, for caches behind a private CA. It's read every time the client is built, so reloading picks up a rotated CA.
+    pub ca_cert: Option<PathBuf>,
+    /// `None` accepts every TLS version the TLS library supports.
+    pub min_tls_version: Option<CacheTlsVersion>,
+    /// Skips verifying the certificate of the cache altogether. Only meant for labs.
+    pub insecure: bool,
+}
+
+/// How far along the latest download request is. Only NARs we actually have to download count, so packages we already had locally don't show up here.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DownloadProgress {
+    pub nars_downloaded: u64,
+    pub nars_total: u64,
+    /// Compressed bytes, as they come from the binary cache.
+    pub bytes_downloaded: u64,
+    /// Only includes NARs whose narinfo we already fetched, so this keeps growing until the last downloads start.
+    pub bytes_total: u64,
+}
+
 #[derive(Debug)]
 pub struct StartedDownloader {
     task: JoinHandle<anyhow::Result<()>>,
     input: StartedDownloaderInput,
+    shutdown_tx: oneshot::Sender<()>,
 }
 
 impl StartedDownloader {
@@ -57,11 +243,8 @@ impl StartedDownloader {
     }
 
     pub async fn shutdown(self) -> anyhow::Result<()> {
-        self.input
-            .input_tx
-            .send(DownloaderRequest::Shutdown)
-            .await?;
-
+        // If the task already stopped, waiting for it tells us why.
+        let _ = self.shutdown_tx.send(());
         self.task.await?
     }
 }
@@ -77,23 +260,67 @@ impl Deref for StartedDownloader {
 #[derive(Clone, Debug)]
 pub struct StartedDownloaderInput {
     input_tx: mpsc::Sender<DownloaderRequest>,
+    progress_rx: watch::Receiver<DownloadProgress>,
 }
 
 impl StartedDownloaderInput {
+    /// Doesn't go through the downloader's queue, so this is always answered right away, even in the middle of a big download.
+    pub fn progress(&self) -> DownloadProgress {
+        self.progress_rx.borrow().clone()
+    }
+
+    /// Lets callers follow the progress of a download as it goes, instead of polling `progress()`.
+    pub fn watch_progress(&self) -> watch::Receiver<DownloadProgress> {
+        self.progress_rx.clone()
+    }
+
     pub async fn download_packages(
         &self,
         package_ids: HashSet<String>,
-    ) -> anyhow::Result<Vec<NarDownloadResult>> {
+        request_id: String,
+    ) -> AgentResult<Vec<NarDownloadResult>> {
         let (resp_tx, resp_rx) = oneshot::channel();
 
         self.input_tx
             .send(DownloaderRequest::DownloadPackages {
                 package_ids,
+                request_id,
+                span: tracing::Span::current(),
                 resp_tx,
             })
-            .await?;
+            .await
+            .map_err(|_| AgentError::channel_closed("downloader"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("downloader"))?
+    }
+
+    pub async fn reload(
+        &self,
+        cache_auth_token: Option<String>,
+        cache_auth_scheme: CacheAuthScheme,
+        cache_netrc_file: Option<PathBuf>,
+        cache_public_key: Option<String>,
+        cache_proxy: Option<CacheProxy>,
+    ) -> AgentResult<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
 
-        resp_rx.await?
+        self.input_tx
+            .send(DownloaderRequest::Reload {
+                cache_auth_token,
+                cache_auth_scheme,
+                cache_netrc_file,
+                cache_public_key,
+                cache_proxy,
+                resp_tx,
+            })
+            .await
+            .map_err(|_| AgentError::channel_closed("downloader"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("downloader"))?
     }
 }
 
@@ -103,7 +330,11 @@ impl Downloader {
     }
 
     pub fn start(self) -> StartedDownloader {
-        let (input_tx, input_rx) = mpsc::channel(10);
+        let (input_tx, input_rx) = mpsc::channel(self.channel_capacity);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let input_stream =
+            actor_input_stream(input_rx, shutdown_rx, |()| DownloaderRequest::Shutdown);
+        let (progress_tx, progress_rx) = watch::channel(DownloadProgress::default());
 
         let task = tokio::spawn(async move {
             match downloader_task(
@@ -111,10 +342,20 @@ impl Downloader {
                 self.temp_download_path,
                 self.cache_url,
                 self.cache_auth_token,
+                self.cache_auth_scheme,
+                self.cache_netrc_file,
                 self.cache_public_key,
+                self.cache_proxy,
+                self.cache_connection_settings,
+                self.cache_user_agent_suffix,
                 self.max_parallel_nar_downloads,
+                self.max_nar_info_size,
+                self.nar_download_timeout,
                 self.nar_info_cache_dir,
-                input_rx,
+                self.preallocate_nar_files,
+                self.keep_temp_downloads,
+                input_stream,
+                progress_tx,
             )
             .await
             {
@@ -131,31 +372,83 @@ impl Downloader {
 
         StartedDownloader {
             task,
-            input: StartedDownloaderInput { input_tx },
+            input: StartedDownloaderInput {
+                input_tx,
+                progress_rx,
+            },
+            shutdown_tx,
         }
     }
 }
 
+/// Removes everything inside the temporary download directory, but keeps the directory itself. Returns how many bytes were reclaimed.
+async fn sweep_temp_downloads(temp_download_path: &Path) -> anyhow::Result<u64> {
+    let mut top_level_entries = match tokio::fs::read_dir(temp_download_path).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut reclaimed_bytes = 0;
+    while let Some(top_level_entry) = top_level_entries.next_entry().await? {
+        // NAR URLs usually have a directory in them (e.g. `nar/<hash>.nar.xz`), so we have to go through any directories to know how much we're reclaiming.
+        let mut paths = vec![top_level_entry.path()];
+        while let Some(path) = paths.pop() {
+            let metadata = tokio::fs::symlink_metadata(&path).await?;
+            if metadata.is_dir() {
+                let mut entries = tokio::fs::read_dir(&path).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    paths.push(entry.path());
+                }
+            } else {
+                reclaimed_bytes += metadata.len();
+            }
+        }
+
+        if top_level_entry.file_type().await?.is_dir() {
+            tokio::fs::remove_dir_all(top_level_entry.path()).await?;
+        } else {
+            tokio::fs::remove_file(top_level_entry.path()).await?;
+        }
+    }
+
+    Ok(reclaimed_bytes)
+}
+
 #[instrument(skip_all)]
 async fn downloader_task(
     nix_store_dir: String,
     temp_download_path: PathBuf,
     cache_url: String,
     cache_auth_token: Option<String>,
+    cache_auth_scheme: CacheAuthScheme,
+    cache_netrc_file: Option<PathBuf>,
     cache_public_key: Option<String>,
+    cache_proxy: Option<CacheProxy>,
+    cache_connection_settings: CacheConnectionSettings,
+    cache_user_agent_suffix: Option<String>,
     max_parallel_nar_downloads: usize,
+    max_nar_info_size: u64,
+    nar_download_timeout: Duration,
     nar_info_cache_dir: PathBuf,
-    input_rx: mpsc::Receiver<DownloaderRequest>,
+    preallocate_nar_files: bool,
+    keep_temp_downloads: bool,
+    mut input_stream: ActorInputStream<DownloaderRequest>,
+    progress_tx: watch::Sender<DownloadProgress>,
 ) -> anyhow::Result<()> {
-    let mut keychain = PublicKeychain::with_known_keys()?;
+    let mut keychain = build_keychain(cache_public_key.as_deref())?;
 
-    if let Some(cache_public_key) = cache_public_key {
-        tracing::info!(
-            cache_public_key,
-            "Adding the configured public key of the binary cache as a trusted key."
-        );
-
-        keychain.add_key(NixStylePublicKey::from_nix_format(&cache_public_key)?)?;
+    if keep_temp_downloads {
+        tracing::info!("Keeping any existing temporary downloads, since they may be needed by the configuration switch we'll resume.");
+    } else {
+        match sweep_temp_downloads(&temp_download_path).await {
+            Ok(reclaimed_bytes) => tracing::info!(
+                reclaimed_bytes,
+                "Removed temporary downloads left over from interrupted downloads."
+            ),
+            // Leftover files only take space, so this shouldn't stop us from working.
+            Err(err) => tracing::warn!(?err, "Failed to remove leftover temporary downloads."),
+        }
     }
 
     tracing::info!(
@@ -170,17 +463,16 @@ async fn downloader_task(
         "Finished reading the nix store to determine all existing packages."
     );
 
-    let mut default_headers = HeaderMap::new();
-
-    if let Some(token) = cache_auth_token {
-        let mut header_value = HeaderValue::from_str(&format!("bearer {}", token))?;
-        header_value.set_sensitive(true);
-        default_headers.insert("authorization", header_value);
-    }
-
-    let client = reqwest::Client::builder()
-        .default_headers(default_headers)
-        .build()?;
+    let mut client = build_client(
+        &cache_url,
+        cache_auth_token.as_deref(),
+        &cache_auth_scheme,
+        cache_netrc_file.as_deref(),
+        cache_proxy.as_ref(),
+        &cache_connection_settings,
+        cache_user_agent_suffix.as_deref(),
+    )
+    .await?;
 
     tracing::debug!(
         cache_url,
@@ -221,9 +513,9 @@ async fn downloader_task(
         tokio::fs::create_dir(&nar_info_cache_dir).await?;
     }
 
-    tracing::info!("Downloader has finished initialisation and will now enter its main loop.");
+    let nar_info_fetches = NarInfoFetches::default();
 
-    let mut input_stream = ReceiverStream::new(input_rx);
+    tracing::info!("Downloader has finished initialisation and will now enter its main loop.");
 
     while let Some(req) = input_stream.next().await {
         match req {
@@ -231,26 +523,96 @@ async fn downloader_task(
                 tracing::info!("Downloader got request to shutdown. Proceeding.");
                 break;
             }
+            DownloaderRequest::Reload {
+                cache_auth_token,
+                cache_auth_scheme,
+                cache_netrc_file,
+                cache_public_key,
+                cache_proxy,
+                resp_tx,
+            } => {
+                tracing::info!("Downloader got a request to reload its cache settings.");
+
+                // We only replace the keychain and the client if both could be built, so a bad reload keeps the previous settings working.
+                let reloaded = match build_keychain(cache_public_key.as_deref()) {
+                    Ok(new_keychain) => build_client(
+                        &cache_url,
+                        cache_auth_token.as_deref(),
+                        &cache_auth_scheme,
+                        cache_netrc_file.as_deref(),
+                        cache_proxy.as_ref(),
+                        &cache_connection_settings,
+                        cache_user_agent_suffix.as_deref(),
+                    )
+                    .await
+                    .map(|new_client| (new_keychain, new_client)),
+                    Err(err) => Err(err),
+                };
+
+                let resp = match reloaded {
+                    Ok((new_keychain, new_client)) => {
+                        keychain = new_keychain;
+                        client = new_client;
+                        tracing::info!("Downloader reloaded its cache settings.");
+                        Ok(())
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            ?err,
+                            "Failed to reload the cache settings, will keep using the previous ones."
+                        );
+                        Err(AgentError::Download(err))
+                    }
+                };
+
+                send_response(resp_tx, resp, "downloader");
+            }
             DownloaderRequest::DownloadPackages {
                 package_ids,
+                request_id,
+                span,
                 resp_tx,
             } => {
+                let client = client.with_request_id(request_id);
                 let mut download_futures = Vec::new();
                 let mut existing_package_ids = Vec::new();
 
                 for package_id in package_ids {
+                    // The set of existing packages is optimistic (it gets updated before the packages are actually unpacked), so we double check the package is really there before skipping it. If it isn't, we forget about it and download it again.
+                    if existing_store_package_ids.contains(&package_id)
+                        && Path::new(&nix_store_dir)
+                            .join(&package_id)
+                            .symlink_metadata()
+                            .is_err()
+                    {
+                        tracing::warn!(package_id, "Package was expected to be in the Nix store, but it isn't. Will download it again.");
+                        existing_store_package_ids.remove(&package_id);
+                    }
+
                     if existing_store_package_ids.contains(&package_id) {
+                        metrics::downloads::nars_skipped().inc();
                         existing_package_ids.push(package_id);
                         continue;
                     }
 
-                    download_futures.push(download_one_nar(
-                        client.clone(),
-                        &temp_download_path,
-                        &nar_info_cache_dir,
-                        &cache_url,
+                    let download = span.in_scope(|| {
+                        download_one_nar(
+                            client.clone(),
+                            &nar_info_fetches,
+                            &temp_download_path,
+                            &nar_info_cache_dir,
+                            &cache_url,
+                            max_nar_info_size,
+                            package_id.clone(),
+                            &keychain,
+                            preallocate_nar_files,
+                            &progress_tx,
+                        )
+                    });
+                    download_futures.push(with_download_timeout(
+                        download,
+                        nar_download_timeout,
                         package_id,
-                        &keychain,
                     ));
                 }
 
@@ -260,14 +622,20 @@ async fn downloader_task(
                     "Started task to download any missing packages."
                 );
 
+                progress_tx.send_replace(DownloadProgress {
+                    nars_total: download_futures.len() as u64,
+                    ..Default::default()
+                });
+
                 let download_futures = futures::stream::iter(download_futures);
                 // We need to collect from the stream into a Vec of Results first, because the stream doesn't allow us to directly convert from a Vec of Results into a Result of Vec.
-                let mut download_results: Result<Vec<_>, _> = download_futures
+                let mut download_results: AgentResult<Vec<_>> = download_futures
                     .buffer_unordered(max_parallel_nar_downloads)
                     .collect::<Vec<_>>()
                     .await
                     .into_iter()
-                    .collect();
+                    .collect::<anyhow::Result<_>>()
+                    .map_err(|err| AgentError::categorise(err, AgentError::Download));
 
                 tracing::info!("Finished downloading all missing packages.");
 
@@ -280,15 +648,21 @@ async fn downloader_task(
                     for existing_package_id in existing_package_ids {
                         let nar_info = cached_download_nar_info(
                             &client,
+                            &nar_info_fetches,
                             &nar_info_cache_dir,
                             &cache_url,
+                            max_nar_info_size,
                             &existing_package_id,
                         )
                         .await?;
                         curr_download_results.push(NarDownloadResult {
+                            reference_ids: dependency_ids(
+                                &existing_package_id,
+                                nar_info.references,
+                            ),
                             package_id: existing_package_id,
                             nar_path: temp_download_path.join(nar_info.url),
-                            reference_ids: nar_info.references,
+                            nar_hash: nar_info.nar_hash,
                             is_already_unpacked: true,
                         });
                     }
@@ -301,25 +675,42 @@ async fn downloader_task(
                             existing_store_package_ids.insert(r.package_id.clone());
                         });
 
-                        // We'll check that all references for the NARs we downloaded exist (or will exist) locally, otherwise we'll have to error to prevent the system from pointing to a path that doesn't exist.
-                        if download_results.iter().any(|r| {
-                            r.reference_ids
-                                .iter()
-                                .any(|rp| !existing_store_package_ids.contains(rp))
-                        }) {
-                            Err(anyhow!(
-                                "the paths that were downloaded have missing references!"
-                            ))
-                        } else {
+                        // We'll check that the whole closure of the NARs we downloaded exists (or will exist) locally, otherwise we'll have to error to prevent the system from pointing to a path that doesn't exist.
+                        let missing_ids = find_missing_closure_references(
+                            &download_results,
+                            &existing_store_package_ids,
+                            max_parallel_nar_downloads,
+                            |package_id| {
+                                let (client, nar_info_fetches, nar_info_cache_dir, cache_url) =
+                                    (&client, &nar_info_fetches, &nar_info_cache_dir, &cache_url);
+                                async move {
+                                    cached_download_nar_info(
+                                        client,
+                                        nar_info_fetches,
+                                        nar_info_cache_dir,
+                                        cache_url,
+                                        max_nar_info_size,
+                                        &package_id,
+                                    )
+                                    .await
+                                }
+                            },
+                        )
+                        .await;
+
+                        if missing_ids.is_empty() {
                             Ok(download_results)
+                        } else {
+                            Err(AgentError::Download(anyhow!(
+                                "the paths that were downloaded have missing references in their closure: {}",
+                                missing_ids.join(", ")
+                            )))
                         }
                     }
                     err => err,
                 };
 
-                resp_tx.send(resp).map_err(|_| {
-                    anyhow!("the channel got closed before we could send a message to it!")
-                })?;
+                send_response(resp_tx, resp, "downloader");
             }
         }
     }
@@ -328,23 +719,328 @@ async fn downloader_task(
     Ok(())
 }
 
+/// Walks the references of the given packages transitively and returns every package in their closure that doesn't exist locally. The packages we were asked for have their references already, but anything they reference that we already had is only known through its narinfo, which is usually cached locally.
+/// Packages we already had are in the store whatever their narinfo says, so failing to get the narinfo of one of them only stops the walk there instead of failing the whole check.
+async fn find_missing_closure_references<F, Fut>(
+    download_results: &[NarDownloadResult],
+    existing_store_package_ids: &HashSet<String>,
+    max_parallel_fetches: usize,
+    fetch_nar_info: F,
+) -> Vec<String>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<OwnedNarInfo>>,
+{
+    let mut visited: HashSet<String> = download_results
+        .iter()
+        .map(|r| r.package_id.clone())
+        .collect();
+    let mut to_visit: Vec<String> = download_results
+        .iter()
+        .flat_map(|r| r.reference_ids.iter())
+        .filter(|id| !visited.contains(*id))
+        .cloned()
+        .collect();
+    let mut missing_ids = Vec::new();
+
+    // We go one level of references at a time, so the narinfos of each level can be fetched concurrently.
+    while !to_visit.is_empty() {
+        let mut present_ids = Vec::new();
+        for package_id in std::mem::take(&mut to_visit) {
+            if !visited.insert(package_id.clone()) {
+                continue;
+            }
+
+            if existing_store_package_ids.contains(&package_id) {
+                present_ids.push(package_id);
+            } else {
+                missing_ids.push(package_id);
+            }
+        }
+
+        let nar_infos: Vec<_> = futures::stream::iter(present_ids)
+            .map(|package_id| {
+                let nar_info = fetch_nar_info(package_id.clone());
+                async move { (package_id, nar_info.await) }
+            })
+            .buffer_unordered(max_parallel_fetches)
+            .collect()
+            .await;
+
+        for (package_id, nar_info) in nar_infos {
+            match nar_info {
+                Ok(nar_info) => to_visit.extend(
+                    dependency_ids(&package_id, nar_info.references)
+                        .into_iter()
+                        .filter(|id| !visited.contains(id)),
+                ),
+                Err(err) => tracing::warn!(
+                    package_id,
+                    ?err,
+                    "Couldn't get the narinfo of a package we already have, so we won't check the rest of the closure through it."
+                ),
+            }
+        }
+    }
+
+    tracing::debug!(
+        closure_size = visited.len(),
+        missing = missing_ids.len(),
+        "Finished checking the closure of the downloaded packages."
+    );
+
+    missing_ids
+}
+
+/// Trusts the well-known keys plus the configured public key of the binary cache, if any.
+fn build_keychain(cache_public_key: Option<&str>) -> anyhow::Result<PublicKeychain> {
+    let mut keychain = PublicKeychain::with_known_keys()?;
+
+    if let Some(cache_public_key) = cache_public_key {
+        tracing::info!(
+            cache_public_key,
+            "Adding the configured public key of the binary cache as a trusted key."
+        );
+
+        keychain.add_key(NixStylePublicKey::from_nix_format(cache_public_key)?)?;
+    }
+
+    Ok(keychain)
+}
+
+/// Wraps the client used for every request to the binary cache, so requests made for a single download request all carry the same `X-Request-Id` header.
+#[derive(Clone)]
+struct CacheClient {
+    client: reqwest::Client,
+    request_id: Option<Arc<str>>,
+}
+
+impl CacheClient {
+    fn with_request_id(&self, request_id: String) -> Self {
+        Self {
+            client: self.client.clone(),
+            request_id: Some(request_id.into()),
+        }
+    }
+
+    fn get(&self, url: String) -> reqwest::RequestBuilder {
+        let request = self.client.get(url);
+
+        match &self.request_id {
+            Some(request_id) => request.header("x-request-id", request_id.as_ref()),
+            None => request,
+        }
+    }
+}
+
+/// Builds the client used for every request to the binary cache, authenticating with the cache if we were given a way to do so, and going through the cache proxy if there's one.
+async fn build_client(
+    cache_url: &str,
+    cache_auth_token: Option<&str>,
+    cache_auth_scheme: &CacheAuthScheme,
+    cache_netrc_file: Option<&Path>,
+    cache_proxy: Option<&CacheProxy>,
+    connection_settings: &CacheConnectionSettings,
+    user_agent_suffix: Option<&str>,
+) -> anyhow::Result<CacheClient> {
+    let mut default_headers = HeaderMap::new();
+
+    // An explicitly configured token takes precedence over anything we'd find in a netrc file.
+    if let Some(token) = cache_auth_token {
+        let (header_name, header_value) = cache_auth_scheme.header(token)?;
+        default_headers.insert(header_name, header_value);
+    } else if let Some(netrc_file) = cache_netrc_file {
+        let cache_host = reqwest::Url::parse(cache_url)?
+            .host_str()
+            .ok_or_else(|| anyhow!("the cache URL doesn't have a host"))?
+            .to_string();
+        let netrc_contents = tokio::fs::read_to_string(netrc_file)
+            .await
+            .with_context(|| format!("failed to read the netrc file {}", netrc_file.display()))?;
+
+        if let Some(credentials) = netrc::find_credentials(&netrc_contents, &cache_host) {
+            tracing::info!(
+                cache_host,
+                "Found credentials for the cache in the netrc file."
+            );
+
+            let encoded_credentials =
+                STANDARD.encode(format!("{}:{}", credentials.login, credentials.password));
+            let mut header_value =
+                HeaderValue::from_str(&format!("Basic {}", encoded_credentials))?;
+            header_value.set_sensitive(true);
+            default_headers.insert("authorization", header_value);
+        } else {
+            tracing::warn!(
+                cache_host,
+                "The netrc file doesn't have credentials for the cache, so we won't authenticate with it."
+            );
+        }
+    }
+
+    // Lets cache operators tell our traffic apart from anyone else's.
+    let user_agent = match user_agent_suffix {
+        Some(suffix) => format!("nixless-agent/{} {}", env!("CARGO_PKG_VERSION"), suffix),
+        None => format!("nixless-agent/{}", env!("CARGO_PKG_VERSION")),
+    };
+
+    let mut client_builder = reqwest::Client::builder()
+        .user_agent(user_agent)
+        .default_headers(default_headers)
+        .pool_max_idle_per_host(connection_settings.pool_max_idle_per_host);
+
+    client_builder = match connection_settings.http_version {
+        CacheHttpVersion::Auto => client_builder,
+        CacheHttpVersion::Http1 => client_builder.http1_only(),
+        CacheHttpVersion::Http2PriorKnowledge => client_builder.http2_prior_knowledge(),
+    };
+
+    if let Some(keep_alive_interval) = connection_settings.keep_alive_interval {
+        // TCP keep-alives stop idle HTTP/1.1 connections in the pool from being dropped along the way, and HTTP/2 pings do the same for the connection that HTTP/2 requests share.
+        client_builder = client_builder
+            .tcp_keepalive(keep_alive_interval)
+            .http2_keep_alive_interval(keep_alive_interval)
+            .http2_keep_alive_while_idle(true);
+    }
+
+    if let Some(ca_cert) = &connection_settings.ca_cert {
+        let ca_cert_pem = tokio::fs::read(ca_cert).await.with_context(|| {
+            format!(
+                "failed to read the CA certificates for the cache from {}",
+                ca_cert.display()
+            )
+        })?;
+        let certificates =
+            reqwest::Certificate::from_pem_bundle(&ca_cert_pem).with_context(|| {
+                format!(
+                    "failed to parse the CA certificates for the cache from {}",
+                    ca_cert.display()
+                )
+            })?;
+
+        if certificates.is_empty() {
+            return Err(anyhow!(
+                "there are no CA certificates for the cache in {}",
+                ca_cert.display()
+            ));
+        }
+
+        tracing::info!(
+            ca_cert = %ca_cert.display(),
+            count = certificates.len(),
+            "Trusting the configured CA certificates for the cache."
+        );
+
+        for certificate in certificates {
+            client_builder = client_builder.add_root_certificate(certificate);
+        }
+    }
+
+    if let Some(min_tls_version) = connection_settings.min_tls_version {
+        client_builder = client_builder.min_tls_version(match min_tls_version {
+            CacheTlsVersion::Tls12 => reqwest::tls::Version::TLS_1_2,
+            CacheTlsVersion::Tls13 => reqwest::tls::Version::TLS_1_3,
+        });
+    }
+
+    if connection_settings.insecure {
+        tracing::warn!("NOT VERIFYING THE CERTIFICATE OF THE CACHE! Anyone between us and the cache can see and change everything we get from it, and only the signatures of the narinfo stand in the way of tampered packages. This must never be used outside of a lab.");
+        client_builder = client_builder.danger_accept_invalid_certs(true);
+    }
+
+    // Without a proxy given to us, reqwest already goes through the proxy in the `HTTPS_PROXY`, `HTTP_PROXY` or `ALL_PROXY` environment variables (skipping the hosts in `NO_PROXY`), so we only have to deal with an explicit one.
+    if let Some(cache_proxy) = cache_proxy {
+        // An explicit proxy makes reqwest ignore the environment, but hosts in `NO_PROXY` should still be reached directly.
+        let mut proxy = reqwest::Proxy::all(&cache_proxy.url)
+            .context("the cache proxy URL is invalid")?
+            .no_proxy(reqwest::NoProxy::from_env());
+
+        if let Some(credentials) = &cache_proxy.credentials {
+            let (username, password) = credentials.split_once(':').ok_or_else(|| {
+                anyhow!("the cache proxy credentials must be in the format <username>:<password>")
+            })?;
+            proxy = proxy.basic_auth(username, password);
+        }
+
+        tracing::info!("Every request to the cache will go through the configured proxy.");
+        client_builder = client_builder.proxy(proxy);
+    }
+
+    let client = client_builder.build()?;
+
+    Ok(CacheClient {
+        client,
+        request_id: None,
+    })
+}
+
 pub struct NarDownloadResult {
     pub package_id: String,
     pub nar_path: PathBuf,
+    /// In the same format as the narinfo, i.e. "sha256:<nix32 hash>".
+    pub nar_hash: String,
+    /// Every package this package references, except for the package itself.
     pub reference_ids: Vec<String>,
     pub is_already_unpacked: bool,
 }
 
+/// Fails the download with `AgentError::DownloadTimeout` if it takes longer than `timeout`, so a single stalled package doesn't keep one of the parallel download slots forever.
+async fn with_download_timeout(
+    download: impl Future<Output = anyhow::Result<NarDownloadResult>>,
+    timeout: Duration,
+    package_id: String,
+) -> anyhow::Result<NarDownloadResult> {
+    tokio::time::timeout(timeout, download).await.map_err(|_| {
+        metrics::downloads::nar_download_timeouts().inc();
+        anyhow::Error::new(AgentError::DownloadTimeout {
+            package_id,
+            timeout,
+        })
+    })?
+}
+
+/// The span of each download records how long each of its phases took, so a slow download can be attributed to fetching the narinfo or to fetching the NAR itself (which includes decompressing and hashing it, since those happen while it streams in).
+#[instrument(
+    skip_all,
+    fields(
+        package_id = %package_id,
+        compressed_bytes = tracing::field::Empty,
+        decompressed_bytes = tracing::field::Empty,
+        nar_info_fetch_secs = tracing::field::Empty,
+        nar_fetch_secs = tracing::field::Empty,
+    )
+)]
 async fn download_one_nar(
-    client: reqwest::Client,
+    client: CacheClient,
+    nar_info_fetches: &NarInfoFetches,
     download_dir: &PathBuf,
     nar_info_cache_dir: &Path,
     cache_url: &str,
+    max_nar_info_size: u64,
     package_id: String,
     keychain: &PublicKeychain,
+    preallocate_nar_files: bool,
+    progress_tx: &watch::Sender<DownloadProgress>,
 ) -> anyhow::Result<NarDownloadResult> {
-    let nar_info =
-        cached_download_nar_info(&client, nar_info_cache_dir, cache_url, &package_id).await?;
+    let span = tracing::Span::current();
+
+    let nar_info_timer = metrics::downloads::nar_info_fetch_duration().start_timer();
+    let nar_info = cached_download_nar_info(
+        &client,
+        nar_info_fetches,
+        nar_info_cache_dir,
+        cache_url,
+        max_nar_info_size,
+        &package_id,
+    )
+    .await?;
+    span.record(
+        "nar_info_fetch_secs",
+        nar_info_timer.stop_and_record().as_secs_f32(),
+    );
+    // The file size is missing when the NAR isn't compressed, in which case we download exactly the NAR.
+    let download_size = nar_info.file_size.unwrap_or(nar_info.nar_size) as u64;
+    progress_tx.send_modify(|progress| progress.bytes_total += download_size);
 
     let nar_hash_parts: Vec<_> = nar_info.nar_hash.split(":").collect();
     let ["sha256", nar_hash] = nar_hash_parts[..] else {
@@ -366,19 +1062,50 @@ async fn download_one_nar(
     };
 
     if !nar_info.verify_fingerprint(keychain)? {
-        return Err(anyhow!(
+        return Err(AgentError::Signature(anyhow!(
             "Couldn't verify the signature of the NAR we downloaded!"
-        ));
+        ))
+        .into());
     }
 
-    // TODO: as an optimisation, if the NAR file already exists in the download location, check if its hash matches what we got. If it does, we can skip downloading entirely.
+    verify_content_address(&nar_info, &package_id).map_err(AgentError::Signature)?;
 
     let nardata_url = format!("{}/{}", cache_url, nar_info.url);
     let mut local_nar_path = download_dir.join(nar_info.url);
 
+    // TODO: deal with multiple compression options for the NAR. Remember when "Compression: none" exists.
+
+    if let Some(ext) = local_nar_path.extension() {
+        if ext == "xz" {
+            local_nar_path = local_nar_path.with_extension("");
+        }
+    }
+
+    // NARs only stay in the download location until they're unpacked, so if one is already there, a previous download got interrupted before we got to unpack it (e.g. the agent restarted in the middle of a switch). If its hash matches, the file was fully written and we can pick up from it instead of downloading it again.
+    if nar_file_matches(&local_nar_path, nar_info.nar_size as u64, nar_hash).await? {
+        tracing::debug!(
+            package_id,
+            "NAR was already downloaded and verified, won't download it again."
+        );
+        metrics::downloads::nars_resumed().inc();
+        progress_tx.send_modify(|progress| {
+            progress.nars_downloaded += 1;
+            progress.bytes_downloaded += download_size;
+        });
+
+        return Ok(NarDownloadResult {
+            reference_ids: dependency_ids(&package_id, nar_info.references),
+            package_id,
+            nar_path: local_nar_path,
+            nar_hash: nar_info.nar_hash.clone(),
+            is_already_unpacked: false,
+        });
+    }
+
     // In case any of the parent directories don't exist, we create them.
     std::fs::create_dir_all(local_nar_path.parent().unwrap())?;
 
+    let nar_timer = metrics::downloads::nar_fetch_duration().start_timer();
     let resp = client
         .get(nardata_url)
         .header("accept", "application/x-nix-nar")
@@ -390,14 +1117,8 @@ async fn download_one_nar(
             result.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
         }));
 
-        // TODO: deal with multiple compression options for the NAR. Remember when "Compression: none" exists.
-
-        if let Some(ext) = local_nar_path.extension() {
-            if ext == "xz" {
-                local_nar_path = local_nar_path.with_extension("");
-            }
-        }
-        // We'll craft the following pipeline: (response body) -> (compressed hasher) -> (xz decoder) -> (decompressed hasher) -> (file writer) -> (file).
+        // We'll craft the following pipeline: (response body) -> (compressed hasher) -> (compressed size limit) -> (xz decoder) -> (decompressed hasher) -> (decompressed size limit) -> (file writer) -> (file).
+        // The size limits come from the narinfo, so a cache can't send us more data than it said the NAR has.
         let file = File::options()
             .create(true)
             .truncate(true)
@@ -405,7 +1126,18 @@ async fn download_one_nar(
             .open(&local_nar_path)
             .await?;
 
-        let file_writer = BufWriter::new(file);
+        // The file will hold the decompressed NAR, so that's the size we know it will end up with.
+        if preallocate_nar_files {
+            preallocate_file(&file, nar_info.nar_size as u64).with_context(|| {
+                format!(
+                    "failed to preallocate {} bytes for {}",
+                    nar_info.nar_size,
+                    local_nar_path.display()
+                )
+            })?;
+        }
+
+        let file_writer = LimitedWriter::new(BufWriter::new(file), nar_info.nar_size as u64);
 
         let mut decompressed_hasher = Sha256::new();
         let decompressed_inspector = InspectWriter::new(file_writer, |chunk| {
@@ -424,12 +1156,23 @@ async fn download_one_nar(
 
         // TODO: In case we don't have a `file_hash`, it would be a good idea to skip doing the hashing here, but the code got somewhat complicated and would need a bit of care to get right.
         let mut compressed_hasher = Sha256::new();
+        let decompresser = LimitedWriter::new(
+            decompresser,
+            nar_info.file_size.map_or(u64::MAX, |size| size as u64),
+        );
         let mut compressed_inspector = InspectWriter::new(decompresser, |chunk| {
             compressed_hasher.update(chunk);
+            progress_tx.send_modify(|progress| progress.bytes_downloaded += chunk.len() as u64);
         });
 
-        tokio::io::copy(&mut stream_reader, &mut compressed_inspector).await?;
-        compressed_inspector.flush().await?;
+        let compressed_bytes =
+            tokio::io::copy(&mut stream_reader, &mut compressed_inspector).await?;
+        // Shutting down (instead of only flushing) lets the decompresser finish the stream and write out anything it was still holding.
+        compressed_inspector.shutdown().await?;
+        metrics::downloads::compressed_bytes().inc_by(compressed_bytes);
+        span.record("nar_fetch_secs", nar_timer.stop_and_record().as_secs_f32());
+        span.record("compressed_bytes", compressed_bytes);
+        span.record("decompressed_bytes", nar_info.nar_size as u64);
 
         let decompressed_hash = to_nix32(&decompressed_hasher.finalize());
         if decompressed_hash != nar_hash {
@@ -451,21 +1194,15 @@ async fn download_one_nar(
             }
         }
 
+        metrics::downloads::nars_downloaded().inc();
+        progress_tx.send_modify(|progress| progress.nars_downloaded += 1);
+        tracing::debug!("Finished downloading NAR.");
+
         Ok(NarDownloadResult {
+            reference_ids: dependency_ids(&package_id, nar_info.references),
             package_id,
             nar_path: local_nar_path,
-            reference_ids: nar_info
-                .references
-                .into_iter()
-                .filter_map(|r| {
-                    let text = r.trim();
-                    if text.is_empty() {
-                        None
-                    } else {
-                        Some(text.to_string())
-                    }
-                })
-                .collect(),
+            nar_hash: nar_info.nar_hash.clone(),
             is_already_unpacked: false,
         })
     } else {
@@ -477,29 +1214,178 @@ async fn download_one_nar(
     }
 }
 
+/// Checks whether a decompressed NAR already exists at `path` with the size and hash given by its narinfo. Anything else (including a partially written file) means the NAR must be downloaded again.
+async fn nar_file_matches(path: &Path, nar_size: u64, nar_hash: &str) -> anyhow::Result<bool> {
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+
+    if file.metadata().await?.len() != nar_size {
+        return Ok(false);
+    }
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(to_nix32(&hasher.finalize()) == nar_hash)
+}
+
+/// Reserves `size` bytes of disk space for `file` without changing its size, so we find out right away if there isn't enough space, and the filesystem gets a chance to avoid fragmenting the file. Filesystems that can't do this just get normal writes.
+fn preallocate_file(file: &File, size: u64) -> anyhow::Result<()> {
+    if size == 0 {
+        return Ok(());
+    }
+
+    match fallocate(
+        file.as_raw_fd(),
+        FallocateFlags::FALLOC_FL_KEEP_SIZE,
+        0,
+        size.try_into()?,
+    ) {
+        Ok(()) => Ok(()),
+        Err(Errno::EOPNOTSUPP | Errno::ENOSYS) => {
+            tracing::debug!(
+                "The filesystem doesn't support preallocating files, so we'll skip it."
+            );
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Turns the references listed in a narinfo into the ids of the packages that must exist locally for this package to work. Packages often reference themselves, and that reference is satisfied by the package itself, so it's left out.
+fn dependency_ids(package_id: &str, references: Vec<String>) -> Vec<String> {
+    references
+        .into_iter()
+        .filter_map(|r| {
+            let text = r.trim();
+            if text.is_empty() || text == package_id {
+                None
+            } else {
+                Some(text.to_string())
+            }
+        })
+        .collect()
+}
+
+/// Narinfo fetches that are going on right now, keyed by the hash of their store path. A fetch of a narinfo that's already being fetched waits for the first fetch to finish, and then reads the narinfo from the local cache instead of asking the binary cache again.
+#[derive(Clone, Default)]
+struct NarInfoFetches(Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>);
+
+impl NarInfoFetches {
+    /// The returned fetch must be kept around for as long as the narinfo is being fetched.
+    fn start(&self, hash: &str) -> NarInfoFetch<'_> {
+        let lock = self
+            .0
+            .lock()
+            .unwrap()
+            .entry(hash.to_string())
+            .or_default()
+            .clone();
+
+        NarInfoFetch {
+            fetches: self,
+            hash: hash.to_string(),
+            lock: Some(lock),
+        }
+    }
+}
+
+/// Releases its entry in `NarInfoFetches` when dropped, so the entry doesn't leak even if the fetch gets cancelled (e.g. because of a timeout or an aborted download).
+struct NarInfoFetch<'a> {
+    fetches: &'a NarInfoFetches,
+    hash: String,
+    lock: Option<Arc<tokio::sync::Mutex<()>>>,
+}
+
+impl NarInfoFetch<'_> {
+    /// Waits until no other fetch of the same narinfo is going on.
+    async fn wait_turn(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.lock.as_ref().unwrap().lock().await
+    }
+}
+
+impl Drop for NarInfoFetch<'_> {
+    fn drop(&mut self) {
+        let mut fetches = self.fetches.0.lock().unwrap();
+        drop(self.lock.take());
+        // The entry is only removed once nobody else is waiting on it, otherwise a fetch starting later wouldn't wait for the ones still going on.
+        if fetches
+            .get(&self.hash)
+            .is_some_and(|fetch_lock| Arc::strong_count(fetch_lock) == 1)
+        {
+            fetches.remove(&self.hash);
+        }
+    }
+}
+
 async fn cached_download_nar_info(
-    client: &reqwest::Client,
+    client: &CacheClient,
+    nar_info_fetches: &NarInfoFetches,
     nar_info_cache_dir: &Path,
     cache_url: &str,
+    max_nar_info_size: u64,
     package_id: &str,
 ) -> anyhow::Result<OwnedNarInfo> {
-    let narinfo_url: String;
-    let cached_path: PathBuf;
-
-    if let Some((hash, _name)) = package_id.split_once("-") {
-        cached_path = nar_info_cache_dir.join(hash);
+    let store_path = StorePath::from_package_id(package_id).with_context(|| {
+        format!(
+            "Received an unexpected package id to download: {}",
+            package_id
+        )
+    })?;
+    let cached_path = nar_info_cache_dir.join(store_path.hash());
 
-        if cached_path.exists() {
-            return parse_nar_info(&tokio::fs::read_to_string(cached_path).await?, package_id);
-        }
+    if cached_path.exists() {
+        return read_cached_nar_info(&cached_path, package_id).await;
+    }
 
-        narinfo_url = format!("{}/{}.narinfo", cache_url, hash);
+    let fetch = nar_info_fetches.start(store_path.hash());
+    let _fetch_turn = fetch.wait_turn().await;
+    // Whoever held the lock before us may have already fetched and cached it.
+    if cached_path.exists() {
+        tracing::debug!(
+            package_id,
+            "Narinfo was fetched while we waited for another fetch of it, will use the cached one."
+        );
+        read_cached_nar_info(&cached_path, package_id).await
     } else {
-        return Err(anyhow!(
-            "Received an unexpected package id to download: {}",
-            package_id
-        ));
+        download_nar_info(
+            client,
+            &cached_path,
+            cache_url,
+            max_nar_info_size,
+            &store_path,
+            package_id,
+        )
+        .await
     }
+}
+
+async fn read_cached_nar_info(
+    cached_path: &Path,
+    package_id: &str,
+) -> anyhow::Result<OwnedNarInfo> {
+    parse_nar_info(&tokio::fs::read_to_string(cached_path).await?, package_id)
+}
+
+async fn download_nar_info(
+    client: &CacheClient,
+    cached_path: &Path,
+    cache_url: &str,
+    max_nar_info_size: u64,
+    store_path: &StorePath,
+    package_id: &str,
+) -> anyhow::Result<OwnedNarInfo> {
+    let narinfo_url = format!("{}/{}.narinfo", cache_url, store_path.hash());
 
     // Protocol as seen in https://github.com/fzakaria/nix-http-binary-cache-api-spec
     let resp = client
@@ -511,7 +1397,11 @@ async fn cached_download_nar_info(
     let nar_info_text: String;
 
     if resp.status().is_success() {
-        nar_info_text = resp.text().await?;
+        nar_info_text = read_limited_body(resp, max_nar_info_size)
+            .await
+            .with_context(|| {
+                format!("failed to read the info from the cache for {}", package_id)
+            })?;
     } else {
         return Err(anyhow!(
             "Got a bad response from the cache server! {}",
@@ -519,13 +1409,66 @@ async fn cached_download_nar_info(
         ));
     }
 
-    tokio::fs::write(&cached_path, &nar_info_text).await?;
-    parse_nar_info(&nar_info_text, package_id)
+    // We only cache info that we could parse, so a malformed response doesn't stick around after the cache gets fixed.
+    let nar_info = parse_nar_info(&nar_info_text, package_id)?;
+    // Written next to its final place and then renamed, since other fetches check for the cached narinfo without waiting for us.
+    let temp_cached_path = cached_path.with_extension("tmp");
+    tokio::fs::write(&temp_cached_path, &nar_info_text).await?;
+    tokio::fs::rename(&temp_cached_path, cached_path).await?;
+    Ok(nar_info)
+}
+
+/// Reads the whole body of a response as text, but fails as soon as we know it's bigger than the limit instead of buffering all of it.
+async fn read_limited_body(resp: reqwest::Response, limit: u64) -> anyhow::Result<String> {
+    if let Some(length) = resp.content_length() {
+        if length > limit {
+            return Err(anyhow!(
+                "the response has {} bytes, which is more than the limit of {} bytes",
+                length,
+                limit
+            ));
+        }
+    }
+
+    // The length header may be missing or lying, so we'll also count what we actually get.
+    let mut body = Vec::new();
+    let mut stream = resp.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        if (body.len() + chunk.len()) as u64 > limit {
+            return Err(anyhow!(
+                "the response is bigger than the limit of {} bytes",
+                limit
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(String::from_utf8(body)?)
 }
 
 fn parse_nar_info(contents: &str, package_id: &str) -> anyhow::Result<OwnedNarInfo> {
-    let nar_info =
-        NarInfo::parse(&contents).map_err(|parsing_error| anyhow!("{:#?}", parsing_error))?;
+    // The narinfo crate doesn't know about the `CA` field and refuses to parse fields it doesn't know, so we take it out before parsing the rest.
+    let mut ca = None;
+    let contents = contents
+        .lines()
+        .filter(|line| match line.strip_prefix("CA:") {
+            Some(value) => {
+                ca = Some(value.trim().to_string());
+                false
+            }
+            None => true,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let nar_info = NarInfo::parse(&contents).map_err(|parsing_error| {
+        anyhow!(
+            "The info from the cache for {} couldn't be parsed: {:?}",
+            package_id,
+            parsing_error
+        )
+    })?;
 
     if !nar_info.store_path.ends_with(&package_id) {
         return Err(anyhow!(
@@ -535,5 +1478,182 @@ fn parse_nar_info(contents: &str, package_id: &str) -> anyhow::Result<OwnedNarIn
         ));
     }
 
-    Ok(nar_info.into())
+    // Without this check, we'd end up trying to download the cache root instead of the NAR, which would fail with a confusing error.
+    if nar_info.url.trim().is_empty() {
+        return Err(anyhow!(
+            "The info from the cache for {} doesn't have a URL to download the NAR from",
+            package_id
+        ));
+    }
+
+    let mut nar_info: OwnedNarInfo = nar_info.into();
+    nar_info.ca = ca;
+    Ok(nar_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKAGE_ID: &str = "0c0fbflsgmvl9j3ag6p0h2ja1bxmd5ii-hello-2.12.1";
+
+    fn nar_info_contents(url_line: Option<&str>) -> String {
+        let mut lines = vec![format!("StorePath: /nix/store/{PACKAGE_ID}")];
+        lines.extend(url_line.map(str::to_string));
+        lines.extend([
+            "Compression: xz".to_string(),
+            "FileHash: sha256:1b2gw8ckrpavyzkmpzf4y0qwqr5rnlhfw8inv1s2a4xv8bsk7n6w".to_string(),
+            "FileSize: 50264".to_string(),
+            "NarHash: sha256:0m7yh1nb5k6xnbghl3d5yjsjpbgw4njzd42kdrrhqbnhvy6psc6y".to_string(),
+            "NarSize: 226560".to_string(),
+            format!("References: {PACKAGE_ID}"),
+        ]);
+        lines.join("\n")
+    }
+
+    #[tokio::test]
+    async fn cancelled_nar_info_fetch_releases_its_entry() {
+        let fetches = NarInfoFetches::default();
+        let first_fetch = fetches.start("hash");
+        let first_fetch_turn = first_fetch.wait_turn().await;
+
+        let waiting_fetch = tokio::time::timeout(Duration::from_millis(10), async {
+            let fetch = fetches.start("hash");
+            let _fetch_turn = fetch.wait_turn().await;
+        })
+        .await;
+        assert!(waiting_fetch.is_err());
+        assert_eq!(fetches.0.lock().unwrap().len(), 1);
+
+        drop(first_fetch_turn);
+        drop(first_fetch);
+        assert!(fetches.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn dependency_ids_filters_self_reference() {
+        let dependency_id = "3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.37-8";
+        let references = vec![
+            PACKAGE_ID.to_string(),
+            dependency_id.to_string(),
+            "".to_string(),
+        ];
+
+        assert_eq!(
+            dependency_ids(PACKAGE_ID, references),
+            vec![dependency_id.to_string()]
+        );
+    }
+
+    #[test]
+    fn parse_nar_info_accepts_url() {
+        let contents = nar_info_contents(Some(
+            "URL: nar/1b2gw8ckrpavyzkmpzf4y0qwqr5rnlhfw8inv1s2a4xv8bsk7n6w.nar.xz",
+        ));
+
+        let nar_info = parse_nar_info(&contents, PACKAGE_ID).unwrap();
+
+        assert_eq!(
+            nar_info.url,
+            "nar/1b2gw8ckrpavyzkmpzf4y0qwqr5rnlhfw8inv1s2a4xv8bsk7n6w.nar.xz"
+        );
+    }
+
+    #[test]
+    fn parse_nar_info_rejects_missing_url() {
+        let contents = nar_info_contents(None);
+
+        let err = parse_nar_info(&contents, PACKAGE_ID)
+            .err()
+            .expect("the narinfo should be rejected");
+
+        assert!(
+            err.to_string().contains(PACKAGE_ID),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn parse_nar_info_rejects_empty_url() {
+        let contents = nar_info_contents(Some("URL: "));
+
+        let err = parse_nar_info(&contents, PACKAGE_ID)
+            .err()
+            .expect("the narinfo should be rejected");
+
+        assert!(
+            err.to_string().contains("doesn't have a URL"),
+            "unexpected error: {err}"
+        );
+    }
+
+    fn nar_info_with_references(references: &[&str]) -> OwnedNarInfo {
+        OwnedNarInfo {
+            store_path: String::new(),
+            url: String::new(),
+            compression: None,
+            nar_hash: String::new(),
+            nar_size: 0,
+            file_hash: None,
+            file_size: None,
+            deriver: None,
+            system: None,
+            references: references.iter().map(|r| r.to_string()).collect(),
+            sigs: vec![],
+            ca: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn closure_check_walks_past_local_packages_it_cant_fetch() {
+        let download_results = vec![NarDownloadResult {
+            package_id: "downloaded".to_string(),
+            nar_path: PathBuf::new(),
+            nar_hash: String::new(),
+            reference_ids: vec![
+                "local".to_string(),
+                "local-without-narinfo".to_string(),
+                "missing".to_string(),
+            ],
+            is_already_unpacked: false,
+        }];
+        let existing_store_package_ids =
+            HashSet::from(["downloaded", "local", "local-without-narinfo"].map(str::to_string));
+
+        let mut missing_ids = find_missing_closure_references(
+            &download_results,
+            &existing_store_package_ids,
+            2,
+            |package_id| async move {
+                match package_id.as_str() {
+                    "local" => Ok(nar_info_with_references(&[
+                        "local",
+                        "missing-through-local",
+                    ])),
+                    _ => Err(anyhow!("no narinfo for {}", package_id)),
+                }
+            },
+        )
+        .await;
+        missing_ids.sort();
+
+        assert_eq!(missing_ids, vec!["missing", "missing-through-local"]);
+    }
+
+    #[tokio::test]
+    async fn stalled_download_fails_with_timeout() {
+        let err = with_download_timeout(
+            std::future::pending(),
+            Duration::from_millis(10),
+            PACKAGE_ID.to_string(),
+        )
+        .await
+        .err()
+        .expect("a download that never finishes should time out");
+
+        assert!(matches!(
+            AgentError::categorise(err, AgentError::Download),
+            AgentError::DownloadTimeout { package_id, .. } if package_id == PACKAGE_ID
+        ));
+    }
 }
@@ -1,3 +1,13 @@
+use std::pin::Pin;
+
+use futures::{
+    future,
+    stream::{self, PollNext},
+    Stream, StreamExt,
+};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+
 mod deleter;
 mod downloader;
 mod server;
@@ -9,3 +19,27 @@ pub use downloader::*;
 pub use server::*;
 pub use state_keeper::*;
 pub use unpacker::*;
+
+/// How many requests can be queued up in an actor's input channel before whoever sends them has to wait. Used unless configured otherwise.
+pub const DEFAULT_ACTOR_CHANNEL_CAPACITY: usize = 10;
+
+/// The requests an actor gets, where a request to shut down always comes before any other requests still queued up, so shutting down never has to wait behind work that's piling up.
+pub type ActorInputStream<T> = Pin<Box<dyn Stream<Item = T> + Send>>;
+
+/// Builds the input of an actor from its regular channel and from the channel only used to shut it down, which is separate so it never fills up. `into_request` turns whatever is sent through the shutdown channel into the actor's shutdown request.
+pub fn actor_input_stream<T: Send + 'static, S: Send + 'static>(
+    input_rx: mpsc::Receiver<T>,
+    shutdown_rx: oneshot::Receiver<S>,
+    into_request: impl FnMut(S) -> T + Send + 'static,
+) -> ActorInputStream<T> {
+    // If the shutdown sender gets dropped without being used, the actor keeps going until its regular channel closes.
+    let shutdown_stream = stream::once(shutdown_rx)
+        .filter_map(|res| future::ready(res.ok()))
+        .map(into_request);
+
+    Box::pin(stream::select_with_strategy(
+        shutdown_stream,
+        ReceiverStream::new(input_rx),
+        |_: &mut ()| PollNext::Left,
+    ))
+}
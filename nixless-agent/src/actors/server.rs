@@ -1,27 +1,113 @@
-use std::{collections::HashSet, net::IpAddr};
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{RwLock, RwLockReadGuard},
+    time::Duration,
+};
 
 use actix_web::{
     dev::ServerHandle, error::InternalError, http::StatusCode, web, App, Either, HttpRequest,
     HttpResponse, HttpServer, Responder,
 };
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use derive_builder::Builder;
 use nix_core::{NixStylePublicKey, PublicKeychain};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::task::JoinHandle;
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
 use tracing::instrument;
 
-use crate::metrics;
+use crate::{
+    error::AgentError, listeners::bind_listeners, metrics, state::RollbackTarget,
+    store_sync::StoreSyncMode, system_configuration::SwitchAction,
+};
+
+use super::{CacheHttpVersion, CacheTlsVersion, ForeignPackagesPolicy, StartedStateKeeperInput};
+
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+/// The contents that must be signed in a request to clean up the state directory.
+const CLEANUP_STATE_REQUEST: &str = "cleanup-state";
+/// The contents that must be signed in a request to drain the agent.
+const DRAIN_REQUEST: &str = "drain";
+/// The contents that must be signed in a request to repair the agent's state.
+const REPAIR_REQUEST: &str = "repair";
+/// The contents that must be signed in a request to retrieve the agent's configuration.
+const CONFIG_REQUEST: &str = "config";
+
+/// Information about the agent build, which never changes while the agent is running.
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    /// Only known if the build environment told us about it.
+    git_commit: Option<&'static str>,
+    nix_store_dir: String,
+}
 
-use super::StartedStateKeeperInput;
+/// The settings the agent is running with, as reported by the `/config` route. Secrets never make it in here: for those we only say whether they were given.
+#[derive(Clone, Debug, Serialize)]
+pub struct AgentConfiguration {
+    pub nix_store_dir: String,
+    pub nix_state_dir: PathBuf,
+    pub current_system_path: PathBuf,
+    pub booted_system_path: PathBuf,
+    pub nixless_state_dir: PathBuf,
+    pub temp_download_path: PathBuf,
+    /// Any password in the URL is redacted.
+    pub cache_url: String,
+    pub has_cache_auth_token: bool,
+    pub cache_auth_scheme: String,
+    pub cache_netrc_file: Option<PathBuf>,
+    /// Any password in the URL is redacted.
+    pub cache_proxy: Option<String>,
+    pub has_cache_proxy_credentials: bool,
+    pub cache_public_key: Option<String>,
+    pub update_public_keys: Vec<String>,
+    pub relative_configuration_activation_command: PathBuf,
+    pub absolute_activation_tracker_command: PathBuf,
+    pub max_system_history_count: usize,
+    pub max_parallel_nar_downloads: usize,
+    pub max_parallel_unpacks: usize,
+    pub store_sync_mode: StoreSyncMode,
+    pub max_nar_info_size: u64,
+    pub nar_download_timeout_secs: u64,
+    pub cache_http_version: CacheHttpVersion,
+    pub cache_pool_max_idle: usize,
+    pub cache_keep_alive_secs: u64,
+    pub cache_ca_cert: Option<PathBuf>,
+    pub cache_min_tls_version: Option<CacheTlsVersion>,
+    pub cache_insecure: bool,
+    pub cache_user_agent_suffix: Option<String>,
+    pub preallocate_nar_files: bool,
+    pub actor_channel_capacity: usize,
+    pub control_workers: usize,
+    pub control_max_body_bytes: usize,
+    pub control_metrics: bool,
+    pub report_optional_metrics: bool,
+    pub foreign_packages_policy: ForeignPackagesPolicy,
+    pub auto_reboot: bool,
+    pub allow_nix_daemon: bool,
+    pub polkit_action_id: String,
+    pub require_non_interactive_auth: bool,
+    pub observer_mode: bool,
+    pub dbus_address: Option<String>,
+    pub activation_properties: Vec<String>,
+}
 
 #[derive(Builder)]
 #[builder(pattern = "owned")]
 pub struct Server {
-    address: IpAddr,
+    addresses: Vec<IpAddr>,
     port: u16,
+    workers: usize,
+    /// Bodies bigger than this are rejected with a 413 response.
+    max_body_bytes: usize,
+    /// Whether `/metrics` and `/metrics.json` exist. When they don't, they get a 404 like any other unknown route.
+    serve_metrics: bool,
     state_keeper_input: StartedStateKeeperInput,
-    update_public_key: String,
+    update_public_keys: Vec<String>,
+    nix_store_dir: String,
+    configuration: AgentConfiguration,
 }
 
 impl Server {
@@ -30,20 +116,51 @@ impl Server {
     }
 
     pub fn start(self) -> anyhow::Result<StartedServer> {
-        let mut keychain = PublicKeychain::new();
-        let public_key = NixStylePublicKey::from_nix_format(&self.update_public_key)?;
-        keychain.add_key(public_key)?;
+        let listeners = bind_listeners("control", &self.addresses, self.port)?;
 
-        let keychain = web::Data::new(keychain);
-        let server_task = HttpServer::new(move || {
+        // Behind a lock so the keys can be replaced while the server is running.
+        let keychain = web::Data::new(RwLock::new(build_update_keychain(
+            &self.update_public_keys,
+        )?));
+        let keychain_clone = keychain.clone();
+        // Also behind a lock, since some settings can be reloaded while the server is running.
+        let configuration = web::Data::new(RwLock::new(self.configuration.clone()));
+        let configuration_clone = configuration.clone();
+        let version_info = web::Data::new(VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: option_env!("NIXLESS_AGENT_GIT_COMMIT"),
+            nix_store_dir: self.nix_store_dir.clone(),
+        });
+        let mut http_server = HttpServer::new(move || {
             App::new()
+                .app_data(web::PayloadConfig::new(self.max_body_bytes))
                 .app_data(web::Data::new(self.state_keeper_input.clone()))
-                .app_data(keychain.clone())
+                .app_data(keychain_clone.clone())
+                .app_data(version_info.clone())
+                .app_data(configuration_clone.clone())
+                .route("/healthz", web::get().to(check_health))
+                .route("/readyz", web::get().to(check_readiness))
+                .route("/version", web::get().to(retrieve_version))
                 .route("/summary", web::get().to(retrieve_system_summary))
+                .route("/history", web::get().to(retrieve_switch_history))
+                .route("/pending-tasks", web::get().to(retrieve_pending_tasks))
+                .route("/consistency", web::get().to(check_consistency))
+                .route("/switch-events", web::get().to(stream_switch_events))
+                .route("/config", web::get().to(retrieve_configuration))
+                .configure(|cfg| {
+                    if self.serve_metrics {
+                        cfg.route("/metrics", web::get().to(retrieve_metrics))
+                            .route("/metrics.json", web::get().to(retrieve_metrics_json));
+                    }
+                })
                 .route(
                     "/new-configuration",
                     web::post().to(handle_new_configuration),
                 )
+                .route("/prefetch", web::post().to(handle_prefetch))
+                .route("/cleanup-state", web::post().to(handle_cleanup_state))
+                .route("/drain", web::post().to(handle_drain))
+                .route("/repair", web::post().to(handle_repair))
                 .route(
                     "/rollback-configuration",
                     web::post().to(rollback_configuration),
@@ -52,14 +169,21 @@ impl Server {
         })
         .disable_signals()
         .shutdown_timeout(5)
-        .workers(2)
-        .bind((self.address, self.port))?
-        .run();
+        .workers(self.workers);
+
+        for listener in listeners {
+            tracing::info!(address = ?listener.local_addr()?, "Control server will listen on address.");
+            http_server = http_server.listen(listener)?;
+        }
+
+        let server_task = http_server.run();
 
         let server_handle = server_task.handle();
         let server_task = tokio::spawn(async { server_task.await });
 
         Ok(StartedServer {
+            keychain,
+            configuration,
             server_task,
             server_handle,
         })
@@ -67,11 +191,39 @@ impl Server {
 }
 
 pub struct StartedServer {
+    keychain: web::Data<RwLock<PublicKeychain>>,
+    configuration: web::Data<RwLock<AgentConfiguration>>,
     server_task: JoinHandle<std::io::Result<()>>,
     server_handle: ServerHandle,
 }
 
 impl StartedServer {
+    /// Replaces the keys used to verify signed requests. The previous keys stay in place if any of the new ones can't be read.
+    /// Takes a keychain that was already built with [`build_update_keychain`], so a reload can check every new setting before applying any of them.
+    pub fn set_update_keychain(&self, new_keychain: PublicKeychain) -> anyhow::Result<()> {
+        *self
+            .keychain
+            .write()
+            .map_err(|_| anyhow!("the lock for the update keys got poisoned"))? = new_keychain;
+
+        tracing::info!("Control server updated the keys used to verify signed requests.");
+        Ok(())
+    }
+
+    /// Changes the configuration reported by the `/config` route, e.g. after some settings got reloaded.
+    pub fn update_configuration(
+        &self,
+        update: impl FnOnce(&mut AgentConfiguration),
+    ) -> anyhow::Result<()> {
+        update(
+            &mut *self
+                .configuration
+                .write()
+                .map_err(|_| anyhow!("the lock for the agent configuration got poisoned"))?,
+        );
+        Ok(())
+    }
+
     pub async fn shutdown(self) -> anyhow::Result<()> {
         tracing::info!(
             "Control server got a request to shutdown. Proceeding with graceful shutdown."
@@ -84,54 +236,308 @@ impl StartedServer {
     }
 }
 
+pub fn build_update_keychain(update_public_keys: &[String]) -> anyhow::Result<PublicKeychain> {
+    let mut keychain = PublicKeychain::new();
+
+    for update_public_key in update_public_keys {
+        let public_key = NixStylePublicKey::from_nix_format(update_public_key.trim())
+            .with_context(|| format!("failed to read update public key {}", update_public_key))?;
+        keychain.add_key(public_key)?;
+    }
+
+    Ok(keychain)
+}
+
+/// The locks only get poisoned if something panicked while holding them, and in that case we'd rather fail the request than panic again in the handler.
+fn read_lock<'a, T>(lock: &'a RwLock<T>, name: &str) -> actix_web::Result<RwLockReadGuard<'a, T>> {
+    lock.read().map_err(|_| {
+        tracing::error!("The lock for the {} got poisoned!", name);
+        InternalError::new(
+            format!("the lock for the {} got poisoned", name),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into()
+    })
+}
+
+/// Signed requests have the signature in their last line, in the format "<key_name>:<signature>" (same as the signatures Nix puts in NAR infos), and everything before it is the signed data. We only check the signature against the key with that name, so we always know which key authorised a request. Returns the name of that key and the signed data if the signature is valid.
+fn verify_signed_payload<'a>(
+    payload_string: &'a str,
+    keychain: &PublicKeychain,
+) -> Option<(&'a str, &'a str)> {
+    let Some((signed_data, signature)) = payload_string.trim().rsplit_once('\n') else {
+        tracing::info!("Request didn't have a signature included!");
+        return None;
+    };
+
+    let Some((key_name, signature_base64)) = signature.trim().split_once(':') else {
+        tracing::info!("Request signature isn't in the expected <key_name>:<signature> format!");
+        return None;
+    };
+
+    if !keychain.has_key(key_name) {
+        tracing::info!(key_name, "Request was signed by a key we don't know about!");
+        return None;
+    }
+
+    let signed_data = signed_data.trim();
+    // A signature we can't even check (e.g. it's malformed or too short) is the requester's problem, same as one that doesn't verify.
+    match keychain.verify(
+        key_name,
+        signed_data.as_bytes(),
+        signature_base64.as_bytes(),
+    ) {
+        Ok(true) => (),
+        Ok(false) => {
+            tracing::info!(key_name, "Request signature didn't verify!");
+            return None;
+        }
+        Err(err) => {
+            tracing::info!(key_name, ?err, "Request signature couldn't be checked!");
+            return None;
+        }
+    }
+
+    tracing::info!(key_name, "Request signature verified.");
+    Some((key_name, signed_data))
+}
+
+#[derive(Deserialize)]
+struct NewConfigurationQuery {
+    /// Given as `?action=switch|boot|test`, and defaults to `switch`. Any other value gets a 400 response. This isn't part of what's signed, but the most it lets someone change is when a configuration that was signed for takes effect.
+    #[serde(default)]
+    action: SwitchAction,
+}
+
 #[instrument(skip_all, fields(uri = req.uri().to_string(), method = req.method().as_str()))]
 async fn handle_new_configuration(
     req: HttpRequest,
+    query: web::Query<NewConfigurationQuery>,
     payload_string: String,
     state_keeper: web::Data<StartedStateKeeperInput>,
-    keychain: web::Data<PublicKeychain>,
+    keychain: web::Data<RwLock<PublicKeychain>>,
 ) -> actix_web::Result<impl Responder> {
     metrics::requests::new_configuration().inc();
 
-    let mut lines = payload_string.lines();
-
-    if let Some(system_package_id) = lines.next() {
-        tracing::info!(system_package_id, "Got a new system configuration request!");
-
-        // A bit convoluted since we first need to grab the last line (which is the signature) and remove it from the list of package ids, and only then turn the list into a set.
-        let mut package_ids: Vec<_> = lines.map(str::to_string).collect();
-        let signature = package_ids.pop();
-        package_ids.push(system_package_id.to_string());
-        let package_ids = HashSet::from_iter(package_ids.into_iter());
-
-        let Some(signature) = signature else {
-            tracing::info!("Request didn't have a signature included!");
-            return Ok(HttpResponse::BadRequest().finish());
-        };
-
-        let signed_data = payload_string.trim().trim_end_matches(&signature).trim();
-        let signature_ok = keychain
-            .verify_any(signed_data.as_bytes(), signature.as_bytes())
-            .map_err(|err| InternalError::new(err, StatusCode::INTERNAL_SERVER_ERROR))?;
+    let Some((key_name, signed_data)) =
+        verify_signed_payload(&payload_string, &*read_lock(&keychain, "update keys")?)
+    else {
+        return Ok(HttpResponse::BadRequest().finish());
+    };
 
-        if !signature_ok {
-            return Ok(HttpResponse::BadRequest().finish());
-        }
+    if let Some((system_package_id, package_ids)) = parse_configuration_packages(signed_data) {
+        tracing::info!(
+            system_package_id,
+            action = query.action.as_str(),
+            authorised_by = key_name,
+            "Got a new system configuration request!"
+        );
 
         tracing::info!("Sending server request to update the system.");
 
         match state_keeper
-            .switch_to_new_configuration(system_package_id.to_string(), package_ids)
+            .switch_to_new_configuration(system_package_id, package_ids, query.action)
             .await
         {
             Ok(()) => Ok(HttpResponse::NoContent().finish()),
-            Err(err) => Ok(HttpResponse::Conflict().body(err.to_string())),
+            Err(err) => Ok(error_response(err)),
         }
     } else {
         Ok(HttpResponse::BadRequest().finish())
     }
 }
 
+/// Configurations are given with the system package id in the first line, followed by the ids of every other package in its closure, one per line. The system package id is also included in the set of package ids.
+fn parse_configuration_packages(signed_data: &str) -> Option<(String, HashSet<String>)> {
+    let mut lines = signed_data.lines();
+    let system_package_id = lines.next()?.to_string();
+
+    let mut package_ids: HashSet<_> = lines.map(str::to_string).collect();
+    package_ids.insert(system_package_id.clone());
+
+    Some((system_package_id, package_ids))
+}
+
+/// Takes the same signed payload as `/new-configuration`, but only downloads and unpacks the configuration. Responds once the prefetch started.
+#[instrument(skip_all)]
+async fn handle_prefetch(
+    payload_string: String,
+    state_keeper: web::Data<StartedStateKeeperInput>,
+    keychain: web::Data<RwLock<PublicKeychain>>,
+) -> actix_web::Result<impl Responder> {
+    metrics::requests::prefetch().inc();
+
+    let Some((key_name, signed_data)) =
+        verify_signed_payload(&payload_string, &*read_lock(&keychain, "update keys")?)
+    else {
+        return Ok(HttpResponse::BadRequest().finish());
+    };
+
+    let Some((system_package_id, package_ids)) = parse_configuration_packages(signed_data) else {
+        return Ok(HttpResponse::BadRequest().finish());
+    };
+
+    tracing::info!(
+        system_package_id,
+        authorised_by = key_name,
+        "Got a request to prefetch a configuration."
+    );
+
+    match state_keeper
+        .prefetch_configuration(system_package_id, package_ids)
+        .await
+    {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(err) => Ok(error_response(err)),
+    }
+}
+
+/// Responds only once the clean up is finished.
+#[instrument(skip_all)]
+async fn handle_cleanup_state(
+    payload_string: String,
+    state_keeper: web::Data<StartedStateKeeperInput>,
+    keychain: web::Data<RwLock<PublicKeychain>>,
+) -> actix_web::Result<impl Responder> {
+    metrics::requests::cleanup_state().inc();
+
+    let Some((_, signed_data)) =
+        verify_signed_payload(&payload_string, &*read_lock(&keychain, "update keys")?)
+    else {
+        return Ok(HttpResponse::BadRequest().finish());
+    };
+
+    // The signed data must be this exact string, so that signatures made for other requests can't be reused here.
+    if signed_data != CLEANUP_STATE_REQUEST {
+        tracing::info!(
+            "Request to clean up the state directory didn't have the expected contents!"
+        );
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+
+    match state_keeper.clean_up_state_dir().await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(err) => Ok(error_response(err)),
+    }
+}
+
+#[instrument(skip_all)]
+async fn handle_drain(
+    payload_string: String,
+    state_keeper: web::Data<StartedStateKeeperInput>,
+    keychain: web::Data<RwLock<PublicKeychain>>,
+) -> actix_web::Result<impl Responder> {
+    metrics::requests::drain().inc();
+
+    let Some((_, signed_data)) =
+        verify_signed_payload(&payload_string, &*read_lock(&keychain, "update keys")?)
+    else {
+        return Ok(HttpResponse::BadRequest().finish());
+    };
+
+    // The signed data must be this exact string, so that signatures made for other requests can't be reused here.
+    if signed_data != DRAIN_REQUEST {
+        tracing::info!("Request to drain the agent didn't have the expected contents!");
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+
+    match state_keeper.drain().await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(err) => Ok(error_response(err)),
+    }
+}
+
+/// Responds with everything that got repaired, and anything that couldn't be.
+#[instrument(skip_all)]
+async fn handle_repair(
+    payload_string: String,
+    state_keeper: web::Data<StartedStateKeeperInput>,
+    keychain: web::Data<RwLock<PublicKeychain>>,
+) -> actix_web::Result<impl Responder> {
+    metrics::requests::repair().inc();
+
+    let Some((key_name, signed_data)) =
+        verify_signed_payload(&payload_string, &*read_lock(&keychain, "update keys")?)
+    else {
+        return Ok(HttpResponse::BadRequest().finish());
+    };
+
+    // The signed data must be this exact string, so that signatures made for other requests can't be reused here.
+    if signed_data != REPAIR_REQUEST {
+        tracing::info!("Request to repair the agent's state didn't have the expected contents!");
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+
+    tracing::info!(
+        authorised_by = key_name,
+        "Got a request to repair the agent's state."
+    );
+
+    match state_keeper.repair().await {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(err) => Ok(error_response(err)),
+    }
+}
+
+/// The configuration may tell more about the host than we'd like anyone to know, so this requires a signed request even though nothing changes.
+#[instrument(skip_all)]
+async fn retrieve_configuration(
+    payload_string: String,
+    configuration: web::Data<RwLock<AgentConfiguration>>,
+    keychain: web::Data<RwLock<PublicKeychain>>,
+) -> actix_web::Result<impl Responder> {
+    metrics::requests::config().inc();
+
+    let Some((_, signed_data)) =
+        verify_signed_payload(&payload_string, &*read_lock(&keychain, "update keys")?)
+    else {
+        return Ok(HttpResponse::BadRequest().finish());
+    };
+
+    // The signed data must be this exact string, so that signatures made for other requests can't be reused here.
+    if signed_data != CONFIG_REQUEST {
+        tracing::info!("Request to retrieve the configuration didn't have the expected contents!");
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+
+    let configuration = read_lock(&configuration, "agent configuration")?.clone();
+    Ok(HttpResponse::Ok().json(configuration))
+}
+
+/// Only tells whether the process is alive, so this never goes through the state keeper, which may be busy.
+async fn check_health() -> impl Responder {
+    metrics::requests::healthz().inc();
+
+    HttpResponse::Ok().finish()
+}
+
+async fn retrieve_version(version_info: web::Data<VersionInfo>) -> impl Responder {
+    metrics::requests::version().inc();
+
+    web::Json(version_info)
+}
+
+/// Tells whether the agent is functional by checking that the state keeper answers quickly.
+#[instrument(skip_all)]
+async fn check_readiness(state_keeper: web::Data<StartedStateKeeperInput>) -> impl Responder {
+    metrics::requests::readyz().inc();
+
+    match tokio::time::timeout(READINESS_TIMEOUT, state_keeper.get_summary()).await {
+        Ok(Ok(_)) => HttpResponse::Ok().finish(),
+        Ok(Err(err)) => {
+            tracing::warn!(
+                ?err,
+                "State keeper returned an error during a readiness check."
+            );
+            HttpResponse::ServiceUnavailable().body(err.to_string())
+        }
+        Err(_) => {
+            tracing::warn!("State keeper didn't answer in time during a readiness check.");
+            HttpResponse::ServiceUnavailable().body("the state keeper isn't responding")
+        }
+    }
+}
+
 #[instrument(skip_all)]
 async fn retrieve_system_summary(
     state_keeper: web::Data<StartedStateKeeperInput>,
@@ -143,6 +549,8 @@ async fn retrieve_system_summary(
             let mut resp = json!({
                 "current_config": serde_json::to_value(summary.stable_configuration).unwrap(),
                 "status": summary.status.as_str(),
+                "draining": summary.draining,
+                "idle": summary.idle,
             });
 
             if let Some(extra_config) = summary.status.into_inner_configuration() {
@@ -152,14 +560,128 @@ async fn retrieve_system_summary(
                 );
             }
 
+            if let Some(prefetched_config) = summary.prefetched_configuration {
+                resp.as_object_mut().unwrap().insert(
+                    "prefetched_config".to_string(),
+                    serde_json::to_value(prefetched_config).unwrap(),
+                );
+            }
+
+            if let Some(progress) = summary.progress {
+                resp.as_object_mut().unwrap().insert(
+                    "progress".to_string(),
+                    serde_json::to_value(progress).unwrap(),
+                );
+            }
+
             Ok(Either::Left(web::Json(resp)))
         }
-        Err(err) => Ok(Either::Right(
-            HttpResponse::Conflict().body(err.to_string()),
-        )),
+        Err(err) => Ok(Either::Right(error_response(err))),
     }
 }
 
+/// Lists the most recent finished configuration switches, oldest first.
+#[instrument(skip_all)]
+async fn retrieve_switch_history(
+    state_keeper: web::Data<StartedStateKeeperInput>,
+) -> actix_web::Result<impl Responder> {
+    metrics::requests::history().inc();
+
+    match state_keeper.get_switch_history().await {
+        Ok(history) => Ok(Either::Left(web::Json(history))),
+        Err(err) => Ok(Either::Right(error_response(err))),
+    }
+}
+
+/// Tells which background tasks the state keeper is running and for how long they've been running, to help debug an agent that looks stuck.
+#[instrument(skip_all)]
+async fn retrieve_pending_tasks(
+    state_keeper: web::Data<StartedStateKeeperInput>,
+) -> actix_web::Result<impl Responder> {
+    metrics::requests::pending_tasks().inc();
+
+    match state_keeper.get_pending_tasks().await {
+        Ok(pending_tasks) => Ok(Either::Left(web::Json(pending_tasks))),
+        Err(err) => Ok(Either::Right(error_response(err))),
+    }
+}
+
+/// Reports any differences between the agent's state and the Nix store or the system profiles, without fixing them.
+#[instrument(skip_all)]
+async fn check_consistency(
+    state_keeper: web::Data<StartedStateKeeperInput>,
+) -> actix_web::Result<impl Responder> {
+    metrics::requests::consistency().inc();
+
+    match state_keeper.check_consistency().await {
+        Ok(report) => Ok(Either::Left(web::Json(report))),
+        Err(err) => Ok(Either::Right(error_response(err))),
+    }
+}
+
+/// Streams switch events as server-sent events, each one with a JSON-encoded `SwitchEvent` as its data. The stream ends after the event that finishes a switch (successful, pending reboot or failed), so clients following a switch don't have to tell when to disconnect.
+#[instrument(skip_all)]
+async fn stream_switch_events(state_keeper: web::Data<StartedStateKeeperInput>) -> impl Responder {
+    metrics::requests::switch_events().inc();
+
+    let switch_events_rx = state_keeper.subscribe_switch_events();
+    let events = futures::stream::unfold(Some(switch_events_rx), |switch_events_rx| async move {
+        // Only `None` once we sent the event that finished the switch.
+        let mut switch_events_rx = switch_events_rx?;
+        loop {
+            match switch_events_rx.recv().await {
+                Ok(event) => {
+                    let data = serde_json::to_string(&event).unwrap();
+                    let message = web::Bytes::from(format!("data: {}\n\n", data));
+                    let switch_events_rx =
+                        (!event.phase.finishes_switch()).then_some(switch_events_rx);
+                    return Some((Ok::<_, actix_web::Error>(message), switch_events_rx));
+                }
+                Err(RecvError::Lagged(skipped_events)) => {
+                    tracing::warn!(
+                        skipped_events,
+                        "A switch events client fell behind and missed some events."
+                    );
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+#[instrument(skip_all)]
+async fn retrieve_metrics(
+    configuration: web::Data<RwLock<AgentConfiguration>>,
+) -> actix_web::Result<impl Responder> {
+    metrics::requests::metrics().inc();
+
+    let report_optional = read_lock(&configuration, "agent configuration")?.report_optional_metrics;
+    let metrics = metrics::collect(report_optional)
+        .map_err(|err| InternalError::new(err, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics))
+}
+
+#[instrument(skip_all)]
+async fn retrieve_metrics_json(
+    configuration: web::Data<RwLock<AgentConfiguration>>,
+) -> actix_web::Result<impl Responder> {
+    metrics::requests::metrics().inc();
+
+    let report_optional = read_lock(&configuration, "agent configuration")?.report_optional_metrics;
+    let metrics = metrics::collect_json(report_optional)
+        .map_err(|err| InternalError::new(err, StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(web::Json(metrics))
+}
+
 #[instrument(skip_all)]
 async fn rollback_configuration(
     payload_string: String,
@@ -167,18 +689,23 @@ async fn rollback_configuration(
 ) -> actix_web::Result<impl Responder> {
     metrics::requests::rollback().inc();
 
-    let version_to_rollback: Option<u32> = if payload_string.is_empty() {
-        None
-    } else {
-        Some(
-            payload_string
-                .parse()
-                .map_err(|err| InternalError::new(err, StatusCode::INTERNAL_SERVER_ERROR))?,
-        )
-    };
+    // Either empty for the previous configuration, a version number, or something like `-2` to go back 2 configurations.
+    let target: RollbackTarget = payload_string
+        .parse()
+        .map_err(|err| InternalError::new(err, StatusCode::BAD_REQUEST))?;
 
-    match state_keeper.perform_rollback(version_to_rollback).await {
+    match state_keeper.perform_rollback(target).await {
         Ok(()) => Ok(HttpResponse::NoContent().finish()),
-        Err(err) => Ok(HttpResponse::Conflict().body(err.to_string())),
+        Err(err) => Ok(error_response(err)),
+    }
+}
+
+/// Errors in the agent's state usually mean that the request can't be fulfilled right now (e.g. we're in the middle of a switch), so those get a conflict status. Anything else means something went wrong on our side.
+fn error_response(err: AgentError) -> HttpResponse {
+    match err {
+        AgentError::State(_) => HttpResponse::Conflict().body(err.to_string()),
+        AgentError::Draining => HttpResponse::ServiceUnavailable().body(err.to_string()),
+        AgentError::ObserverMode => HttpResponse::Forbidden().body(err.to_string()),
+        _ => HttpResponse::InternalServerError().body(err.to_string()),
     }
 }
@@ -1,25 +1,266 @@
-use std::{collections::HashSet, ops::Deref, sync::Arc};
+use std::{
+    collections::HashSet,
+    future::Future,
+    ops::Deref,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::anyhow;
+use clap::ValueEnum;
 use derive_builder::Builder;
+use serde::Serialize;
 use tokio::{
-    sync::{mpsc, oneshot},
-    task::JoinHandle,
+    sync::{broadcast, mpsc, oneshot, watch},
+    task::{AbortHandle, JoinHandle},
 };
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
-use tracing::instrument;
+use tokio_stream::StreamExt;
+use tracing::{instrument, Instrument};
 
 use crate::{
-    dbus_connection::StartedDBusConnection,
+    dbus_connection::{StartedDBusConnection, StartedDBusConnectionInput},
+    error::{send_response, AgentError, AgentResult},
     metrics,
     path_utils::clean_up_nix_var_dir,
+    process_init::SystemdNotifyHandle,
     state::{
-        calculate_switch_duration, check_switching_status, record_switch_start, AgentState,
-        AgentStateStatus, SystemSummary, SystemSwitchStatus,
+        calculate_switch_duration, check_switching_status, new_switch_id, record_switch_start,
+        remove_stale_tracking_files, AgentState, AgentStateStatus, ConsistencyReport, RepairReport,
+        RollbackTarget, SwitchHistoryEntry, SwitchProgress, SwitchProgressPhase, SystemSummary,
+        SystemSwitchStatus,
     },
+    system_configuration::{SwitchAction, SystemConfiguration},
+};
+
+use super::{
+    actor_input_stream, ActorInputStream, DownloadProgress, StartedDeleter, StartedDownloader,
+    StartedUnpacker, DEFAULT_ACTOR_CHANNEL_CAPACITY,
 };
 
-use super::{StartedDeleter, StartedDownloader, StartedUnpacker};
+/// How many switch events we keep around for subscribers that are slow to read them. Subscribers that fall further behind than this will miss some events, but will never hold up the state keeper.
+const SWITCH_EVENTS_CAPACITY: usize = 16;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SwitchPhase {
+    Downloading,
+    Unpacking,
+    Activating,
+    Successful,
+    PendingReboot,
+    Failed,
+}
+
+/// Published by the state keeper whenever a configuration switch moves to a different phase.
+#[derive(Clone, Debug, Serialize)]
+pub struct SwitchEvent {
+    pub phase: SwitchPhase,
+    pub system_package_id: String,
+    pub timestamp_ms: u64,
+}
+
+impl SwitchPhase {
+    /// No other phases come after these for the same switch.
+    pub fn finishes_switch(&self) -> bool {
+        matches!(self, Self::Successful | Self::PendingReboot | Self::Failed)
+    }
+
+    /// What systemd shows as our status while we're in this phase.
+    fn systemd_status(&self, system_package_id: &str) -> String {
+        match self {
+            Self::Downloading => format!("Downloading configuration {}", system_package_id),
+            Self::Unpacking => format!("Unpacking configuration {}", system_package_id),
+            Self::Activating => format!("Activating configuration {}", system_package_id),
+            Self::Successful => format!("Standby, running configuration {}", system_package_id),
+            Self::PendingReboot => format!(
+                "Waiting for a reboot to finish switching to configuration {}",
+                system_package_id
+            ),
+            Self::Failed => format!(
+                "Failed to switch to configuration {}, waiting to be recovered",
+                system_package_id
+            ),
+        }
+    }
+}
+
+/// Keeps the systemd status of a switch that's downloading packages up to date with how many NARs were downloaded so far. Only runs while the switch is downloading, so it never overwrites the status of a later phase.
+async fn report_download_progress(
+    mut progress_rx: watch::Receiver<DownloadProgress>,
+    switch_events: SwitchEventPublisher,
+    system_package_id: String,
+) {
+    // Whatever is there now is from an earlier download.
+    progress_rx.borrow_and_update();
+    let mut last_reported = None;
+
+    while progress_rx.changed().await.is_ok() {
+        let progress = progress_rx.borrow_and_update().clone();
+        // The progress also changes with every chunk of bytes downloaded, but systemd only needs to hear about it when a NAR finishes.
+        let nars = (progress.nars_downloaded, progress.nars_total);
+        if progress.nars_total == 0 || last_reported == Some(nars) {
+            continue;
+        }
+
+        last_reported = Some(nars);
+        switch_events.notify_systemd_status(&format!(
+            "Downloading configuration {} ({}/{} NARs)",
+            system_package_id, progress.nars_downloaded, progress.nars_total
+        ));
+    }
+}
+
+/// A task the state keeper runs in the background, along with when it started.
+struct PendingTask {
+    handle: JoinHandle<()>,
+    started_at: Instant,
+    // Nothing is ever sent through this, it only gets closed once the task finishes or is aborted.
+    finished_rx: watch::Receiver<()>,
+}
+
+impl PendingTask {
+    fn spawn(future: impl Future<Output = ()> + Send + 'static) -> Self {
+        let (finished_tx, finished_rx) = watch::channel(());
+        Self {
+            handle: tokio::spawn(async move {
+                let _finished_tx = finished_tx;
+                future.await;
+            }),
+            started_at: Instant::now(),
+            finished_rx,
+        }
+    }
+
+    fn info(&self) -> PendingTaskInfo {
+        PendingTaskInfo {
+            running_for_ms: self.started_at.elapsed().as_millis() as u64,
+        }
+    }
+
+    /// Lets other tasks wait for this one to finish, without taking it away from whoever keeps track of it.
+    fn finished(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut finished_rx = self.finished_rx.clone();
+        async move {
+            let _ = finished_rx.changed().await;
+        }
+    }
+}
+
+/// Aborts a task once this gets dropped, so a task that got handed over to another one doesn't outlive it.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// A switch can't go ahead while a prefetch is still downloading or unpacking, since both could end up unpacking the same packages at the same time. The downloader and unpacker do the prefetch's work, so we can't cancel it and wait for it to finish instead.
+async fn wait_for_prefetch(prefetch_task: Option<PendingTask>) {
+    if let Some(task) = prefetch_task {
+        tracing::info!("Waiting for a configuration prefetch to finish before switching.");
+        let _abort_on_drop = AbortOnDrop(task.handle.abort_handle());
+        // The prefetch reports its own result to the state keeper, so we only care that it finished.
+        let _ = task.handle.await;
+    }
+}
+
+/// A package deletion only spares the packages that were live when it started, so it may still delete packages of a configuration we started switching to (or prefetching) since then. Waiting for it means whatever it deleted gets downloaded again.
+async fn wait_for_package_deletion(package_deletion_finished: Option<impl Future<Output = ()>>) {
+    if let Some(package_deletion_finished) = package_deletion_finished {
+        tracing::info!("Waiting for a package deletion to finish before downloading anything.");
+        package_deletion_finished.await;
+    }
+}
+
+/// Everything a switch has to wait for before it starts downloading.
+fn wait_before_switching(
+    prefetch_task: Option<PendingTask>,
+    package_delete_task: Option<&PendingTask>,
+) -> impl Future<Output = ()> + Send + 'static {
+    let package_deletion_finished = package_delete_task.map(PendingTask::finished);
+    async move {
+        wait_for_package_deletion(package_deletion_finished).await;
+        wait_for_prefetch(prefetch_task).await;
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PendingTaskInfo {
+    pub running_for_ms: u64,
+}
+
+/// Which background tasks the state keeper is waiting on. Each one is `None` if the task isn't running. Meant to help tell an idle agent apart from one that's stuck.
+#[derive(Clone, Debug, Serialize)]
+pub struct PendingTasks {
+    pub clean_up: Option<PendingTaskInfo>,
+    pub system_switch: Option<PendingTaskInfo>,
+    pub prefetch: Option<PendingTaskInfo>,
+    pub package_delete: Option<PendingTaskInfo>,
+}
+
+/// The latest phase a configuration switch moved to, and whether that switch went through downloading packages (rollbacks don't), which tells whether the downloader's progress is about this switch.
+#[derive(Clone, Copy, Debug)]
+struct LatestSwitchPhase {
+    phase: SwitchPhase,
+    downloaded: bool,
+}
+
+/// Tells everyone who cares that a configuration switch moved to a different phase: subscribers of switch events, and systemd (through our status).
+#[derive(Clone)]
+struct SwitchEventPublisher {
+    switch_events_tx: broadcast::Sender<SwitchEvent>,
+    systemd_handle: SystemdNotifyHandle,
+    latest_phase_tx: watch::Sender<Option<LatestSwitchPhase>>,
+}
+
+impl SwitchEventPublisher {
+    fn notify_systemd_status(&self, status: &str) {
+        if let Err(err) = self.systemd_handle.notify_status(status) {
+            tracing::warn!(?err, "Failed to update our status with systemd.");
+        }
+    }
+
+    fn publish(&self, phase: SwitchPhase, system_package_id: String) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        tracing::debug!(?phase, system_package_id, "Publishing a switch event.");
+        self.notify_systemd_status(&phase.systemd_status(&system_package_id));
+        self.latest_phase_tx.send_modify(|latest| {
+            let downloaded = match phase {
+                SwitchPhase::Downloading => true,
+                SwitchPhase::Unpacking | SwitchPhase::Activating => {
+                    latest.is_some_and(|latest| latest.downloaded)
+                }
+                SwitchPhase::Successful | SwitchPhase::PendingReboot | SwitchPhase::Failed => false,
+            };
+            *latest = Some(LatestSwitchPhase { phase, downloaded });
+        });
+
+        // This only fails when there are no subscribers, in which case nobody cares about the event anyway.
+        let _ = self.switch_events_tx.send(SwitchEvent {
+            phase,
+            system_package_id,
+            timestamp_ms,
+        });
+    }
+}
+
+/// What to do with packages in the Nix store that aren't part of any configuration we're tracking, which means something other than the agent put them there.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForeignPackagesPolicy {
+    /// Leave them alone without saying anything.
+    Ignore,
+    /// Leave them alone, but log them every time we sweep the store.
+    Warn,
+    /// Delete them together with the packages from old configurations.
+    Gc,
+}
 
 #[derive(Builder)]
 #[builder(pattern = "owned")]
@@ -29,6 +270,14 @@ pub struct StateKeeper {
     downloader: StartedDownloader,
     unpacker: StartedUnpacker,
     deleter: StartedDeleter,
+    auto_reboot: bool,
+    foreign_packages_policy: ForeignPackagesPolicy,
+    /// Refuses anything that would change the system, and doesn't need to be authorised to manage systemd units.
+    #[builder(default)]
+    observer_mode: bool,
+    systemd_handle: SystemdNotifyHandle,
+    #[builder(default = "DEFAULT_ACTOR_CHANNEL_CAPACITY")]
+    channel_capacity: usize,
 }
 
 impl StateKeeper {
@@ -37,27 +286,65 @@ impl StateKeeper {
     }
 
     pub fn start(self) -> StartedStateKeeper {
-        let (input_tx, input_rx) = mpsc::channel(10);
+        let (input_tx, input_rx) = mpsc::channel(self.channel_capacity);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let input_stream = actor_input_stream(input_rx, shutdown_rx, |reason| {
+            StateKeeperRequest::Shutdown { reason }
+        });
+        let (switch_events_tx, _) = broadcast::channel(SWITCH_EVENTS_CAPACITY);
 
         let input_tx_clone = input_tx.clone();
-        let task = tokio::spawn(async {
-            match state_keeper_task(
-                self.state,
+        let switch_events = SwitchEventPublisher {
+            switch_events_tx: switch_events_tx.clone(),
+            systemd_handle: self.systemd_handle,
+            latest_phase_tx: watch::Sender::new(None),
+        };
+        let task = tokio::spawn(async move {
+            let mut state = self.state;
+            let result = state_keeper_task(
+                &mut state,
                 self.dbus_connection,
                 self.downloader,
                 self.unpacker,
                 self.deleter,
-                input_rx,
+                self.auto_reboot,
+                self.foreign_packages_policy,
+                self.observer_mode,
+                input_stream,
                 input_tx_clone,
+                switch_events,
             )
-            .await
-            {
-                Ok(()) => Ok(()),
+            .await;
+
+            // Every way out of the state keeper task gets recorded here, so the next run can always tell how this one stopped.
+            let reason = match &result {
+                Ok(reason) => reason.clone(),
                 Err(err) => {
                     tracing::error!(
                         ?err,
                         "The state keeper task encountered a fatal error and has stopped."
                     );
+                    format!(
+                        "the state keeper stopped because of a fatal error: {:#}",
+                        err
+                    )
+                }
+            };
+            let record_result = state.record_shutdown(reason);
+
+            match result {
+                Ok(_) => {
+                    record_result?;
+                    tracing::info!("State keeper has finished shutting down.");
+                    Ok(())
+                }
+                Err(err) => {
+                    if let Err(record_err) = record_result {
+                        tracing::warn!(
+                            ?record_err,
+                            "Failed to record why the state keeper stopped."
+                        );
+                    }
                     Err(err)
                 }
             }
@@ -65,37 +352,78 @@ impl StateKeeper {
 
         StartedStateKeeper {
             task,
-            input: StartedStateKeeperInput { input_tx },
+            input: StartedStateKeeperInput {
+                input_tx,
+                switch_events_tx,
+            },
+            shutdown_tx,
         }
     }
 }
 
-// TODO: add a message to sweep the nix store dir and check for any foreign packages.
 enum StateKeeperRequest {
-    CleanUpStateDir,
+    /// `resp_tx` is only set when someone outside the state keeper asked for the clean up, in which case they'll get a response once it finishes.
+    CleanUpStateDir {
+        resp_tx: Option<oneshot::Sender<AgentResult<()>>>,
+    },
     CleanUpStateDirResult(anyhow::Result<()>),
     SwitchToNewConfiguration {
         system_package_id: String,
         package_ids: HashSet<String>,
-        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+        action: SwitchAction,
+        resp_tx: oneshot::Sender<AgentResult<()>>,
+    },
+    /// Sent by the state keeper to itself on startup when it finds a switch that was interrupted before the new configuration started being activated.
+    ResumeConfigurationSwitch,
+    PrefetchConfiguration {
+        system_package_id: String,
+        package_ids: HashSet<String>,
+        resp_tx: oneshot::Sender<AgentResult<()>>,
+    },
+    PrefetchResult {
+        system_package_id: String,
+        result: AgentResult<()>,
     },
-    ConfigurationSwitchStartResult(anyhow::Result<()>),
+    ConfigurationSwitchStartResult(AgentResult<()>),
+    /// Sent once the activation of the configuration we're switching to finished, however it went.
+    ConfigurationSwitchFinished(anyhow::Result<SystemSwitchStatus>),
     CleanupConfigurationHistory,
-    PackageDeletionResult(anyhow::Result<()>),
+    PackageDeletionResult(AgentResult<()>),
     GetSummary {
-        resp_tx: oneshot::Sender<anyhow::Result<SystemSummary>>,
+        resp_tx: oneshot::Sender<AgentResult<SystemSummary>>,
+    },
+    Drain {
+        resp_tx: oneshot::Sender<()>,
+    },
+    CheckConsistency {
+        resp_tx: oneshot::Sender<AgentResult<ConsistencyReport>>,
+    },
+    Repair {
+        resp_tx: oneshot::Sender<AgentResult<RepairReport>>,
+    },
+    GetPendingTasks {
+        resp_tx: oneshot::Sender<PendingTasks>,
+    },
+    GetSwitchHistory {
+        resp_tx: oneshot::Sender<AgentResult<Vec<SwitchHistoryEntry>>>,
+    },
+    Heartbeat {
+        resp_tx: oneshot::Sender<()>,
     },
     PerformRollback {
-        to_version: Option<u32>,
-        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+        target: RollbackTarget,
+        resp_tx: oneshot::Sender<AgentResult<()>>,
+    },
+    Shutdown {
+        reason: String,
     },
-    Shutdown,
 }
 
 #[derive(Debug)]
 pub struct StartedStateKeeper {
     task: JoinHandle<anyhow::Result<()>>,
     input: StartedStateKeeperInput,
+    shutdown_tx: oneshot::Sender<String>,
 }
 
 impl Deref for StartedStateKeeper {
@@ -111,11 +439,10 @@ impl StartedStateKeeper {
         self.input.clone()
     }
 
-    pub async fn shutdown(self) -> anyhow::Result<()> {
-        self.input
-            .input_tx
-            .send(StateKeeperRequest::Shutdown)
-            .await?;
+    /// `reason` gets recorded in the state, so the next time the agent starts it can tell why it stopped.
+    pub async fn shutdown(self, reason: String) -> anyhow::Result<()> {
+        // If the task already stopped, waiting for it tells us why.
+        let _ = self.shutdown_tx.send(reason);
         self.task.await?
     }
 }
@@ -123,115 +450,486 @@ impl StartedStateKeeper {
 #[derive(Clone, Debug)]
 pub struct StartedStateKeeperInput {
     input_tx: mpsc::Sender<StateKeeperRequest>,
+    switch_events_tx: broadcast::Sender<SwitchEvent>,
 }
 
 impl StartedStateKeeperInput {
+    /// The receiver only gets events published after this call.
+    pub fn subscribe_switch_events(&self) -> broadcast::Receiver<SwitchEvent> {
+        self.switch_events_tx.subscribe()
+    }
+
     pub async fn switch_to_new_configuration(
         &self,
         system_package_id: String,
         package_ids: HashSet<String>,
-    ) -> anyhow::Result<()> {
+        action: SwitchAction,
+    ) -> AgentResult<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
 
         self.input_tx
             .send(StateKeeperRequest::SwitchToNewConfiguration {
                 system_package_id,
                 package_ids,
+                action,
                 resp_tx,
             })
-            .await?;
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?;
 
-        resp_rx.await?
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?
     }
 
-    pub async fn get_summary(&self) -> anyhow::Result<SystemSummary> {
+    /// Downloads and unpacks a configuration without switching to it, so a later switch to it doesn't have to wait for that. Returns once the prefetch started, and `get_summary()` tells when it finished.
+    pub async fn prefetch_configuration(
+        &self,
+        system_package_id: String,
+        package_ids: HashSet<String>,
+    ) -> AgentResult<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
 
         self.input_tx
-            .send(StateKeeperRequest::GetSummary { resp_tx })
-            .await?;
+            .send(StateKeeperRequest::PrefetchConfiguration {
+                system_package_id,
+                package_ids,
+                resp_tx,
+            })
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?;
 
-        resp_rx.await?
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?
     }
 
-    pub async fn perform_rollback(&self, to_version: Option<u32>) -> anyhow::Result<()> {
+    pub async fn clean_up_state_dir(&self) -> AgentResult<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
 
         self.input_tx
-            .send(StateKeeperRequest::PerformRollback {
-                to_version,
-                resp_tx,
+            .send(StateKeeperRequest::CleanUpStateDir {
+                resp_tx: Some(resp_tx),
             })
-            .await?;
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?
+    }
 
-        resp_rx.await?
+    /// Only returns once the state keeper's main loop gets to handle the request, so it tells whether the main loop is stuck.
+    pub async fn heartbeat(&self) -> AgentResult<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        self.input_tx
+            .send(StateKeeperRequest::Heartbeat { resp_tx })
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))
     }
+
+    pub async fn get_summary(&self) -> AgentResult<SystemSummary> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        self.input_tx
+            .send(StateKeeperRequest::GetSummary { resp_tx })
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?
+    }
+
+    /// Makes the state keeper refuse any new configuration switches (including rollbacks) until the agent restarts. A switch that's already in progress carries on, and `get_summary()` tells when the agent is idle.
+    pub async fn drain(&self) -> AgentResult<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        self.input_tx
+            .send(StateKeeperRequest::Drain { resp_tx })
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))
+    }
+
+    pub async fn check_consistency(&self) -> AgentResult<ConsistencyReport> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        self.input_tx
+            .send(StateKeeperRequest::CheckConsistency { resp_tx })
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?
+    }
+
+    /// Fixes what `check_consistency()` finds as far as it can, and reports what it did. Only allowed on standby or after a failed switch, and never while packages are being deleted.
+    pub async fn repair(&self) -> AgentResult<RepairReport> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        self.input_tx
+            .send(StateKeeperRequest::Repair { resp_tx })
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?
+    }
+
+    pub async fn get_pending_tasks(&self) -> AgentResult<PendingTasks> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        self.input_tx
+            .send(StateKeeperRequest::GetPendingTasks { resp_tx })
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))
+    }
+
+    pub async fn get_switch_history(&self) -> AgentResult<Vec<SwitchHistoryEntry>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        self.input_tx
+            .send(StateKeeperRequest::GetSwitchHistory { resp_tx })
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?
+    }
+
+    pub async fn perform_rollback(&self, target: RollbackTarget) -> AgentResult<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        self.input_tx
+            .send(StateKeeperRequest::PerformRollback { target, resp_tx })
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?;
+
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("state keeper"))?
+    }
+}
+
+/// Every log of a switch is made inside this span, so the logs of a single switch can be found by its `switch_id`. The id only gets recorded once the switch task starts with `start_switch_span()`, since making it can fail.
+fn switch_span(configuration: &SystemConfiguration) -> tracing::Span {
+    tracing::info_span!(
+        "switch",
+        switch_id = tracing::field::Empty,
+        system_package_id = configuration.system_package_id
+    )
+}
+
+/// Must be called first thing in the switch task, so every log of the switch gets the id. Returns the id, which is also given to the transient unit doing the switch so its tracking files can be matched with the logs.
+fn start_switch_span() -> AgentResult<String> {
+    let switch_id = new_switch_id().map_err(AgentError::Activation)?;
+    tracing::Span::current().record("switch_id", switch_id.as_str());
+    Ok(switch_id)
 }
 
+/// How far along the ongoing switch is, if there's one downloading, unpacking or activating a configuration. Switches that didn't download anything report no downloads instead of whatever the downloader did last.
+fn switch_progress(
+    state: &AgentState,
+    switch_events: &SwitchEventPublisher,
+    downloader: &StartedDownloader,
+) -> Option<SwitchProgress> {
+    if !matches!(
+        state.status(),
+        AgentStateStatus::DownloadingNewConfiguration { .. }
+            | AgentStateStatus::SwitchingToConfiguration { .. }
+    ) {
+        return None;
+    }
+
+    let latest = (*switch_events.latest_phase_tx.borrow())?;
+    let phase = match latest.phase {
+        SwitchPhase::Downloading => SwitchProgressPhase::Downloading,
+        SwitchPhase::Unpacking => SwitchProgressPhase::Unpacking,
+        SwitchPhase::Activating => SwitchProgressPhase::Activating,
+        SwitchPhase::Successful | SwitchPhase::PendingReboot | SwitchPhase::Failed => return None,
+    };
+    let download_progress = if latest.downloaded {
+        downloader.progress()
+    } else {
+        DownloadProgress::default()
+    };
+
+    Some(SwitchProgress {
+        phase,
+        nars_downloaded: download_progress.nars_downloaded,
+        nars_total: download_progress.nars_total,
+        bytes_downloaded: download_progress.bytes_downloaded,
+        bytes_total: download_progress.bytes_total,
+    })
+}
+
+/// Downloads, unpacks and activates the configuration we're switching to (which must already be marked in the state), reporting back to the state keeper once the activation started or as soon as any of these steps fails. Packages that are already in the Nix store or fully downloaded get skipped, which is what lets an interrupted switch pick up where it left off.
+fn spawn_configuration_switch(
+    state: &AgentState,
+    input_tx: &mpsc::Sender<StateKeeperRequest>,
+    downloader: &StartedDownloader,
+    unpacker: &StartedUnpacker,
+    dbus_connection: &StartedDBusConnection,
+    switch_events: &SwitchEventPublisher,
+    earlier_tasks: impl Future<Output = ()> + Send + 'static,
+) -> PendingTask {
+    let configuration = state.status().inner_configuration().unwrap(); // Callers only get here after marking that we're switching to a configuration.
+    let system_package_id_arc = Arc::new(configuration.system_package_id.clone());
+    let package_ids = configuration.package_ids.clone();
+    let switch_action = configuration.switch_action;
+    let input_tx_clone = input_tx.clone();
+    let downloader_input = downloader.input();
+    let unpacker_input = unpacker.input();
+    let dbus_connection_input = dbus_connection.input();
+    let switch_events_clone = switch_events.clone();
+    // A bit annoying that we have to grab this from agent state, but seems like the better option. There are other ways to structure the code here to allow moving this stuff all inside the agent state so we don't need to clone the agent state or make an Arc or whatever, but I think this is fine for now.
+    let switch_start_file_path = state.absolute_switch_start_time_path();
+    let state_base_dir = state.base_dir();
+    let new_configuration_path = state.new_configuration_system_package_path().unwrap();
+    let switch_span = switch_span(configuration);
+
+    PendingTask::spawn(async move {
+        let switch_id = match start_switch_span() {
+            Ok(switch_id) => switch_id,
+            Err(err) => {
+                tracing::error!(?err, "Failed to make an id for the system switch.");
+                input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Err(err))).await.unwrap();
+                return;
+            }
+        };
+        earlier_tasks.await;
+
+        let download_timer = metrics::system::configuration_download_duration(&system_package_id_arc).start_timer();
+        let status_updates = tokio::spawn(report_download_progress(downloader_input.watch_progress(), switch_events_clone.clone(), system_package_id_arc.to_string()));
+        let _abort_status_updates = AbortOnDrop(status_updates.abort_handle());
+        let res = downloader_input.download_packages(package_ids, system_package_id_arc.to_string()).await;
+        // Whatever comes next reports its own status, so the updates must be stopped before that.
+        status_updates.abort();
+        let _ = status_updates.await;
+        let res = match res {
+            Ok(v) => v,
+            Err(err) => {
+                tracing::error!(?err, "Got an error when downloading packages during system switch.");
+                input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Err(err))).await.unwrap();
+                return;
+            },
+        };
+        let download_duration = download_timer.stop_and_record();
+        tracing::info!(download_duration_secs = download_duration.as_secs_f32(), "Finished downloading new system configuration.");
+        switch_events_clone.publish(SwitchPhase::Unpacking, system_package_id_arc.to_string());
+
+        let setup_timer = metrics::system::configuration_setup_duration(&system_package_id_arc).start_timer();
+        match unpacker_input.unpack_downloads(res).await {
+            Ok(()) => (),
+            Err(err) => {
+                tracing::error!(?err, "Got an error when unpacking downloads during system switch.");
+                input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Err(err))).await.unwrap();
+                return;
+            }
+        };
+        let setup_duration = setup_timer.stop_and_record();
+        tracing::info!(setup_duration_secs = setup_duration.as_secs_f32(), "Finished unpacking new system configuration.");
+        switch_events_clone.publish(SwitchPhase::Activating, system_package_id_arc.to_string());
+
+        if let Err(err) = remove_stale_tracking_files(&state_base_dir).await {
+            tracing::warn!(?err, "Failed to remove tracking files from an earlier switch. The switch may fail because of them.");
+        }
+        if let Err(err) = record_switch_start(switch_start_file_path.clone(), &switch_id) {
+            tracing::error!(?err, "Failed to record when the system switch started.");
+            input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Err(AgentError::Activation(err)))).await.unwrap();
+            return;
+        }
+        match dbus_connection_input.perform_configuration_switch(new_configuration_path, switch_action, switch_id).await {
+            Ok(()) => (),
+            Err(err) => {
+                tracing::error!(?err, "Got an error when performing a system switch after unpacking all downloads.");
+                input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Err(err))).await.unwrap();
+                return;
+            }
+        }
+
+        // We'll check if system switch was made successfully inside the state keeper code instead of this ad-hoc task.
+        input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Ok(()))).await.unwrap();
+    }.instrument(switch_span))
+}
+
+/// Gives back the reason the state keeper stopped, which is up to the caller to record.
 #[instrument(skip_all)]
 async fn state_keeper_task(
-    mut state: AgentState,
+    state: &mut AgentState,
     dbus_connection: StartedDBusConnection,
     downloader: StartedDownloader,
     unpacker: StartedUnpacker,
     deleter: StartedDeleter,
-    input_rx: mpsc::Receiver<StateKeeperRequest>,
+    auto_reboot: bool,
+    foreign_packages_policy: ForeignPackagesPolicy,
+    observer_mode: bool,
+    mut input_stream: ActorInputStream<StateKeeperRequest>,
     input_tx: mpsc::Sender<StateKeeperRequest>,
-) -> anyhow::Result<()> {
-    tracing::info!("Checking if we can possibly be authorised to manage systemd units.");
-
-    if !dbus_connection.check_authorisation_possibility().await? {
-        return Err(anyhow!(
-            "we're not authorised to manage systemd units, so we won't be able to switch systems"
-        ));
-    }
-
-    tracing::info!("We might be authorised to manage systemd units, continuing initialisation.");
-
-    let mut input_stream = ReceiverStream::new(input_rx);
-
+    switch_events: SwitchEventPublisher,
+) -> anyhow::Result<String> {
     // If we're here, we just got started, so we'll check what was our previous status and figure out next steps from there.
     match state.status() {
         AgentStateStatus::Temporary => unreachable!("Temporary agent status should be unreachable"),
+        AgentStateStatus::New | AgentStateStatus::Standby if observer_mode => {
+            // Cleaning up the state directory deletes things in the Nix state dir, and we never change the system in observer mode, so we'll leave the state (and the state directory) as they are.
+        }
         AgentStateStatus::New | AgentStateStatus::Standby => {
             // We can start operating normally, but we'll enqueue a job to clean up the state directory.
             state.set_standby()?;
-            input_tx.send(StateKeeperRequest::CleanUpStateDir).await?;
+            input_tx
+                .send(StateKeeperRequest::CleanUpStateDir { resp_tx: None })
+                .await?;
         }
         AgentStateStatus::FailedSwitch { .. } => {
             // We'll start in a "read-only" mode.
         }
+        AgentStateStatus::DownloadingNewConfiguration { .. }
+        | AgentStateStatus::SwitchingToConfiguration { .. }
+        | AgentStateStatus::PendingReboot { .. }
+            if observer_mode =>
+        {
+            // Picking the switch back up could change the system, so we'll only report whatever an earlier run of the agent left behind.
+            tracing::warn!("An earlier run of the agent left a configuration switch unfinished, but we're in observer mode, so we'll leave it as it is.");
+        }
         AgentStateStatus::DownloadingNewConfiguration { configuration } => {
             // We'll continue downloading the new system, but aside from that will operate normally.
             downloader
-                .download_packages(configuration.package_ids.clone())
+                .download_packages(
+                    configuration.package_ids.clone(),
+                    configuration.system_package_id.clone(),
+                )
                 .await?;
         }
         AgentStateStatus::SwitchingToConfiguration { .. } => {
-            input_tx
-                .send(StateKeeperRequest::ConfigurationSwitchStartResult(Ok(())))
-                .await
-                .unwrap();
+            // The switch start file is only written right before we ask for the new configuration to be activated, so if it's not there, we got interrupted while downloading or unpacking and will pick up from there. Otherwise the activation may have happened (or may still be happening) without us, and the tracker files will tell how it went.
+            if state.absolute_switch_start_time_path().exists() {
+                input_tx
+                    .send(StateKeeperRequest::ConfigurationSwitchStartResult(Ok(())))
+                    .await
+                    .unwrap();
+            } else {
+                input_tx
+                    .send(StateKeeperRequest::ResumeConfigurationSwitch)
+                    .await?;
+            }
+        }
+        AgentStateStatus::PendingReboot { .. } => {
+            if state.finish_pending_reboot().await? {
+                tracing::info!("We booted into the configuration that was waiting for a reboot, so it's now the stable configuration.");
+                input_tx
+                    .send(StateKeeperRequest::CleanupConfigurationHistory)
+                    .await?;
+                input_tx
+                    .send(StateKeeperRequest::CleanUpStateDir { resp_tx: None })
+                    .await?;
+            } else {
+                // We won't reboot here even if we're allowed to, because the system may have been intentionally booted into another configuration and we don't want to get into a reboot loop.
+                tracing::warn!("The system still hasn't booted into the configuration that is waiting for a reboot. Rolling back or switching to a new configuration will replace it.");
+            }
         }
     }
 
+    // Only the phase matters for the status shown by systemd, even if we're not actually going through that phase right now.
+    let (startup_phase, startup_system_package_id) = match state.status() {
+        AgentStateStatus::Temporary => {
+            unreachable!(
+                "we should never be in a temporary state after the early status decision-making"
+            )
+        }
+        // We only stay in a new state in observer mode, where it means the same as standby.
+        AgentStateStatus::New | AgentStateStatus::Standby => {
+            (SwitchPhase::Successful, state.latest_package_id())
+        }
+        AgentStateStatus::DownloadingNewConfiguration { configuration } => (
+            SwitchPhase::Downloading,
+            configuration.system_package_id.clone(),
+        ),
+        AgentStateStatus::SwitchingToConfiguration { configuration } => (
+            SwitchPhase::Activating,
+            configuration.system_package_id.clone(),
+        ),
+        AgentStateStatus::PendingReboot { configuration } => (
+            SwitchPhase::PendingReboot,
+            configuration.system_package_id.clone(),
+        ),
+        AgentStateStatus::FailedSwitch { configuration } => {
+            (SwitchPhase::Failed, configuration.system_package_id.clone())
+        }
+    };
+    switch_events.notify_systemd_status(&startup_phase.systemd_status(&startup_system_package_id));
+
     tracing::info!("State keeper finished early status decision-making, will now enter its main processing loop.");
 
-    let mut pending_clean_up_task: Option<JoinHandle<()>> = None;
-    let mut pending_system_switch_task: Option<JoinHandle<()>> = None;
-    let mut pending_package_delete_task: Option<JoinHandle<()>> = None;
+    let mut pending_clean_up_task: Option<PendingTask> = None;
+    let mut pending_clean_up_resp_tx: Option<oneshot::Sender<AgentResult<()>>> = None;
+    let mut pending_system_switch_task: Option<PendingTask> = None;
+    let mut pending_prefetch_task: Option<PendingTask> = None;
+    let mut pending_package_delete_task: Option<PendingTask> = None;
+    // Once draining, we refuse any new switches until the agent restarts.
+    let mut draining = false;
+    let mut shutdown_reason = None;
 
     while let Some(req) = input_stream.next().await {
         match req {
-            StateKeeperRequest::Shutdown => {
-                tracing::info!("State keeper got a request to shut down. Shutting down.");
+            StateKeeperRequest::Shutdown { reason } => {
+                tracing::info!(
+                    reason,
+                    "State keeper got a request to shut down. Shutting down."
+                );
+                shutdown_reason = Some(reason);
                 break;
             }
-            StateKeeperRequest::CleanUpStateDir => {
+            StateKeeperRequest::CleanUpStateDir { resp_tx } => {
+                if observer_mode {
+                    if let Some(resp_tx) = resp_tx {
+                        send_response(resp_tx, Err(AgentError::ObserverMode), "state keeper");
+                    }
+                    continue;
+                }
+
+                if let Some(resp_tx) = resp_tx {
+                    // Requests from outside the state keeper only get to clean up when nothing else is going on with the system.
+                    let rejection = if pending_clean_up_task.is_some() {
+                        Some("The state directory is already being cleaned up.")
+                    } else if !matches!(state.status(), AgentStateStatus::Standby) {
+                        Some("The state directory can only be cleaned up when the system is in standby.")
+                    } else {
+                        None
+                    };
+
+                    if let Some(rejection) = rejection {
+                        send_response(
+                            resp_tx,
+                            Err(AgentError::State(anyhow!(rejection))),
+                            "state keeper",
+                        );
+                        continue;
+                    }
+
+                    pending_clean_up_resp_tx = Some(resp_tx);
+                }
+
                 let input_tx_clone = input_tx.clone();
                 let dir = state.base_dir_nix();
                 tracing::info!("Starting a task to clean up the Nix state dir.");
-                pending_clean_up_task = Some(tokio::spawn(async move {
+                pending_clean_up_task = Some(PendingTask::spawn(async move {
                     let res = clean_up_nix_var_dir(dir).await;
                     input_tx_clone
                         .send(StateKeeperRequest::CleanUpStateDirResult(res))
@@ -239,181 +937,334 @@ async fn state_keeper_task(
                         .unwrap();
                 }));
             }
-            StateKeeperRequest::CleanUpStateDirResult(Err(err)) => {
-                tracing::warn!(?err, "We failed to clean up the state directory!");
-                pending_clean_up_task = None;
-            }
-            StateKeeperRequest::CleanUpStateDirResult(Ok(())) => {
-                tracing::info!("Task to clean up the Nix state dir succeeded!");
+            StateKeeperRequest::CleanUpStateDirResult(res) => {
+                match &res {
+                    Ok(()) => tracing::info!("Task to clean up the Nix state dir succeeded!"),
+                    Err(err) => tracing::warn!(?err, "We failed to clean up the state directory!"),
+                }
                 pending_clean_up_task = None;
+
+                if let Some(resp_tx) = pending_clean_up_resp_tx.take() {
+                    // The clean up may take a while, so whoever asked for it may have given up already. That's not a reason to stop the state keeper.
+                    if resp_tx
+                        .send(
+                            res.map_err(|err| {
+                                AgentError::categorise(err, AgentError::StateCleanup)
+                            }),
+                        )
+                        .is_err()
+                    {
+                        tracing::warn!("Whoever asked for the state directory clean up went away before it finished.");
+                    }
+                }
             }
-            StateKeeperRequest::PerformRollback {
-                to_version,
-                resp_tx,
-            } => {
+            StateKeeperRequest::PerformRollback { target, resp_tx } => {
                 tracing::info!(
-                    ?to_version,
+                    ?target,
                     "State keeper got a request to rollback configuration."
                 );
 
+                if observer_mode {
+                    send_response(resp_tx, Err(AgentError::ObserverMode), "state keeper");
+                    continue;
+                }
+
+                if draining {
+                    send_response(resp_tx, Err(AgentError::Draining), "state keeper");
+                    continue;
+                }
+
                 match state.status() {
                     AgentStateStatus::New | AgentStateStatus::Temporary => unreachable!("should have never been in a new or temporary state during the state keeper main loop"),
                     AgentStateStatus::DownloadingNewConfiguration { .. } => {
-                        resp_tx.send(Err(anyhow!("The system is already downloading a new system configuration."))).map_err(|_| anyhow!("channel closed before we could send the response"))?;
+                        send_response(resp_tx, Err(AgentError::State(anyhow!("The system is already downloading a new system configuration."))), "state keeper");
                     }
                     AgentStateStatus::SwitchingToConfiguration { .. } => {
-                        resp_tx.send(Err(anyhow!("The system is already switching to a new system configuration."))).map_err(|_| anyhow!("channel closed before we could send the response"))?;
+                        send_response(resp_tx, Err(AgentError::State(anyhow!("The system is already switching to a new system configuration."))), "state keeper");
                     }
-                    AgentStateStatus::FailedSwitch { .. } | AgentStateStatus::Standby => {
-                        state.mark_performing_rollback(to_version).await?;
-
-                        let input_tx_clone = input_tx.clone();
-                        let dbus_connection_input = dbus_connection.input();
-                        // A bit annoying that we have to grab this from agent state, but seems like the better option. There are other ways to structure the code here to allow moving this stuff all inside the agent state so we don't need to clone the agent state or make an Arc or whatever, but I think this is fine for now.
-                        let switch_start_file_path = state.absolute_switch_start_time_path();
-                        let new_configuration_path = state.new_configuration_system_package_path().unwrap(); // We just marked that we're switching to a new system, so the `unwrap()` should never fail.
+                    // A configuration waiting for a reboot may never get booted into, so it can be rolled back from, the same as a failed one.
+                    AgentStateStatus::FailedSwitch { .. } | AgentStateStatus::PendingReboot { .. } | AgentStateStatus::Standby => {
+                        // The target may not exist (e.g. going back more configurations than we keep, or its packages were already deleted), which is the requester's problem and not ours.
+                        if let Err(err) = state.check_rollback_target(target) {
+                            send_response(resp_tx, Err(AgentError::State(err)), "state keeper");
+                            continue;
+                        }
+                        if let Err(err) = state.mark_performing_rollback(target).await {
+                            send_response(resp_tx, Err(AgentError::State(err)), "state keeper");
+                            continue;
+                        }
+                        // The packages of the configuration we roll back to should all still be in the Nix store, so downloading and unpacking them won't do anything.
+                        switch_events.publish(SwitchPhase::Downloading, state.status().inner_configuration_system_package_id().unwrap());
+
                         // We send the response just before starting the task just to try to avoid as much as possible any issues with never sending a response back if the system switch is almost immediate.
                         // TODO: guarantee that we'll wait until a response is sent back all the way through the server before we proceed with system switch?
-                        resp_tx.send(Ok(())).map_err(|_| anyhow!("channel closed before we could send the response"))?;
-                        pending_system_switch_task = Some(tokio::spawn(async move {
-                            record_switch_start(switch_start_file_path.clone()).unwrap();
-                            match dbus_connection_input.perform_configuration_switch(new_configuration_path).await {
-                                Ok(()) => (),
-                                Err(err) => {
-                                    tracing::error!(?err, "Got an error when performing a system switch for a rollback.");
-                                    input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Err(err))).await.unwrap();
-                                    return;
-                                }
-                            }
-
-                            // We'll check if system switch was made successfully inside the state keeper code instead of this ad-hoc task.
-                            input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Ok(()))).await.unwrap();
-                        }));
+                        send_response(resp_tx, Ok(()), "state keeper");
+                        pending_system_switch_task = Some(spawn_configuration_switch(state, &input_tx, &downloader, &unpacker, &dbus_connection, &switch_events, wait_before_switching(pending_prefetch_task.take(), pending_package_delete_task.as_ref())));
                     }
                 }
             }
             StateKeeperRequest::SwitchToNewConfiguration {
                 system_package_id,
                 package_ids,
+                action,
                 resp_tx,
             } => {
                 tracing::info!(
                     system_package_id,
+                    action = action.as_str(),
                     "State keeper got a request to switch to new configuration."
                 );
 
+                if observer_mode {
+                    send_response(resp_tx, Err(AgentError::ObserverMode), "state keeper");
+                    continue;
+                }
+
+                if draining {
+                    send_response(resp_tx, Err(AgentError::Draining), "state keeper");
+                    continue;
+                }
+
                 match state.status() {
                     AgentStateStatus::New | AgentStateStatus::Temporary => unreachable!("should have never been in a new or temporary state during the state keeper main loop"),
                     AgentStateStatus::FailedSwitch { .. } => {
-                        resp_tx.send(Err(anyhow!("The system already failed a system switch and must be recovered before switching to a new configuration."))).map_err(|_| anyhow!("channel closed before we could send the response"))?;
+                        send_response(resp_tx, Err(AgentError::State(anyhow!("The system already failed a system switch and must be recovered before switching to a new configuration."))), "state keeper");
                     }
                     AgentStateStatus::DownloadingNewConfiguration { .. } => {
-                        resp_tx.send(Err(anyhow!("The system is already downloading a new system configuration."))).map_err(|_| anyhow!("channel closed before we could send the response"))?;
+                        send_response(resp_tx, Err(AgentError::State(anyhow!("The system is already downloading a new system configuration."))), "state keeper");
                     }
                     AgentStateStatus::SwitchingToConfiguration { .. } => {
-                        resp_tx.send(Err(anyhow!("The system is already switching to a new system configuration."))).map_err(|_| anyhow!("channel closed before we could send the response"))?;
+                        send_response(resp_tx, Err(AgentError::State(anyhow!("The system is already switching to a new system configuration."))), "state keeper");
                     }
-                    AgentStateStatus::Standby => {
-                        let system_package_id_arc = Arc::new(system_package_id.clone());
-                        state.mark_switching_new_system(system_package_id, package_ids.clone())?;
-
-                        let input_tx_clone = input_tx.clone();
-                        let downloader_input = downloader.input();
-                        let unpacker_input = unpacker.input();
-                        let dbus_connection_input = dbus_connection.input();
-                        // A bit annoying that we have to grab this from agent state, but seems like the better option. There are other ways to structure the code here to allow moving this stuff all inside the agent state so we don't need to clone the agent state or make an Arc or whatever, but I think this is fine for now.
-                        let switch_start_file_path = state.absolute_switch_start_time_path();
-                        let new_configuration_path = state.new_configuration_system_package_path().unwrap(); // We just marked that we're switching to a new system, so the `unwrap()` should never fail.
+                    // A new configuration supersedes one that is still waiting for a reboot.
+                    AgentStateStatus::PendingReboot { .. } | AgentStateStatus::Standby => {
+                        state.mark_switching_new_system(system_package_id.clone(), package_ids, action)?;
+                        switch_events.publish(SwitchPhase::Downloading, system_package_id);
+
                         // We send the response just before starting the task just to try to avoid as much as possible any issues with never sending a response back if the system switch is almost immediate (e.g. everything already downloaded).
                         // TODO: guarantee that we'll wait until a response is sent back all the way through the server before we proceed with system switch?
-                        resp_tx.send(Ok(())).map_err(|_| anyhow!("channel closed before we could send the response"))?;
-                        pending_system_switch_task = Some(tokio::spawn(async move {
-                            let download_timer = metrics::system::configuration_download_duration(&system_package_id_arc).start_timer();
-                            let res = match downloader_input.download_packages(package_ids).await {
-                                Ok(v) => v,
-                                Err(err) => {
-                                    tracing::error!(?err, "Got an error when downloading packages during system switch.");
-                                    input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Err(err))).await.unwrap();
-                                    return;
-                                },
-                            };
-                            let download_duration = download_timer.stop_and_record();
-                            tracing::info!(download_duration_secs = download_duration.as_secs_f32(), "Finished downloading new system configuration.");
-
-                            let setup_timer = metrics::system::configuration_setup_duration(&system_package_id_arc).start_timer();
-                            match unpacker_input.unpack_downloads(res).await {
-                                Ok(()) => (),
-                                Err(err) => {
-                                    tracing::error!(?err, "Got an error when unpacking downloads during system switch.");
-                                    input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Err(err))).await.unwrap();
-                                    return;
-                                }
-                            };
-                            let setup_duration = setup_timer.stop_and_record();
-                            tracing::info!(setup_duration_secs = setup_duration.as_secs_f32(), "Finished unpacking new system configuration.");
-
-                            record_switch_start(switch_start_file_path.clone()).unwrap();
-                            match dbus_connection_input.perform_configuration_switch(new_configuration_path).await {
-                                Ok(()) => (),
-                                Err(err) => {
-                                    tracing::error!(?err, "Got an error when performing a system switch after unpacking all downloads.");
-                                    input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Err(err))).await.unwrap();
-                                    return;
-                                }
-                            }
-
-                            // We'll check if system switch was made successfully inside the state keeper code instead of this ad-hoc task.
-                            input_tx_clone.send(StateKeeperRequest::ConfigurationSwitchStartResult(Ok(()))).await.unwrap();
-                        }));
+                        send_response(resp_tx, Ok(()), "state keeper");
+                        pending_system_switch_task = Some(spawn_configuration_switch(state, &input_tx, &downloader, &unpacker, &dbus_connection, &switch_events, wait_before_switching(pending_prefetch_task.take(), pending_package_delete_task.as_ref())));
+                    }
+                }
+            }
+            StateKeeperRequest::PrefetchConfiguration {
+                system_package_id,
+                package_ids,
+                resp_tx,
+            } => {
+                tracing::info!(
+                    system_package_id,
+                    "State keeper got a request to prefetch a configuration."
+                );
+
+                let rejection = if observer_mode {
+                    Some(AgentError::ObserverMode)
+                } else if draining {
+                    Some(AgentError::Draining)
+                } else if pending_prefetch_task.is_some() {
+                    Some(AgentError::State(anyhow!(
+                        "The system is already prefetching a configuration."
+                    )))
+                } else if !matches!(state.status(), AgentStateStatus::Standby) {
+                    Some(AgentError::State(anyhow!(
+                        "A configuration can only be prefetched when the system is in standby."
+                    )))
+                } else {
+                    None
+                };
+
+                if let Some(rejection) = rejection {
+                    send_response(resp_tx, Err(rejection), "state keeper");
+                    continue;
+                }
+
+                // The packages get pinned before we download anything, so nothing deletes them while we're still prefetching.
+                state.mark_prefetching_configuration(
+                    system_package_id.clone(),
+                    package_ids.clone(),
+                )?;
+                send_response(resp_tx, Ok(()), "state keeper");
+
+                let input_tx_clone = input_tx.clone();
+                let downloader_input = downloader.input();
+                let unpacker_input = unpacker.input();
+                let request_id = system_package_id.clone();
+                let package_deletion_finished = pending_package_delete_task
+                    .as_ref()
+                    .map(PendingTask::finished);
+                pending_prefetch_task = Some(PendingTask::spawn(async move {
+                    wait_for_package_deletion(package_deletion_finished).await;
+                    let result = match downloader_input
+                        .download_packages(package_ids, request_id)
+                        .await
+                    {
+                        Ok(downloads) => unpacker_input.unpack_downloads(downloads).await,
+                        Err(err) => Err(err),
+                    };
+                    input_tx_clone
+                        .send(StateKeeperRequest::PrefetchResult {
+                            system_package_id,
+                            result,
+                        })
+                        .await
+                        .unwrap();
+                }));
+            }
+            StateKeeperRequest::PrefetchResult {
+                system_package_id,
+                result,
+            } => {
+                match &result {
+                    Ok(()) => {
+                        tracing::info!(system_package_id, "Finished prefetching a configuration.")
                     }
+                    Err(err) => tracing::error!(
+                        system_package_id,
+                        ?err,
+                        "Failed to prefetch a configuration."
+                    ),
                 }
+                state.mark_prefetch_finished(&system_package_id, result.is_ok())?;
+                pending_prefetch_task = None;
+            }
+            StateKeeperRequest::ResumeConfigurationSwitch => {
+                let Some(configuration) = state.status().inner_configuration() else {
+                    continue;
+                };
+                tracing::info!(
+                    system_package_id = configuration.system_package_id,
+                    "Resuming a configuration switch that was interrupted before the new configuration got activated."
+                );
+                switch_events.publish(
+                    SwitchPhase::Downloading,
+                    configuration.system_package_id.clone(),
+                );
+                pending_system_switch_task = Some(spawn_configuration_switch(
+                    state,
+                    &input_tx,
+                    &downloader,
+                    &unpacker,
+                    &dbus_connection,
+                    &switch_events,
+                    wait_before_switching(
+                        pending_prefetch_task.take(),
+                        pending_package_delete_task.as_ref(),
+                    ),
+                ));
             }
             StateKeeperRequest::ConfigurationSwitchStartResult(Err(err)) => {
                 pending_system_switch_task = None;
+                // Otherwise we'd try the switch again (and likely fail the same way) every time the agent starts.
+                state.mark_new_system_failed().await?;
+                switch_events.publish(
+                    SwitchPhase::Failed,
+                    state
+                        .status()
+                        .inner_configuration_system_package_id()
+                        .unwrap_or_else(|| state.latest_package_id()),
+                );
 
-                let switch_duration =
-                    calculate_switch_duration(state.absolute_switch_start_time_path()).unwrap();
-                metrics::system::configuration_switch_duration(&Arc::new(
-                    state.latest_package_id(),
-                ))
-                .observe(switch_duration.as_nanos().try_into().unwrap());
+                let switch_duration = observe_switch_duration(state);
                 tracing::info!(
-                    switch_duration_secs = switch_duration.as_secs_f32(),
+                    switch_duration_secs = switch_duration.map(|duration| duration.as_secs_f32()),
                     ?err,
                     "Failed to switch to new system configuration."
                 );
             }
             StateKeeperRequest::ConfigurationSwitchStartResult(Ok(())) => {
                 tracing::info!("Configuration switch was successful!");
-                wait_for_system_update_and_update_state(&mut state, &dbus_connection).await?;
+                // The activation can take a long time, and we must keep answering heartbeats while it goes on.
+                let input_tx_clone = input_tx.clone();
+                let dbus_connection_input = dbus_connection.input();
+                let state_base_dir = state.base_dir();
+                let switch_span = state
+                    .status()
+                    .inner_configuration()
+                    .map_or_else(tracing::Span::current, switch_span);
+                pending_system_switch_task = Some(PendingTask::spawn(
+                    async move {
+                        let status =
+                            wait_for_system_switch(state_base_dir, dbus_connection_input).await;
+                        input_tx_clone
+                            .send(StateKeeperRequest::ConfigurationSwitchFinished(status))
+                            .await
+                            .unwrap();
+                    }
+                    .instrument(switch_span),
+                ));
+            }
+            StateKeeperRequest::ConfigurationSwitchFinished(status) => {
+                update_state_after_system_switch(state, status?).await?;
                 pending_system_switch_task = None;
                 tracing::info!("State updated!");
 
-                let switch_duration =
-                    calculate_switch_duration(state.absolute_switch_start_time_path()).unwrap();
-                metrics::system::configuration_switch_duration(&Arc::new(
-                    state.latest_package_id(),
-                ))
-                .observe(switch_duration.as_nanos().try_into().unwrap());
+                match state.status() {
+                    AgentStateStatus::Standby => {
+                        switch_events.publish(SwitchPhase::Successful, state.latest_package_id())
+                    }
+                    AgentStateStatus::PendingReboot { configuration } => switch_events.publish(
+                        SwitchPhase::PendingReboot,
+                        configuration.system_package_id.clone(),
+                    ),
+                    AgentStateStatus::FailedSwitch { configuration } => switch_events
+                        .publish(SwitchPhase::Failed, configuration.system_package_id.clone()),
+                    _ => (),
+                }
+
+                let switch_duration = observe_switch_duration(state);
                 tracing::info!(
-                    switch_duration_secs = switch_duration.as_secs_f32(),
+                    switch_duration_secs = switch_duration.map(|duration| duration.as_secs_f32()),
                     "Finished switching to new system configuration."
                 );
 
-                input_tx
-                    .send(StateKeeperRequest::CleanupConfigurationHistory)
-                    .await?;
+                match state.status() {
+                    AgentStateStatus::Standby => {
+                        input_tx
+                            .send(StateKeeperRequest::CleanupConfigurationHistory)
+                            .await?;
+                    }
+                    AgentStateStatus::PendingReboot { .. } if auto_reboot => {
+                        tracing::info!("The new system configuration requires a reboot, and we're allowed to reboot automatically.");
+                        if let Err(err) = dbus_connection.reboot().await {
+                            tracing::error!(?err, "Failed to reboot the system. The new system configuration will stay pending until the system is rebooted.");
+                        }
+                    }
+                    AgentStateStatus::PendingReboot { .. } => {
+                        tracing::info!("The new system configuration requires a reboot, and will stay pending until the system is rebooted.");
+                    }
+                    _ => (),
+                }
             }
             StateKeeperRequest::CleanupConfigurationHistory => {
                 tracing::info!("Cleaning up configuration history.");
                 state.cleanup_configuration_history().await?;
 
+                if foreign_packages_policy != ForeignPackagesPolicy::Ignore {
+                    let foreign_package_ids = state.find_foreign_packages().await?;
+
+                    if !foreign_package_ids.is_empty() {
+                        tracing::warn!(
+                            count = foreign_package_ids.len(),
+                            ?foreign_package_ids,
+                            "Found packages in the Nix store that aren't part of any configuration we're tracking."
+                        );
+
+                        if foreign_packages_policy == ForeignPackagesPolicy::Gc {
+                            state.mark_packages_for_removal(foreign_package_ids)?;
+                        }
+                    }
+                }
+
                 if state.has_packages_to_cleanup() {
                     let input_tx_clone = input_tx.clone();
                     let deleter_input = deleter.input();
                     let packages_to_cleanup = state.packages_to_cleanup();
-                    pending_package_delete_task = Some(tokio::spawn(async move {
-                        let res = deleter_input.delete_packages(packages_to_cleanup).await;
+                    let live_package_ids = state.live_package_ids();
+                    pending_package_delete_task = Some(PendingTask::spawn(async move {
+                        let res = deleter_input
+                            .delete_packages(packages_to_cleanup, live_package_ids)
+                            .await;
                         input_tx_clone
                             .send(StateKeeperRequest::PackageDeletionResult(res))
                             .await
@@ -430,7 +1281,82 @@ async fn state_keeper_task(
                 pending_package_delete_task = None;
             }
             StateKeeperRequest::GetSummary { resp_tx } => {
-                resp_tx.send(Ok(state.summary())).unwrap();
+                let mut summary = state.summary();
+                summary.draining = draining;
+                summary.idle = pending_system_switch_task.is_none()
+                    && pending_prefetch_task.is_none()
+                    && pending_package_delete_task.is_none()
+                    && pending_clean_up_task.is_none();
+                summary.progress = switch_progress(state, &switch_events, &downloader);
+                send_response(resp_tx, Ok(summary), "state keeper");
+            }
+            StateKeeperRequest::Drain { resp_tx } => {
+                if !draining {
+                    tracing::info!("State keeper got a request to drain. New configuration switches will be refused from now on.");
+                    draining = true;
+                }
+
+                // Whoever asked may have given up waiting already, and draining happens anyway.
+                let _ = resp_tx.send(());
+            }
+            StateKeeperRequest::CheckConsistency { resp_tx } => {
+                let report = state.check_consistency().await.map_err(AgentError::State);
+                send_response(resp_tx, report, "state keeper");
+            }
+            StateKeeperRequest::Repair { resp_tx } => {
+                tracing::info!("State keeper got a request to repair the state.");
+
+                if observer_mode {
+                    send_response(resp_tx, Err(AgentError::ObserverMode), "state keeper");
+                    continue;
+                }
+
+                // Package deletion clears every package marked for removal once it finishes, which would also clear the ones marked by the repair.
+                if pending_system_switch_task.is_some() || pending_package_delete_task.is_some() {
+                    send_response(
+                        resp_tx,
+                        Err(AgentError::State(anyhow!(
+                            "The state can't be repaired while a switch or a package deletion is in progress."
+                        ))),
+                        "state keeper",
+                    );
+                    continue;
+                }
+
+                let was_failed = matches!(state.status(), AgentStateStatus::FailedSwitch { .. });
+                let report = state.repair().await.map_err(AgentError::State);
+
+                if matches!(state.status(), AgentStateStatus::Standby) {
+                    if was_failed {
+                        switch_events.notify_systemd_status(
+                            &SwitchPhase::Successful.systemd_status(&state.latest_package_id()),
+                        );
+                    }
+                    if state.has_packages_to_cleanup() {
+                        input_tx
+                            .send(StateKeeperRequest::CleanupConfigurationHistory)
+                            .await?;
+                    }
+                }
+
+                // Whoever asked may have given up waiting already, but the repair happened anyway.
+                send_response(resp_tx, report, "state keeper");
+            }
+            StateKeeperRequest::GetPendingTasks { resp_tx } => {
+                let pending_tasks = PendingTasks {
+                    clean_up: pending_clean_up_task.as_ref().map(PendingTask::info),
+                    system_switch: pending_system_switch_task.as_ref().map(PendingTask::info),
+                    prefetch: pending_prefetch_task.as_ref().map(PendingTask::info),
+                    package_delete: pending_package_delete_task.as_ref().map(PendingTask::info),
+                };
+                send_response(resp_tx, pending_tasks, "state keeper");
+            }
+            StateKeeperRequest::GetSwitchHistory { resp_tx } => {
+                send_response(resp_tx, Ok(state.switch_history()), "state keeper");
+            }
+            StateKeeperRequest::Heartbeat { resp_tx } => {
+                // Whoever sent the heartbeat may have given up waiting already, which is fine.
+                let _ = resp_tx.send(());
             }
         }
     }
@@ -439,17 +1365,24 @@ async fn state_keeper_task(
 
     if let Some(task) = pending_clean_up_task {
         tracing::info!("We have a pending clean up task, waiting for it to finish.");
-        task.await?;
+        task.handle.await?;
     }
 
     if let Some(task) = pending_system_switch_task {
         tracing::info!("We have a pending system switch task, but we'll abort it because it could be the task getting us to shut down.");
-        task.abort();
+        task.handle.abort();
+    }
+
+    if let Some(task) = pending_prefetch_task {
+        tracing::info!(
+            "We have a pending prefetch task, but we'll abort it since it can be done again later."
+        );
+        task.handle.abort();
     }
 
     if let Some(task) = pending_package_delete_task {
         tracing::info!("We have a pending package deletion task, waiting for it to finish.");
-        task.await?;
+        task.handle.await?;
     }
 
     let shutdown_results = tokio::join!(
@@ -458,42 +1391,148 @@ async fn state_keeper_task(
         dbus_connection.shutdown(),
         deleter.shutdown(),
     );
-    [
-        shutdown_results.0,
-        shutdown_results.1,
-        shutdown_results.2,
-        shutdown_results.3,
+    // Every sub-actor gets a chance to shut down even if another one fails, and we report all failures instead of only the first one.
+    let failed_actors: Vec<_> = [
+        ("downloader", shutdown_results.0),
+        ("unpacker", shutdown_results.1),
+        ("dbus connection", shutdown_results.2),
+        ("deleter", shutdown_results.3),
     ]
     .into_iter()
-    .collect::<Result<_, _>>()?;
+    .filter_map(|(actor, result)| match result {
+        Ok(()) => None,
+        Err(err) => {
+            tracing::error!(
+                actor,
+                ?err,
+                "Failed to shut down a sub-actor of the state keeper."
+            );
+            Some(format!("{}: {:#}", actor, err))
+        }
+    })
+    .collect();
 
-    tracing::info!("State keeper has finished shutting down.");
-    Ok(())
+    if !failed_actors.is_empty() {
+        return Err(anyhow!(
+            "failed to shut down some of the state keeper's sub-actors: {}",
+            failed_actors.join("; ")
+        ));
+    }
+
+    Ok(
+        shutdown_reason
+            .unwrap_or_else(|| "the state keeper's input channel was closed".to_string()),
+    )
 }
 
-async fn wait_for_system_update_and_update_state(
-    state: &mut AgentState,
-    dbus_connection: &StartedDBusConnection,
-) -> anyhow::Result<()> {
-    let state_base_dir = state.base_dir();
+/// A switch that never got to the activation (or was resumed after a restart) has no start time, so there's nothing to observe for it.
+fn observe_switch_duration(state: &AgentState) -> Option<Duration> {
+    let switch_duration = calculate_switch_duration(state.absolute_switch_start_time_path())?;
+    metrics::system::configuration_switch_duration(&Arc::new(state.latest_package_id()))
+        .observe(switch_duration.as_nanos().try_into().unwrap());
+    Some(switch_duration)
+}
 
+/// Waits until the activation started by a switch task finishes.
+async fn wait_for_system_switch(
+    state_base_dir: PathBuf,
+    dbus_connection_input: StartedDBusConnectionInput,
+) -> anyhow::Result<SystemSwitchStatus> {
     loop {
         match check_switching_status(&state_base_dir).await? {
-            SystemSwitchStatus::Successful { reboot_required } => {
-                // TODO: check if we have to reboot also due to a kernel or some other thing that changed that requires a reboot. If we do, only consider things successful after the reboot. https://github.com/thefossguy/nixos-needsreboot
-                state.mark_new_system_successful().await?;
-                break;
-            }
             SystemSwitchStatus::InProgress => {
-                dbus_connection.wait_configuration_switch_complete().await?;
-                // After the wait, we'll continue through the loop so we can evaluate the results once again.
+                dbus_connection_input
+                    .wait_configuration_switch_complete()
+                    .await?;
+                // After the wait, we'll check the tracking files once again to evaluate the results.
             }
-            SystemSwitchStatus::Failed(_) => {
-                state.mark_new_system_failed().await?;
-                break;
+            status => return Ok(status),
+        }
+    }
+}
+
+async fn update_state_after_system_switch(
+    state: &mut AgentState,
+    status: SystemSwitchStatus,
+) -> anyhow::Result<()> {
+    let switch_action = state
+        .status()
+        .inner_configuration()
+        .map(|configuration| configuration.switch_action)
+        .unwrap_or_default();
+
+    match status {
+        SystemSwitchStatus::Successful { reboot_required } => {
+            let reboot_required = match switch_action {
+                // The activation command only tells us about some of the cases that require a reboot, so we'll also check by ourselves.
+                SwitchAction::Switch => {
+                    reboot_required
+                        || state
+                            .new_configuration_changes_boot_components()
+                            .await
+                            .unwrap_or_else(|err| {
+                                tracing::warn!(?err, "Failed to check whether the new system configuration requires a reboot. Will assume it doesn't.");
+                                false
+                            })
+                }
+                // Nothing got activated, so the configuration only takes effect once the system boots into it.
+                SwitchAction::Boot => true,
+                // The configuration isn't the boot default, so a reboot would never get us into it. It's as done as it will ever be.
+                SwitchAction::Test => false,
+            };
+
+            if reboot_required {
+                state.mark_new_system_pending_reboot().await?;
+            } else {
+                state.mark_new_system_successful().await?;
             }
         }
+        // We only get the status once `wait_for_system_switch` saw the activation finish.
+        SystemSwitchStatus::InProgress => unreachable!("the activation is still in progress"),
+        SystemSwitchStatus::Failed(status_codes) => {
+            if status_codes.tracking_file_already_existed() {
+                tracing::error!("The system switch failed because system-switch-tracker found a tracking file left over from an earlier switch.");
+            } else {
+                tracing::error!(service_result = %status_codes.service_result, exit_code = %status_codes.exit_code, exit_status = %status_codes.exit_status, "The system switch failed.");
+            }
+            state.mark_new_system_failed().await?;
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn switch_waits_for_package_deletion() {
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        let package_delete_task = PendingTask::spawn(async move {
+            let _ = release_rx.await;
+        });
+
+        let mut waiting = tokio::spawn(wait_before_switching(None, Some(&package_delete_task)));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), &mut waiting)
+                .await
+                .is_err()
+        );
+
+        release_tx.send(()).unwrap();
+        waiting.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn switch_stops_waiting_for_aborted_package_deletion() {
+        let package_delete_task = PendingTask::spawn(std::future::pending());
+        let waiting = tokio::spawn(wait_before_switching(None, Some(&package_delete_task)));
+
+        package_delete_task.handle.abort();
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
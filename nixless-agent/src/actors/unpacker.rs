@@ -9,29 +9,43 @@ use std::{
 
 use anyhow::{anyhow, Context};
 use derive_builder::Builder;
+use futures::{StreamExt, TryStreamExt};
 use nix::sys::{
     stat::{utimensat, UtimensatFlags},
     time::TimeSpec,
 };
-use nix_nar::Decoder;
+use nix_core::to_nix32;
+use nix_nar::{Decoder, Encoder};
+use sha2::{Digest, Sha256};
 use tokio::{
+    runtime::Handle,
     sync::{mpsc, oneshot},
     task::JoinHandle,
 };
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tracing::instrument;
 
-use super::NarDownloadResult;
+use super::{
+    actor_input_stream, ActorInputStream, NarDownloadResult, DEFAULT_ACTOR_CHANNEL_CAPACITY,
+};
+use crate::{
+    error::{send_response, AgentError, AgentResult},
+    path_utils::remove_readonly_path,
+    store_sync::{sync_dir, sync_store_object, StoreSyncMode},
+};
 
 #[derive(Builder)]
 pub struct Unpacker {
     nix_store_dir: PathBuf,
+    max_parallel_unpacks: usize,
+    store_sync_mode: StoreSyncMode,
+    #[builder(default = "DEFAULT_ACTOR_CHANNEL_CAPACITY")]
+    channel_capacity: usize,
 }
 
 pub enum UnpackerRequest {
     UnpackDownloads {
         downloads: Vec<NarDownloadResult>,
-        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+        resp_tx: oneshot::Sender<AgentResult<()>>,
     },
     Shutdown,
 }
@@ -40,6 +54,7 @@ pub enum UnpackerRequest {
 pub struct StartedUnpacker {
     task: JoinHandle<anyhow::Result<()>>,
     input: StartedUnpackerInput,
+    shutdown_tx: oneshot::Sender<()>,
 }
 
 impl StartedUnpacker {
@@ -48,7 +63,8 @@ impl StartedUnpacker {
     }
 
     pub async fn shutdown(self) -> anyhow::Result<()> {
-        self.input.input_tx.send(UnpackerRequest::Shutdown).await?;
+        // If the task already stopped, waiting for it tells us why.
+        let _ = self.shutdown_tx.send(());
         self.task.await?
     }
 }
@@ -67,14 +83,17 @@ pub struct StartedUnpackerInput {
 }
 
 impl StartedUnpackerInput {
-    pub async fn unpack_downloads(&self, downloads: Vec<NarDownloadResult>) -> anyhow::Result<()> {
+    pub async fn unpack_downloads(&self, downloads: Vec<NarDownloadResult>) -> AgentResult<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
 
         self.input_tx
             .send(UnpackerRequest::UnpackDownloads { downloads, resp_tx })
-            .await?;
+            .await
+            .map_err(|_| AgentError::channel_closed("unpacker"))?;
 
-        resp_rx.await?
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("unpacker"))?
     }
 }
 
@@ -84,13 +103,22 @@ impl Unpacker {
     }
 
     pub fn start(self) -> StartedUnpacker {
-        let (input_tx, input_rx) = mpsc::channel(10);
-
-        let task = tokio::spawn(unpacker_task(self.nix_store_dir, input_rx));
+        let (input_tx, input_rx) = mpsc::channel(self.channel_capacity);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let input_stream =
+            actor_input_stream(input_rx, shutdown_rx, |()| UnpackerRequest::Shutdown);
+
+        let task = tokio::spawn(unpacker_task(
+            self.nix_store_dir,
+            self.max_parallel_unpacks,
+            self.store_sync_mode,
+            input_stream,
+        ));
 
         StartedUnpacker {
             task,
             input: StartedUnpackerInput { input_tx },
+            shutdown_tx,
         }
     }
 }
@@ -98,10 +126,10 @@ impl Unpacker {
 #[instrument(skip_all)]
 async fn unpacker_task(
     nix_store_dir: PathBuf,
-    input_rx: mpsc::Receiver<UnpackerRequest>,
+    max_parallel_unpacks: usize,
+    store_sync_mode: StoreSyncMode,
+    mut input_stream: ActorInputStream<UnpackerRequest>,
 ) -> anyhow::Result<()> {
-    let mut input_stream = ReceiverStream::new(input_rx);
-
     tracing::info!("Unpacker will now enter its main loop.");
 
     while let Some(req) = input_stream.next().await {
@@ -111,26 +139,42 @@ async fn unpacker_task(
                 break;
             }
             UnpackerRequest::UnpackDownloads { downloads, resp_tx } => {
-                // TODO: this currently runs on a single thread. Moving it to multiple threads (but still bounded by some limit) is not too trivial and will require a bit of thought.
-                let nix_store_dir_clone = nix_store_dir.clone();
-                let unpack_task = tokio::task::spawn_blocking(move || {
-                    let downloads_to_unpack =
-                        downloads.into_iter().filter(|d| !d.is_already_unpacked);
-                    for download in downloads_to_unpack {
-                        unpack_one_nar(
-                            &nix_store_dir_clone,
-                            &download.package_id,
-                            &download.nar_path,
-                        )?;
-                    }
-
-                    Ok(())
-                });
-
-                let res = unpack_task.await?;
-                resp_tx
-                    .send(res)
-                    .map_err(|_| anyhow!("channel closed before we could send the response"))?;
+                let expected_package_ids: Vec<String> =
+                    downloads.iter().map(|d| d.package_id.clone()).collect();
+
+                // Each NAR gets unpacked to its own temporary directory and final path, so they can all be unpacked independently. Each unpack runs on its own blocking thread, and `buffer_unordered` bounds how many of those run at the same time. The closure passed to `map()` only runs when the stream is polled, so we don't spawn more threads than that limit.
+                let downloads_to_unpack = downloads.into_iter().filter(|d| !d.is_already_unpacked);
+                let res = futures::stream::iter(downloads_to_unpack)
+                    .map(|download| {
+                        let nix_store_dir_clone = nix_store_dir.clone();
+                        async move {
+                            tokio::task::spawn_blocking(move || {
+                                unpack_one_nar(
+                                    &nix_store_dir_clone,
+                                    &download.package_id,
+                                    &download.nar_path,
+                                    &download.nar_hash,
+                                    store_sync_mode,
+                                )
+                            })
+                            .await?
+                        }
+                    })
+                    .buffer_unordered(max_parallel_unpacks)
+                    // Stops at the first error, so any single failure fails the whole batch.
+                    .try_collect::<Vec<()>>()
+                    .await
+                    .map(|_| ())
+                    .and_then(|_| ensure_packages_in_store(&nix_store_dir, &expected_package_ids))
+                    .and_then(|_| {
+                        // Makes all the renames into the store durable at once, instead of syncing the store directory after each package.
+                        if store_sync_mode.syncs_files() {
+                            sync_dir(&nix_store_dir)?;
+                        }
+                        Ok(())
+                    })
+                    .map_err(AgentError::Unpack);
+                send_response(resp_tx, res, "unpacker");
             }
         }
     }
@@ -139,32 +183,128 @@ async fn unpacker_task(
     Ok(())
 }
 
+/// Checks that every package in `package_ids` exists in the Nix store, returning an error listing all the ones that are missing. This covers both the packages we just unpacked and the ones the downloader assumed were already in the store.
+fn ensure_packages_in_store(nix_store_dir: &PathBuf, package_ids: &[String]) -> anyhow::Result<()> {
+    // `symlink_metadata()` so that dangling symlinks still count as present, same as in `unpack_one_nar()`.
+    let missing_package_ids: Vec<&str> = package_ids
+        .iter()
+        .filter(|package_id| nix_store_dir.join(package_id).symlink_metadata().is_err())
+        .map(|package_id| package_id.as_str())
+        .collect();
+
+    if missing_package_ids.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Unpacking finished, but {} package(s) are still missing from the Nix store: {}",
+            missing_package_ids.len(),
+            missing_package_ids.join(", ")
+        ))
+    }
+}
+
 fn unpack_one_nar(
     nix_store_dir: &PathBuf,
     package_id: &str,
     nar_path: &PathBuf,
+    nar_hash: &str,
+    store_sync_mode: StoreSyncMode,
 ) -> anyhow::Result<()> {
-    // TODO: double check that the NAR exists and the store path to unpack to doesn't exist.
+    let final_path = nix_store_dir.join(package_id);
+
+    // `symlink_metadata()` so that we also catch store paths that are dangling symlinks.
+    if final_path.symlink_metadata().is_ok() {
+        let existing_hash = nar_hash_of_path(&final_path)?;
+
+        if existing_hash != nar_hash {
+            return Err(anyhow!(
+                "{} already exists in the Nix store, but its contents don't match the package we downloaded. Got hash {}, expected {}",
+                package_id,
+                existing_hash,
+                nar_hash
+            ));
+        }
+
+        tracing::info!(
+            package_id,
+            "Package already exists in the Nix store with the expected contents, so we'll skip unpacking it."
+        );
+        if nar_path.exists() {
+            std::fs::remove_file(nar_path)?;
+        }
+        return Ok(());
+    }
+
+    if !nar_path.exists() {
+        return Err(anyhow!(
+            "The NAR for {} should have been downloaded to {}, but it's not there",
+            package_id,
+            nar_path.display()
+        ));
+    }
 
     let tmp_dir_name: String = repeat_with(fastrand::alphanumeric).take(12).collect();
     let tmp_dir = nix_store_dir.join(tmp_dir_name);
+    unpack_into_store(nar_path, &tmp_dir, &final_path, store_sync_mode)?;
+
+    // Since the NAR unpacking is done, we'll delete it.
+    std::fs::remove_file(nar_path)?;
 
+    Ok(())
+}
+
+/// Unpacks the NAR into a temporary directory and moves it into its final place in the store only once it's fully finalised, so a failure never leaves a partially finalised package in the store.
+fn unpack_into_store(
+    nar_path: &PathBuf,
+    tmp_dir: &PathBuf,
+    final_path: &PathBuf,
+    store_sync_mode: StoreSyncMode,
+) -> anyhow::Result<()> {
+    let result = unpack_and_move_into_store(nar_path, tmp_dir, final_path, store_sync_mode);
+
+    if result.is_err() {
+        // Whatever step failed, we don't want the temporary directory to linger in the store. We're in a blocking thread from the runtime, so we can block on the async removal here.
+        if let Err(clean_up_err) = Handle::current().block_on(remove_readonly_path(tmp_dir.clone()))
+        {
+            tracing::warn!(
+                ?clean_up_err,
+                ?tmp_dir,
+                "Failed to remove the temporary directory of a failed unpack."
+            );
+        }
+    }
+
+    result
+}
+
+fn unpack_and_move_into_store(
+    nar_path: &PathBuf,
+    tmp_dir: &PathBuf,
+    final_path: &PathBuf,
+    store_sync_mode: StoreSyncMode,
+) -> anyhow::Result<()> {
     let file = File::options().read(true).open(nar_path)?;
     let nar_decoder = Decoder::new(file)?;
     nar_decoder
-        .unpack(&tmp_dir)
+        .unpack(tmp_dir)
         .context("Failed to unpack a NAR with the decoder")?;
     drop(nar_decoder);
 
-    let final_path = nix_store_dir.join(package_id);
+    finalise_nix_store_object(tmp_dir)?;
+    // The contents must be on disk before the rename, otherwise a power loss could leave a package in its final place with truncated files.
+    sync_store_object(tmp_dir, store_sync_mode)?;
+    std::fs::rename(tmp_dir, final_path)?;
 
-    std::fs::rename(&tmp_dir, &final_path)?;
-    finalise_nix_store_object(&final_path)?;
+    Ok(())
+}
 
-    // Since the NAR unpacking is done, we'll delete it.
-    std::fs::remove_file(nar_path)?;
+/// Serialises the path as a NAR and hashes it, returning the hash in the same format used by narinfo files.
+fn nar_hash_of_path(path: &PathBuf) -> anyhow::Result<String> {
+    let mut encoder = Encoder::new(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut encoder, &mut hasher)?;
 
-    Ok(())
+    Ok(format!("sha256:{}", to_nix32(&hasher.finalize())))
 }
 
 /// Objects in the Nix store shouldn't be writable, their timestamps should be set to the epoch, certain attributes removed and so on. This function handles all of that.
@@ -208,3 +348,84 @@ fn finalise_nix_store_object(object_path: &PathBuf) -> anyhow::Result<()> {
     lchown(object_path, Some(0), Some(0))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, path::Path};
+
+    use super::*;
+
+    const PACKAGE_ID: &str = "0c0fbflsgmvl9j3ag6p0h2ja1bxmd5ii-hello-2.12.1";
+
+    fn package_nar(source_dir: &Path) -> Vec<u8> {
+        let package_dir = source_dir.join("package");
+        std::fs::create_dir(&package_dir).unwrap();
+        std::fs::write(package_dir.join("a"), vec![b'a'; 1 << 12]).unwrap();
+        std::fs::write(package_dir.join("b"), vec![b'b'; 1 << 12]).unwrap();
+
+        let mut nar = Vec::new();
+        Encoder::new(&package_dir)
+            .unwrap()
+            .read_to_end(&mut nar)
+            .unwrap();
+        nar
+    }
+
+    #[tokio::test]
+    async fn failed_unpack_leaves_no_temporary_directory() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let mut nar = package_nar(source_dir.path());
+        // Cutting the NAR in the middle of the second file means the decoder will have already created the temporary directory (and some of its contents) when it fails.
+        nar.truncate(nar.len() - (1 << 11));
+
+        let nar_path = source_dir.path().join("package.nar");
+        std::fs::write(&nar_path, nar).unwrap();
+
+        let store_dir = tempfile::tempdir().unwrap();
+        let nix_store_dir = store_dir.path().to_path_buf();
+
+        let result = tokio::task::spawn_blocking(move || {
+            unpack_one_nar(
+                &nix_store_dir,
+                PACKAGE_ID,
+                &nar_path,
+                "sha256:0000000000000000000000000000000000000000000000000000",
+                StoreSyncMode::None,
+            )
+        })
+        .await
+        .unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_dir(store_dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_move_into_store_leaves_no_temporary_directory() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let nar_path = source_dir.path().join("package.nar");
+        std::fs::write(&nar_path, package_nar(source_dir.path())).unwrap();
+
+        let store_dir = tempfile::tempdir().unwrap();
+        let tmp_dir = store_dir.path().join("tmp");
+        // Something already in the final place means the unpacked package gets fully finalised, but can't be moved there.
+        let final_path = store_dir.path().join(PACKAGE_ID);
+        std::fs::create_dir(&final_path).unwrap();
+        std::fs::write(final_path.join("existing"), "").unwrap();
+
+        let result = tokio::task::spawn_blocking(move || {
+            unpack_into_store(&nar_path, &tmp_dir, &final_path, StoreSyncMode::None)
+        })
+        .await
+        .unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(
+            std::fs::read_dir(store_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>(),
+            vec![PACKAGE_ID]
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use anyhow::anyhow;
+use nix_core::{from_nix32, to_nix32, StorePath};
+use sha2::{Digest, Sha256};
+
+use crate::owned_nar_info::OwnedNarInfo;
+
+/// How a content-addressed store path got its hash, as given by the `CA` field of a narinfo. We only know how to check paths hashed with sha256, which is what Nix uses by default.
+#[derive(Debug, PartialEq)]
+pub enum ContentAddress {
+    /// `text:sha256:<hash>`, used for files created with `builtins.toFile` and for derivations.
+    Text { hash: [u8; 32] },
+    /// `fixed:r:sha256:<hash>` if `recursive` (the hash is of the NAR), or `fixed:sha256:<hash>` if not (the hash is of the contents of a single file).
+    Fixed { recursive: bool, hash: [u8; 32] },
+}
+
+impl ContentAddress {
+    /// Returns `None` for content addresses that are valid but that we don't know how to check, e.g. ones using a hash other than sha256.
+    pub fn parse(s: &str) -> anyhow::Result<Option<Self>> {
+        let (kind, rest) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("the content address {:?} has no method", s))?;
+
+        let (recursive, hash) = match kind {
+            "text" => (false, rest),
+            "fixed" => match rest.strip_prefix("r:") {
+                Some(hash) => (true, hash),
+                None if rest.starts_with("git:") => return Ok(None),
+                None => (false, rest),
+            },
+            _ => return Err(anyhow!("the content address {:?} has an unknown method", s)),
+        };
+
+        let Some(hash) = hash.strip_prefix("sha256:") else {
+            return Ok(None);
+        };
+        let hash = parse_sha256(hash)
+            .ok_or_else(|| anyhow!("the content address {:?} has an invalid hash", s))?;
+
+        Ok(Some(match kind {
+            "text" => Self::Text { hash },
+            _ => Self::Fixed { recursive, hash },
+        }))
+    }
+
+    /// Computes the store path (as a package id, without the store dir) that a package with this content address, `name` and `references` must have. `references` may include the package itself, as long as its content address allows it.
+    /// https://github.com/NixOS/nix/blob/2.24.9/src/libstore/store-dir-config.cc
+    pub fn store_path(
+        &self,
+        store_dir: &str,
+        name: &str,
+        own_package_id: &str,
+        references: &[String],
+    ) -> anyhow::Result<String> {
+        let self_reference = references.iter().any(|r| r == own_package_id);
+        let mut other_references: Vec<_> =
+            references.iter().filter(|r| *r != own_package_id).collect();
+        // Nix keeps references in a sorted set, and the order matters for the hash.
+        other_references.sort();
+
+        let path_type = |mut path_type: String| {
+            for reference in &other_references {
+                path_type.push_str(&format!(":{}/{}", store_dir, reference));
+            }
+            if self_reference {
+                path_type.push_str(":self");
+            }
+            path_type
+        };
+
+        match self {
+            Self::Text { hash } => {
+                if self_reference {
+                    return Err(anyhow!(
+                        "text content-addressed paths can't refer to themselves"
+                    ));
+                }
+                Ok(make_store_path(
+                    &path_type("text".to_string()),
+                    hash,
+                    store_dir,
+                    name,
+                ))
+            }
+            Self::Fixed {
+                recursive: true,
+                hash,
+            } => Ok(make_store_path(
+                &path_type("source".to_string()),
+                hash,
+                store_dir,
+                name,
+            )),
+            Self::Fixed {
+                recursive: false,
+                hash,
+            } => {
+                if !references.is_empty() {
+                    return Err(anyhow!(
+                        "flat fixed-output paths can't have references, but this one has {}",
+                        references.len()
+                    ));
+                }
+                let inner_hash: [u8; 32] =
+                    Sha256::digest(format!("fixed:out:sha256:{}:", hex(hash))).into();
+                Ok(make_store_path("output:out", &inner_hash, store_dir, name))
+            }
+        }
+    }
+}
+
+/// If the narinfo has a content address we understand, checks that the store path really is the one the content address leads to. For `fixed:r:sha256` paths, the content address must also match the NAR hash, which gets checked against the NAR once it's downloaded, so the store path gets tied to the data we unpack. For other content addresses, the hash is of something other than the NAR, so we can only check that the narinfo is consistent and have to keep trusting the signature for the data.
+pub fn verify_content_address(nar_info: &OwnedNarInfo, package_id: &str) -> anyhow::Result<()> {
+    let Some(ca) = nar_info.ca.as_deref() else {
+        return Ok(());
+    };
+
+    let Some(content_address) = ContentAddress::parse(ca)? else {
+        tracing::debug!(
+            package_id,
+            ca,
+            "Package has a content address we can't check, will rely on its signature only."
+        );
+        return Ok(());
+    };
+
+    let store_path = StorePath::from_package_id(package_id)?;
+    let (store_dir, _) = nar_info
+        .store_path
+        .rsplit_once('/')
+        .ok_or_else(|| anyhow!("this NAR info doesn't have a store path in the expected format"))?;
+
+    let expected_package_id = content_address.store_path(
+        store_dir,
+        store_path.name(),
+        package_id,
+        &nar_info.references,
+    )?;
+    if expected_package_id != package_id {
+        return Err(anyhow!(
+            "the content address of {} leads to a different store path: {}",
+            package_id,
+            expected_package_id
+        ));
+    }
+
+    if let ContentAddress::Fixed {
+        recursive: true,
+        hash,
+    } = content_address
+    {
+        let nar_hash = nar_info
+            .nar_hash
+            .strip_prefix("sha256:")
+            .and_then(parse_sha256);
+        if nar_hash != Some(hash) {
+            return Err(anyhow!(
+                "the content address of {} doesn't match its NAR hash {}",
+                package_id,
+                nar_info.nar_hash
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// https://github.com/NixOS/nix/blob/2.24.9/src/libstore/store-dir-config.cc#L22
+fn make_store_path(path_type: &str, hash: &[u8; 32], store_dir: &str, name: &str) -> String {
+    let fingerprint = format!("{}:sha256:{}:{}:{}", path_type, hex(hash), store_dir, name);
+    let full_hash = Sha256::digest(fingerprint);
+
+    // Nix compresses the hash down to 20 bytes by xor-ing the extra bytes into the first ones.
+    let mut compressed_hash = [0u8; 20];
+    for (i, byte) in full_hash.iter().enumerate() {
+        compressed_hash[i % compressed_hash.len()] ^= byte;
+    }
+
+    format!("{}-{}", to_nix32(&compressed_hash), name)
+}
+
+/// Nix accepts hashes in nix32 and in base16, so we do the same.
+fn parse_sha256(s: &str) -> Option<[u8; 32]> {
+    match s.len() {
+        52 => from_nix32(s)?.try_into().ok(),
+        64 if s.is_ascii() => (0..32)
+            .map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok())
+            .collect::<Option<Vec<_>>>()?
+            .try_into()
+            .ok(),
+        _ => None,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From the worked examples in Nix Pills (chapter 18): `echo mycontent > myfile`.
+    const MYFILE_FLAT_SHA256: &str =
+        "f3f3c4763037e059b4d834eaf68595bbc02ba19f6d2a500dce06d124e2cd99bb";
+    const MYFILE_NAR_SHA256: &str =
+        "2bfef67de873c54551d884fdab3055d84d573e654efa79db3c0d7b98883f9ee3";
+    const MYFILE_ADDED_PACKAGE_ID: &str = "xv2iccirbrvklck36f1g7vldn5v58vck-myfile";
+    const MYFILE_FIXED_OUTPUT_PACKAGE_ID: &str = "a00d5f71k0vp5a6klkls0mvr1f7sx6ch-bar";
+
+    fn nar_info(package_id: &str, nar_hash: &str, ca: &str) -> OwnedNarInfo {
+        OwnedNarInfo {
+            store_path: format!("/nix/store/{package_id}"),
+            url: "nar/x.nar".to_string(),
+            compression: None,
+            nar_hash: nar_hash.to_string(),
+            nar_size: 0,
+            file_hash: None,
+            file_size: None,
+            deriver: None,
+            system: None,
+            references: vec![],
+            sigs: vec![],
+            ca: Some(ca.to_string()),
+        }
+    }
+
+    #[test]
+    fn recursive_store_path_matches_nix() {
+        let ca = ContentAddress::parse(&format!("fixed:r:sha256:{MYFILE_NAR_SHA256}"))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            ca.store_path("/nix/store", "myfile", MYFILE_ADDED_PACKAGE_ID, &[])
+                .unwrap(),
+            MYFILE_ADDED_PACKAGE_ID
+        );
+    }
+
+    #[test]
+    fn flat_store_path_matches_nix() {
+        let ca = ContentAddress::parse(&format!("fixed:sha256:{MYFILE_FLAT_SHA256}"))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            ca.store_path("/nix/store", "bar", MYFILE_FIXED_OUTPUT_PACKAGE_ID, &[])
+                .unwrap(),
+            MYFILE_FIXED_OUTPUT_PACKAGE_ID
+        );
+    }
+
+    #[test]
+    fn parses_nix32_and_base16_hashes_the_same() {
+        let hash = parse_sha256(MYFILE_NAR_SHA256).unwrap();
+        let nix32 = format!("fixed:r:sha256:{}", to_nix32(&hash));
+
+        assert_eq!(
+            ContentAddress::parse(&nix32).unwrap(),
+            Some(ContentAddress::Fixed {
+                recursive: true,
+                hash
+            })
+        );
+        assert_eq!(
+            ContentAddress::parse(&format!("text:sha256:{MYFILE_NAR_SHA256}")).unwrap(),
+            Some(ContentAddress::Text { hash })
+        );
+    }
+
+    #[test]
+    fn skips_content_addresses_we_cant_check() {
+        assert_eq!(
+            ContentAddress::parse("fixed:git:sha1:0000000000000000000000000000000000000000")
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            ContentAddress::parse("fixed:r:sha1:0000000000000000000000000000000000000000").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_content_addresses() {
+        assert!(ContentAddress::parse("sha256").is_err());
+        assert!(ContentAddress::parse(&format!("unknown:sha256:{MYFILE_NAR_SHA256}")).is_err());
+        assert!(ContentAddress::parse("fixed:r:sha256:not-a-hash").is_err());
+    }
+
+    #[test]
+    fn text_paths_cant_refer_to_themselves() {
+        let ca = ContentAddress::parse(&format!("text:sha256:{MYFILE_FLAT_SHA256}"))
+            .unwrap()
+            .unwrap();
+        let own_package_id = "0c0fbflsgmvl9j3ag6p0h2ja1bxmd5ii-foo";
+
+        assert!(ca
+            .store_path(
+                "/nix/store",
+                "foo",
+                own_package_id,
+                &[own_package_id.to_string()]
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn verifies_matching_narinfo() {
+        let nar_info = nar_info(
+            MYFILE_ADDED_PACKAGE_ID,
+            &format!("sha256:{MYFILE_NAR_SHA256}"),
+            &format!("fixed:r:sha256:{MYFILE_NAR_SHA256}"),
+        );
+
+        verify_content_address(&nar_info, MYFILE_ADDED_PACKAGE_ID).unwrap();
+    }
+
+    #[test]
+    fn rejects_narinfo_with_different_store_path() {
+        let package_id = "0c0fbflsgmvl9j3ag6p0h2ja1bxmd5ii-myfile";
+        let nar_info = nar_info(
+            package_id,
+            &format!("sha256:{MYFILE_NAR_SHA256}"),
+            &format!("fixed:r:sha256:{MYFILE_NAR_SHA256}"),
+        );
+
+        assert!(verify_content_address(&nar_info, package_id).is_err());
+    }
+
+    #[test]
+    fn rejects_narinfo_with_different_nar_hash() {
+        let nar_info = nar_info(
+            MYFILE_ADDED_PACKAGE_ID,
+            &format!("sha256:{MYFILE_FLAT_SHA256}"),
+            &format!("fixed:r:sha256:{MYFILE_NAR_SHA256}"),
+        );
+
+        assert!(verify_content_address(&nar_info, MYFILE_ADDED_PACKAGE_ID).is_err());
+    }
+}
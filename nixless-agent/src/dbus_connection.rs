@@ -1,8 +1,11 @@
-use std::{collections::HashMap, ops::Deref, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap, fmt, ops::Deref, path::PathBuf, str::FromStr, sync::Arc, time::Duration,
+};
 
 use anyhow::{anyhow, Context};
 use dbus::{
     arg::{RefArg, Variant},
+    channel::Channel,
     nonblock::{stdintf::org_freedesktop_dbus::Properties, Proxy, SyncConnection},
     Path,
 };
@@ -11,15 +14,193 @@ use tokio::{
     sync::{mpsc, oneshot},
     task::JoinHandle,
 };
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tokio_stream::StreamExt;
+
+#[cfg(feature = "mock-activation")]
+use crate::mock_activation::{mock_dbus_connection_task, MockActivationOutcome};
+use crate::{
+    actors::{actor_input_stream, ActorInputStream, DEFAULT_ACTOR_CHANNEL_CAPACITY},
+    error::{send_response, AgentError, AgentResult},
+    system_configuration::SwitchAction,
+};
 
 const TRANSIENT_SERVICE_NAME: &str = "nixless-agent-system-switch.service";
+/// systemd checks this action itself whenever we manage units, so it's the one we check by default.
+const DEFAULT_POLKIT_ACTION_ID: &str = "org.freedesktop.systemd1.manage-units";
+
+/// The polkit action we check at startup to find out whether we can possibly be authorised to switch systems. polkit only accepts lowercase ASCII letters, digits, `.` and `-` in action ids, and action ids are namespaced like D-Bus names, so we expect at least two non-empty components separated by dots.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolkitActionId(String);
+
+impl Default for PolkitActionId {
+    fn default() -> Self {
+        Self(DEFAULT_POLKIT_ACTION_ID.to_string())
+    }
+}
+
+impl FromStr for PolkitActionId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let well_formed = s.contains('.')
+            && s.split('.').all(|component| {
+                !component.is_empty()
+                    && component
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            });
+
+        if !well_formed {
+            return Err(anyhow!(
+                "invalid polkit action id {:?}, expected dot-separated components with only lowercase letters, digits and dashes, e.g. {}",
+                s,
+                DEFAULT_POLKIT_ACTION_ID
+            ));
+        }
+
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl fmt::Display for PolkitActionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A resource limit for the transient unit that activates new configurations. Parsed from `Key=Value`, using the same keys as systemd unit files:
+/// - `MemoryMax`: bytes, with an optional `K`, `M`, `G` or `T` suffix (base 1024), or `infinity`.
+/// - `CPUQuota`: a percentage of a single CPU, e.g. `50%` or `200%`.
+/// - `OOMScoreAdjust`: an integer from -1000 to 1000.
+/// - `TimeoutStartSec`: whole seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ActivationProperty {
+    MemoryMax(u64),
+    /// Stored as a percentage, since that's how it's given, but sent to systemd as CPU time per second.
+    CpuQuota(u64),
+    OomScoreAdjust(i32),
+    TimeoutStart(Duration),
+}
+
+impl ActivationProperty {
+    /// The names and types of the properties come from https://www.freedesktop.org/software/systemd/man/latest/org.freedesktop.systemd1.html, which don't always match the names used in unit files.
+    fn to_dbus_property(self) -> (&'static str, Variant<Box<dyn RefArg>>) {
+        match self {
+            Self::MemoryMax(bytes) => ("MemoryMax", Variant(Box::new(bytes))),
+            Self::CpuQuota(percent) => (
+                "CPUQuotaPerSecUSec",
+                Variant(Box::new(percent.saturating_mul(1_000_000 / 100))),
+            ),
+            Self::OomScoreAdjust(adjust) => ("OOMScoreAdjust", Variant(Box::new(adjust))),
+            Self::TimeoutStart(timeout) => (
+                "TimeoutStartUSec",
+                Variant(Box::new(timeout.as_micros() as u64)),
+            ),
+        }
+    }
+}
+
+impl FromStr for ActivationProperty {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected Key=Value, got {}", s))?;
+
+        match key {
+            "MemoryMax" => {
+                if value == "infinity" {
+                    return Ok(Self::MemoryMax(u64::MAX));
+                }
+
+                let (number, multiplier) = match value.char_indices().last() {
+                    Some((i, 'K')) => (&value[..i], 1024),
+                    Some((i, 'M')) => (&value[..i], 1024 * 1024),
+                    Some((i, 'G')) => (&value[..i], 1024 * 1024 * 1024),
+                    Some((i, 'T')) => (&value[..i], 1024 * 1024 * 1024 * 1024),
+                    _ => (value, 1),
+                };
+                let bytes = number
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|number| number.checked_mul(multiplier))
+                    .ok_or_else(|| anyhow!("invalid value for MemoryMax: {}", value))?;
+                Ok(Self::MemoryMax(bytes))
+            }
+            "CPUQuota" => {
+                let percent = value
+                    .strip_suffix('%')
+                    .and_then(|percent| percent.parse::<u64>().ok())
+                    .filter(|percent| *percent > 0)
+                    .ok_or_else(|| {
+                        anyhow!("invalid value for CPUQuota, expected a percentage: {}", value)
+                    })?;
+                Ok(Self::CpuQuota(percent))
+            }
+            "OOMScoreAdjust" => {
+                let adjust = value
+                    .parse::<i32>()
+                    .ok()
+                    .filter(|adjust| (-1000..=1000).contains(adjust))
+                    .ok_or_else(|| anyhow!("invalid value for OOMScoreAdjust: {}", value))?;
+                Ok(Self::OomScoreAdjust(adjust))
+            }
+            "TimeoutStartSec" => {
+                let secs = value
+                    .parse::<u64>()
+                    .map_err(|_| anyhow!("invalid value for TimeoutStartSec: {}", value))?;
+                Ok(Self::TimeoutStart(Duration::from_secs(secs)))
+            }
+            _ => Err(anyhow!(
+                "unsupported activation property {}, expected one of MemoryMax, CPUQuota, OOMScoreAdjust or TimeoutStartSec",
+                key
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ActivationProperty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MemoryMax(u64::MAX) => write!(f, "MemoryMax=infinity"),
+            Self::MemoryMax(bytes) => write!(f, "MemoryMax={}", bytes),
+            Self::CpuQuota(percent) => write!(f, "CPUQuota={}%", percent),
+            Self::OomScoreAdjust(adjust) => write!(f, "OOMScoreAdjust={}", adjust),
+            Self::TimeoutStart(timeout) => write!(f, "TimeoutStartSec={}", timeout.as_secs()),
+        }
+    }
+}
+
+/// What polkit told us when we checked whether we could be authorised to switch systems.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PolkitAuthorisation {
+    Authorised,
+    /// polkit would only authorise us after someone authenticates interactively, which never happens for a daemon running on its own.
+    ChallengeRequired,
+    NotAuthorised,
+}
 
 #[derive(Builder)]
 pub struct DBusConnection {
     relative_configuration_activation_command: PathBuf,
     absolute_activation_tracker_command: PathBuf,
     activation_track_dir: PathBuf,
+    /// If not given, we'll connect to the system bus. Note that libdbus already honours `DBUS_SYSTEM_BUS_ADDRESS` when connecting to the system bus.
+    #[builder(default)]
+    bus_address: Option<String>,
+    /// Added to the properties of the transient unit that activates new configurations.
+    #[builder(default)]
+    activation_properties: Vec<ActivationProperty>,
+    /// Checked at startup, in addition to systemd's `manage-units` action, since systemd checks that one itself whenever we manage units.
+    #[builder(default)]
+    polkit_action_id: PolkitActionId,
+    /// If given, we won't connect to D-Bus at all and will only pretend to switch configurations. Only meant for tests.
+    #[cfg(feature = "mock-activation")]
+    #[builder(default)]
+    mock_activation_outcome: Option<MockActivationOutcome>,
+    #[builder(default = "DEFAULT_ACTOR_CHANNEL_CAPACITY")]
+    channel_capacity: usize,
 }
 
 impl DBusConnection {
@@ -28,16 +209,37 @@ impl DBusConnection {
     }
 
     pub fn start(self) -> StartedDBusConnection {
-        let (input_tx, input_rx) = mpsc::channel(10);
+        let (input_tx, input_rx) = mpsc::channel(self.channel_capacity);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let input_stream =
+            actor_input_stream(input_rx, shutdown_rx, |()| DBusConnectionRequest::Shutdown);
+
+        #[cfg(feature = "mock-activation")]
+        if let Some(outcome) = self.mock_activation_outcome {
+            let task = tokio::spawn(mock_dbus_connection_task(
+                input_stream,
+                outcome,
+                self.activation_track_dir,
+            ));
+
+            return StartedDBusConnection {
+                task,
+                input: StartedDBusConnectionInput { input_tx },
+                shutdown_tx,
+            };
+        }
 
         let input_tx_clone = input_tx.clone();
         let task = tokio::spawn(async {
             match dbus_connection_task(
-                input_rx,
+                input_stream,
                 input_tx_clone,
                 self.relative_configuration_activation_command,
                 self.absolute_activation_tracker_command,
                 self.activation_track_dir,
+                self.bus_address,
+                self.activation_properties,
+                self.polkit_action_id,
             )
             .await
             {
@@ -55,6 +257,7 @@ impl DBusConnection {
         StartedDBusConnection {
             task,
             input: StartedDBusConnectionInput { input_tx },
+            shutdown_tx,
         }
     }
 }
@@ -63,6 +266,7 @@ impl DBusConnection {
 pub struct StartedDBusConnection {
     task: JoinHandle<anyhow::Result<()>>,
     input: StartedDBusConnectionInput,
+    shutdown_tx: oneshot::Sender<()>,
 }
 
 impl StartedDBusConnection {
@@ -71,10 +275,8 @@ impl StartedDBusConnection {
     }
 
     pub async fn shutdown(self) -> anyhow::Result<()> {
-        self.input
-            .input_tx
-            .send(DBusConnectionRequest::Shutdown)
-            .await?;
+        // If the task already stopped, waiting for it tells us why.
+        let _ = self.shutdown_tx.send(());
         self.task.await?
     }
 }
@@ -93,63 +295,104 @@ pub struct StartedDBusConnectionInput {
 }
 
 impl StartedDBusConnectionInput {
-    pub async fn check_authorisation_possibility(&self) -> anyhow::Result<bool> {
+    pub async fn check_authorisation_possibility(&self) -> AgentResult<PolkitAuthorisation> {
         let (resp_tx, resp_rx) = oneshot::channel();
 
         self.input_tx
             .send(DBusConnectionRequest::CheckAuthorisationPossibility { resp_tx })
-            .await?;
-        resp_rx.await?
+            .await
+            .map_err(|_| AgentError::channel_closed("D-Bus connection"))?;
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("D-Bus connection"))?
     }
 
     pub async fn perform_configuration_switch(
         &self,
         system_package_path: PathBuf,
-    ) -> anyhow::Result<()> {
+        action: SwitchAction,
+        switch_id: String,
+    ) -> AgentResult<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
 
         self.input_tx
             .send(DBusConnectionRequest::PerformConfigurationSwitch {
                 system_package_path,
+                action,
+                switch_id,
                 resp_tx,
             })
-            .await?;
-        resp_rx.await?
+            .await
+            .map_err(|_| AgentError::channel_closed("D-Bus connection"))?;
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("D-Bus connection"))?
     }
 
-    pub async fn wait_configuration_switch_complete(&self) -> anyhow::Result<()> {
+    pub async fn wait_configuration_switch_complete(&self) -> AgentResult<()> {
         let (resp_tx, resp_rx) = oneshot::channel();
 
         self.input_tx
             .send(DBusConnectionRequest::WaitConfigurationSwitchComplete { resp_tx })
-            .await?;
-        resp_rx.await?
+            .await
+            .map_err(|_| AgentError::channel_closed("D-Bus connection"))?;
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("D-Bus connection"))?
+    }
+
+    pub async fn reboot(&self) -> AgentResult<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        self.input_tx
+            .send(DBusConnectionRequest::Reboot { resp_tx })
+            .await
+            .map_err(|_| AgentError::channel_closed("D-Bus connection"))?;
+        resp_rx
+            .await
+            .map_err(|_| AgentError::channel_closed("D-Bus connection"))?
     }
 }
 
 pub enum DBusConnectionRequest {
     CheckAuthorisationPossibility {
-        resp_tx: oneshot::Sender<anyhow::Result<bool>>,
+        resp_tx: oneshot::Sender<AgentResult<PolkitAuthorisation>>,
     },
     PerformConfigurationSwitch {
         system_package_path: PathBuf,
-        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+        action: SwitchAction,
+        /// Passed on to the tracking files, so the agent can tell which switch they came from.
+        switch_id: String,
+        resp_tx: oneshot::Sender<AgentResult<()>>,
     },
     WaitConfigurationSwitchComplete {
-        resp_tx: oneshot::Sender<anyhow::Result<()>>,
+        resp_tx: oneshot::Sender<AgentResult<()>>,
+    },
+    Reboot {
+        resp_tx: oneshot::Sender<AgentResult<()>>,
     },
     ClearPendingSwitchTask,
     Shutdown,
 }
 
 async fn dbus_connection_task(
-    input_rx: mpsc::Receiver<DBusConnectionRequest>,
+    mut input_stream: ActorInputStream<DBusConnectionRequest>,
     input_tx: mpsc::Sender<DBusConnectionRequest>,
     relative_configuration_activation_command: PathBuf,
     absolute_activation_tracker_command: PathBuf,
     activation_track_dir: PathBuf,
+    bus_address: Option<String>,
+    activation_properties: Vec<ActivationProperty>,
+    polkit_action_id: PolkitActionId,
 ) -> anyhow::Result<()> {
-    let (resource, conn) = dbus_tokio::connection::new_system_sync()?;
+    let (resource, conn) = if let Some(bus_address) = bus_address {
+        tracing::info!(bus_address, "Connecting to the configured D-Bus bus.");
+        let mut channel = Channel::open_private(&bus_address)?;
+        channel.register()?;
+        dbus_tokio::connection::from_channel::<SyncConnection>(channel)?
+    } else {
+        dbus_tokio::connection::new_system_sync()?
+    };
 
     let dbus_task = tokio::spawn(async move {
         let err = resource.await;
@@ -157,8 +400,6 @@ async fn dbus_connection_task(
         panic!("D-Bus got disconnected with the following error: {}", err);
     });
 
-    let mut input_stream = ReceiverStream::new(input_rx);
-
     tracing::info!(
         "D-Bus connection has finished initialisation and will now enter its main loop."
     );
@@ -180,13 +421,15 @@ async fn dbus_connection_task(
                 pending_switch_task = None;
             }
             DBusConnectionRequest::CheckAuthorisationPossibility { resp_tx } => {
-                let res = check_polkit_authorised(conn.clone()).await;
-                resp_tx
-                    .send(res)
-                    .map_err(|_| anyhow!("channel closed before we could send the response"))?;
+                let res = check_polkit_authorised(conn.clone(), &polkit_action_id)
+                    .await
+                    .map_err(AgentError::Activation);
+                send_response(resp_tx, res, "D-Bus connection");
             }
             DBusConnectionRequest::PerformConfigurationSwitch {
                 system_package_path,
+                action,
+                switch_id,
                 resp_tx,
             } => {
                 if pending_switch_task.is_some() {
@@ -201,18 +444,21 @@ async fn dbus_connection_task(
                 let absolute_activation_tracker_command_clone =
                     absolute_activation_tracker_command.clone();
                 let activation_track_dir_clone = activation_track_dir.clone();
+                let activation_properties_clone = activation_properties.clone();
                 let input_tx_clone = input_tx.clone();
                 pending_switch_task = Some(tokio::spawn(async move {
                     let res = perform_configuration_switch(
                         conn_clone,
                         activation_command_path,
+                        action,
+                        &switch_id,
                         &absolute_activation_tracker_command_clone,
                         &activation_track_dir_clone,
+                        &activation_properties_clone,
                     )
-                    .await;
-                    resp_tx
-                        .send(res)
-                        .map_err(|_| anyhow!("channel closed before we could send the response"))?;
+                    .await
+                    .map_err(AgentError::Activation);
+                    send_response(resp_tx, res, "D-Bus connection");
                     input_tx_clone
                         .send(DBusConnectionRequest::ClearPendingSwitchTask)
                         .await
@@ -221,10 +467,14 @@ async fn dbus_connection_task(
                 }));
             }
             DBusConnectionRequest::WaitConfigurationSwitchComplete { resp_tx } => {
-                let res = wait_configuration_switch_complete(conn.clone()).await;
-                resp_tx
-                    .send(res)
-                    .map_err(|_| anyhow!("channel closed before we could send the response"))?;
+                let res = wait_configuration_switch_complete(conn.clone())
+                    .await
+                    .map_err(AgentError::Activation);
+                send_response(resp_tx, res, "D-Bus connection");
+            }
+            DBusConnectionRequest::Reboot { resp_tx } => {
+                let res = reboot(conn.clone()).await.map_err(AgentError::Activation);
+                send_response(resp_tx, res, "D-Bus connection");
             }
         }
     }
@@ -242,7 +492,41 @@ async fn dbus_connection_task(
     Ok(())
 }
 
-async fn check_polkit_authorised(conn: Arc<SyncConnection>) -> anyhow::Result<bool> {
+/// systemd checks `manage-units` itself whenever we manage units, so that one is always checked, and a custom action is checked on top of it. We're only as authorised as the least authorised of the two.
+async fn check_polkit_authorised(
+    conn: Arc<SyncConnection>,
+    polkit_action_id: &PolkitActionId,
+) -> anyhow::Result<PolkitAuthorisation> {
+    let manage_units_authorisation =
+        check_polkit_action(conn.clone(), DEFAULT_POLKIT_ACTION_ID).await?;
+    if polkit_action_id.0 == DEFAULT_POLKIT_ACTION_ID {
+        return Ok(manage_units_authorisation);
+    }
+
+    let custom_authorisation = check_polkit_action(conn, &polkit_action_id.0).await?;
+    tracing::info!(
+        ?manage_units_authorisation,
+        ?custom_authorisation,
+        polkit_action_id = polkit_action_id.0,
+        "Checked both systemd's action and the custom polkit action."
+    );
+
+    Ok(match (manage_units_authorisation, custom_authorisation) {
+        (PolkitAuthorisation::NotAuthorised, _) | (_, PolkitAuthorisation::NotAuthorised) => {
+            PolkitAuthorisation::NotAuthorised
+        }
+        (PolkitAuthorisation::ChallengeRequired, _)
+        | (_, PolkitAuthorisation::ChallengeRequired) => PolkitAuthorisation::ChallengeRequired,
+        (PolkitAuthorisation::Authorised, PolkitAuthorisation::Authorised) => {
+            PolkitAuthorisation::Authorised
+        }
+    })
+}
+
+async fn check_polkit_action(
+    conn: Arc<SyncConnection>,
+    polkit_action_id: &str,
+) -> anyhow::Result<PolkitAuthorisation> {
     let conn_name = conn.unique_name().to_string();
 
     // https://www.freedesktop.org/software/polkit/docs/latest/eggdbus-interface-org.freedesktop.PolicyKit1.Authority.html
@@ -265,7 +549,7 @@ async fn check_polkit_authorised(conn: Arc<SyncConnection>) -> anyhow::Result<bo
                 // https://www.freedesktop.org/software/polkit/docs/latest/eggdbus-interface-org.freedesktop.PolicyKit1.Authority.html#eggdbus-method-org.freedesktop.PolicyKit1.Authority.CheckAuthorization
                 (
                     ("system-bus-name", subject_details),
-                    "org.freedesktop.systemd1.manage-units",
+                    polkit_action_id,
                     action_details,
                     0u32,
                     "",
@@ -274,15 +558,24 @@ async fn check_polkit_authorised(conn: Arc<SyncConnection>) -> anyhow::Result<bo
             .await?;
 
     // We'll never fully know if we are 100% authorised until we actually try to perform the action because we can't pass details on the check to policy kit, so this is the best we can do.
-    Ok(is_authorised || is_challenge)
+    Ok(if is_authorised {
+        PolkitAuthorisation::Authorised
+    } else if is_challenge {
+        PolkitAuthorisation::ChallengeRequired
+    } else {
+        PolkitAuthorisation::NotAuthorised
+    })
 }
 
 #[tracing::instrument(skip_all)]
 async fn perform_configuration_switch(
     conn: Arc<SyncConnection>,
     activation_command_path: PathBuf,
+    action: SwitchAction,
+    switch_id: &str,
     absolute_activation_tracker_command: &PathBuf,
     activation_track_dir: &PathBuf,
+    activation_properties: &[ActivationProperty],
 ) -> anyhow::Result<()> {
     // https://www.freedesktop.org/software/systemd/man/latest/org.freedesktop.systemd1.html
     let systemd_proxy = Proxy::new(
@@ -292,13 +585,16 @@ async fn perform_configuration_switch(
         conn.clone(),
     );
 
-    tracing::info!(activation_command_path = ?activation_command_path.to_str(), "Will start a system switch.");
+    tracing::info!(activation_command_path = ?activation_command_path.to_str(), action = action.as_str(), switch_id, "Will start a system switch.");
 
     let aux_not_used: Vec<(String, Vec<(String, Variant<&str>)>)> = Vec::new();
     let transient_service_properties = build_transient_service_properties(
         activation_command_path,
+        action,
+        switch_id,
         absolute_activation_tracker_command,
         activation_track_dir,
+        activation_properties,
     )?;
 
     let (job_path,): (Path,) = systemd_proxy
@@ -322,7 +618,7 @@ async fn perform_configuration_switch(
     );
 
     // We'll keep checking until the job is running or done (means it doesn't exist anymore).
-    loop {
+    let job_watch_result = loop {
         match job_proxy
             .get::<String>("org.freedesktop.systemd1.Job", "State")
             .await
@@ -330,7 +626,7 @@ async fn perform_configuration_switch(
             Ok(state) => {
                 if state == "running" {
                     // Means we can get a unit object already, so we'll stop checking for the job specifically. In theory we could only rely on whether the job exists or not, but we want to check the unit to make sure it will not be kept around once it's done.
-                    break;
+                    break Ok(());
                 }
 
                 tokio::time::sleep(Duration::from_millis(100)).await;
@@ -339,18 +635,80 @@ async fn perform_configuration_switch(
             Err(err) => {
                 if let Some("org.freedesktop.DBus.Error.UnknownObject") = err.name() {
                     // Job is finished running.
-                    break;
+                    break Ok(());
                 }
 
-                return Err(err).context("trying to get status of the job we created");
+                break Err(err).context("trying to get status of the job we created");
             }
         }
+    };
+
+    if job_watch_result.is_err() {
+        // The unit already exists at this point, and `CollectMode` won't get rid of it unless it becomes inactive or fails, so it could block the next switch.
+        clean_up_transient_unit(&systemd_proxy).await;
     }
+    job_watch_result?;
 
     wait_configuration_switch_complete(conn.clone()).await?;
     Ok(())
 }
 
+/// Best effort, so it only logs how it went. Once the activation is running, systemd refuses to stop the unit (since it has `RefuseManualStop`), and the unit goes away on its own once the activation finishes.
+async fn clean_up_transient_unit(systemd_proxy: &Proxy<'_, Arc<SyncConnection>>) {
+    let stop_result: Result<(Path,), _> = systemd_proxy
+        .method_call(
+            "org.freedesktop.systemd1.Manager",
+            "StopUnit",
+            (TRANSIENT_SERVICE_NAME, "replace"),
+        )
+        .await;
+    match stop_result {
+        Ok(_) => tracing::info!("Asked systemd to stop the transient unit we created."),
+        Err(err) => tracing::warn!(?err, "Failed to stop the transient unit we created."),
+    }
+
+    let reset_result: Result<(), _> = systemd_proxy
+        .method_call(
+            "org.freedesktop.systemd1.Manager",
+            "ResetFailedUnit",
+            (TRANSIENT_SERVICE_NAME,),
+        )
+        .await;
+    match reset_result {
+        Ok(()) => tracing::info!("Reset the failed state of the transient unit we created."),
+        Err(err) if err.name() == Some("org.freedesktop.systemd1.NoSuchUnit") => {
+            tracing::info!("The transient unit we created is already gone.")
+        }
+        Err(err) => tracing::warn!(
+            ?err,
+            "Failed to reset the failed state of the transient unit we created."
+        ),
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn reboot(conn: Arc<SyncConnection>) -> anyhow::Result<()> {
+    let systemd_proxy = Proxy::new(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        Duration::from_millis(1000),
+        conn,
+    );
+
+    tracing::info!("Asking systemd to reboot the system.");
+
+    // This is what `systemctl reboot` does as well. The `Reboot()` method of the manager skips the orderly shutdown of all units, so we don't use it.
+    let (_job_path,): (Path,) = systemd_proxy
+        .method_call(
+            "org.freedesktop.systemd1.Manager",
+            "StartUnit",
+            ("reboot.target", "replace-irreversibly"),
+        )
+        .await?;
+
+    Ok(())
+}
+
 #[tracing::instrument(skip_all)]
 async fn wait_configuration_switch_complete(conn: Arc<SyncConnection>) -> anyhow::Result<()> {
     let systemd_proxy = Proxy::new(
@@ -420,8 +778,11 @@ async fn wait_configuration_switch_complete(conn: Arc<SyncConnection>) -> anyhow
 
 fn build_transient_service_properties(
     activation_command_path: PathBuf,
+    action: SwitchAction,
+    switch_id: &str,
     absolute_activation_tracker_command: &PathBuf,
     activation_track_dir: &PathBuf,
+    activation_properties: &[ActivationProperty],
 ) -> anyhow::Result<Vec<(&'static str, Variant<Box<dyn RefArg>>)>> {
     let activation_command_path_string = activation_command_path
         .to_str()
@@ -451,7 +812,7 @@ fn build_transient_service_properties(
     // a(sasb)
     let exec_start: Vec<(String, Vec<String>, bool)> = vec![(
         activation_command_path_string.clone(),
-        vec![activation_command_path_string, "switch".to_string()],
+        vec![activation_command_path_string, action.as_str().to_string()],
         false,
     )];
     let exec_start_pre: Vec<(String, Vec<String>, bool)> = vec![(
@@ -488,6 +849,9 @@ fn build_transient_service_properties(
     res.push(("ExecStartPre", Variant(Box::new(exec_start_pre))));
     res.push(("ExecStartPost", Variant(Box::new(exec_start_post))));
     res.push(("ExecStopPost", Variant(Box::new(exec_stop_post))));
+    // `system-switch-tracker` writes this to the tracking files, along with the invocation id that systemd sets for the unit.
+    let environment: Vec<String> = vec![format!("NIXLESS_AGENT_SWITCH_ID={}", switch_id)];
+    res.push(("Environment", Variant(Box::new(environment))));
     res.push(("Type", Variant(Box::new("oneshot".to_string()))));
     res.push(("RefuseManualStop", Variant(Box::new(true))));
     res.push(("RemainAfterExit", Variant(Box::new(false))));
@@ -496,6 +860,11 @@ fn build_transient_service_properties(
         "CollectMode",
         Variant(Box::new("inactive-or-failed".to_string())),
     ));
+    res.extend(
+        activation_properties
+            .iter()
+            .map(|property| property.to_dbus_property()),
+    );
 
     Ok(res)
 }
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use crate::metrics;
+
+/// The categories of failures that the actors report back to whoever sent them a request. The underlying error is kept around so we don't lose any context when logging, but callers can match on the category to decide what to do next (e.g. which status code to respond with).
+#[derive(Error, Debug)]
+pub enum AgentError {
+    #[error("failed to download packages: {0:#}")]
+    Download(anyhow::Error),
+    /// Kept apart from other download failures, since a stalled binary cache (or a timeout that's too short for the connection) is fixed differently from a missing or corrupt package.
+    #[error("downloading {package_id} timed out after {} seconds", timeout.as_secs())]
+    DownloadTimeout {
+        package_id: String,
+        timeout: Duration,
+    },
+    #[error("failed to verify a signature: {0:#}")]
+    Signature(anyhow::Error),
+    #[error("failed to unpack packages: {0:#}")]
+    Unpack(anyhow::Error),
+    #[error("failed to activate the configuration: {0:#}")]
+    Activation(anyhow::Error),
+    #[error("failed to delete packages: {0:#}")]
+    Deletion(anyhow::Error),
+    #[error("failed to clean up the state directory: {0:#}")]
+    StateCleanup(anyhow::Error),
+    #[error("the agent is draining, so it doesn't accept new configuration switches")]
+    Draining,
+    #[error("the agent is in observer mode, so it never changes the system")]
+    ObserverMode,
+    /// A channel to or from an actor closed while we still needed it, which usually means the task on the other side died. Build it with `AgentError::channel_closed()` so it gets counted.
+    #[error("a channel of the {actor} got closed unexpectedly, its task has likely died")]
+    ChannelClosed { actor: &'static str },
+    #[error("{0:#}")]
+    State(anyhow::Error),
+}
+
+pub type AgentResult<T> = Result<T, AgentError>;
+
+impl AgentError {
+    /// Code deeper in the stack may already have figured out a more specific category for an error and returned it wrapped in an `anyhow::Error`, so we'll keep that category if it exists, and only use `category` otherwise.
+    pub fn categorise(err: anyhow::Error, category: fn(anyhow::Error) -> Self) -> Self {
+        match err.downcast::<AgentError>() {
+            Ok(agent_error) => agent_error,
+            Err(err) => category(err),
+        }
+    }
+
+    /// Also counts the error in the metrics, since a dead actor otherwise only shows up as confusing failures further down the line.
+    pub fn channel_closed(actor: &'static str) -> Self {
+        metrics::actors::channel_closed(actor).inc();
+        Self::ChannelClosed { actor }
+    }
+}
+
+/// Sends an actor's response back to whoever made the request. The requester going away before the response arrives (e.g. an HTTP client that disconnected) is normal and says nothing about the actor's health, so it only gets logged instead of being counted as a closed channel.
+pub fn send_response<T>(resp_tx: oneshot::Sender<T>, response: T, actor: &'static str) {
+    if resp_tx.send(response).is_err() {
+        tracing::debug!(
+            actor,
+            "The requester went away before getting its response."
+        );
+    }
+}
@@ -47,10 +47,12 @@ impl Fingerprint for NarInfo<'_> {
         let fingerprint = self.fingerprint()?;
         let fingerprint_bytes = fingerprint.as_bytes();
 
+        // A malformed signature counts as one that doesn't verify, since another signature in the NAR info may still be good.
         Ok(self.sigs.iter().any(|sig| {
-            keychain
-                .verify(&sig.key_name, &fingerprint_bytes, sig.sig.as_bytes())
-                .is_ok()
+            matches!(
+                keychain.verify(&sig.key_name, &fingerprint_bytes, sig.sig.as_bytes()),
+                Ok(true)
+            )
         }))
     }
 }
@@ -91,10 +93,12 @@ impl Fingerprint for OwnedNarInfo {
         let fingerprint = self.fingerprint()?;
         let fingerprint_bytes = fingerprint.as_bytes();
 
+        // A malformed signature counts as one that doesn't verify, since another signature in the NAR info may still be good.
         Ok(self.sigs.iter().any(|sig| {
-            keychain
-                .verify(&sig.key_name, &fingerprint_bytes, sig.sig.as_bytes())
-                .is_ok()
+            matches!(
+                keychain.verify(&sig.key_name, &fingerprint_bytes, sig.sig.as_bytes()),
+                Ok(true)
+            )
         }))
     }
 }
@@ -0,0 +1,56 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::AsyncWrite;
+
+/// A writer that fails once more than `limit` bytes are written through it. Useful when we know up front how much data we should get, so that something sending more than that can't fill up the disk.
+pub struct LimitedWriter<W> {
+    inner: W,
+    remaining: u64,
+}
+
+impl<W> LimitedWriter<W> {
+    pub fn new(inner: W, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for LimitedWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.remaining == 0 && !buf.is_empty() {
+            return Poll::Ready(Err(io::Error::other(
+                "got more data than the limit we were given",
+            )));
+        }
+
+        // We'll only let through what fits in the limit. If there's more, the next write will fail.
+        let max_len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let res = Pin::new(&mut self.inner).poll_write(cx, &buf[..max_len]);
+
+        if let Poll::Ready(Ok(written)) = res {
+            self.remaining -= written as u64;
+        }
+
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,36 @@
+use std::net::{IpAddr, TcpListener};
+
+use anyhow::anyhow;
+
+/// Binds to `port` on every one of `addresses`. Addresses we can't bind to are only logged, so a server fails to start only if it can't listen on any of its addresses.
+pub fn bind_listeners(
+    server_name: &str,
+    addresses: &[IpAddr],
+    port: u16,
+) -> anyhow::Result<Vec<TcpListener>> {
+    let listeners: Vec<TcpListener> = addresses
+        .iter()
+        .filter_map(|address| match TcpListener::bind((*address, port)) {
+            Ok(listener) => Some(listener),
+            Err(err) => {
+                tracing::warn!(
+                    ?err,
+                    %address,
+                    port,
+                    server_name,
+                    "Failed to bind to one of the addresses, will keep going with the other ones."
+                );
+                None
+            }
+        })
+        .collect();
+
+    if listeners.is_empty() {
+        Err(anyhow!(
+            "the {} server couldn't bind to any of its addresses",
+            server_name
+        ))
+    } else {
+        Ok(listeners)
+    }
+}
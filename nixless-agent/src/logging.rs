@@ -0,0 +1,71 @@
+use clap::ValueEnum;
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
+
+/// Where the agent sends its logs.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum LogTarget {
+    /// Human-readable lines on stderr. When running under systemd, these still end up in the journal, but as plain messages.
+    Stderr,
+    /// Straight to the systemd journal, keeping the fields of every event and of the spans it's in as journal fields, so they can be queried with e.g. `journalctl SYSTEM_PACKAGE_ID=...`.
+    Journald,
+}
+
+/// How logs sent to stderr are formatted.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, for log pipelines that want to parse them. The fields of the event are under `fields`, and the fields of the spans it's in (e.g. `system_package_id`) are under `span` (for the innermost one) and `spans` (for all of them).
+    Json,
+}
+
+/// Lets the log level be changed after logging was set up, e.g. when reloading configuration.
+#[derive(Clone)]
+pub struct LogFilterHandle(reload::Handle<EnvFilter, Registry>);
+
+impl LogFilterHandle {
+    /// Takes a filter that was already built with [`build_filter`], so a reload can check every new setting before applying any of them.
+    pub fn set_filter(&self, filter: EnvFilter) -> anyhow::Result<()> {
+        self.0.reload(filter)?;
+        Ok(())
+    }
+}
+
+/// `log_level` uses the same directives as `RUST_LOG`. If `RUST_LOG` is also set, its directives are added after `log_level`'s, so they take precedence for the targets they mention.
+pub fn build_filter(log_level: &str) -> anyhow::Result<EnvFilter> {
+    let directives = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(env_directives) if !env_directives.is_empty() => {
+            format!("{},{}", log_level, env_directives)
+        }
+        _ => log_level.to_string(),
+    };
+
+    Ok(EnvFilter::builder().parse(directives)?)
+}
+
+/// Can only be called once, since it sets the global subscriber. The format only applies to stderr, since the journal always gets the fields separately.
+pub fn init(
+    target: LogTarget,
+    format: LogFormat,
+    log_level: &str,
+) -> anyhow::Result<LogFilterHandle> {
+    let (filter, handle) = reload::Layer::new(build_filter(log_level)?);
+    let subscriber = tracing_subscriber::registry().with(filter);
+
+    match (target, format) {
+        (LogTarget::Stderr, LogFormat::Text) => subscriber
+            .with(tracing_subscriber::fmt::layer())
+            .try_init()?,
+        (LogTarget::Stderr, LogFormat::Json) => subscriber
+            .with(tracing_subscriber::fmt::layer().json())
+            .try_init()?,
+        (LogTarget::Journald, _) => {
+            // Journald's own fields are all prefixed with `_` or are well-known names like `MESSAGE` and `PRIORITY`, so our fields don't need a prefix to stay clear of them.
+            let journald_layer = tracing_journald::layer()?.with_field_prefix(None);
+            subscriber.with(journald_layer).try_init()?
+        }
+    }
+
+    Ok(LogFilterHandle(handle))
+}
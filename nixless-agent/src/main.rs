@@ -1,27 +1,47 @@
-use std::{net::IpAddr, path::PathBuf};
-
-use actors::{Deleter, Downloader, Server, StateKeeper, Unpacker};
-use anyhow::anyhow;
-use clap::Parser;
-use dbus_connection::DBusConnection;
+use std::{collections::HashSet, net::IpAddr, num::NonZeroUsize, path::PathBuf, time::Duration};
+
+use actors::{
+    build_update_keychain, AgentConfiguration, CacheAuthScheme, CacheConnectionSettings,
+    CacheHttpVersion, CacheProxy, CacheTlsVersion, Deleter, Downloader, ForeignPackagesPolicy,
+    Server, StartedDownloaderInput, StartedServer, StateKeeper, Unpacker,
+    DEFAULT_ACTOR_CHANNEL_CAPACITY,
+};
+use anyhow::{anyhow, Context};
+use caps::{Capability, CapsHashSet};
+use clap::{Parser, ValueEnum};
+use dbus_connection::{ActivationProperty, DBusConnection, PolkitActionId, PolkitAuthorisation};
 use futures::StreamExt;
+use logging::{LogFilterHandle, LogFormat, LogTarget};
 use nix::ifaddrs::getifaddrs;
 use process_init::SystemdNotifyHandle;
 use signal_hook::consts::signal;
 use signal_hook_tokio::Signals;
-use state::AgentState;
+use state::{AgentState, SystemPaths};
+use store_sync::StoreSyncMode;
 
-use crate::{process_init::ensure_nix_daemon_not_present, telemetry::TelemetryServer};
+use crate::process_init::ensure_nix_daemon_not_present;
+#[cfg(feature = "telemetry-server")]
+use crate::telemetry::TelemetryServer;
 
 mod actors;
+mod content_address;
 mod dbus_connection;
+mod error;
 mod fingerprint;
+mod limited_writer;
+mod listeners;
+mod logging;
 mod metrics;
+#[cfg(feature = "mock-activation")]
+mod mock_activation;
+mod netrc;
 mod owned_nar_info;
 mod path_utils;
 mod process_init;
 mod state;
+mod store_sync;
 mod system_configuration;
+#[cfg(feature = "telemetry-server")]
 mod telemetry;
 
 #[derive(Parser, Debug)]
@@ -31,25 +51,78 @@ struct Args {
     #[arg(long, env = "NIXLESS_AGENT_LISTEN_PORT")]
     control_port: u16,
 
-    /// Interface to listen on for the control server.
-    #[arg(long, env = "NIXLESS_AGENT_CONTROL_LISTEN_IFACE")]
-    control_interface: Option<String>,
+    /// Interfaces to listen on for the control server. Can be given multiple times (or as a comma-separated list), and combined with `--control-address`.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        env = "NIXLESS_AGENT_CONTROL_LISTEN_IFACE"
+    )]
+    control_interface: Vec<String>,
 
-    /// Address to listen on for the control server.
-    #[arg(long, env = "NIXLESS_AGENT_CONTROL_LISTEN_ADDRESS")]
-    control_address: Option<String>,
+    /// Addresses to listen on for the control server. Can be given multiple times (or as a comma-separated list). If neither an address nor an interface is given, the control server listens on 0.0.0.0.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        env = "NIXLESS_AGENT_CONTROL_LISTEN_ADDRESS"
+    )]
+    control_address: Vec<String>,
+
+    /// When listening on an interface (given by `--control-interface` or `--telemetry-interface`) that has addresses from both families, the agent will listen on the first address from this family. If the interface has no address from this family, the first address from the other family is used instead.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = AddressFamily::Ipv4,
+        env = "NIXLESS_AGENT_INTERFACE_ADDRESS_FAMILY"
+    )]
+    interface_address_family: AddressFamily,
+
+    /// Also consider link-local IPv6 addresses (fe80::/10) when choosing the address of an interface to listen on. They're skipped by default, since they're rarely what's wanted and can't be reached from outside the link.
+    #[arg(long, env = "NIXLESS_AGENT_INTERFACE_ALLOW_LINK_LOCAL")]
+    interface_allow_link_local: bool,
+
+    /// Number of worker threads the control server uses to handle requests.
+    #[arg(long, default_value_t = 2, env = "NIXLESS_AGENT_CONTROL_WORKERS")]
+    control_workers: usize,
+
+    /// Maximum size of a request body the control server accepts. Requests with bigger bodies are rejected with a 413 response without being processed. Configuration requests list every package in the system closure, so big closures may need this to be raised.
+    #[arg(long, default_value_t = 4 * 1024 * 1024, env = "NIXLESS_AGENT_CONTROL_MAX_BODY_BYTES")]
+    control_max_body_bytes: usize,
+
+    /// Don't serve metrics (`/metrics` and `/metrics.json`) on the control server, for setups that want metrics strictly on the telemetry server. Without the telemetry server, this leaves no way to get metrics out of the agent.
+    #[arg(long, env = "NIXLESS_AGENT_DISABLE_CONTROL_METRICS")]
+    disable_control_metrics: bool,
 
     /// Port to listen on to serve metrics and other telemetry insights.
+    #[cfg(feature = "telemetry-server")]
     #[arg(long, env = "NIXLESS_AGENT_TELEMETRY_LISTEN_PORT")]
     telemetry_port: u16,
 
-    /// Interface to listen on for the telemetry server.
-    #[arg(long, env = "NIXLESS_AGENT_TELEMETRY_LISTEN_IFACE")]
-    telemetry_interface: Option<String>,
+    /// Interfaces to listen on for the telemetry server. Can be given multiple times (or as a comma-separated list), and combined with `--telemetry-address`.
+    #[cfg(feature = "telemetry-server")]
+    #[arg(
+        long,
+        value_delimiter = ',',
+        env = "NIXLESS_AGENT_TELEMETRY_LISTEN_IFACE"
+    )]
+    telemetry_interface: Vec<String>,
+
+    /// Addresses to listen on for the telemetry server. Can be given multiple times (or as a comma-separated list). If neither an address nor an interface is given, the telemetry server listens on 0.0.0.0.
+    #[cfg(feature = "telemetry-server")]
+    #[arg(
+        long,
+        value_delimiter = ',',
+        env = "NIXLESS_AGENT_TELEMETRY_LISTEN_ADDRESS"
+    )]
+    telemetry_address: Vec<String>,
+
+    /// Let the telemetry server serve heap profiles. This has some overhead, so it's off by default.
+    #[cfg(feature = "memory-profiler")]
+    #[arg(long, env = "NIXLESS_AGENT_ENABLE_MEMORY_PROFILER")]
+    enable_memory_profiler: bool,
 
-    /// Address to listen on for the telemetry server.
-    #[arg(long, env = "NIXLESS_AGENT_TELEMETRY_LISTEN_ADDRESS")]
-    telemetry_address: Option<String>,
+    /// Leave out metrics marked as optional, wherever metrics are served. Trades some observability for smaller metrics payloads.
+    #[arg(long, env = "NIXLESS_AGENT_SKIP_OPTIONAL_METRICS")]
+    skip_optional_metrics: bool,
 
     /// Path to the Nix store.
     #[arg(
@@ -63,6 +136,31 @@ struct Args {
     #[arg(long, default_value = "/nix/var", env = "NIXLESS_AGENT_NIX_STATE_DIR")]
     nix_state_dir: PathBuf,
 
+    /// Path where the running system is linked from. Only worth changing for tests or nonstandard setups.
+    #[arg(
+        long,
+        default_value = "/run/current-system",
+        env = "NIXLESS_AGENT_CURRENT_SYSTEM_PATH"
+    )]
+    current_system_path: PathBuf,
+
+    /// Path where the system we booted into is linked from. Used to figure out whether a configuration that was waiting for a reboot got booted into. Only worth changing for tests or nonstandard setups.
+    #[arg(
+        long,
+        default_value = "/run/booted-system",
+        env = "NIXLESS_AGENT_BOOTED_SYSTEM_PATH"
+    )]
+    booted_system_path: PathBuf,
+
+    /// Capabilities to keep after startup, separated by commas (e.g. "CAP_CHOWN,CAP_DAC_OVERRIDE"). Every other capability is dropped once the Nix store and state dirs are prepared. CAP_CHOWN is needed to unpack NARs into the store, so it should be kept unless the store is owned by the agent.
+    #[arg(
+        long,
+        default_value = "CAP_CHOWN",
+        value_delimiter = ',',
+        env = "NIXLESS_AGENT_RETAINED_CAPABILITIES"
+    )]
+    retained_capabilities: Vec<Capability>,
+
     /// Path where we keep our own state.
     #[arg(
         long,
@@ -80,17 +178,46 @@ struct Args {
     #[arg(long, env = "NIXLESS_AGENT_CACHE_URL")]
     cache_url: String,
 
-    /// Cache authorization token. Will be sent in an "Authorization" header on every request.
+    /// Cache authorization token. Will be sent on every request, as set by the cache auth scheme.
     #[arg(long, env = "NIXLESS_AGENT_CACHE_AUTH_TOKEN")]
     cache_auth_token: Option<String>,
 
+    /// How the cache authorization token is sent. `bearer` sends it in an "Authorization: bearer <token>" header. `basic` sends it with basic authentication, and needs the token to be "<username>:<password>". `header:<name>` sends the token as-is in the header with the given name. Any other word is used as the scheme in the "Authorization: <scheme> <token>" header, e.g. `Bearer` or `Token` for caches that expect those.
+    #[arg(
+        long,
+        default_value = "bearer",
+        env = "NIXLESS_AGENT_CACHE_AUTH_SCHEME"
+    )]
+    cache_auth_scheme: CacheAuthScheme,
+
+    /// Path to a netrc file (the same format used by Nix and curl) with credentials for the cache. The credentials for the cache's host will be sent with basic authentication on every request. Ignored if a cache authorization token is given.
+    #[arg(long, env = "NIXLESS_AGENT_CACHE_NETRC_FILE")]
+    cache_netrc_file: Option<PathBuf>,
+
+    /// URL of an HTTP(S) proxy to reach the cache through, e.g. "http://proxy.example.com:3128". Hosts in the NO_PROXY environment variable are still reached directly. If not given, the proxy in the HTTPS_PROXY, HTTP_PROXY or ALL_PROXY environment variables is used, if there's one.
+    #[arg(long, env = "NIXLESS_AGENT_CACHE_PROXY")]
+    cache_proxy: Option<String>,
+
+    /// Credentials for the cache proxy in the format "<username>:<password>", sent with basic authentication. Ignored if no cache proxy is given.
+    #[arg(long, env = "NIXLESS_AGENT_CACHE_PROXY_CREDENTIALS")]
+    cache_proxy_credentials: Option<String>,
+
+    /// Added after the agent's name and version in the User-Agent header sent to the cache, e.g. to tell apart the hosts or fleets making requests.
+    #[arg(long, env = "NIXLESS_AGENT_CACHE_USER_AGENT_SUFFIX")]
+    cache_user_agent_suffix: Option<String>,
+
     /// Public key used by the cache in the format "<key_name>:<encoded_key>".
     #[arg(long, env = "NIXLESS_AGENT_CACHE_PUBLIC_KEY")]
     cache_public_key: Option<String>,
 
-    /// Public key used by the system that will request nixless-agent to update. Requests must be signed, and this public key will be used to verify the request. Uses the same format "<key_name>:<encoded_key>" as the cache key.
-    #[arg(long, env = "NIXLESS_AGENT_UPDATE_PUBLIC_KEY")]
-    update_public_key: String,
+    /// Public keys used by the systems that will request nixless-agent to update, separated by commas. Requests must be signed, and the public key with the same name as the one in the request signature will be used to verify the request. Each key uses the same format "<key_name>:<encoded_key>" as the cache key. Trusting more than one key at a time allows signing keys to be rotated without downtime.
+    #[arg(
+        long,
+        env = "NIXLESS_AGENT_UPDATE_PUBLIC_KEY",
+        value_delimiter = ',',
+        required = true
+    )]
+    update_public_key: Vec<String>,
 
     /// Path to the command used to activate a new system configuration, relative to the configuration top-level package root.
     #[arg(
@@ -115,29 +242,407 @@ struct Args {
     #[arg(long, env = "NIXLESS_AGENT_ABSOLUTE_ACTIVATION_TRACKER_COMMAND")]
     absolute_activation_tracker_command: PathBuf, // TODO: figure out a better way to handle this.
 
+    /// Resource limits for the transient unit that activates new configurations, as `Key=Value`, so a heavy activation can't take the whole host down with it. Can be given multiple times (or as a comma-separated list). Accepted keys are `MemoryMax` (bytes, with an optional `K`, `M`, `G` or `T` suffix, or `infinity`), `CPUQuota` (a percentage of a single CPU, e.g. `50%`), `OOMScoreAdjust` (-1000 to 1000) and `TimeoutStartSec` (whole seconds). Empty items are ignored.
+    #[arg(long, value_delimiter = ',', env = "NIXLESS_AGENT_ACTIVATION_PROPERTY")]
+    activation_property: Vec<String>,
+
+    /// A polkit action checked at startup to find out whether the agent can be authorised to switch systems. systemd checks `org.freedesktop.systemd1.manage-units` itself whenever the agent manages units, so that action is always checked as well, and a custom action needs a polkit rule that allows both.
+    #[arg(
+        long,
+        default_value_t = PolkitActionId::default(),
+        env = "NIXLESS_AGENT_POLKIT_ACTION_ID"
+    )]
+    polkit_action_id: PolkitActionId,
+
+    /// Refuse to start when polkit would only authorise the agent after interactive authentication. Nobody is around to authenticate, so configuration switches would fail later anyway. Without this, the agent only warns about it.
+    #[arg(long, env = "NIXLESS_AGENT_REQUIRE_NON_INTERACTIVE_AUTH")]
+    require_non_interactive_auth: bool,
+
     /// The agent will download NAR files for new configurations. This setting controls the maximum number of parallel downloads.
     #[arg(long, default_value_t = 5, env = "NIXLESS_MAX_PARALLEL_NAR_DOWNLOADS")]
     max_parallel_nar_downloads: usize,
+
+    /// After downloading NAR files for new configurations, the agent will unpack them into the Nix store. This setting controls the maximum number of NAR files unpacked in parallel. Defaults to the number of CPUs available.
+    #[arg(long, env = "NIXLESS_MAX_PARALLEL_UNPACKS")]
+    max_parallel_unpacks: Option<usize>,
+
+    /// Controls whether and how aggressively the agent fsyncs the packages it unpacks into the Nix store and the system profile links before going on with a switch. `none` leaves it up to the OS, `files` syncs every file and directory in new packages and the directories the agent adds entries to, and `full` also syncs the agent's state file.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = StoreSyncMode::Files,
+        env = "NIXLESS_AGENT_STORE_SYNC_MODE"
+    )]
+    store_sync_mode: StoreSyncMode,
+
+    /// The maximum size, in bytes, of a narinfo response from the cache. Bigger responses are rejected before they're fully read.
+    #[arg(long, default_value_t = 1024 * 1024, env = "NIXLESS_AGENT_MAX_NAR_INFO_SIZE")]
+    max_nar_info_size: u64,
+
+    /// The maximum number of seconds that downloading a single NAR file can take. A download that takes longer fails, freeing up its download slot.
+    #[arg(
+        long,
+        default_value_t = 600,
+        env = "NIXLESS_AGENT_NAR_DOWNLOAD_TIMEOUT_SECS"
+    )]
+    nar_download_timeout_secs: u64,
+
+    /// Which HTTP version to use with the cache. `auto` negotiates HTTP/2 with caches over HTTPS and falls back to HTTP/1.1 if the cache doesn't support it. HTTP/2 lets all narinfo and NAR requests share a single connection, which speeds up fetching many small packages. `http1` never uses HTTP/2. `http2-prior-knowledge` uses HTTP/2 without negotiating it, which also works for caches over plain HTTP, but makes every request fail if the cache doesn't support HTTP/2.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = CacheHttpVersion::Auto,
+        env = "NIXLESS_AGENT_CACHE_HTTP_VERSION"
+    )]
+    cache_http_version: CacheHttpVersion,
+
+    /// The maximum number of idle connections to the cache kept around to be reused by later requests. Defaults to the maximum number of parallel NAR downloads, which is as many connections as the agent uses at once over HTTP/1.1.
+    #[arg(long, env = "NIXLESS_AGENT_CACHE_POOL_MAX_IDLE")]
+    cache_pool_max_idle: Option<usize>,
+
+    /// How often, in seconds, keep-alives are sent on connections to the cache, so idle connections aren't dropped along the way (e.g. by firewalls or proxies). 0 disables keep-alives.
+    #[arg(
+        long,
+        default_value_t = 30,
+        env = "NIXLESS_AGENT_CACHE_KEEP_ALIVE_SECS"
+    )]
+    cache_keep_alive_secs: u64,
+
+    /// Path to a PEM file with CA certificates to trust when talking to the cache, on top of the usual ones. Needed for caches whose certificate comes from a private CA. The file is read again when reloading.
+    #[arg(long, env = "NIXLESS_AGENT_CACHE_CA_CERT")]
+    cache_ca_cert: Option<PathBuf>,
+
+    /// The oldest TLS version accepted when talking to the cache over HTTPS. TLS 1.2 is the oldest version the agent supports at all.
+    #[arg(long, value_enum, env = "NIXLESS_AGENT_CACHE_MIN_TLS_VERSION")]
+    cache_min_tls_version: Option<CacheTlsVersion>,
+
+    /// Don't verify the certificate of the cache at all. Anyone between the agent and the cache can then see and change everything the agent gets from the cache, with only the signatures of the narinfo left to catch tampered packages. Only meant for labs!
+    #[arg(long, env = "NIXLESS_AGENT_CACHE_INSECURE")]
+    cache_insecure: bool,
+
+    /// Reserve the disk space for each NAR file before downloading it, on filesystems that support it. Avoids fragmentation of big NAR files, and makes downloads fail right away if there isn't enough disk space for them.
+    #[arg(long, env = "NIXLESS_AGENT_PREALLOCATE_NAR_FILES")]
+    preallocate_nar_files: bool,
+
+    /// How many requests each of the agent's internal actors can have queued up before whoever sends them has to wait. The state keeper and the downloader get the most requests during a big switch, especially when clients follow switch progress or poll the agent often, so raise this if requests to the agent slow down while a switch is happening.
+    #[arg(
+        long,
+        default_value_t = NonZeroUsize::new(DEFAULT_ACTOR_CHANNEL_CAPACITY).unwrap(),
+        env = "NIXLESS_AGENT_ACTOR_CHANNEL_CAPACITY"
+    )]
+    actor_channel_capacity: NonZeroUsize,
+
+    /// Where to send logs. `journald` keeps the fields of log events as journal fields, so e.g. every log of a switch can be found with `journalctl SWITCH_ID=<version number>`.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = LogTarget::Stderr,
+        env = "NIXLESS_AGENT_LOG_TARGET"
+    )]
+    log_target: LogTarget,
+
+    /// How to format logs sent to stderr. `json` makes them easier to ship to log pipelines that parse them.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = LogFormat::Text,
+        env = "NIXLESS_AGENT_LOG_FORMAT"
+    )]
+    log_format: LogFormat,
+
+    /// Which logs to show, using the same directives as `RUST_LOG` (e.g. `info` or `info,nixless_agent=debug`). `RUST_LOG` can still be used, and takes precedence for the targets it mentions. Can be changed without a restart by reloading the configuration.
+    #[arg(long, default_value = "info", env = "NIXLESS_AGENT_LOG_LEVEL")]
+    log_level: String,
+
+    /// Address of the D-Bus bus to connect to, instead of the system bus. Mostly useful for testing against a bus that has test doubles for systemd and polkit.
+    #[arg(long, env = "NIXLESS_AGENT_DBUS_ADDRESS")]
+    dbus_address: Option<String>,
+
+    /// Only meant for tests: instead of talking to systemd, configuration switches will be mocked and will always have the given outcome.
+    #[cfg(feature = "mock-activation")]
+    #[arg(long, env = "NIXLESS_AGENT_MOCK_ACTIVATION_OUTCOME")]
+    mock_activation_outcome: Option<mock_activation::MockActivationOutcome>,
+
+    /// What to do with packages in the Nix store that aren't part of any configuration the agent is tracking. The agent looks for them after every successful switch: `ignore` leaves them alone, `warn` logs them, and `gc` deletes them together with the packages from old configurations.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ForeignPackagesPolicy::Ignore,
+        env = "NIXLESS_AGENT_FOREIGN_PACKAGES_POLICY"
+    )]
+    foreign_packages_policy: ForeignPackagesPolicy,
+
+    /// Only warn instead of refusing to start when the nix daemon (or the nix binary) seems to be running, or when we can't check for it (e.g. because /proc can't be read). This is risky: the agent assumes nothing else changes the Nix store or the Nix database while it runs, so a daemon that garbage collects, builds or substitutes packages at the same time can make the agent delete packages still in use, or leave the store inconsistent with the configurations the agent tracks. Only use this when the daemon is known to stay dormant, e.g. when only its socket is kept around.
+    #[arg(long, env = "NIXLESS_AGENT_ALLOW_NIX_DAEMON")]
+    allow_nix_daemon: bool,
+
+    /// If a new system configuration requires a reboot to be fully applied, the agent will reboot the system automatically. Otherwise, the configuration will stay pending until the system is rebooted by someone else.
+    #[arg(long, env = "NIXLESS_AGENT_AUTO_REBOOT")]
+    auto_reboot: bool,
+
+    /// Only observe the system: the agent still serves the summary, history, configurations and metrics, but refuses to switch, roll back, prefetch or repair, and never changes the Nix store. The agent doesn't need any capabilities or polkit authorisation in this mode, and drops every capability it was started with.
+    #[arg(long, env = "NIXLESS_AGENT_OBSERVER_MODE")]
+    observer_mode: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+/// Settings that can't be changed without restarting the process, because we'd have to drop the listening sockets.
+#[derive(Debug, PartialEq)]
+struct ListenSettings {
+    control_port: u16,
+    control_interface: Vec<String>,
+    control_address: Vec<String>,
+    interface_address_family: AddressFamily,
+    interface_allow_link_local: bool,
+    #[cfg(feature = "telemetry-server")]
+    telemetry_port: u16,
+    #[cfg(feature = "telemetry-server")]
+    telemetry_interface: Vec<String>,
+    #[cfg(feature = "telemetry-server")]
+    telemetry_address: Vec<String>,
+}
+
+impl From<&Args> for ListenSettings {
+    fn from(args: &Args) -> Self {
+        Self {
+            control_port: args.control_port,
+            control_interface: args.control_interface.clone(),
+            control_address: args.control_address.clone(),
+            interface_address_family: args.interface_address_family,
+            interface_allow_link_local: args.interface_allow_link_local,
+            #[cfg(feature = "telemetry-server")]
+            telemetry_port: args.telemetry_port,
+            #[cfg(feature = "telemetry-server")]
+            telemetry_interface: args.telemetry_interface.clone(),
+            #[cfg(feature = "telemetry-server")]
+            telemetry_address: args.telemetry_address.clone(),
+        }
+    }
+}
+
+/// Builds the configuration reported by the control server. The store dir and the number of parallel unpacks are given separately because they're only known after we resolve them from the arguments.
+fn agent_configuration(
+    args: &Args,
+    nix_store_dir: String,
+    max_parallel_unpacks: usize,
+    activation_properties: &[ActivationProperty],
+) -> AgentConfiguration {
+    AgentConfiguration {
+        nix_store_dir,
+        nix_state_dir: args.nix_state_dir.clone(),
+        current_system_path: args.current_system_path.clone(),
+        booted_system_path: args.booted_system_path.clone(),
+        nixless_state_dir: args.nixless_state_dir.clone(),
+        temp_download_path: args.temp_download_path.clone(),
+        cache_url: redact_url_password(&args.cache_url),
+        has_cache_auth_token: args.cache_auth_token.is_some(),
+        cache_auth_scheme: args.cache_auth_scheme.to_string(),
+        cache_netrc_file: args.cache_netrc_file.clone(),
+        cache_proxy: args.cache_proxy.as_deref().map(redact_url_password),
+        has_cache_proxy_credentials: args.cache_proxy_credentials.is_some(),
+        cache_public_key: args.cache_public_key.clone(),
+        update_public_keys: args.update_public_key.clone(),
+        relative_configuration_activation_command: args
+            .relative_configuration_activation_command
+            .clone(),
+        absolute_activation_tracker_command: args.absolute_activation_tracker_command.clone(),
+        max_system_history_count: args.max_system_history_count,
+        max_parallel_nar_downloads: args.max_parallel_nar_downloads,
+        max_parallel_unpacks,
+        store_sync_mode: args.store_sync_mode,
+        max_nar_info_size: args.max_nar_info_size,
+        nar_download_timeout_secs: args.nar_download_timeout_secs,
+        cache_http_version: args.cache_http_version,
+        cache_pool_max_idle: cache_pool_max_idle(args),
+        cache_keep_alive_secs: args.cache_keep_alive_secs,
+        cache_ca_cert: args.cache_ca_cert.clone(),
+        cache_min_tls_version: args.cache_min_tls_version,
+        cache_insecure: args.cache_insecure,
+        cache_user_agent_suffix: args.cache_user_agent_suffix.clone(),
+        preallocate_nar_files: args.preallocate_nar_files,
+        actor_channel_capacity: args.actor_channel_capacity.get(),
+        control_workers: args.control_workers,
+        control_max_body_bytes: args.control_max_body_bytes,
+        control_metrics: !args.disable_control_metrics,
+        report_optional_metrics: !args.skip_optional_metrics,
+        foreign_packages_policy: args.foreign_packages_policy,
+        auto_reboot: args.auto_reboot,
+        allow_nix_daemon: args.allow_nix_daemon,
+        polkit_action_id: args.polkit_action_id.to_string(),
+        require_non_interactive_auth: args.require_non_interactive_auth,
+        observer_mode: args.observer_mode,
+        dbus_address: args.dbus_address.clone(),
+        activation_properties: activation_properties
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+    }
+}
+
+/// Cache URLs may have credentials in them, which we don't want to show to anyone.
+fn redact_url_password(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            // This only fails for URLs that can't have credentials, and this one already has them.
+            let _ = parsed.set_password(Some("redacted"));
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+fn cache_pool_max_idle(args: &Args) -> usize {
+    args.cache_pool_max_idle
+        .unwrap_or(args.max_parallel_nar_downloads)
+}
+
+fn cache_connection_settings(args: &Args) -> CacheConnectionSettings {
+    CacheConnectionSettings {
+        http_version: args.cache_http_version,
+        pool_max_idle_per_host: cache_pool_max_idle(args),
+        keep_alive_interval: (args.cache_keep_alive_secs > 0)
+            .then(|| Duration::from_secs(args.cache_keep_alive_secs)),
+        ca_cert: args.cache_ca_cert.clone(),
+        min_tls_version: args.cache_min_tls_version,
+        insecure: args.cache_insecure,
+    }
+}
+
+/// Empty items are skipped, so an empty environment variable (or a stray comma) doesn't keep the agent from starting.
+fn activation_properties(args: &Args) -> anyhow::Result<Vec<ActivationProperty>> {
+    args.activation_property
+        .iter()
+        .map(|property| property.trim())
+        .filter(|property| !property.is_empty())
+        .map(|property| {
+            property
+                .parse()
+                .with_context(|| format!("invalid activation property {:?}", property))
+        })
+        .collect()
+}
+
+/// Only set when a cache proxy was explicitly given, since the proxy in the environment variables is picked up by the downloader on its own.
+fn cache_proxy(args: &Args) -> Option<CacheProxy> {
+    args.cache_proxy.clone().map(|url| CacheProxy {
+        url,
+        credentials: args.cache_proxy_credentials.clone(),
+    })
+}
+
+/// Re-reads the extra env file and the arguments, and applies the settings that can be changed while running: the cache credentials, proxy and public key, and the update public keys. Any other changes only take effect after a restart.
+async fn reload_configuration(
+    listen_settings: &ListenSettings,
+    downloader: &StartedDownloaderInput,
+    server: &StartedServer,
+    log_filter: &LogFilterHandle,
+) -> anyhow::Result<()> {
+    tracing::info!("Reloading configuration.");
+
+    let env_file_path = process_init::load_extra_env_file(true)?;
+    tracing::info!(
+        ?env_file_path,
+        "Reloaded additional environment variables, if there were any."
+    );
+    let args = Args::try_parse()?;
+
+    // Everything gets built before anything is applied, so a bad setting leaves the agent running entirely with the old configuration instead of a mix of old and new. The downloader builds its new client before replacing the old one, so it only changes anything if its settings are valid.
+    let new_log_filter = logging::build_filter(&args.log_level)?;
+    let new_update_keychain = build_update_keychain(&args.update_public_key)?;
+
+    let new_listen_settings = ListenSettings::from(&args);
+    if new_listen_settings != *listen_settings {
+        tracing::warn!(
+            ?listen_settings,
+            ?new_listen_settings,
+            "The listen addresses and ports can't be changed without a restart, so the changes to them will be ignored."
+        );
+    }
+
+    downloader
+        .reload(
+            args.cache_auth_token.clone(),
+            args.cache_auth_scheme.clone(),
+            args.cache_netrc_file.clone(),
+            args.cache_public_key.clone(),
+            cache_proxy(&args),
+        )
+        .await?;
+    log_filter.set_filter(new_log_filter)?;
+    server.set_update_keychain(new_update_keychain)?;
+    server.update_configuration(|configuration| {
+        configuration.has_cache_auth_token = args.cache_auth_token.is_some();
+        configuration.cache_auth_scheme = args.cache_auth_scheme.to_string();
+        configuration.cache_netrc_file = args.cache_netrc_file;
+        configuration.cache_proxy = args.cache_proxy.as_deref().map(redact_url_password);
+        configuration.has_cache_proxy_credentials = args.cache_proxy_credentials.is_some();
+        configuration.cache_public_key = args.cache_public_key;
+        configuration.update_public_keys = args.update_public_key;
+    })?;
+
+    tracing::info!("Finished reloading configuration.");
+    Ok(())
 }
 
-async fn handle_signals(mut signals: Signals) {
+async fn handle_signals(
+    mut signals: Signals,
+    systemd_handle: SystemdNotifyHandle,
+    listen_settings: ListenSettings,
+    downloader: StartedDownloaderInput,
+    server: &StartedServer,
+    log_filter: LogFilterHandle,
+) -> String {
     while let Some(signal) = signals.next().await {
         match signal {
             signal::SIGHUP => {
-                // Reload configuration
+                if let Err(err) = systemd_handle.notify_reloading() {
+                    tracing::warn!(?err, "Failed to notify systemd that we're reloading.");
+                }
+
+                if let Err(err) =
+                    reload_configuration(&listen_settings, &downloader, server, &log_filter).await
+                {
+                    tracing::error!(
+                        ?err,
+                        "Failed to reload configuration, will keep running with the previous one."
+                    );
+                }
                 // Reopen the log file
+
+                if let Err(err) = systemd_handle.notify_ready() {
+                    tracing::warn!(?err, "Failed to notify systemd that we finished reloading.");
+                }
             }
-            signal::SIGTERM => {
-                break;
+            signal::SIGTERM | signal::SIGINT => {
+                if let Err(err) = systemd_handle.notify_stopping() {
+                    tracing::warn!(?err, "Failed to notify systemd that we're stopping.");
+                }
+                let name = if signal == signal::SIGTERM {
+                    "SIGTERM"
+                } else {
+                    "SIGINT"
+                };
+                return format!("received {}", name);
             }
             _ => unreachable!(),
         }
     }
+
+    "stopped receiving signals".to_string()
 }
 
-pub fn find_interface_ip(interface_name: &str) -> anyhow::Result<IpAddr> {
-    let addrs = getifaddrs()?;
-    let sock: Option<IpAddr> = addrs
+/// Returns the first address of the interface that belongs to `preferred_family`, or the first address from the other family if there isn't one. Addresses are considered in the order the kernel lists them, so the same interface configuration always gives the same address.
+pub fn find_interface_ip(
+    interface_name: &str,
+    preferred_family: AddressFamily,
+    allow_link_local: bool,
+) -> anyhow::Result<IpAddr> {
+    let addrs: Vec<IpAddr> = getifaddrs()?
         .filter(|i| i.interface_name == interface_name)
         .filter_map(|i| match i.address {
             None => None,
@@ -149,76 +654,225 @@ pub fn find_interface_ip(interface_name: &str) -> anyhow::Result<IpAddr> {
             }
             Some(_) => None,
         })
-        .next();
+        .filter(|addr| match addr {
+            // Link-local addresses are in fe80::/10.
+            IpAddr::V6(v6) => allow_link_local || (v6.segments()[0] & 0xffc0) != 0xfe80,
+            IpAddr::V4(_) => true,
+        })
+        .collect();
 
-    sock.ok_or_else(|| anyhow!("the chosen interface doesn't exist or have an IP address"))
+    let is_preferred_family = |addr: &&IpAddr| match preferred_family {
+        AddressFamily::Ipv4 => addr.is_ipv4(),
+        AddressFamily::Ipv6 => addr.is_ipv6(),
+    };
+
+    addrs
+        .iter()
+        .find(is_preferred_family)
+        .or_else(|| addrs.first())
+        .copied()
+        .ok_or_else(|| anyhow!("the chosen interface doesn't exist or have a usable IP address"))
 }
 
-#[tokio::main]
-async fn async_main(args: Args, systemd_handle: SystemdNotifyHandle) -> anyhow::Result<()> {
-    let control_server_address = match (args.control_address, args.control_interface) {
-        (Some(a), _) => a.parse()?,
-        (None, Some(iface)) => find_interface_ip(&iface)?,
-        (None, None) => "0.0.0.0".parse()?,
-    };
+/// Resolves every address and interface a server should listen on, falling back to 0.0.0.0 if none were given.
+fn resolve_listen_addresses(
+    addresses: &[String],
+    interfaces: &[String],
+    preferred_family: AddressFamily,
+    allow_link_local: bool,
+) -> anyhow::Result<Vec<IpAddr>> {
+    let mut resolved = Vec::new();
+
+    for address in addresses {
+        resolved.push(
+            address
+                .parse()
+                .with_context(|| format!("failed to parse listen address {}", address))?,
+        );
+    }
 
-    let telemetry_server_address = match (args.telemetry_address, args.telemetry_interface) {
-        (Some(a), _) => a.parse()?,
-        (None, Some(iface)) => find_interface_ip(&iface)?,
-        (None, None) => "0.0.0.0".parse()?,
-    };
+    for interface in interfaces {
+        resolved.push(
+            find_interface_ip(interface, preferred_family, allow_link_local).with_context(
+                || format!("failed to find an address for interface {}", interface),
+            )?,
+        );
+    }
+
+    if resolved.is_empty() {
+        resolved.push("0.0.0.0".parse()?);
+    }
+
+    // Interfaces may resolve to an address that was also given explicitly, and binding twice to the same address would fail.
+    let mut seen = HashSet::new();
+    resolved.retain(|address| seen.insert(*address));
 
-    let store_path_string = args.nix_store_dir.canonicalize()?.to_str().ok_or_else(|| anyhow!("The nix store path given to us can't be represented as an UTF-8 string, but this is required!"))?.to_string();
+    Ok(resolved)
+}
+
+#[tokio::main]
+async fn async_main(
+    args: Args,
+    systemd_handle: SystemdNotifyHandle,
+    log_filter: LogFilterHandle,
+) -> anyhow::Result<()> {
+    let listen_settings = ListenSettings::from(&args);
+
+    let control_server_addresses = resolve_listen_addresses(
+        &args.control_address,
+        &args.control_interface,
+        args.interface_address_family,
+        args.interface_allow_link_local,
+    )?;
+
+    #[cfg(feature = "telemetry-server")]
+    let telemetry_server_addresses = resolve_listen_addresses(
+        &args.telemetry_address,
+        &args.telemetry_interface,
+        args.interface_address_family,
+        args.interface_allow_link_local,
+    )?;
+
+    // The store is created during process initialisation if it didn't exist yet, so failing here means something else is wrong with the path.
+    let store_path = args.nix_store_dir.canonicalize().with_context(|| {
+        format!(
+            "failed to canonicalize the Nix store path {}",
+            args.nix_store_dir.display()
+        )
+    })?;
+    let store_path_string = store_path.to_str().ok_or_else(|| anyhow!("The nix store path given to us can't be represented as an UTF-8 string, but this is required!"))?.to_string();
+
+    let max_parallel_unpacks = match args.max_parallel_unpacks {
+        Some(v) => v,
+        None => std::thread::available_parallelism()?.get(),
+    };
+    let activation_properties = activation_properties(&args)?;
+    let configuration = agent_configuration(
+        &args,
+        store_path_string.clone(),
+        max_parallel_unpacks,
+        &activation_properties,
+    );
+    let cache_proxy = cache_proxy(&args);
+    let cache_connection_settings = cache_connection_settings(&args);
 
     let signals = Signals::new(&[
         // Used when asked to reload configuration files by systemd.
         signal::SIGHUP,
         // Used when asked to terminate by systemd.
         signal::SIGTERM,
+        // Used when running in a terminal and someone hits Ctrl-C. We shut down the same way as with SIGTERM.
+        signal::SIGINT,
     ])?;
-    let signals_task = tokio::spawn(handle_signals(signals));
 
-    let telemetry_server = TelemetryServer::builder()
-        .address(telemetry_server_address)
+    #[cfg(feature = "telemetry-server")]
+    let telemetry_server_builder = TelemetryServer::builder()
+        .addresses(telemetry_server_addresses)
         .port(args.telemetry_port)
-        .start()?;
+        .report_optional_metrics(!args.skip_optional_metrics);
+    #[cfg(feature = "memory-profiler")]
+    let telemetry_server_builder =
+        telemetry_server_builder.enable_memory_profiler(args.enable_memory_profiler);
+    #[cfg(feature = "telemetry-server")]
+    let telemetry_server = telemetry_server_builder.start()?;
+    #[cfg(not(feature = "telemetry-server"))]
+    metrics::init(!args.skip_optional_metrics)?;
 
     let nar_info_cache_dir = args.nixless_state_dir.join("nar_info_cache");
 
+    // Loading the state may require a full scan of the Nix store, which can take a while on large hosts, so we'll keep systemd from timing us out until we're ready.
+    let startup_timeout_extender = systemd_handle.spawn_startup_timeout_extender();
+
     let state = AgentState::from_saved_state_or_new(
         store_path_string.clone(),
         args.nix_state_dir,
         args.nixless_state_dir,
         args.max_system_history_count,
+        args.store_sync_mode,
+        SystemPaths {
+            current_system: args.current_system_path,
+            booted_system: args.booted_system_path,
+        },
     )
     .await?;
 
-    let dbus_connection = DBusConnection::builder()
+    let mut dbus_connection_builder = DBusConnection::builder();
+    dbus_connection_builder
         .relative_configuration_activation_command(args.relative_configuration_activation_command)
         .absolute_activation_tracker_command(args.absolute_activation_tracker_command)
         .activation_track_dir(state.absolute_state_path().parent().unwrap().to_path_buf())
-        .build()?
-        .start();
+        .bus_address(args.dbus_address)
+        .activation_properties(activation_properties)
+        .polkit_action_id(args.polkit_action_id)
+        .channel_capacity(args.actor_channel_capacity.get());
+    #[cfg(feature = "mock-activation")]
+    dbus_connection_builder.mock_activation_outcome(args.mock_activation_outcome);
+    let dbus_connection = dbus_connection_builder.build()?.start();
+
+    // Nothing that accepts requests is running yet, so if we can't ever switch systems, we'll exit now instead of starting up only to fail every request.
+    if args.observer_mode {
+        tracing::info!("We're in observer mode and will never switch systems, so we won't check if we can be authorised to manage systemd units.");
+    } else {
+        tracing::info!("Checking if we can possibly be authorised to manage systemd units.");
+
+        match dbus_connection
+            .input()
+            .check_authorisation_possibility()
+            .await?
+        {
+            PolkitAuthorisation::Authorised => {
+                tracing::info!(
+                    "We might be authorised to manage systemd units, continuing initialisation."
+                );
+            }
+            PolkitAuthorisation::ChallengeRequired if args.require_non_interactive_auth => {
+                dbus_connection.shutdown().await?;
+                return Err(anyhow!("polkit only authorises us to manage systemd units after interactive authentication, which can't happen for the agent. Make sure the polkit rule for the agent's user returns `polkit.Result.YES`"));
+            }
+            PolkitAuthorisation::ChallengeRequired => {
+                tracing::warn!("polkit only authorises us to manage systemd units after interactive authentication, which can't happen for the agent, so configuration switches will most likely fail! Make sure the polkit rule for the agent's user returns `polkit.Result.YES`, or use --require-non-interactive-auth to refuse to start in this case.");
+            }
+            PolkitAuthorisation::NotAuthorised => {
+                dbus_connection.shutdown().await?;
+                return Err(anyhow!("we're not authorised to manage systemd units, so we won't be able to switch systems. Make sure polkit allows the agent's user to manage systemd units, or run the agent in observer mode"));
+            }
+        }
+    }
 
     let downloader = Downloader::builder()
-        .nix_store_dir(store_path_string)
+        .nix_store_dir(store_path_string.clone())
         .temp_download_path(args.temp_download_path)
         .cache_url(args.cache_url)
         .cache_auth_token(args.cache_auth_token)
+        .cache_auth_scheme(args.cache_auth_scheme)
+        .cache_netrc_file(args.cache_netrc_file)
         .cache_public_key(args.cache_public_key)
+        .cache_proxy(cache_proxy)
+        .cache_connection_settings(cache_connection_settings)
+        .cache_user_agent_suffix(args.cache_user_agent_suffix)
         .max_parallel_nar_downloads(args.max_parallel_nar_downloads)
+        .max_nar_info_size(args.max_nar_info_size)
+        .nar_download_timeout(Duration::from_secs(args.nar_download_timeout_secs))
         .nar_info_cache_dir(nar_info_cache_dir.clone())
+        .preallocate_nar_files(args.preallocate_nar_files)
+        .keep_temp_downloads(state.may_resume_downloads())
+        .channel_capacity(args.actor_channel_capacity.get())
         .build()?;
     let downloader = downloader.start();
+    let downloader_input = downloader.input();
 
     let unpacker = Unpacker::builder()
         .nix_store_dir(args.nix_store_dir.clone())
+        .max_parallel_unpacks(max_parallel_unpacks)
+        .store_sync_mode(args.store_sync_mode)
+        .channel_capacity(args.actor_channel_capacity.get())
         .build()?;
     let unpacker = unpacker.start();
 
     let deleter = Deleter::builder()
         .nix_store_dir(args.nix_store_dir.clone())
         .nar_info_cache_dir(nar_info_cache_dir)
+        .channel_capacity(args.actor_channel_capacity.get())
         .build()?;
     let deleter = deleter.start();
 
@@ -228,23 +882,58 @@ async fn async_main(args: Args, systemd_handle: SystemdNotifyHandle) -> anyhow::
         .downloader(downloader)
         .unpacker(unpacker)
         .deleter(deleter)
+        .auto_reboot(args.auto_reboot)
+        .foreign_packages_policy(args.foreign_packages_policy)
+        .observer_mode(args.observer_mode)
+        .systemd_handle(systemd_handle.clone())
+        .channel_capacity(args.actor_channel_capacity.get())
         .build()?
         .start();
 
     let server = Server::builder()
-        .address(control_server_address)
+        .addresses(control_server_addresses)
         .port(args.control_port)
+        .workers(args.control_workers)
+        .max_body_bytes(args.control_max_body_bytes)
+        .serve_metrics(!args.disable_control_metrics)
         .state_keeper_input(state_keeper.input())
-        .update_public_key(args.update_public_key)
+        .update_public_keys(args.update_public_key)
+        .nix_store_dir(store_path_string)
+        .configuration(configuration)
         .build()?
         .start()?;
 
+    startup_timeout_extender.abort();
     systemd_handle.notify_ready()?;
-    signals_task.await?;
 
-    tracing::info!("Process was asked to terminate, proceeding with graceful shutdown.");
+    // The state keeper's main loop is where the agent would get stuck, so the watchdog only gets pinged while it keeps answering.
+    let state_keeper_input = state_keeper.input();
+    let watchdog_task = systemd_handle.spawn_watchdog(move || {
+        let state_keeper_input = state_keeper_input.clone();
+        async move { state_keeper_input.heartbeat().await.is_ok() }
+    });
+
+    // Any signals we got during startup were queued up, so we'll handle them now.
+    let shutdown_reason = handle_signals(
+        signals,
+        systemd_handle,
+        listen_settings,
+        downloader_input,
+        &server,
+        log_filter,
+    )
+    .await;
+
+    tracing::info!(
+        shutdown_reason,
+        "Process was asked to terminate, proceeding with graceful shutdown."
+    );
+    if let Some(task) = watchdog_task {
+        task.abort();
+    }
     server.shutdown().await?;
-    state_keeper.shutdown().await?;
+    state_keeper.shutdown(shutdown_reason).await?;
+    #[cfg(feature = "telemetry-server")]
     telemetry_server.shutdown().await?;
     tracing::info!("Process done with graceful shutdown.");
     Ok(())
@@ -252,19 +941,43 @@ async fn async_main(args: Args, systemd_handle: SystemdNotifyHandle) -> anyhow::
 
 // Main is not async because we need to make sure we deal with all the capabilities on the initial thread before we spawn any others.
 fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+    // The log target may come from the extra env file, so logging can only be set up after loading it.
+    let env_file_path = process_init::load_extra_env_file(false)?;
+    let args = Args::parse();
+
+    let log_filter = logging::init(args.log_target, args.log_format, &args.log_level)?;
     tracing::info!("nixless-agent finished initialising logging, will now proceed with the rest of initialisation.");
+    tracing::info!(
+        ?env_file_path,
+        "Loaded additional environment variables, if there were any."
+    );
 
     let systemd_handle = process_init::retrieve_once_systemd_notify_handle();
 
-    process_init::load_extra_env_file()?;
-    let args = Args::parse();
+    if args.observer_mode {
+        tracing::info!("Running in observer mode, so we won't prepare the Nix store and will drop every capability.");
+        process_init::drop_caps(&CapsHashSet::new())?;
+        return async_main(args, systemd_handle, log_filter);
+    }
 
-    process_init::ensure_caps()?;
-    ensure_nix_daemon_not_present()?;
+    let retained_caps = args.retained_capabilities.iter().copied().collect();
+    process_init::ensure_caps(&retained_caps)?;
+    systemd_handle.extend_startup_timeout()?;
+    if let Err(err) = ensure_nix_daemon_not_present() {
+        if !args.allow_nix_daemon {
+            return Err(err);
+        }
+
+        tracing::warn!(
+            ?err,
+            "The nix daemon may be running (or we couldn't check for it), but we were told to allow it, so we'll carry on. Anything else changing the Nix store while we run may break our configurations."
+        );
+    }
     process_init::prepare_nix_store(&args.nix_store_dir)?;
+    // Preparing the state dir goes through every directory in it, so this might take a while.
+    systemd_handle.extend_startup_timeout()?;
     process_init::prepare_nix_state(&args.nix_state_dir)?;
-    process_init::drop_caps()?;
+    process_init::drop_caps(&retained_caps)?;
 
-    async_main(args, systemd_handle)
+    async_main(args, systemd_handle, log_filter)
 }
@@ -1,6 +1,133 @@
-use foundations::telemetry::metrics::{metrics, Counter, Gauge, HistogramBuilder, TimeHistogram};
 use std::sync::Arc;
 
+use anyhow::anyhow;
+use foundations::telemetry::{
+    metrics::{metrics, Counter, Gauge, HistogramBuilder, TimeHistogram},
+    settings::MetricsSettings,
+};
+use serde_json::{json, Map, Value};
+
+/// Settings used whenever metrics are reported, so the control server and the telemetry server report the same thing.
+pub fn settings(report_optional: bool) -> MetricsSettings {
+    let mut settings = MetricsSettings::default();
+    settings.report_optional = report_optional;
+    settings
+}
+
+/// Sets up the metrics registry. Only needed when the telemetry server isn't used, since starting the telemetry server already does this.
+#[cfg(not(feature = "telemetry-server"))]
+pub fn init(report_optional: bool) -> anyhow::Result<()> {
+    let service_info = foundations::service_info!();
+    foundations::telemetry::init(
+        &service_info,
+        &foundations::telemetry::settings::TelemetrySettings {
+            metrics: settings(report_optional),
+        },
+    )
+}
+
+/// Renders all metrics in the Prometheus text format.
+pub fn collect(report_optional: bool) -> anyhow::Result<String> {
+    foundations::telemetry::metrics::collect(&settings(report_optional)).map_err(|err| anyhow!(err))
+}
+
+/// Renders all metrics as JSON, keyed by metric name. Each metric has its type, description and a list of samples with their labels and values. This is built from the Prometheus text format, so it always reports the same thing as `collect()`.
+pub fn collect_json(report_optional: bool) -> anyhow::Result<Value> {
+    let text = collect(report_optional)?;
+    let mut metrics = Map::new();
+    let mut current_metric: Option<String> = None;
+
+    for line in text.lines() {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            let (Some(kind), Some(name)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            let rest = parts.next().unwrap_or_default();
+
+            let entry = metrics
+                .entry(name.to_string())
+                .or_insert_with(|| json!({ "samples": [] }));
+            match kind {
+                "HELP" => entry["help"] = Value::String(rest.to_string()),
+                "TYPE" => entry["type"] = Value::String(rest.to_string()),
+                _ => continue,
+            }
+            current_metric = Some(name.to_string());
+            continue;
+        }
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let (sample_name, labels, value) = parse_sample(line)?;
+        // Samples like histogram buckets have a suffix after the metric name, so we'll group them under the metric we last saw a description for.
+        let metric_name = match &current_metric {
+            Some(name) if sample_name.starts_with(name.as_str()) => name.clone(),
+            _ => sample_name.clone(),
+        };
+
+        let entry = metrics
+            .entry(metric_name)
+            .or_insert_with(|| json!({ "samples": [] }));
+        entry["samples"].as_array_mut().unwrap().push(json!({
+            "name": sample_name,
+            "labels": labels,
+            "value": value,
+        }));
+    }
+
+    Ok(Value::Object(metrics))
+}
+
+/// Parses a line like `name{label="value",other="value"} 1.0` into its parts.
+fn parse_sample(line: &str) -> anyhow::Result<(String, Map<String, Value>, f64)> {
+    let malformed = || anyhow!("malformed metric sample: {}", line);
+
+    let name_end = line.find(['{', ' ']).ok_or_else(malformed)?;
+    let name = line[..name_end].to_string();
+    let mut rest = &line[name_end..];
+    let mut labels = Map::new();
+
+    if let Some(mut label_str) = rest.strip_prefix('{') {
+        loop {
+            label_str = label_str.trim_start_matches(',');
+            if let Some(after) = label_str.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+
+            let (label_name, after_name) = label_str.split_once("=\"").ok_or_else(malformed)?;
+            let mut label_value = String::new();
+            let mut chars = after_name.char_indices();
+            let value_end = loop {
+                match chars.next().ok_or_else(malformed)? {
+                    (i, '"') => break i,
+                    (_, '\\') => match chars.next().ok_or_else(malformed)?.1 {
+                        'n' => label_value.push('\n'),
+                        c => label_value.push(c),
+                    },
+                    (_, c) => label_value.push(c),
+                }
+            };
+
+            labels.insert(label_name.to_string(), Value::String(label_value));
+            label_str = &after_name[value_end + 1..];
+        }
+    }
+
+    // There may be a timestamp after the value, but we don't use those.
+    let value = rest
+        .split_whitespace()
+        .next()
+        .ok_or_else(malformed)?
+        .parse()
+        .map_err(|_| malformed())?;
+
+    Ok((name, labels, value))
+}
+
 #[metrics]
 pub mod system {
     /// Current system version.
@@ -30,9 +157,86 @@ pub mod requests {
     /// Number of summary requests made to the agent since it started up.
     pub fn summary() -> Counter;
 
+    /// Number of switch history requests made to the agent since it started up.
+    pub fn history() -> Counter;
+
+    /// Number of pending tasks requests made to the agent since it started up.
+    pub fn pending_tasks() -> Counter;
+
+    /// Number of consistency checks made to the agent since it started up.
+    pub fn consistency() -> Counter;
+
     /// Number of new configuration requests made to the agent since it started up.
     pub fn new_configuration() -> Counter;
 
+    /// Number of prefetch requests made to the agent since it started up.
+    pub fn prefetch() -> Counter;
+
     /// Number of rollback requests made to the agent since it started up.
     pub fn rollback() -> Counter;
+
+    /// Number of metrics requests (in any format) made to the agent's control server since it started up.
+    pub fn metrics() -> Counter;
+
+    /// Number of liveness checks made to the agent since it started up.
+    pub fn healthz() -> Counter;
+
+    /// Number of readiness checks made to the agent since it started up.
+    pub fn readyz() -> Counter;
+
+    /// Number of version requests made to the agent since it started up.
+    pub fn version() -> Counter;
+
+    /// Number of configuration requests made to the agent since it started up.
+    pub fn config() -> Counter;
+
+    /// Number of drain requests made to the agent since it started up.
+    pub fn drain() -> Counter;
+
+    /// Number of requests to clean up the state directory made to the agent since it started up.
+    pub fn cleanup_state() -> Counter;
+
+    /// Number of requests to repair the state made to the agent since it started up.
+    pub fn repair() -> Counter;
+
+    /// Number of subscriptions to switch events made to the agent since it started up.
+    pub fn switch_events() -> Counter;
+}
+
+#[metrics]
+pub mod actors {
+    /// Number of times a channel to or from one of the agent's actors closed unexpectedly since the agent started up, by actor. Anything above zero usually means that the actor's task died.
+    pub fn channel_closed(actor: &'static str) -> Counter;
+}
+
+#[metrics]
+pub mod downloads {
+    /// Number of compressed bytes downloaded from the binary cache since the agent started up.
+    pub fn compressed_bytes() -> Counter;
+
+    /// Number of NARs downloaded from the binary cache since the agent started up.
+    pub fn nars_downloaded() -> Counter;
+
+    /// Number of NARs that didn't need to be downloaded because their packages already existed locally.
+    pub fn nars_skipped() -> Counter;
+
+    /// Number of NARs that didn't need to be downloaded because an earlier, interrupted download had already fully written and verified them.
+    pub fn nars_resumed() -> Counter;
+
+    /// Number of NAR downloads that took longer than the NAR download timeout and were given up on since the agent started up.
+    pub fn nar_download_timeouts() -> Counter;
+
+    /// Time taken to get the narinfo of each package, whether from the binary cache or from our local cache of narinfos.
+    #[ctor = HistogramBuilder {
+        // 10 milliseconds to 10 seconds.
+        buckets: &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0],
+    }]
+    pub fn nar_info_fetch_duration() -> TimeHistogram;
+
+    /// Time taken to download each NAR from the binary cache, including decompressing and hashing it, since those happen while it's being downloaded.
+    #[ctor = HistogramBuilder {
+        // 50 milliseconds to 10 minutes.
+        buckets: &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 300.0, 600.0],
+    }]
+    pub fn nar_fetch_duration() -> TimeHistogram;
 }
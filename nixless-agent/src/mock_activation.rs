@@ -0,0 +1,122 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use serde_json::json;
+use tokio_stream::StreamExt;
+use tracing::instrument;
+
+use crate::{
+    actors::ActorInputStream,
+    dbus_connection::{DBusConnectionRequest, PolkitAuthorisation},
+    error::{send_response, AgentError},
+};
+
+/// The outcome every mocked configuration switch will have.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum MockActivationOutcome {
+    Success,
+    RebootRequired,
+    Failure,
+    /// The switch starts but never finishes, like a switch that hangs.
+    Hang,
+}
+
+/// Stands in for the D-Bus connection task without talking to D-Bus at all. Configuration switches don't activate anything: we only write the tracking files the same way `system-switch-tracker` would when called by the transient unit, so the rest of the agent goes through the same flow as with a real switch.
+#[instrument(skip_all)]
+pub async fn mock_dbus_connection_task(
+    mut input_stream: ActorInputStream<DBusConnectionRequest>,
+    outcome: MockActivationOutcome,
+    activation_track_dir: PathBuf,
+) -> anyhow::Result<()> {
+    tracing::warn!(
+        ?outcome,
+        "Configuration switches are mocked! No configuration will actually be activated."
+    );
+
+    while let Some(req) = input_stream.next().await {
+        match req {
+            DBusConnectionRequest::Shutdown => {
+                tracing::info!("Mock D-Bus connection got a request to shut down. Proceeding.");
+                break;
+            }
+            DBusConnectionRequest::ClearPendingSwitchTask => (),
+            DBusConnectionRequest::CheckAuthorisationPossibility { resp_tx } => {
+                send_response(
+                    resp_tx,
+                    Ok(PolkitAuthorisation::Authorised),
+                    "D-Bus connection",
+                );
+            }
+            DBusConnectionRequest::PerformConfigurationSwitch {
+                system_package_path,
+                action,
+                switch_id,
+                resp_tx,
+            } => {
+                tracing::info!(
+                    ?system_package_path,
+                    action = action.as_str(),
+                    switch_id,
+                    "Mocking a configuration switch."
+                );
+                let res = write_tracking_files(outcome, &activation_track_dir, &switch_id)
+                    .await
+                    .map_err(AgentError::Activation);
+                send_response(resp_tx, res, "D-Bus connection");
+            }
+            DBusConnectionRequest::WaitConfigurationSwitchComplete { resp_tx } => {
+                if let MockActivationOutcome::Hang = outcome {
+                    // Gives the caller a chance to check the tracking files again, the same way it would after waiting on a real unit that's taking a long time.
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                }
+
+                send_response(resp_tx, Ok(()), "D-Bus connection");
+            }
+            DBusConnectionRequest::Reboot { resp_tx } => {
+                tracing::info!("Mocking a reboot, which does nothing.");
+                send_response(resp_tx, Ok(()), "D-Bus connection");
+            }
+        }
+    }
+
+    tracing::info!("Mock D-Bus connection has finished shutting down.");
+    Ok(())
+}
+
+async fn write_tracking_files(
+    outcome: MockActivationOutcome,
+    activation_track_dir: &Path,
+    switch_id: &str,
+) -> anyhow::Result<()> {
+    let written_at_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let written_at = json!({ "written_at_ms": written_at_ms, "switch_id": switch_id }).to_string();
+    tokio::fs::write(activation_track_dir.join("pre_switch"), &written_at).await?;
+
+    // The status codes in the finish file are what systemd passes in $SERVICE_RESULT, $EXIT_CODE and $EXIT_STATUS.
+    let (service_result, exit_code, exit_status) = match outcome {
+        MockActivationOutcome::Success => {
+            tokio::fs::write(activation_track_dir.join("switch_success"), &written_at).await?;
+            ("success", "exited", "0")
+        }
+        MockActivationOutcome::RebootRequired => ("exit-code", "exited", "100"),
+        MockActivationOutcome::Failure => ("exit-code", "exited", "1"),
+        MockActivationOutcome::Hang => return Ok(()),
+    };
+
+    let finish_contents = json!({
+        "written_at_ms": written_at_ms,
+        "switch_id": switch_id,
+        "service_result": service_result,
+        "exit_code": exit_code,
+        "exit_status": exit_status,
+    });
+    tokio::fs::write(
+        activation_track_dir.join("post_switch"),
+        finish_contents.to_string(),
+    )
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,74 @@
+/// Credentials for a single machine, as found in a netrc file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct NetrcCredentials {
+    pub login: String,
+    pub password: String,
+}
+
+/// Which entry the tokens we're reading belong to.
+enum CurrentEntry {
+    Matching,
+    Default,
+    Ignored,
+}
+
+/// Finds the credentials for `host` in the contents of a netrc file, following the same rules as curl (which is what Nix uses): the first `machine` entry matching the host wins, and a `default` entry is used if no machine matches.
+pub fn find_credentials(contents: &str, host: &str) -> Option<NetrcCredentials> {
+    let mut matching: Option<NetrcCredentials> = None;
+    let mut default: Option<NetrcCredentials> = None;
+    let mut current = CurrentEntry::Ignored;
+    let mut in_macdef = false;
+
+    for line in contents.lines() {
+        // Macro definitions go on until an empty line, and their contents aren't tokens.
+        if in_macdef {
+            in_macdef = !line.trim().is_empty();
+            continue;
+        }
+
+        let mut tokens = line.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" => {
+                    current = if tokens.next() == Some(host) && matching.is_none() {
+                        matching = Some(NetrcCredentials::default());
+                        CurrentEntry::Matching
+                    } else {
+                        CurrentEntry::Ignored
+                    };
+                }
+                "default" => {
+                    current = if default.is_none() {
+                        default = Some(NetrcCredentials::default());
+                        CurrentEntry::Default
+                    } else {
+                        CurrentEntry::Ignored
+                    };
+                }
+                "login" | "password" | "account" => {
+                    let value = tokens.next().unwrap_or_default().to_string();
+                    let credentials = match current {
+                        CurrentEntry::Matching => matching.as_mut(),
+                        CurrentEntry::Default => default.as_mut(),
+                        CurrentEntry::Ignored => None,
+                    };
+
+                    if let Some(credentials) = credentials {
+                        match token {
+                            "login" => credentials.login = value,
+                            "password" => credentials.password = value,
+                            _ => (),
+                        }
+                    }
+                }
+                "macdef" => {
+                    in_macdef = true;
+                    break;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    matching.or(default)
+}
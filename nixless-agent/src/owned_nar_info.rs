@@ -26,6 +26,8 @@ pub struct OwnedNarInfo {
     pub system: Option<String>,
     pub references: Vec<String>,
     pub sigs: Vec<OwnedSig>,
+    /// The content address of the package, if it's content-addressed.
+    pub ca: Option<String>,
 }
 
 impl<'a> From<NarInfo<'a>> for OwnedNarInfo {
@@ -46,6 +48,8 @@ impl<'a> From<NarInfo<'a>> for OwnedNarInfo {
                 .map(|v| v.to_string())
                 .collect(),
             sigs: value.sigs.into_iter().map(|v| v.into()).collect(),
+            // The narinfo crate doesn't know about this field, so whoever parses the narinfo has to fill it in.
+            ca: None,
         }
     }
 }
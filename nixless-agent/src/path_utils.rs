@@ -9,6 +9,7 @@ use std::{
 use anyhow::anyhow;
 use futures::future::join_all;
 use nix::unistd::geteuid;
+use nix_core::StorePath;
 use tracing::instrument;
 
 pub fn get_number_from_numbered_system_name(name: &OsStr) -> anyhow::Result<u32> {
@@ -165,25 +166,69 @@ pub async fn remove_file_with_check(path: impl AsRef<Path>) -> anyhow::Result<()
 pub async fn collect_nix_store_packages(
     store_dir: impl AsRef<Path>,
 ) -> anyhow::Result<HashSet<String>> {
-    let store_dir_str = store_dir.as_ref().to_str().ok_or_else(|| {
-        anyhow!("the store directory path couldn't be transformed to a utf-8 string")
-    })?;
-
     let mut entries = tokio::fs::read_dir(&store_dir).await?;
     let mut package_id_set = HashSet::new();
 
     while let Some(entry) = entries.next_entry().await? {
-        if let Some(path_str) = entry.path().to_str() {
-            package_id_set.insert(
-                path_str
-                    .trim_start_matches(store_dir_str)
-                    .trim_start_matches("/")
-                    .to_string(),
-            );
-        } else {
-            return Err(anyhow!("found a package in the store with a path containing non-UTF-8 characters, which is unexpected: {}", entry.path().to_string_lossy()));
+        match entry.file_name().into_string() {
+            Ok(package_id) if StorePath::from_package_id(&package_id).is_ok() => {
+                package_id_set.insert(package_id);
+            }
+            Ok(file_name) => {
+                // Nix's `.links` directory and the temporary directories used while unpacking live here too, so this is expected and not worth a warning.
+                tracing::debug!(
+                    file_name,
+                    "Skipping an entry in the Nix store that doesn't look like a store path."
+                );
+            }
+            Err(file_name) => {
+                // Nix never creates these, so this must be something foreign in the store. It can't be part of a configuration we manage, so we'll leave it alone instead of failing the whole scan.
+                tracing::warn!(
+                    ?file_name,
+                    "Skipping an entry in the Nix store with a name containing non-UTF-8 characters."
+                );
+            }
         }
     }
 
+    // Nix keeps a `<package id>.lock` file next to a store path while it's building or substituting it. These look like store paths themselves, but we can tell them apart because the path they lock is also there.
+    let lock_files: Vec<String> = package_id_set
+        .iter()
+        .filter(|package_id| {
+            package_id
+                .strip_suffix(".lock")
+                .is_some_and(|locked_id| package_id_set.contains(locked_id))
+        })
+        .cloned()
+        .collect();
+
+    for lock_file in lock_files {
+        tracing::debug!(lock_file, "Skipping a Nix lock file in the Nix store.");
+        package_id_set.remove(&lock_file);
+    }
+
     Ok(package_id_set)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID_PACKAGE_ID: &str = "0c0fbflsgmvl9j3ag6p0h2ja1bxmd5ii-hello-2.12.1";
+
+    #[tokio::test]
+    async fn collect_nix_store_packages_skips_non_store_paths() {
+        let store_dir = tempfile::tempdir().unwrap();
+        let store_path = store_dir.path();
+
+        std::fs::create_dir(store_path.join(VALID_PACKAGE_ID)).unwrap();
+        std::fs::write(store_path.join(format!("{VALID_PACKAGE_ID}.lock")), "").unwrap();
+        std::fs::write(store_path.join("foo.lock"), "").unwrap();
+        std::fs::create_dir(store_path.join(".links")).unwrap();
+        std::fs::create_dir(store_path.join("not-a-store-path")).unwrap();
+
+        let packages = collect_nix_store_packages(store_path).await.unwrap();
+
+        assert_eq!(packages, HashSet::from([VALID_PACKAGE_ID.to_string()]));
+    }
+}
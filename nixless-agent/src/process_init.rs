@@ -1,40 +1,64 @@
 use std::{
     env,
-    fs::{read_dir, read_link, read_to_string},
+    fs::{create_dir_all, read_dir, read_link, read_to_string, set_permissions, Permissions},
+    future::Future,
     io::ErrorKind,
-    os::unix::{fs::lchown, net::UnixDatagram},
+    os::unix::{
+        fs::{lchown, MetadataExt, PermissionsExt},
+        net::UnixDatagram,
+    },
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context};
-use caps::{CapSet, Capability};
+use caps::{CapSet, Capability, CapsHashSet};
 use nix::{
     mount::{mount, MsFlags},
     sched::{unshare, CloneFlags},
     sys::statvfs::{statvfs, FsFlags},
-    unistd::{chown, getegid, Gid},
+    time::{clock_gettime, ClockId},
+    unistd::{chown, getegid, Gid, Uid},
 };
+use tokio::task::JoinHandle;
 
 use crate::path_utils::set_group_write_perm;
 
-pub fn ensure_caps() -> anyhow::Result<()> {
+/// Capabilities we need during startup to prepare the Nix store and state dirs.
+const STARTUP_CAPABILITIES: [Capability; 4] = [
+    Capability::CAP_SETPCAP,
+    Capability::CAP_SYS_ADMIN,
+    Capability::CAP_CHOWN,
+    Capability::CAP_FOWNER,
+];
+
+/// How many threads at most go through the Nix state dir at startup. Most of the time goes into waiting on the filesystem, so a few threads help even on small machines, but more than that would mostly contend on the same directories.
+const MAX_NIX_STATE_THREADS: usize = 8;
+
+/// Raises the startup capabilities and `retained_caps` into the effective set. Startup capabilities that we weren't permitted to have are only warned about (some restricted environments can't grant them, and the startup steps that need them will fail with a more specific error if they turn out to be needed), but the retained capabilities must all be available.
+pub fn ensure_caps(retained_caps: &CapsHashSet) -> anyhow::Result<()> {
+    let permitted_set = caps::read(None, CapSet::Permitted)?;
     let mut effective_set = caps::read(None, CapSet::Effective)?;
     let mut should_raise = false;
 
-    if !effective_set.contains(&Capability::CAP_SETPCAP) {
-        effective_set.insert(Capability::CAP_SETPCAP);
-        should_raise = true;
-    }
-    if !effective_set.contains(&Capability::CAP_SYS_ADMIN) {
-        effective_set.insert(Capability::CAP_SYS_ADMIN);
-        should_raise = true;
-    }
-    if !effective_set.contains(&Capability::CAP_CHOWN) {
-        effective_set.insert(Capability::CAP_CHOWN);
-        should_raise = true;
-    }
-    if !effective_set.contains(&Capability::CAP_FOWNER) {
-        effective_set.insert(Capability::CAP_FOWNER);
+    for cap in STARTUP_CAPABILITIES.iter().chain(retained_caps.iter()) {
+        if effective_set.contains(cap) {
+            continue;
+        }
+
+        if !permitted_set.contains(cap) {
+            if retained_caps.contains(cap) {
+                return Err(anyhow!(
+                    "we were asked to retain {} but we aren't permitted to have it",
+                    cap
+                ));
+            }
+
+            tracing::warn!(%cap, "We aren't permitted to have a capability used during startup, so we'll continue without it.");
+            continue;
+        }
+
+        effective_set.insert(*cap);
         should_raise = true;
     }
 
@@ -47,27 +71,77 @@ pub fn ensure_caps() -> anyhow::Result<()> {
 
 // Adapted from https://github.com/NixOS/nix/blob/845b2a9256bd1541abbe66b3129c87713983d073/src/libstore/local-store.cc#L574
 pub fn prepare_nix_store(store_path: &PathBuf) -> anyhow::Result<()> {
-    let stat = statvfs(store_path)?;
+    // When provisioning a fresh machine, the Nix store may not exist until we put something in it.
+    let store_exists = store_path.try_exists().with_context(|| {
+        format!(
+            "failed to check if the Nix store at {} exists",
+            store_path.display()
+        )
+    })?;
+    if !store_exists {
+        tracing::info!(
+            ?store_path,
+            "The Nix store doesn't exist yet, so we'll create it."
+        );
+        create_dir_all(store_path).with_context(|| {
+            format!("failed to create the Nix store at {}", store_path.display())
+        })?;
+        // Same ownership and permissions that Nix gives the store. The sticky bit prevents anyone who can write to the store from removing entries that aren't theirs.
+        set_permissions(store_path, Permissions::from_mode(0o1775))?;
+        chown(store_path, Some(Uid::from_raw(0)), None)?;
+    }
 
-    if stat.flags().contains(FsFlags::ST_RDONLY) {
+    if !is_mounted_read_only(store_path)? {
+        tracing::info!(
+            ?store_path,
+            "The Nix store is already writable, so we don't need a private mount namespace."
+        );
+    } else {
         // The read-only mount to prevent changes to the Nix store exists, so we'll get rid of the mount by moving into a different mount namespace and remounting the store. This will ensure only this process has write access to the Nix store.
-        unshare(CloneFlags::CLONE_NEWNS)?;
+        unshare(CloneFlags::CLONE_NEWNS).context(
+            "the Nix store is mounted read-only, and we failed to move into a private mount namespace to make it writable for us only. The agent needs CAP_SYS_ADMIN for this, which some container setups don't give",
+        )?;
         mount(
             None::<&PathBuf>,
             store_path,
             None::<&str>,
             MsFlags::MS_BIND | MsFlags::MS_REMOUNT,
             None::<&str>,
-        )?;
+        )
+        .with_context(|| {
+            format!(
+                "the Nix store at {} is mounted read-only, and we failed to remount it as writable in our private mount namespace. The agent needs CAP_SYS_ADMIN and write access to the store",
+                store_path.display()
+            )
+        })?;
+
+        // Remounting only gets rid of the read-only bind mount, so the store can still be read-only if the filesystem it's on is.
+        if is_mounted_read_only(store_path)? {
+            return Err(anyhow!(
+                "the Nix store at {} is still read-only after remounting it, so the filesystem it's on is probably read-only itself",
+                store_path.display()
+            ));
+        }
     }
 
     let current_gid = getegid();
     // Allows us to unpack NARs into the store.
-    chown(store_path, None, Some(current_gid))?;
+    chown(store_path, None, Some(current_gid)).with_context(|| {
+        format!(
+            "failed to give our group ownership of the Nix store at {}",
+            store_path.display()
+        )
+    })?;
 
     Ok(())
 }
 
+fn is_mounted_read_only(path: &Path) -> anyhow::Result<bool> {
+    let stat = statvfs(path)
+        .with_context(|| format!("failed to check how {} is mounted", path.display()))?;
+    Ok(stat.flags().contains(FsFlags::ST_RDONLY))
+}
+
 pub fn prepare_nix_state(state_path: &PathBuf) -> anyhow::Result<()> {
     let current_gid = getegid();
 
@@ -78,37 +152,117 @@ pub fn prepare_nix_state(state_path: &PathBuf) -> anyhow::Result<()> {
     lchown(parent, None, Some(current_gid.as_raw()))?;
     set_group_write_perm(parent)?;
 
-    prepare_nix_state_dir(state_path, &current_gid)?;
+    let start = Instant::now();
+    let prepared_dirs = prepare_nix_state_dirs(state_path, current_gid)?;
+    tracing::info!(
+        prepared_dirs,
+        duration_ms = start.elapsed().as_millis() as u64,
+        "Finished preparing the Nix state dir."
+    );
+
     Ok(())
 }
 
-fn prepare_nix_state_dir(curr_dir_path: &Path, gid: &Gid) -> anyhow::Result<()> {
-    lchown(curr_dir_path, None, Some(gid.as_raw()))?;
-    set_group_write_perm(curr_dir_path)?;
+/// Goes through the Nix state dir one level at a time, preparing all directories of a level in parallel before going into the next one. This way every directory is still prepared before anything inside it. Returns how many directories were prepared.
+fn prepare_nix_state_dirs(state_path: &Path, gid: Gid) -> anyhow::Result<usize> {
+    let thread_count = std::thread::available_parallelism()
+        .map_or(1, |count| count.get())
+        .min(MAX_NIX_STATE_THREADS);
+    let mut current_level = vec![state_path.to_path_buf()];
+    let mut prepared_dirs = 0;
+
+    while !current_level.is_empty() {
+        prepared_dirs += current_level.len();
+        let chunk_size = current_level.len().div_ceil(thread_count);
+
+        current_level = std::thread::scope(|scope| {
+            let handles: Vec<_> = current_level
+                .chunks(chunk_size)
+                .map(|dirs| scope.spawn(move || prepare_nix_state_level(dirs, gid)))
+                .collect();
+
+            let mut next_level = Vec::new();
+            for handle in handles {
+                let subdirs = handle
+                    .join()
+                    .map_err(|_| anyhow!("a thread preparing the Nix state dir panicked"))??;
+                next_level.extend(subdirs);
+            }
+            anyhow::Ok(next_level)
+        })?;
+    }
 
-    for entry in read_dir(curr_dir_path)? {
-        let entry = entry?;
-        if entry.file_type()?.is_dir() {
-            prepare_nix_state_dir(&entry.path(), gid)?;
+    Ok(prepared_dirs)
+}
+
+/// Prepares each of `dirs`, and returns the directories inside them.
+fn prepare_nix_state_level(dirs: &[PathBuf], gid: Gid) -> anyhow::Result<Vec<PathBuf>> {
+    let mut subdirs = Vec::new();
+
+    for dir in dirs {
+        prepare_nix_state_dir(dir, gid)?;
+
+        for entry in read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                subdirs.push(entry.path());
+            }
         }
     }
 
-    Ok(())
+    Ok(subdirs)
+}
+
+/// Only changes the group and permissions of the directory if they aren't right yet, which is already the case for most directories on every startup after the first one.
+fn prepare_nix_state_dir(dir_path: &Path, gid: Gid) -> anyhow::Result<()> {
+    if std::fs::symlink_metadata(dir_path)?.gid() != gid.as_raw() {
+        lchown(dir_path, None, Some(gid.as_raw()))?;
+    }
+
+    set_group_write_perm(dir_path)
 }
 
-pub fn drop_caps() -> anyhow::Result<()> {
-    // We'll still need CAP_CHOWN when unpacking NARs into the store, but the other caps can go away.
+/// Drops every capability except `retained_caps`. By default we only retain CAP_CHOWN, which we need when unpacking NARs into the store.
+pub fn drop_caps(retained_caps: &CapsHashSet) -> anyhow::Result<()> {
     caps::clear(None, CapSet::Ambient)?;
     caps::clear(None, CapSet::Inheritable)?;
-    caps::drop(None, CapSet::Effective, Capability::CAP_SYS_ADMIN)?;
-    caps::drop(None, CapSet::Effective, Capability::CAP_SETPCAP)?;
-    caps::drop(None, CapSet::Effective, Capability::CAP_FOWNER)?;
-    caps::drop(None, CapSet::Permitted, Capability::CAP_SYS_ADMIN)?;
-    caps::drop(None, CapSet::Permitted, Capability::CAP_SETPCAP)?;
-    caps::drop(None, CapSet::Permitted, Capability::CAP_FOWNER)?;
+    // The effective set must always be a subset of the permitted set, so it has to go first.
+    caps::set(None, CapSet::Effective, retained_caps)?;
+    caps::set(None, CapSet::Permitted, retained_caps)?;
+
+    // We're about to run with these for as long as the agent lives, so we make sure the kernel left us with exactly what we asked for instead of trusting each call did what we wanted.
+    let no_caps = CapsHashSet::new();
+    for (cap_set, expected) in [
+        (CapSet::Ambient, &no_caps),
+        (CapSet::Inheritable, &no_caps),
+        (CapSet::Effective, retained_caps),
+        (CapSet::Permitted, retained_caps),
+    ] {
+        let actual = caps::read(None, cap_set)?;
+        if actual != *expected {
+            return Err(anyhow!(
+                "after dropping capabilities, the {:?} set has {:?} instead of {:?}",
+                cap_set,
+                sorted_cap_names(&actual),
+                sorted_cap_names(expected)
+            ));
+        }
+    }
+
+    tracing::info!(
+        retained = ?sorted_cap_names(retained_caps),
+        "Dropped all capabilities except the ones we retain."
+    );
+
     Ok(())
 }
 
+fn sorted_cap_names(caps: &CapsHashSet) -> Vec<String> {
+    let mut names: Vec<_> = caps.iter().map(|cap| cap.to_string()).collect();
+    names.sort();
+    names
+}
+
 /// This can never guarantee the nix daemon isn't running in the system, but for all common cases, it will catch the daemon and error when it does.
 pub fn ensure_nix_daemon_not_present() -> anyhow::Result<()> {
     tracing::info!("Going to read /proc");
@@ -179,7 +333,8 @@ pub fn ensure_nix_daemon_not_present() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn load_extra_env_file() -> anyhow::Result<()> {
+/// When `override_existing` is set, variables in the file replace any that are already set in the environment. This is needed when reloading, since by then the environment already has the values loaded at startup. Returns the path of the file we looked for, even if it didn't exist. This is called before logging is set up, so it's up to callers to log it.
+pub fn load_extra_env_file(override_existing: bool) -> anyhow::Result<PathBuf> {
     let env_file_path = match ::std::env::var("NIXLESS_AGENT_EXTRA_ENV_FILE") {
         Ok(val) => PathBuf::from(val),
         Err(_) => {
@@ -192,9 +347,13 @@ pub fn load_extra_env_file() -> anyhow::Result<()> {
         }
     };
 
-    tracing::info!(?env_file_path, "Loading additional environment variables.");
+    let res = if override_existing {
+        dotenvy::from_path_override(&env_file_path)
+    } else {
+        dotenvy::from_path(&env_file_path)
+    };
 
-    dotenvy::from_path(env_file_path).or_else(|e| match e {
+    res.or_else(|e| match e {
         dotenvy::Error::Io(io_error)
             if matches!(io_error.kind(), ::std::io::ErrorKind::NotFound) =>
         {
@@ -204,22 +363,110 @@ pub fn load_extra_env_file() -> anyhow::Result<()> {
         other => Err(other),
     })?;
 
-    Ok(())
+    Ok(env_file_path)
 }
 
+/// How much time we ask systemd for whenever a startup phase is taking a while.
+const STARTUP_TIMEOUT_EXTENSION: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
 pub struct SystemdNotifyHandle {
     socket_path: Option<String>,
+    /// Only set if systemd wants us to ping its watchdog.
+    watchdog_interval: Option<Duration>,
 }
 
 impl SystemdNotifyHandle {
     pub fn notify_ready(&self) -> std::io::Result<()> {
-        if self.socket_path.is_none() {
+        self.notify("READY=1\n")
+    }
+
+    /// Must be followed by a call to `notify_ready()` once the reload is complete, otherwise systemd will consider the service stuck reloading.
+    pub fn notify_reloading(&self) -> std::io::Result<()> {
+        // systemd uses the timestamp to tell this reload apart from any previous ones, and requires it for `Type=notify-reload` services.
+        let now = clock_gettime(ClockId::CLOCK_MONOTONIC)?;
+        let monotonic_usec = now.tv_sec() as u64 * 1_000_000 + now.tv_nsec() as u64 / 1_000;
+        self.notify(&format!("RELOADING=1\nMONOTONIC_USEC={}\n", monotonic_usec))
+    }
+
+    pub fn notify_stopping(&self) -> std::io::Result<()> {
+        self.notify("STOPPING=1\n")
+    }
+
+    /// Sets the free-form status that systemd shows for us (e.g. in `systemctl status`).
+    pub fn notify_status(&self, status: &str) -> std::io::Result<()> {
+        // The status must fit in a single line.
+        self.notify(&format!("STATUS={}\n", status.replace('\n', " ")))
+    }
+
+    pub fn notify_watchdog(&self) -> std::io::Result<()> {
+        self.notify("WATCHDOG=1\n")
+    }
+
+    /// Pings the systemd watchdog at half its interval, but only after `is_responsive` tells us (within that same half interval) that we're still working properly. If we stop being responsive, we stop pinging the watchdog and let systemd deal with us. Returns `None` if systemd doesn't want us to ping its watchdog.
+    pub fn spawn_watchdog<F, Fut>(&self, is_responsive: F) -> Option<JoinHandle<()>>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = bool> + Send,
+    {
+        let watchdog_interval = self.watchdog_interval?;
+        let handle = self.clone();
+
+        Some(tokio::spawn(async move {
+            let ping_interval = watchdog_interval / 2;
+            let mut interval = tokio::time::interval(ping_interval);
+
+            loop {
+                interval.tick().await;
+
+                let responsive = tokio::time::timeout(ping_interval, is_responsive())
+                    .await
+                    .unwrap_or(false);
+
+                if !responsive {
+                    tracing::warn!("We're not responsive, so we won't ping the systemd watchdog.");
+                    continue;
+                }
+
+                if let Err(err) = handle.notify_watchdog() {
+                    tracing::warn!(?err, "Failed to ping the systemd watchdog.");
+                }
+            }
+        }))
+    }
+
+    /// Asks systemd to give us some more time to finish starting up. Only has an effect before we call `notify_ready()`.
+    pub fn extend_startup_timeout(&self) -> std::io::Result<()> {
+        self.notify(&format!(
+            "EXTEND_TIMEOUT_USEC={}\n",
+            STARTUP_TIMEOUT_EXTENSION.as_micros()
+        ))
+    }
+
+    /// Keeps extending the startup timeout until the returned task is aborted. Meant to be used during startup phases whose duration depends on the size of the host (e.g. scanning the Nix store), so systemd doesn't kill us before we're ready.
+    pub fn spawn_startup_timeout_extender(&self) -> JoinHandle<()> {
+        let handle = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(STARTUP_TIMEOUT_EXTENSION / 2);
+
+            loop {
+                interval.tick().await;
+
+                if let Err(err) = handle.extend_startup_timeout() {
+                    tracing::warn!(?err, "Failed to ask systemd to extend our startup timeout.");
+                }
+            }
+        })
+    }
+
+    fn notify(&self, msg: &str) -> std::io::Result<()> {
+        let Some(socket_path) = self.socket_path.as_ref() else {
             return Ok(());
-        }
+        };
 
-        let msg = "READY=1\n";
         let sock = UnixDatagram::unbound()?;
-        let len = sock.send_to(msg.as_bytes(), self.socket_path.as_ref().unwrap())?;
+        let len = sock.send_to(msg.as_bytes(), socket_path)?;
 
         if len != msg.len() {
             Err(std::io::Error::new(
@@ -236,5 +483,20 @@ pub fn retrieve_once_systemd_notify_handle() -> SystemdNotifyHandle {
     let socket_path = env::var_os("NOTIFY_SOCKET").map(|s| s.into_string().unwrap());
     env::remove_var("NOTIFY_SOCKET");
 
-    SystemdNotifyHandle { socket_path }
+    // systemd may also set WATCHDOG_PID, in which case the watchdog is only meant for us if it has our pid.
+    let watchdog_is_ours = env::var("WATCHDOG_PID")
+        .map(|pid| pid == std::process::id().to_string())
+        .unwrap_or(true);
+    let watchdog_interval = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|_| watchdog_is_ours)
+        .map(Duration::from_micros);
+    env::remove_var("WATCHDOG_USEC");
+    env::remove_var("WATCHDOG_PID");
+
+    SystemdNotifyHandle {
+        socket_path,
+        watchdog_interval,
+    }
 }
@@ -1,4 +1,9 @@
-use std::{collections::HashSet, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
@@ -9,28 +14,207 @@ use crate::{
         collect_nix_store_packages, get_number_from_numbered_system_name,
         overwrite_symlink_atomically_with_check,
     },
-    system_configuration::SystemConfiguration,
+    store_sync::{sync_dir, StoreSyncMode},
+    system_configuration::{SwitchAction, SystemConfiguration},
 };
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SystemSummary {
     pub stable_configuration: SystemConfiguration,
     pub status: AgentStateStatus,
+    /// Only known by the state keeper, which fills this in.
+    pub draining: bool,
+    /// Whether the agent has no work in progress (switches, prefetches, package deletions, or state clean ups), and so can be stopped safely. Only known by the state keeper, which fills this in.
+    pub idle: bool,
+    #[serde(default)]
+    pub prefetched_configuration: Option<PrefetchedConfiguration>,
+    /// Only set while a switch is going on, and only known by the state keeper, which fills this in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<SwitchProgress>,
+    /// How the previous run of the agent stopped. `None` if it didn't record a shutdown (so it likely crashed or was killed), or if there was no previous run.
+    #[serde(default)]
+    pub last_shutdown: Option<ShutdownRecord>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SwitchProgressPhase {
+    Downloading,
+    Unpacking,
+    Activating,
 }
 
+/// How far along the ongoing switch is. The download numbers stay at their final values once the switch moves past downloading.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub enum AgentStateStatus {
-    New,
-    Standby,
-    FailedSwitch {
-        configuration: SystemConfiguration,
+pub struct SwitchProgress {
+    pub phase: SwitchProgressPhase,
+    pub nars_downloaded: u64,
+    pub nars_total: u64,
+    pub bytes_downloaded: u64,
+    pub bytes_total: u64,
+}
+
+/// Where the running system and the system we booted into are linked from. NixOS always uses the paths in `/run`, but tests and nonstandard setups may need others.
+#[derive(Clone, Debug)]
+pub struct SystemPaths {
+    pub current_system: PathBuf,
+    pub booted_system: PathBuf,
+}
+
+impl Default for SystemPaths {
+    fn default() -> Self {
+        Self {
+            current_system: PathBuf::from("/run/current-system"),
+            booted_system: PathBuf::from("/run/booted-system"),
+        }
+    }
+}
+
+/// A configuration that got downloaded and unpacked ahead of a switch to it, so the switch doesn't have to wait for that. Its packages count as live until a switch starts (whether to this configuration or not), or until another configuration gets prefetched instead.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PrefetchedConfiguration {
+    pub system_package_id: String,
+    pub package_ids: HashSet<String>,
+    /// Whether all packages were already downloaded and unpacked.
+    pub ready: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SwitchOutcome {
+    Successful,
+    Failed,
+}
+
+/// A finished configuration switch. These are kept in the state as an audit trail that doesn't depend on logs.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SwitchHistoryEntry {
+    pub version_number: u32,
+    pub system_package_id: String,
+    /// In milliseconds since the Unix epoch. Unknown for switches that started before the agent kept track of this.
+    pub started_at_ms: Option<u64>,
+    /// In milliseconds since the Unix epoch.
+    pub finished_at_ms: u64,
+    pub outcome: SwitchOutcome,
+}
+
+/// Why the agent stopped the last time it ran, so the next run can tell a clean shutdown apart from a crash.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ShutdownRecord {
+    pub reason: String,
+    /// In milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+}
+
+/// Which configuration a rollback goes to.
+#[derive(Clone, Copy, Debug)]
+pub enum RollbackTarget {
+    /// The configuration before the current one, or the current one itself if a switch to a new configuration just failed or is waiting for a reboot.
+    Previous,
+    Version(u32),
+    /// Goes back the given number of configurations, counting the same way as `Previous`. Going back 1 configuration is the same as `Previous`.
+    ConfigurationsBack(u32),
+}
+
+impl FromStr for RollbackTarget {
+    type Err = anyhow::Error;
+
+    /// An empty string means the previous configuration, `-N` means going back N configurations, and anything else must be a version number.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(Self::Previous);
+        }
+
+        if let Some(configurations_back) = s.strip_prefix('-') {
+            let configurations_back: u32 = configurations_back.parse().map_err(|_| {
+                anyhow!("'{}' isn't a valid number of configurations to go back", s)
+            })?;
+            if configurations_back == 0 {
+                return Err(anyhow!("must go back at least one configuration"));
+            }
+            return Ok(Self::ConfigurationsBack(configurations_back));
+        }
+
+        s.parse()
+            .map(Self::Version)
+            .map_err(|_| anyhow!("'{}' isn't a valid version number", s))
+    }
+}
+
+/// Something in the Nix store or in the system profiles that doesn't match what the state says.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ConsistencyIssue {
+    /// The system package of a configuration we're tracking isn't in the Nix store.
+    MissingSystemPackage {
+        version_number: u32,
+        system_package_id: String,
+    },
+    /// One of the packages of a configuration we're tracking isn't in the Nix store.
+    MissingPackage {
+        version_number: u32,
+        package_id: String,
     },
-    DownloadingNewConfiguration {
-        configuration: SystemConfiguration,
+    /// The `system-<num>-link` of a configuration we're tracking doesn't point to its system package. `actual` is `None` if the link doesn't exist.
+    WrongProfileLink {
+        version_number: u32,
+        expected: PathBuf,
+        actual: Option<PathBuf>,
     },
-    SwitchingToConfiguration {
-        configuration: SystemConfiguration,
+    /// There's a `system-<num>-link` for a configuration we're not tracking.
+    UntrackedProfileLink { name: String },
+    /// The `system` link doesn't point to the latest configuration we're tracking. `actual` is `None` if the link doesn't exist.
+    WrongSystemLink {
+        expected: PathBuf,
+        actual: Option<PathBuf>,
     },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ConsistencyReport {
+    pub consistent: bool,
+    pub issues: Vec<ConsistencyIssue>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RepairReport {
+    /// Everything that got fixed, in the order it happened.
+    pub repairs: Vec<String>,
+    /// Problems that were found but that need someone to step in, e.g. with a rollback or a new configuration.
+    pub unrepaired: Vec<String>,
+}
+
+impl RepairReport {
+    fn repaired(&mut self, repair: String) {
+        tracing::info!(repair, "Repaired the agent's state.");
+        self.repairs.push(repair);
+    }
+
+    fn unrepaired(&mut self, problem: String) {
+        tracing::warn!(
+            problem,
+            "Found a problem with the agent's state that can't be repaired automatically."
+        );
+        self.unrepaired.push(problem);
+    }
+}
+
+/// The status is saved with the rest of the state, so the agent can figure out what to do if it gets restarted (e.g. during its own upgrade). What it does on startup is documented in each variant.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum AgentStateStatus {
+    /// On startup, the agent goes to standby.
+    New,
+    /// On startup, the agent stays in standby and cleans up the state directory.
+    Standby,
+    /// On startup, the agent stays in this status, and won't switch to a new configuration until it is repaired or rolled back.
+    FailedSwitch { configuration: SystemConfiguration },
+    /// Not set by current versions of the agent, which go straight to `SwitchingToConfiguration`. On startup, the agent downloads the configuration's packages again, but doesn't switch to it.
+    DownloadingNewConfiguration { configuration: SystemConfiguration },
+    /// On startup, if the agent was interrupted before it asked for the configuration to be activated, it resumes the switch: packages already in the Nix store and NARs already downloaded and verified are skipped. Otherwise, it uses the tracker files to find out how the activation went, waiting for it to finish if needed.
+    SwitchingToConfiguration { configuration: SystemConfiguration },
+    /// The switch was successful, but the configuration will only be considered stable once the system boots into it. On startup, the agent checks if the system booted into it, and if so goes to standby.
+    PendingReboot { configuration: SystemConfiguration },
     /// Only used as a temporary variant to avoid copying/cloning the SystemConfiguration of other variants. The agent state should never be left at this value.
     Temporary,
 }
@@ -43,6 +227,7 @@ impl AgentStateStatus {
             Self::FailedSwitch { .. } => "failed",
             Self::DownloadingNewConfiguration { .. } => "downloading",
             Self::SwitchingToConfiguration { .. } => "switching",
+            Self::PendingReboot { .. } => "pending_reboot",
             Self::Temporary => unreachable!("Temporary agent status shouldn't be reachable"),
         }
     }
@@ -52,7 +237,19 @@ impl AgentStateStatus {
             Self::New | Self::Standby => None,
             Self::FailedSwitch { configuration }
             | Self::DownloadingNewConfiguration { configuration }
-            | Self::SwitchingToConfiguration { configuration } => Some(configuration),
+            | Self::SwitchingToConfiguration { configuration }
+            | Self::PendingReboot { configuration } => Some(configuration),
+            Self::Temporary => unreachable!("Temporary agent status shouldn't be reachable"),
+        }
+    }
+
+    pub fn inner_configuration(&self) -> Option<&SystemConfiguration> {
+        match self {
+            Self::New | Self::Standby => None,
+            Self::FailedSwitch { configuration }
+            | Self::DownloadingNewConfiguration { configuration }
+            | Self::SwitchingToConfiguration { configuration }
+            | Self::PendingReboot { configuration } => Some(configuration),
             Self::Temporary => unreachable!("Temporary agent status shouldn't be reachable"),
         }
     }
@@ -62,7 +259,8 @@ impl AgentStateStatus {
             Self::New | Self::Standby => None,
             Self::FailedSwitch { configuration }
             | Self::DownloadingNewConfiguration { configuration }
-            | Self::SwitchingToConfiguration { configuration } => {
+            | Self::SwitchingToConfiguration { configuration }
+            | Self::PendingReboot { configuration } => {
                 Some(configuration.system_package_id.clone())
             }
             Self::Temporary => unreachable!("Temporary agent status shouldn't be reachable"),
@@ -70,7 +268,7 @@ impl AgentStateStatus {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct AgentState {
     #[serde(skip)]
     nix_store_dir: String,
@@ -82,11 +280,35 @@ pub struct AgentState {
     state_file_path: PathBuf,
     #[serde(skip)]
     max_system_history_count: usize,
+    #[serde(skip)]
+    store_sync_mode: StoreSyncMode,
+    #[serde(skip)]
+    system_paths: SystemPaths,
 
     system_configurations: Vec<SystemConfiguration>,
     current_status: AgentStateStatus,
     // When cleaning up old configurations, we don't immediately remove the packages from disk, and instead keep track of them in this Vec. Removing the packages from disk happens asynchronously and is started by the state keeper, not this state object.
     packages_to_cleanup: HashSet<String>,
+    // The `serde(default)`s allow us to keep reading states saved before these fields existed.
+    #[serde(default)]
+    current_switch_started_at_ms: Option<u64>,
+    #[serde(default)]
+    switch_history: Vec<SwitchHistoryEntry>,
+    // Only set between a clean shutdown and the next start. If it's missing when we load a saved state, the previous run didn't get to shut down cleanly.
+    #[serde(default)]
+    last_shutdown: Option<ShutdownRecord>,
+    #[serde(default)]
+    prefetched_configuration: Option<PrefetchedConfiguration>,
+    // What `last_shutdown` had when we loaded the state, kept only for this run so it can be reported.
+    #[serde(skip)]
+    previous_shutdown: Option<ShutdownRecord>,
+}
+
+fn unix_timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
 }
 
 // If we can't determine the configuration of the system, we'll use this instead.
@@ -102,10 +324,6 @@ impl AgentState {
         "state"
     }
 
-    fn current_system_path() -> &'static str {
-        "/run/current-system"
-    }
-
     fn relative_system_profile_path() -> &'static str {
         "nix/profiles/system"
     }
@@ -142,6 +360,8 @@ impl AgentState {
         nix_state_base_dir: PathBuf,
         nixless_state_dir: PathBuf,
         max_system_history_count: usize,
+        store_sync_mode: StoreSyncMode,
+        system_paths: SystemPaths,
     ) -> anyhow::Result<Self> {
         let state_file_path = Self::absolute_state_path_associated(&nixless_state_dir);
 
@@ -152,6 +372,8 @@ impl AgentState {
                 nixless_state_dir,
                 state_file_path,
                 max_system_history_count,
+                store_sync_mode,
+                system_paths,
             )
             .await
         } else {
@@ -163,6 +385,32 @@ impl AgentState {
             state.nixless_state_dir = nixless_state_dir;
             state.state_file_path = state_file_path;
             state.max_system_history_count = max_system_history_count;
+            state.store_sync_mode = store_sync_mode;
+            state.system_paths = system_paths;
+
+            state.previous_shutdown = state.last_shutdown.take();
+            match &state.previous_shutdown {
+                Some(ShutdownRecord {
+                    reason,
+                    timestamp_ms,
+                }) => {
+                    tracing::info!(reason, timestamp_ms, "Previous run of the agent shut down.");
+                }
+                None => {
+                    tracing::warn!("Previous run of the agent didn't record a shutdown reason, so it likely crashed or was killed.");
+                }
+            }
+
+            // Prefetches don't get resumed, so whatever an unfinished one pinned would otherwise stay pinned until the next switch.
+            if state
+                .prefetched_configuration
+                .as_ref()
+                .is_some_and(|prefetched| !prefetched.ready)
+            {
+                state.release_prefetched_configuration();
+            }
+
+            state.save()?;
             Ok(state)
         };
 
@@ -180,8 +428,11 @@ impl AgentState {
         nixless_state_dir: PathBuf,
         state_file_path: PathBuf,
         max_system_history_count: usize,
+        store_sync_mode: StoreSyncMode,
+        system_paths: SystemPaths,
     ) -> anyhow::Result<Self> {
-        let current_configuration = match tokio::fs::canonicalize(Self::current_system_path()).await
+        let current_configuration = match tokio::fs::canonicalize(&system_paths.current_system)
+            .await
         {
             Err(_) => build_tombstone_value(&nix_store_dir).await?,
             Ok(current_version_path)
@@ -222,9 +473,16 @@ impl AgentState {
             nixless_state_dir,
             state_file_path,
             max_system_history_count,
+            store_sync_mode,
+            system_paths,
             system_configurations: vec![current_configuration],
             current_status: AgentStateStatus::New,
             packages_to_cleanup: HashSet::new(),
+            current_switch_started_at_ms: None,
+            switch_history: Vec::new(),
+            last_shutdown: None,
+            prefetched_configuration: None,
+            previous_shutdown: None,
         })
     }
 
@@ -240,6 +498,18 @@ impl AgentState {
         &self.current_status
     }
 
+    /// Whether the state keeper will pick up a configuration switch from its downloads when it starts, in which case any NARs we already downloaded may still be needed.
+    pub fn may_resume_downloads(&self) -> bool {
+        match self.current_status {
+            AgentStateStatus::DownloadingNewConfiguration { .. } => true,
+            // Same logic the state keeper uses to decide whether to resume the switch.
+            AgentStateStatus::SwitchingToConfiguration { .. } => {
+                !self.absolute_switch_start_time_path().exists()
+            }
+            _ => false,
+        }
+    }
+
     pub fn set_standby(&mut self) -> anyhow::Result<()> {
         self.current_status = AgentStateStatus::Standby;
         self.save()
@@ -252,6 +522,44 @@ impl AgentState {
         SystemSummary {
             stable_configuration,
             status,
+            draining: false,
+            idle: false,
+            prefetched_configuration: self.prefetched_configuration.clone(),
+            progress: None,
+            last_shutdown: self.previous_shutdown.clone(),
+        }
+    }
+
+    pub fn record_shutdown(&mut self, reason: String) -> anyhow::Result<()> {
+        self.last_shutdown = Some(ShutdownRecord {
+            reason,
+            timestamp_ms: unix_timestamp_ms(),
+        });
+        self.save()
+    }
+
+    /// Oldest switches come first.
+    pub fn switch_history(&self) -> Vec<SwitchHistoryEntry> {
+        self.switch_history.clone()
+    }
+
+    /// Adds the switch to `configuration` to the switch history, keeping at most `max_system_history_count` entries in it. Doesn't save the state, so callers must do it.
+    fn record_switch_outcome(
+        &mut self,
+        configuration: &SystemConfiguration,
+        outcome: SwitchOutcome,
+    ) {
+        self.switch_history.push(SwitchHistoryEntry {
+            version_number: configuration.version_number,
+            system_package_id: configuration.system_package_id.clone(),
+            started_at_ms: self.current_switch_started_at_ms.take(),
+            finished_at_ms: unix_timestamp_ms(),
+            outcome,
+        });
+
+        if self.switch_history.len() > self.max_system_history_count {
+            let excess = self.switch_history.len() - self.max_system_history_count;
+            self.switch_history.drain(..excess);
         }
     }
 
@@ -273,6 +581,16 @@ impl AgentState {
             .unwrap()
     }
 
+    /// Rollbacks move older configurations to the end of the list, so the latest configuration doesn't always have the highest version number.
+    fn next_configuration_version(&self) -> u32 {
+        self.system_configurations
+            .iter()
+            .map(|c| c.version_number)
+            .max()
+            .unwrap()
+            + 1
+    }
+
     pub fn latest_package_id(&self) -> String {
         self.system_configurations
             .last()
@@ -344,16 +662,125 @@ impl AgentState {
         )
         .await?;
 
+        if self.store_sync_mode.syncs_files() {
+            sync_dir(&self.absolute_profiles_dir())?;
+        }
+
         Ok(())
     }
 
+    /// Checks that the Nix store and the system profiles match what the state says, but doesn't fix anything. Configurations we couldn't figure out at startup (tombstones) are skipped, since we don't know what they should look like. While a switch is in progress, the profiles may not have caught up with the state yet, so the results are only meaningful in standby.
+    pub async fn check_consistency(&self) -> anyhow::Result<ConsistencyReport> {
+        let store_dir = PathBuf::from(&self.nix_store_dir);
+        let mut issues = Vec::new();
+
+        for config in self
+            .system_configurations
+            .iter()
+            .filter(|c| !c.is_tombstone())
+        {
+            let system_package_path = store_dir.join(&config.system_package_id);
+            if tokio::fs::symlink_metadata(&system_package_path)
+                .await
+                .is_err()
+            {
+                issues.push(ConsistencyIssue::MissingSystemPackage {
+                    version_number: config.version_number,
+                    system_package_id: config.system_package_id.clone(),
+                });
+            }
+
+            // Sorted so the report doesn't change from one check to the next.
+            let mut package_ids: Vec<_> = config
+                .package_ids
+                .iter()
+                .filter(|p| **p != config.system_package_id)
+                .collect();
+            package_ids.sort();
+            for package_id in package_ids {
+                if tokio::fs::symlink_metadata(store_dir.join(package_id))
+                    .await
+                    .is_err()
+                {
+                    issues.push(ConsistencyIssue::MissingPackage {
+                        version_number: config.version_number,
+                        package_id: package_id.clone(),
+                    });
+                }
+            }
+
+            let actual = tokio::fs::read_link(
+                self.absolute_numbered_system_profile_path(config.version_number),
+            )
+            .await
+            .ok();
+            if actual.as_ref() != Some(&system_package_path) {
+                issues.push(ConsistencyIssue::WrongProfileLink {
+                    version_number: config.version_number,
+                    expected: system_package_path,
+                    actual,
+                });
+            }
+        }
+
+        let mut dir_entries = tokio::fs::read_dir(self.absolute_profiles_dir()).await?;
+        while let Some(entry) = dir_entries.next_entry().await? {
+            // Other profiles (and anything else that isn't a numbered system link) aren't ours to check.
+            let Ok(entry_number) = get_number_from_numbered_system_name(&entry.file_name()) else {
+                continue;
+            };
+
+            if self
+                .system_configurations
+                .iter()
+                .all(|c| c.version_number != entry_number)
+            {
+                issues.push(ConsistencyIssue::UntrackedProfileLink {
+                    name: entry.file_name().to_string_lossy().to_string(),
+                });
+            }
+        }
+
+        if !self.system_configurations.last().unwrap().is_tombstone() {
+            let latest_version = self.latest_configuration_version();
+            let actual = tokio::fs::read_link(self.absolute_system_profile_path())
+                .await
+                .ok();
+            // We point `system` straight to the system package, but Nix points it to the numbered link instead, and both are fine.
+            let acceptable_targets = [
+                self.latest_system_package_path(),
+                PathBuf::from(format!("system-{}-link", latest_version)),
+                self.absolute_numbered_system_profile_path(latest_version),
+            ];
+            if !actual
+                .as_ref()
+                .is_some_and(|actual| acceptable_targets.contains(actual))
+            {
+                issues.push(ConsistencyIssue::WrongSystemLink {
+                    expected: self.latest_system_package_path(),
+                    actual,
+                });
+            }
+        }
+
+        Ok(ConsistencyReport {
+            consistent: issues.is_empty(),
+            issues,
+        })
+    }
+
     pub async fn mark_new_system_successful(&mut self) -> anyhow::Result<()> {
-        if let AgentStateStatus::SwitchingToConfiguration { .. } = &self.current_status {
+        if let AgentStateStatus::SwitchingToConfiguration { .. }
+        | AgentStateStatus::PendingReboot { .. } = &self.current_status
+        {
             let previous_status =
                 std::mem::replace(&mut self.current_status, AgentStateStatus::Standby);
-            // TODO: if the configuration that we switched to is the same as the latest configuration in `self.system_configurations` (this can happen in case of a rollback after a failed switch), should we just change the version number of the config that exists in `self.system_configurations` instead of adding another entry there? Or perhaps mark it as a rollback and not count it against the max number of configurations?
+            let configuration = previous_status.into_inner_configuration().unwrap();
+            self.record_switch_outcome(&configuration, SwitchOutcome::Successful);
+            // A rollback switches to a configuration we already have, which becomes the latest one instead of getting a second entry.
             self.system_configurations
-                .push(previous_status.into_inner_configuration().unwrap());
+                .retain(|c| c.version_number != configuration.version_number);
+            self.system_configurations.push(configuration);
             self.save()?;
 
             metrics::system::version().set(self.latest_configuration_version() as u64);
@@ -367,11 +794,11 @@ impl AgentState {
         }
     }
 
-    pub async fn mark_new_system_failed(&mut self) -> anyhow::Result<()> {
+    pub async fn mark_new_system_pending_reboot(&mut self) -> anyhow::Result<()> {
         if let AgentStateStatus::SwitchingToConfiguration { .. } = &self.current_status {
             let previous_status =
                 std::mem::replace(&mut self.current_status, AgentStateStatus::Temporary);
-            self.current_status = AgentStateStatus::FailedSwitch {
+            self.current_status = AgentStateStatus::PendingReboot {
                 configuration: previous_status.into_inner_configuration().unwrap(),
             };
             self.save()?;
@@ -382,90 +809,307 @@ impl AgentState {
         }
     }
 
-    pub async fn mark_performing_rollback(
-        &mut self,
-        to_version: Option<u32>,
-    ) -> anyhow::Result<()> {
+    /// Checks whether the configuration we're switching to changed any of the parts of the system that are only picked up after a reboot, compared to the configuration the system booted with. This mirrors what https://github.com/thefossguy/nixos-needsreboot does.
+    pub async fn new_configuration_changes_boot_components(&self) -> anyhow::Result<bool> {
+        let new_system_path = self
+            .new_configuration_system_package_path()
+            .ok_or_else(|| anyhow!("we're not switching to a new system at the moment"))?;
+        let booted_system_path = &self.system_paths.booted_system;
+
+        for component in ["kernel", "initrd", "systemd"] {
+            // Some systems (e.g. containers) don't have all of these, so a component missing in both systems counts as unchanged.
+            let booted_component = tokio::fs::canonicalize(booted_system_path.join(component))
+                .await
+                .ok();
+            let new_component = tokio::fs::canonicalize(new_system_path.join(component))
+                .await
+                .ok();
+
+            if booted_component != new_component {
+                tracing::info!(
+                    component,
+                    ?booted_component,
+                    ?new_component,
+                    "New system configuration changed a component that requires a reboot."
+                );
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// If the system booted into the configuration that was pending a reboot, that configuration becomes the stable one. Returns whether that happened.
+    pub async fn finish_pending_reboot(&mut self) -> anyhow::Result<bool> {
+        if !matches!(self.current_status, AgentStateStatus::PendingReboot { .. }) {
+            return Err(anyhow!("we're not waiting for a reboot at the moment"));
+        }
+
+        let booted_system_path = tokio::fs::canonicalize(&self.system_paths.booted_system).await?;
+
+        if Some(booted_system_path) == self.new_configuration_system_package_path() {
+            self.mark_new_system_successful().await?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    pub async fn mark_new_system_failed(&mut self) -> anyhow::Result<()> {
+        if let AgentStateStatus::SwitchingToConfiguration { .. } = &self.current_status {
+            let previous_status =
+                std::mem::replace(&mut self.current_status, AgentStateStatus::Temporary);
+            let configuration = previous_status.into_inner_configuration().unwrap();
+            self.record_switch_outcome(&configuration, SwitchOutcome::Failed);
+            self.current_status = AgentStateStatus::FailedSwitch { configuration };
+            self.save()?;
+
+            Ok(())
+        } else {
+            Err(anyhow!("we're not switching to a new system at the moment"))
+        }
+    }
+
+    pub async fn mark_performing_rollback(&mut self, target: RollbackTarget) -> anyhow::Result<()> {
         if !matches!(
             self.current_status,
-            AgentStateStatus::Standby | AgentStateStatus::FailedSwitch { .. }
+            AgentStateStatus::Standby
+                | AgentStateStatus::FailedSwitch { .. }
+                | AgentStateStatus::PendingReboot { .. }
         ) {
             return Err(anyhow!(
-                "can only rollback if a configuration switch failed or the agent is on standby"
+                "can only rollback if a configuration switch failed or is waiting for a reboot, or the agent is on standby"
             ));
         }
 
-        // Make sure the version we're rolling back to is still in our state.
-        let new_config = if let Some(version) = to_version {
-            self.system_configurations
+        // We keep the version number of the configuration we roll back to, the same way Nix would point the system profile back to an older generation.
+        let mut new_config = self.rollback_configuration(target)?.clone();
+        // Rollbacks always make the configuration we roll back to the current and boot default, whatever it was originally switched with.
+        new_config.switch_action = SwitchAction::Switch;
+
+        let previous_status = std::mem::replace(
+            &mut self.current_status,
+            AgentStateStatus::SwitchingToConfiguration {
+                configuration: new_config,
+            },
+        );
+
+        // This has to happen only once we're switching to the configuration we roll back to, so that its packages are kept.
+        if let AgentStateStatus::FailedSwitch { configuration }
+        | AgentStateStatus::PendingReboot { configuration } = previous_status
+        {
+            // We'll get rid of the failed (or never booted) configuration, which means its packages have to be cleaned up.
+            self.mark_configs_for_removal(vec![configuration]);
+        }
+        // Same as when switching to a new configuration, whatever got prefetched is released once we're switching.
+        self.release_prefetched_configuration();
+
+        self.current_switch_started_at_ms = Some(unix_timestamp_ms());
+
+        self.save()
+    }
+
+    /// Makes sure the configuration we're rolling back to is still in our state and was managed by the agent.
+    fn rollback_configuration(
+        &self,
+        target: RollbackTarget,
+    ) -> anyhow::Result<&SystemConfiguration> {
+        let config = match target {
+            RollbackTarget::Version(version) => self
+                .system_configurations
                 .iter()
                 .find(|c| c.version_number == version)
                 .ok_or_else(|| {
                     anyhow!("the given version to rollback to isn't in the agent's state anymore")
-                })?
-        } else {
-            if let AgentStateStatus::FailedSwitch { .. } = &self.current_status {
-                self.system_configurations.last().unwrap()
-            } else {
-                self.system_configurations
-                    .iter()
-                    .rev()
-                    .skip(1)
-                    .next()
-                    .ok_or_else(|| anyhow!("not enough versions to rollback to"))?
+                })?,
+            RollbackTarget::Previous => self.configuration_before_current(1)?,
+            RollbackTarget::ConfigurationsBack(configurations_back) => {
+                self.configuration_before_current(configurations_back)?
             }
         };
 
-        if new_config.is_tombstone() {
+        if config.is_tombstone() {
             return Err(anyhow!(
                 "can't rollback to a configuration that wasn't managed by the agent"
             ));
         }
 
-        let mut new_config = new_config.clone();
-        new_config.version_number = self.latest_configuration_version() + 1;
+        Ok(config)
+    }
 
-        let previous_status =
-            std::mem::replace(&mut self.current_status, AgentStateStatus::Temporary);
+    fn configuration_system_package_path(&self, config: &SystemConfiguration) -> PathBuf {
+        PathBuf::from(&self.nix_store_dir).join(&config.system_package_id)
+    }
 
-        if let AgentStateStatus::FailedSwitch { configuration } = previous_status {
-            // We'll get rid of the failed configuration, which means its packages have to be cleaned up.
-            self.mark_configs_for_removal(vec![configuration]);
-        }
+    /// Checks that a rollback to the given target could actually go ahead, without changing anything. Otherwise, the switch would only fail once the activation command couldn't be found. The error lists the versions that can be rolled back to instead.
+    pub fn check_rollback_target(&self, target: RollbackTarget) -> anyhow::Result<()> {
+        let res = self.rollback_configuration(target).and_then(|config| {
+            if self
+                .configuration_system_package_path(config)
+                .try_exists()?
+            {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "the system package of version {} isn't in the Nix store anymore",
+                    config.version_number
+                ))
+            }
+        });
 
-        self.current_status = AgentStateStatus::SwitchingToConfiguration {
-            configuration: new_config,
+        res.map_err(|err| {
+            let available_versions: Vec<u32> = self
+                .system_configurations
+                .iter()
+                .filter(|c| {
+                    !c.is_tombstone()
+                        && self
+                            .configuration_system_package_path(c)
+                            .try_exists()
+                            .unwrap_or(false)
+                })
+                .map(|c| c.version_number)
+                .collect();
+            anyhow!(
+                "{}. Versions available to rollback to: {:?}",
+                err,
+                available_versions
+            )
+        })
+    }
+
+    /// If a switch just failed or is waiting for a reboot, the last configuration we have is still the stable one, so going back 1 configuration goes to it.
+    fn configuration_before_current(
+        &self,
+        configurations_back: u32,
+    ) -> anyhow::Result<&SystemConfiguration> {
+        let skip = match self.current_status {
+            AgentStateStatus::FailedSwitch { .. } | AgentStateStatus::PendingReboot { .. } => {
+                configurations_back.saturating_sub(1)
+            }
+            _ => configurations_back,
         };
 
-        self.save()
+        self.system_configurations
+            .iter()
+            .rev()
+            .nth(skip as usize)
+            .ok_or_else(|| {
+                anyhow!(
+                    "not enough versions to go back {} configurations, the agent only keeps {}",
+                    configurations_back,
+                    self.system_configurations.len()
+                )
+            })
     }
 
     pub fn mark_switching_new_system(
         &mut self,
         system_package_id: String,
         package_ids: HashSet<String>,
+        switch_action: SwitchAction,
     ) -> anyhow::Result<()> {
-        if !matches!(self.current_status, AgentStateStatus::Standby) {
+        if !matches!(
+            self.current_status,
+            AgentStateStatus::Standby | AgentStateStatus::PendingReboot { .. }
+        ) {
             return Err(anyhow!(
-                "current state is not standby, we can't switch to a new system"
+                "current state is not standby or waiting for a reboot, we can't switch to a new system"
             ));
         }
 
-        let next_version_number = self.latest_configuration_version() + 1;
+        let next_version_number = self.next_configuration_version();
 
         let new_configuration = SystemConfiguration::builder()
             .version_number(next_version_number)
             .system_package_id(system_package_id)
             .package_ids(package_ids)
+            .switch_action(switch_action)
             .build()?;
 
-        self.current_status = AgentStateStatus::SwitchingToConfiguration {
-            configuration: new_configuration,
-        };
+        // If we prefetched this configuration, its packages are kept by the configuration we're switching to from now on. Anything else that got prefetched is released once we're switching.
+        if self
+            .prefetched_configuration
+            .as_ref()
+            .is_some_and(|prefetched| {
+                prefetched.system_package_id == new_configuration.system_package_id
+            })
+        {
+            self.prefetched_configuration = None;
+        }
+
+        let previous_status = std::mem::replace(
+            &mut self.current_status,
+            AgentStateStatus::SwitchingToConfiguration {
+                configuration: new_configuration,
+            },
+        );
+        self.current_switch_started_at_ms = Some(unix_timestamp_ms());
+        self.release_prefetched_configuration();
+
+        if let AgentStateStatus::PendingReboot { configuration } = previous_status {
+            // The system never booted into this configuration and the new one supersedes it, so we'll get rid of it the same way as with a rollback.
+            self.mark_configs_for_removal(vec![configuration]);
+        }
 
         self.save()
     }
 
+    /// Pins the packages of the configuration we're about to prefetch, so nothing deletes them while they're being downloaded or before we switch to them. Any configuration that got prefetched before is released.
+    pub fn mark_prefetching_configuration(
+        &mut self,
+        system_package_id: String,
+        package_ids: HashSet<String>,
+    ) -> anyhow::Result<()> {
+        if !matches!(self.current_status, AgentStateStatus::Standby) {
+            return Err(anyhow!(
+                "current state is not standby, we can't prefetch a configuration"
+            ));
+        }
+
+        self.release_prefetched_configuration();
+        self.prefetched_configuration = Some(PrefetchedConfiguration {
+            system_package_id,
+            package_ids,
+            ready: false,
+        });
+
+        self.save()
+    }
+
+    /// A prefetch that failed releases its configuration, since we can't tell how much of it made it to the Nix store.
+    pub fn mark_prefetch_finished(
+        &mut self,
+        system_package_id: &str,
+        successful: bool,
+    ) -> anyhow::Result<()> {
+        match &mut self.prefetched_configuration {
+            Some(prefetched) if prefetched.system_package_id == system_package_id => {
+                if successful {
+                    prefetched.ready = true;
+                } else {
+                    self.release_prefetched_configuration();
+                }
+                self.save()
+            }
+            // We already started switching to some configuration, which took care of what got prefetched.
+            _ => Ok(()),
+        }
+    }
+
+    /// The packages that no other configuration needs get cleaned up together with the packages from old configurations.
+    fn release_prefetched_configuration(&mut self) {
+        if let Some(prefetched) = self.prefetched_configuration.take() {
+            tracing::info!(
+                prefetched.system_package_id,
+                "Releasing the configuration we prefetched."
+            );
+            let mut package_ids = prefetched.package_ids;
+            package_ids.insert(prefetched.system_package_id);
+            self.mark_unused_packages_for_removal(package_ids);
+        }
+    }
+
     fn save(&self) -> anyhow::Result<()> {
         let mut file = std::fs::File::options()
             .create(true)
@@ -473,6 +1117,10 @@ impl AgentState {
             .truncate(true)
             .open(&self.state_file_path)?;
         serde_json::to_writer(&mut file, self)?;
+
+        if self.store_sync_mode.syncs_state_file() {
+            file.sync_all()?;
+        }
         Ok(())
     }
 
@@ -504,6 +1152,118 @@ impl AgentState {
         }
     }
 
+    /// Fixes what `check_consistency()` finds, as far as that can be done without downloading anything. Configurations with packages missing from the Nix store are dropped, except for the latest one, since the system is supposed to be running it. A failed switch is forgotten if the system booted into (and is still running) the latest configuration anyway. Finally, the profile links get recreated. Packages that only belonged to dropped configurations are marked for removal, so callers should clean them up afterwards.
+    #[tracing::instrument(skip_all)]
+    pub async fn repair(&mut self) -> anyhow::Result<RepairReport> {
+        // All the changes are made to a copy, so if any step fails we keep the state we had instead of one that's only partly repaired. The profile links may already have been changed by then, but they always get recreated from the state anyway.
+        let mut repaired = self.clone();
+        let report = repaired.repair_copy().await?;
+        *self = repaired;
+        Ok(report)
+    }
+
+    async fn repair_copy(&mut self) -> anyhow::Result<RepairReport> {
+        if !matches!(
+            self.current_status,
+            AgentStateStatus::Standby | AgentStateStatus::FailedSwitch { .. }
+        ) {
+            return Err(anyhow!(
+                "can only repair the state if a configuration switch failed or the agent is on standby"
+            ));
+        }
+
+        let store_dir = PathBuf::from(&self.nix_store_dir);
+        let latest_version = self.latest_configuration_version();
+        let mut report = RepairReport::default();
+        let mut vanished_versions = HashSet::new();
+
+        for config in self
+            .system_configurations
+            .iter()
+            .filter(|c| !c.is_tombstone())
+        {
+            let mut missing_package_id = None;
+            for package_id in std::iter::once(&config.system_package_id).chain(&config.package_ids)
+            {
+                if tokio::fs::symlink_metadata(store_dir.join(package_id))
+                    .await
+                    .is_err()
+                {
+                    missing_package_id = Some(package_id);
+                    break;
+                }
+            }
+
+            let Some(missing_package_id) = missing_package_id else {
+                continue;
+            };
+
+            if config.version_number == latest_version {
+                report.unrepaired(format!(
+                    "configuration {} is the latest one but its package {} is missing from the Nix store, so a new configuration must be deployed to bring it back",
+                    config.version_number, missing_package_id
+                ));
+            } else {
+                report.repaired(format!(
+                    "dropped configuration {} because its package {} is missing from the Nix store",
+                    config.version_number, missing_package_id
+                ));
+                vanished_versions.insert(config.version_number);
+            }
+        }
+
+        let (vanished_configs, remaining_configs) = std::mem::take(&mut self.system_configurations)
+            .into_iter()
+            .partition(|c| vanished_versions.contains(&c.version_number));
+        self.system_configurations = remaining_configs;
+        self.mark_configs_for_removal(vanished_configs);
+
+        if let AgentStateStatus::FailedSwitch { configuration } = &self.current_status {
+            let latest_system_package_path = self.latest_system_package_path();
+            let booted_system_path = tokio::fs::canonicalize(&self.system_paths.booted_system)
+                .await
+                .ok();
+            let current_system_path = tokio::fs::canonicalize(&self.system_paths.current_system)
+                .await
+                .ok();
+
+            if booted_system_path.as_ref() == Some(&latest_system_package_path)
+                && current_system_path.as_ref() == Some(&latest_system_package_path)
+            {
+                report.repaired(format!(
+                    "cleared the failed switch to {}, since the system is running the latest configuration {}",
+                    configuration.system_package_id, latest_version
+                ));
+                let previous_status =
+                    std::mem::replace(&mut self.current_status, AgentStateStatus::Standby);
+                // Same as with a rollback, we'll get rid of the failed configuration.
+                self.mark_configs_for_removal(vec![previous_status
+                    .into_inner_configuration()
+                    .unwrap()]);
+            } else {
+                report.unrepaired(format!(
+                    "the switch to {} failed and the system isn't running the latest configuration {}, so a rollback is needed",
+                    configuration.system_package_id, latest_version
+                ));
+            }
+        }
+
+        for issue in self.check_consistency().await?.issues {
+            if matches!(
+                issue,
+                ConsistencyIssue::WrongProfileLink { .. }
+                    | ConsistencyIssue::UntrackedProfileLink { .. }
+                    | ConsistencyIssue::WrongSystemLink { .. }
+            ) {
+                report.repaired(format!("fixed profile link: {:?}", issue));
+            }
+        }
+        self.repair_profile_links().await?;
+
+        self.save()?;
+        Ok(report)
+    }
+
     #[tracing::instrument(skip_all)]
     pub async fn cleanup_configuration_history(&mut self) -> anyhow::Result<()> {
         if !matches!(self.current_status, AgentStateStatus::Standby) {
@@ -552,20 +1312,41 @@ impl AgentState {
         for config in configs_to_remove {
             tracing::info!(config.system_package_id, "Adding packages to be removed");
             packages_from_removed_configs.insert(config.system_package_id);
-            packages_from_removed_configs.extend(config.package_ids.into_iter());
+            packages_from_removed_configs.extend(config.package_ids);
         }
 
-        for config in self.system_configurations.iter() {
-            tracing::info!(config.system_package_id, "Removing packages to be removed");
-            packages_from_removed_configs.remove(&config.system_package_id);
+        self.mark_unused_packages_for_removal(packages_from_removed_configs);
+    }
 
-            for pkg in config.package_ids.iter() {
-                packages_from_removed_configs.remove(pkg);
-            }
+    /// Only the packages that aren't live (see `live_package_ids()`) get marked for removal.
+    fn mark_unused_packages_for_removal(&mut self, mut package_ids: HashSet<String>) {
+        for package_id in self.live_package_ids() {
+            package_ids.remove(&package_id);
         }
 
-        self.packages_to_cleanup
-            .extend(packages_from_removed_configs.into_iter());
+        self.packages_to_cleanup.extend(package_ids);
+    }
+
+    /// Scans the Nix store for packages that aren't part of any configuration we're tracking and aren't already waiting to be cleaned up.
+    pub async fn find_foreign_packages(&self) -> anyhow::Result<HashSet<String>> {
+        let live_package_ids = self.live_package_ids();
+        let store_package_ids = collect_nix_store_packages(&self.nix_store_dir).await?;
+
+        Ok(store_package_ids
+            .into_iter()
+            .filter(|package_id| {
+                !live_package_ids.contains(package_id)
+                    && !self.packages_to_cleanup.contains(package_id)
+            })
+            .collect())
+    }
+
+    pub fn mark_packages_for_removal(
+        &mut self,
+        package_ids: HashSet<String>,
+    ) -> anyhow::Result<()> {
+        self.packages_to_cleanup.extend(package_ids);
+        self.save()
     }
 
     pub fn has_packages_to_cleanup(&self) -> bool {
@@ -576,8 +1357,291 @@ impl AgentState {
         self.packages_to_cleanup.clone()
     }
 
+    /// Every package that belongs to a configuration we're still tracking, including any configuration we're in the middle of switching to or that got prefetched. None of these should ever be deleted.
+    pub fn live_package_ids(&self) -> HashSet<String> {
+        let prefetched_package_ids = self.prefetched_configuration.iter().flat_map(|prefetched| {
+            prefetched
+                .package_ids
+                .iter()
+                .chain(std::iter::once(&prefetched.system_package_id))
+        });
+
+        self.system_configurations
+            .iter()
+            .chain(self.current_status.inner_configuration())
+            .flat_map(|config| {
+                config
+                    .package_ids
+                    .iter()
+                    .chain(std::iter::once(&config.system_package_id))
+            })
+            .chain(prefetched_package_ids)
+            .cloned()
+            .collect()
+    }
+
     pub async fn clear_packages_to_cleanup(&mut self) -> anyhow::Result<()> {
         self.packages_to_cleanup.clear();
         self.save()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    async fn test_state(dir: &Path) -> AgentState {
+        for subdir in ["store", "nix-state", "nixless-state"] {
+            std::fs::create_dir(dir.join(subdir)).unwrap();
+        }
+
+        load_state(dir).await
+    }
+
+    async fn load_state(dir: &Path) -> AgentState {
+        AgentState::from_saved_state_or_new(
+            dir.join("store").to_str().unwrap().to_string(),
+            dir.join("nix-state"),
+            dir.join("nixless-state"),
+            5,
+            StoreSyncMode::None,
+            SystemPaths {
+                current_system: dir.join("current-system"),
+                booted_system: dir.join("booted-system"),
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    fn configuration(version_number: u32, package_ids: &[&str]) -> SystemConfiguration {
+        SystemConfiguration::builder()
+            .version_number(version_number)
+            .system_package_id(format!("system-{}", version_number))
+            .package_ids(package_ids.iter().map(|p| p.to_string()).collect())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rollback_from_failed_switch_cleans_up_failed_configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = test_state(dir.path()).await;
+        state.system_configurations = vec![configuration(1, &["shared", "only-in-1"])];
+        state.current_status = AgentStateStatus::FailedSwitch {
+            configuration: configuration(2, &["shared", "only-in-2"]),
+        };
+
+        state
+            .mark_performing_rollback(RollbackTarget::Previous)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            state.status().inner_configuration().unwrap().version_number,
+            1
+        );
+        assert_eq!(
+            state.packages_to_cleanup(),
+            HashSet::from(["system-2".to_string(), "only-in-2".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn rollback_from_pending_reboot_goes_back_to_stable_configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = test_state(dir.path()).await;
+        state.system_configurations = vec![
+            configuration(1, &["only-in-1"]),
+            configuration(2, &["shared"]),
+        ];
+        state.current_status = AgentStateStatus::PendingReboot {
+            configuration: configuration(3, &["shared", "only-in-3"]),
+        };
+
+        state
+            .mark_performing_rollback(RollbackTarget::Previous)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            state.status().inner_configuration().unwrap().version_number,
+            2
+        );
+        assert_eq!(
+            state.packages_to_cleanup(),
+            HashSet::from(["system-3".to_string(), "only-in-3".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn new_configuration_supersedes_pending_reboot() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = test_state(dir.path()).await;
+        state.system_configurations = vec![configuration(1, &["shared"])];
+        state.current_status = AgentStateStatus::PendingReboot {
+            configuration: configuration(2, &["shared", "only-in-2", "kept-by-3"]),
+        };
+
+        state
+            .mark_switching_new_system(
+                "system-3".to_string(),
+                HashSet::from(["kept-by-3".to_string()]),
+                SwitchAction::Switch,
+            )
+            .unwrap();
+
+        assert!(matches!(
+            state.status(),
+            AgentStateStatus::SwitchingToConfiguration { configuration }
+                if configuration.system_package_id == "system-3"
+        ));
+        assert_eq!(
+            state.packages_to_cleanup(),
+            HashSet::from(["system-2".to_string(), "only-in-2".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_resumed_switch_is_kept_as_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = test_state(dir.path()).await;
+        state.system_configurations = vec![configuration(1, &["shared"])];
+        state.current_status = AgentStateStatus::Standby;
+        state
+            .mark_switching_new_system(
+                "system-2".to_string(),
+                HashSet::from(["shared".to_string()]),
+                SwitchAction::Switch,
+            )
+            .unwrap();
+
+        // The agent restarted before the switch got to the activation, so there's no record of when it started.
+        let mut state = load_state(dir.path()).await;
+        assert!(!state.absolute_switch_start_time_path().exists());
+        state.mark_new_system_failed().await.unwrap();
+
+        let state = load_state(dir.path()).await;
+        assert!(matches!(
+            state.status(),
+            AgentStateStatus::FailedSwitch { configuration }
+                if configuration.system_package_id == "system-2"
+        ));
+        assert!(matches!(
+            state.switch_history().as_slice(),
+            [SwitchHistoryEntry {
+                version_number: 2,
+                outcome: SwitchOutcome::Failed,
+                ..
+            }]
+        ));
+    }
+
+    fn prefetched(
+        system_package_id: &str,
+        package_ids: &[&str],
+        ready: bool,
+    ) -> PrefetchedConfiguration {
+        PrefetchedConfiguration {
+            system_package_id: system_package_id.to_string(),
+            package_ids: package_ids.iter().map(|p| p.to_string()).collect(),
+            ready,
+        }
+    }
+
+    #[tokio::test]
+    async fn rollback_releases_prefetched_configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = test_state(dir.path()).await;
+        state.system_configurations = vec![
+            configuration(1, &["shared"]),
+            configuration(2, &["only-in-2"]),
+        ];
+        state.current_status = AgentStateStatus::Standby;
+        state.prefetched_configuration =
+            Some(prefetched("system-3", &["shared", "only-prefetched"], true));
+
+        state
+            .mark_performing_rollback(RollbackTarget::Previous)
+            .await
+            .unwrap();
+
+        assert!(state.prefetched_configuration.is_none());
+        assert_eq!(
+            state.packages_to_cleanup(),
+            HashSet::from(["system-3".to_string(), "only-prefetched".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn unfinished_prefetch_is_released_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = test_state(dir.path()).await;
+        state.current_status = AgentStateStatus::Standby;
+        state.prefetched_configuration = Some(prefetched("system-3", &["only-prefetched"], false));
+        state.save().unwrap();
+
+        let state = load_state(dir.path()).await;
+
+        assert!(state.prefetched_configuration.is_none());
+        assert_eq!(
+            state.packages_to_cleanup(),
+            HashSet::from(["system-3".to_string(), "only-prefetched".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn finished_prefetch_is_kept_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = test_state(dir.path()).await;
+        state.current_status = AgentStateStatus::Standby;
+        state.prefetched_configuration = Some(prefetched("system-3", &["only-prefetched"], true));
+        state.save().unwrap();
+
+        let state = load_state(dir.path()).await;
+
+        assert!(state.prefetched_configuration.is_some());
+        assert!(state.packages_to_cleanup().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_repair_keeps_the_state_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = test_state(dir.path()).await;
+        // Only the latest configuration's packages exist, so the repair would drop the first one.
+        std::fs::create_dir(dir.path().join("store/system-2")).unwrap();
+        state.system_configurations = vec![configuration(1, &[]), configuration(2, &[])];
+        state.current_status = AgentStateStatus::Standby;
+        // Saving the repaired state fails, since it can't be written over a directory.
+        state.state_file_path = dir.path().to_path_buf();
+
+        assert!(state.repair().await.is_err());
+
+        assert_eq!(state.system_configurations.len(), 2);
+        assert!(state.packages_to_cleanup().is_empty());
+    }
+
+    #[tokio::test]
+    async fn summary_reports_how_the_previous_run_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = test_state(dir.path()).await;
+        assert!(state.summary().last_shutdown.is_none());
+
+        state.record_shutdown("test shutdown".to_string()).unwrap();
+        let state = load_state(dir.path()).await;
+        assert_eq!(
+            state.summary().last_shutdown.unwrap().reason,
+            "test shutdown"
+        );
+
+        // The record is only kept for the run right after the shutdown, so a crash after it doesn't look like a clean shutdown.
+        state.save().unwrap();
+        assert!(load_state(dir.path())
+            .await
+            .summary()
+            .last_shutdown
+            .is_none());
+    }
+}
@@ -1,11 +1,13 @@
 use std::{
     fs::File,
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
     time::{Duration, SystemTime},
 };
 
 use anyhow::anyhow;
+use nix::time::{clock_gettime, ClockId};
+use serde::{Deserialize, Serialize};
 
 use crate::path_utils::remove_file_with_check;
 
@@ -19,11 +21,112 @@ pub struct SwitchStatusCodes {
     pub service_result: String,
     pub exit_code: String,
     pub exit_status: String,
+    tracking_file_conflict: bool,
+}
+
+/// What `system-switch-tracker` writes when a tracking file it should create already exists. Must be kept in sync with the tracker.
+const TRACKING_FILE_CONFLICT_FILE_NAME: &str = "tracking_file_conflict";
+/// The pre-switch file must come first, since the invocation id in it is the one we expect from the other files.
+const TRACKING_FILE_NAMES: [&str; 4] = [
+    "pre_switch",
+    "switch_success",
+    "post_switch",
+    TRACKING_FILE_CONFLICT_FILE_NAME,
+];
+
+impl SwitchStatusCodes {
+    /// Whether the switch failed because `system-switch-tracker` found a tracking file left over from an earlier switch, rather than because of the activation itself.
+    pub fn tracking_file_already_existed(&self) -> bool {
+        self.tracking_file_conflict
+    }
+}
+
+/// What `system-switch-tracker` writes to the finish tracking file. Older versions of it wrote the status codes on separate lines instead.
+#[derive(Deserialize)]
+struct FinishTrackingFile {
+    service_result: String,
+    exit_code: String,
+    exit_status: String,
+}
+
+/// What `system-switch-tracker` writes to every tracking file so we can tell which switch the file came from. Older versions of it didn't write any of these, so we assume their files belong to the current switch.
+#[derive(Default, Deserialize)]
+struct TrackingFileOrigin {
+    /// The id we gave the switch when we recorded its start.
+    switch_id: Option<String>,
+    /// The systemd invocation id of the transient unit that ran the tracker.
+    invocation_id: Option<String>,
+    /// A reading of `CLOCK_BOOTTIME`, only comparable with other readings taken in the same boot.
+    boottime_ns: Option<u64>,
+    boot_id: Option<String>,
+}
+
+impl TrackingFileOrigin {
+    /// Returns why the tracking file doesn't belong to the given switch, if it doesn't.
+    fn mismatch(
+        &self,
+        switch_start: &SwitchStart,
+        expected_invocation_id: Option<&str>,
+    ) -> Option<&'static str> {
+        if let (Some(switch_id), Some(expected_switch_id)) =
+            (&self.switch_id, &switch_start.switch_id)
+        {
+            if switch_id != expected_switch_id {
+                return Some("the switch id doesn't match the switch we started");
+            }
+        }
+
+        if let (Some(invocation_id), Some(expected_invocation_id)) =
+            (&self.invocation_id, expected_invocation_id)
+        {
+            if invocation_id != expected_invocation_id {
+                return Some(
+                    "the invocation id doesn't match the one in the pre-switch tracking file",
+                );
+            }
+        }
+
+        if let (Some(boottime_ns), Some(boot_id)) = (self.boottime_ns, &self.boot_id) {
+            if *boot_id == switch_start.boot_id
+                && Duration::from_nanos(boottime_ns) < switch_start.boottime
+            {
+                return Some("it was written before the switch started");
+            }
+        }
+
+        None
+    }
+}
+
+fn parse_finish_tracking_file(contents: &str) -> anyhow::Result<SwitchStatusCodes> {
+    if let Ok(finish) = serde_json::from_str::<FinishTrackingFile>(contents) {
+        return Ok(SwitchStatusCodes {
+            service_result: finish.service_result,
+            exit_code: finish.exit_code,
+            exit_status: finish.exit_status,
+            tracking_file_conflict: false,
+        });
+    }
+
+    let [service_result, exit_code, exit_status] = contents.lines().collect::<Vec<_>>()[..] else {
+        return Err(anyhow!(
+            "the tracking file for finished status didn't follow the expected format"
+        ));
+    };
+
+    Ok(SwitchStatusCodes {
+        service_result: service_result.to_string(),
+        exit_code: exit_code.to_string(),
+        exit_status: exit_status.to_string(),
+        tracking_file_conflict: false,
+    })
 }
 
 // TODO: perhaps move this inside agent_state.
-/// Will also clean up the tracking files if they exist.
+/// Will also clean up the tracking files if they exist. Tracking files that don't belong to the switch we recorded the start of are removed and treated as if they were never written.
 pub async fn check_switching_status(directory: &PathBuf) -> anyhow::Result<SystemSwitchStatus> {
+    remove_tracking_files_from_other_switches(directory).await?;
+
     let started_path = directory.join("pre_switch");
     let success_path = directory.join("switch_success");
     let finish_path = directory.join("post_switch");
@@ -41,27 +144,18 @@ pub async fn check_switching_status(directory: &PathBuf) -> anyhow::Result<Syste
         }
         (true, true, false) => {
             let status_code_contents = tokio::fs::read_to_string(finish_path).await?;
-            let [service_result, exit_code, exit_status] =
-                status_code_contents.lines().collect::<Vec<_>>()[..]
-            else {
-                return Err(anyhow!(
-                    "the tracking file for finished status didn't follow the expected format"
-                ));
-            };
+            let mut status_codes = parse_finish_tracking_file(&status_code_contents)?;
+            status_codes.tracking_file_conflict = directory
+                .join(TRACKING_FILE_CONFLICT_FILE_NAME)
+                .try_exists()?;
 
             clean_up_system_switch_tracking_files(directory).await?;
 
-            if service_result == "exit-code" && exit_status == "100" {
+            if status_codes.service_result == "exit-code" && status_codes.exit_status == "100" {
                 Ok(SystemSwitchStatus::Successful {
                     reboot_required: true,
                 })
             } else {
-                let status_codes = SwitchStatusCodes {
-                    service_result: service_result.to_string(),
-                    exit_code: exit_code.to_string(),
-                    exit_status: exit_status.to_string(),
-                };
-
                 Ok(SystemSwitchStatus::Failed(status_codes))
             }
         }
@@ -69,50 +163,244 @@ pub async fn check_switching_status(directory: &PathBuf) -> anyhow::Result<Syste
     }
 }
 
+/// Tracking files left over from an earlier switch would be mistaken for the results of the next one, and `system-switch-tracker` refuses to overwrite them, so they must be gone before a switch starts.
+pub async fn remove_stale_tracking_files(directory: &PathBuf) -> anyhow::Result<()> {
+    let mut stale = false;
+    for file_name in TRACKING_FILE_NAMES {
+        stale |= directory.join(file_name).try_exists()?;
+    }
+
+    if stale {
+        tracing::warn!("Found tracking files from an earlier switch, will remove them before starting a new one.");
+        clean_up_system_switch_tracking_files(directory).await?;
+    }
+
+    Ok(())
+}
+
+async fn remove_tracking_files_from_other_switches(directory: &Path) -> anyhow::Result<()> {
+    let Some(switch_start) = read_switch_start(directory) else {
+        // Without a recorded switch start we have nothing to compare the tracking files with.
+        return Ok(());
+    };
+
+    let mut expected_invocation_id = None;
+    for file_name in TRACKING_FILE_NAMES {
+        let path = directory.join(file_name);
+        let contents = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        let origin: TrackingFileOrigin = serde_json::from_str(&contents).unwrap_or_default();
+        match origin.mismatch(&switch_start, expected_invocation_id.as_deref()) {
+            Some(reason) => {
+                tracing::warn!(file_name, reason, "Found a tracking file that doesn't belong to the current switch, will remove it.");
+                remove_file_with_check(path).await?;
+            }
+            None => {
+                if expected_invocation_id.is_none() {
+                    expected_invocation_id = origin.invocation_id;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
 async fn clean_up_system_switch_tracking_files(directory: &PathBuf) -> anyhow::Result<()> {
     let started_path = directory.join("pre_switch");
     let success_path = directory.join("switch_success");
     let finish_path = directory.join("post_switch");
+    let conflict_path = directory.join(TRACKING_FILE_CONFLICT_FILE_NAME);
 
-    let (r1, r2, r3) = tokio::join!(
+    let (r1, r2, r3, r4) = tokio::join!(
         remove_file_with_check(started_path),
         remove_file_with_check(success_path),
-        remove_file_with_check(finish_path)
+        remove_file_with_check(finish_path),
+        remove_file_with_check(conflict_path)
     );
     r1?;
     r2?;
     r3?;
+    r4?;
 
     Ok(())
 }
 
-pub fn record_switch_start(file_path: PathBuf) -> anyhow::Result<()> {
+/// When a switch started. The wall clock can be stepped (e.g. by NTP) while a switch is going on, so we also keep a reading of `CLOCK_BOOTTIME`, which only ever moves forward (even while suspended) but restarts on every boot. The boot id tells us whether the boottime reading is still comparable.
+#[derive(Deserialize, Serialize)]
+struct SwitchStart {
+    wall_clock: SystemTime,
+    boottime: Duration,
+    boot_id: String,
+    /// Passed to the transient unit doing the switch, so the tracking files can say which switch they came from. Older versions of the agent didn't write this.
+    switch_id: Option<String>,
+}
+
+fn read_boottime() -> anyhow::Result<Duration> {
+    let now = clock_gettime(ClockId::CLOCK_BOOTTIME)?;
+    Ok(Duration::new(now.tv_sec() as u64, now.tv_nsec() as u32))
+}
+
+fn read_boot_id() -> anyhow::Result<String> {
+    Ok(std::fs::read_to_string("/proc/sys/kernel/random/boot_id")?
+        .trim()
+        .to_string())
+}
+
+/// Returns `None` if there's no recorded switch start, or if it was recorded by an older version of the agent.
+fn read_switch_start(directory: &Path) -> Option<SwitchStart> {
+    let contents = std::fs::read_to_string(directory.join("switch_start")).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// Unique for every switch, even across reboots, since the boottime reading never repeats within a boot. Made when the switch starts, so it's in every log of the switch and is the same one the transient unit doing the switch gets.
+pub fn new_switch_id() -> anyhow::Result<String> {
+    Ok(format!(
+        "{}-{}",
+        read_boot_id()?,
+        read_boottime()?.as_nanos()
+    ))
+}
+
+/// `switch_id` must be the one passed on to the transient unit doing the switch.
+pub fn record_switch_start(file_path: PathBuf, switch_id: &str) -> anyhow::Result<()> {
     let mut file = File::options()
         .write(true)
         .truncate(true)
         .create(true)
         .open(file_path)?;
 
-    let now = SystemTime::now();
-    serde_json::to_writer(&mut file, &now)?;
+    let switch_start = SwitchStart {
+        wall_clock: SystemTime::now(),
+        boottime: read_boottime()?,
+        boot_id: read_boot_id()?,
+        switch_id: Some(switch_id.to_string()),
+    };
+    serde_json::to_writer(&mut file, &switch_start)?;
     file.flush()?;
     file.sync_all()?;
 
     Ok(())
 }
 
-/// Will also clean up the tracking file if it exists.
-pub fn calculate_switch_duration(file_path: PathBuf) -> anyhow::Result<Duration> {
+/// Will also clean up the tracking file if it exists. Gives `None` if there's no file with the time the switch started (e.g. the switch failed before it got to the activation, or was resumed after a restart) or if we can't make sense of it.
+pub fn calculate_switch_duration(file_path: PathBuf) -> Option<Duration> {
     if !file_path.exists() {
-        return Err(anyhow!("there is no file with the time the switch started"));
+        return None;
     }
 
-    let now = SystemTime::now();
+    let duration = read_switch_duration(&file_path)
+        .inspect_err(|err| {
+            tracing::warn!(
+                ?err,
+                "Failed to read the time the switch started, so we can't tell how long the switch took."
+            )
+        })
+        .ok();
+
+    if let Err(err) = std::fs::remove_file(file_path) {
+        tracing::warn!(
+            ?err,
+            "Failed to remove the file with the time the switch started."
+        );
+    }
+    duration
+}
+
+/// Prefers the boottime reading, and only falls back to the wall clock if the system rebooted since the switch started (or the file was written by an older version of the agent). If even the wall clock moved backwards, we'll give a zero duration instead of failing.
+fn read_switch_duration(file_path: &Path) -> anyhow::Result<Duration> {
+    let contents = std::fs::read_to_string(file_path)?;
+    let (start_wall_clock, start_boottime) = match serde_json::from_str::<SwitchStart>(&contents) {
+        Ok(switch_start) => {
+            let same_boot = read_boot_id().is_ok_and(|boot_id| boot_id == switch_start.boot_id);
+            (
+                switch_start.wall_clock,
+                same_boot.then_some(switch_start.boottime),
+            )
+        }
+        // Older versions of the agent only wrote the wall clock time.
+        Err(_) => (serde_json::from_str::<SystemTime>(&contents)?, None),
+    };
+
+    let boottime_duration = start_boottime.and_then(|start_boottime| {
+        read_boottime()
+            .ok()
+            .and_then(|now| now.checked_sub(start_boottime))
+    });
 
-    let start_time = std::fs::read_to_string(&file_path)?;
-    let start_time: SystemTime = serde_json::from_str(&start_time)?;
+    let duration = match boottime_duration {
+        Some(duration) => duration,
+        None => SystemTime::now()
+            .duration_since(start_wall_clock)
+            .unwrap_or_else(|err| {
+                tracing::warn!(
+                    ?err,
+                    "The wall clock moved backwards since the switch started, so we can't tell how long the switch took."
+                );
+                Duration::ZERO
+            }),
+    };
 
-    let duration = now.duration_since(start_time)?;
-    std::fs::remove_file(file_path)?;
     Ok(duration)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAILED_ACTIVATION: &str =
+        r#"{"service_result": "exit-code", "exit_code": "exited", "exit_status": "17"}"#;
+
+    async fn failed_status_codes(directory: &PathBuf) -> SwitchStatusCodes {
+        match check_switching_status(directory).await.unwrap() {
+            SystemSwitchStatus::Failed(status_codes) => status_codes,
+            _ => panic!("expected the switch to have failed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn activation_exit_status_isnt_mistaken_for_tracking_file_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().to_path_buf();
+        std::fs::write(directory.join("pre_switch"), "{}").unwrap();
+        std::fs::write(directory.join("post_switch"), FAILED_ACTIVATION).unwrap();
+
+        assert!(!failed_status_codes(&directory)
+            .await
+            .tracking_file_already_existed());
+    }
+
+    #[tokio::test]
+    async fn tracking_file_conflict_is_reported_and_cleaned_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().to_path_buf();
+        std::fs::write(directory.join("pre_switch"), "{}").unwrap();
+        std::fs::write(directory.join("post_switch"), FAILED_ACTIVATION).unwrap();
+        std::fs::write(directory.join(TRACKING_FILE_CONFLICT_FILE_NAME), "{}").unwrap();
+
+        assert!(failed_status_codes(&directory)
+            .await
+            .tracking_file_already_existed());
+        assert!(!directory.join(TRACKING_FILE_CONFLICT_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn switch_duration_is_missing_without_a_start_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("switch_start");
+
+        assert_eq!(calculate_switch_duration(file_path.clone()), None);
+
+        record_switch_start(file_path.clone(), "switch").unwrap();
+        assert!(calculate_switch_duration(file_path.clone()).is_some());
+        assert!(!file_path.exists());
+
+        std::fs::write(&file_path, "not a switch start").unwrap();
+        assert_eq!(calculate_switch_duration(file_path.clone()), None);
+        assert!(!file_path.exists());
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    fs::{read_dir, File},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// How much effort the agent puts into making sure that what it writes to the Nix store and to the system profiles survives a power loss before it goes on with a switch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum StoreSyncMode {
+    /// Never fsync anything, and leave it up to the OS to write things to disk eventually. Fastest, but a power loss right after a switch may leave truncated files in the store.
+    None,
+    /// Fsync every file and directory unpacked into the Nix store, and the directories that got new entries from the agent (the Nix store and the profiles directory). A file's contents alone don't survive a power loss without the entry for it in its directory, so every directory is synced once all its entries were written.
+    #[default]
+    Files,
+    /// Same as `files`, but also fsyncs the agent's state file whenever it's saved.
+    Full,
+}
+
+impl StoreSyncMode {
+    pub fn syncs_files(&self) -> bool {
+        !matches!(self, Self::None)
+    }
+
+    pub fn syncs_state_file(&self) -> bool {
+        matches!(self, Self::Full)
+    }
+}
+
+/// Fsyncs an object that was written to the Nix store, recursing into directories. Each directory is synced after everything in it, so its entries (including symlinks, which can't be fsynced on their own) are durable by the time it is.
+pub fn sync_store_object(path: &Path, mode: StoreSyncMode) -> anyhow::Result<()> {
+    if !mode.syncs_files() {
+        return Ok(());
+    }
+
+    let stat = std::fs::symlink_metadata(path)?;
+
+    if stat.is_dir() {
+        for entry in read_dir(path)? {
+            sync_store_object(&entry?.path(), mode)?;
+        }
+
+        sync_dir(path)?;
+    } else if stat.is_file() {
+        File::open(path)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Fsyncs a directory, making any entries that were added to, renamed into, or removed from it durable.
+pub fn sync_dir(path: &Path) -> std::io::Result<()> {
+    File::open(path)?.sync_all()
+}
@@ -3,12 +3,39 @@ use std::collections::HashSet;
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
 
+/// The verb passed to `switch-to-configuration` when activating a configuration.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SwitchAction {
+    /// Activates the configuration and makes it the boot default.
+    #[default]
+    Switch,
+    /// Makes the configuration the boot default without activating it, so it only takes effect after a reboot.
+    Boot,
+    /// Activates the configuration without making it the boot default, so a reboot goes back to the previous configuration.
+    Test,
+}
+
+impl SwitchAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Switch => "switch",
+            Self::Boot => "boot",
+            Self::Test => "test",
+        }
+    }
+}
+
 #[derive(Builder, Clone, Debug, Deserialize, Serialize)]
 pub struct SystemConfiguration {
     pub version_number: u32,
     pub system_package_id: String,
     #[builder(default)]
     pub package_ids: HashSet<String>,
+    /// States saved before this existed only ever switched.
+    #[builder(default)]
+    #[serde(default)]
+    pub switch_action: SwitchAction,
 }
 
 impl SystemConfiguration {
@@ -21,6 +48,7 @@ impl SystemConfiguration {
             version_number: 0,
             system_package_id: "unknown".to_string(),
             package_ids: HashSet::new(),
+            switch_action: SwitchAction::Switch,
         }
     }
 
@@ -1,21 +1,32 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use anyhow::anyhow;
 use derive_builder::Builder;
+#[cfg(feature = "memory-profiler")]
+use foundations::telemetry::settings::MemoryProfilerSettings;
 use foundations::telemetry::{
     init_with_server,
-    settings::{
-        MemoryProfilerSettings, MetricsSettings, TelemetryServerSettings, TelemetrySettings,
-    },
+    settings::{TelemetryServerSettings, TelemetrySettings},
+};
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
 };
-use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 
+use crate::{listeners::bind_listeners, metrics};
+
 #[derive(Builder)]
 #[builder(pattern = "owned", build_fn(private, name = "build"))]
 pub struct TelemetryServer {
-    address: IpAddr,
+    addresses: Vec<IpAddr>,
     port: u16,
+    report_optional_metrics: bool,
+    /// Only has an effect when built with the memory profiler.
+    #[builder(default)]
+    #[cfg_attr(not(feature = "memory-profiler"), allow(dead_code))]
+    enable_memory_profiler: bool,
 }
 
 impl TelemetryServer {
@@ -26,6 +37,7 @@ impl TelemetryServer {
 
 pub struct StartedTelemetryServer {
     server_task: JoinHandle<anyhow::Result<()>>,
+    forwarder_tasks: Vec<JoinHandle<()>>,
     shutdown_token: CancellationToken,
 }
 
@@ -36,6 +48,9 @@ impl StartedTelemetryServer {
         );
 
         self.shutdown_token.cancel();
+        for forwarder_task in self.forwarder_tasks {
+            forwarder_task.await?;
+        }
         self.server_task.await?
     }
 }
@@ -43,22 +58,41 @@ impl StartedTelemetryServer {
 impl TelemetryServerBuilder {
     pub fn start(self) -> anyhow::Result<StartedTelemetryServer> {
         let server_info = self.build()?;
+        let listeners = bind_listeners("telemetry", &server_info.addresses, server_info.port)?;
 
+        // foundations can only run a single telemetry server, and it binds its own socket, so it can't take the sockets we bound. It gets an ephemeral port on loopback instead, and we forward connections from every address we listen on to it. This keeps our sockets open the whole time, so nothing else can grab one of our addresses in between.
+        let loopback: IpAddr = if listeners[0].local_addr()?.is_ipv6() {
+            Ipv6Addr::LOCALHOST.into()
+        } else {
+            Ipv4Addr::LOCALHOST.into()
+        };
         let service_info = foundations::service_info!();
         let telemetry_server = init_with_server(
             &service_info,
-            &telemetry_server_settings(server_info),
+            &telemetry_server_settings(&server_info, (loopback, 0).into()),
             Vec::new(),
         )?;
 
-        if let Some(addr) = telemetry_server.server_addr() {
-            tracing::info!(%addr, "Telemetry server has started.");
-        } else {
+        let Some(main_addr) = telemetry_server.server_addr() else {
             return Err(anyhow!("telemetry server was unable to bind to an address"));
-        }
+        };
+        tracing::info!(addr = %main_addr, "Telemetry server has started.");
 
         let shutdown_token = CancellationToken::new();
 
+        let mut forwarder_tasks = Vec::new();
+        for listener in listeners {
+            listener.set_nonblocking(true)?;
+            let listener = TcpListener::from_std(listener)?;
+            tracing::info!(addr = %listener.local_addr()?, "Telemetry server will listen on address.");
+
+            forwarder_tasks.push(tokio::spawn(forward_connections(
+                listener,
+                main_addr,
+                shutdown_token.child_token(),
+            )));
+        }
+
         let cancel_future = shutdown_token.child_token().cancelled_owned();
         let server_task =
             tokio::spawn(
@@ -67,24 +101,61 @@ impl TelemetryServerBuilder {
 
         Ok(StartedTelemetryServer {
             server_task,
+            forwarder_tasks,
             shutdown_token,
         })
     }
 }
 
-fn telemetry_server_settings(info: TelemetryServer) -> TelemetrySettings {
-    let mut metrics = MetricsSettings::default();
-    metrics.report_optional = true;
+/// Accepts connections on `listener` and forwards each one to the main telemetry server at `main_addr` until `cancel_token` is cancelled. The main telemetry server only ever sees connections from us, so the address of whoever connected is only in our logs.
+async fn forward_connections(
+    listener: TcpListener,
+    main_addr: SocketAddr,
+    cancel_token: CancellationToken,
+) {
+    loop {
+        let (mut inbound, peer_addr) = tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            res = listener.accept() => match res {
+                Ok(v) => v,
+                Err(err) => {
+                    tracing::warn!(?err, "Failed to accept a telemetry connection.");
+                    continue;
+                }
+            },
+        };
+
+        tracing::debug!(%peer_addr, "Forwarding a telemetry connection.");
+        tokio::spawn(async move {
+            let res = match TcpStream::connect(main_addr).await {
+                Ok(mut outbound) => copy_bidirectional(&mut inbound, &mut outbound)
+                    .await
+                    .map(|_| ()),
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = res {
+                tracing::debug!(?err, %peer_addr, "Failed to forward a telemetry connection.");
+            }
+        });
+    }
+}
 
-    let mut memory_profiler = MemoryProfilerSettings::default();
-    memory_profiler.enabled = true;
+fn telemetry_server_settings(server_info: &TelemetryServer, addr: SocketAddr) -> TelemetrySettings {
+    #[cfg(feature = "memory-profiler")]
+    let memory_profiler = {
+        let mut memory_profiler = MemoryProfilerSettings::default();
+        memory_profiler.enabled = server_info.enable_memory_profiler;
+        memory_profiler
+    };
 
     TelemetrySettings {
-        metrics,
+        metrics: metrics::settings(server_info.report_optional_metrics),
+        #[cfg(feature = "memory-profiler")]
         memory_profiler,
         server: TelemetryServerSettings {
             enabled: true,
-            addr: (info.address, info.port).into(),
+            addr: (addr.ip(), addr.port()).into(),
         },
     }
 }
@@ -13,7 +13,7 @@ struct Args {
 
 #[derive(Debug, Subcommand)]
 enum Command {
-    /// Signs a file.
+    /// Signs a file. The signature is printed as "<key_name>:<signature>", which is the format nixless-agent expects in the last line of a request.
     Sign {
         #[arg(long)]
         file_path: PathBuf,
@@ -52,7 +52,7 @@ fn sign_file(path: PathBuf, private_key_encoded: String) -> anyhow::Result<Strin
         )
     })?;
     Ok(pk
-        .sign_to_base64(file_contents.trim().as_bytes())
+        .sign_to_nix_format(file_contents.trim().as_bytes())
         .context("failed to sign the contents of the file")?)
 }
 
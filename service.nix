@@ -2,6 +2,7 @@
 { lib, config, ... }:
 let
   cfg = config.services.nixless-agent;
+  capabilities = lib.concatStringsSep " " (lib.unique ([ "CAP_SYS_ADMIN" "CAP_CHOWN" "CAP_SETPCAP" "CAP_FOWNER" ] ++ cfg.retainedCapabilities));
 in
 {
   options = {
@@ -48,6 +49,41 @@ in
         type = lib.types.port;
         default = 56678;
       };
+      controlMetrics = lib.mkOption {
+        description = ''
+          Whether to also serve metrics on the main port, for hosts where only that port can be scraped.
+          Disable it to keep metrics strictly on the telemetry port.
+        '';
+        type = lib.types.bool;
+        default = true;
+      };
+      memoryProfiler = lib.mkOption {
+        description = ''
+          Whether the telemetry server serves heap profiles. This has some overhead, so it's off by default.
+          Only has an effect if the agent was built with the `memory-profiler` feature.
+        '';
+        type = lib.types.bool;
+        default = false;
+      };
+      reportOptionalMetrics = lib.mkOption {
+        description = ''
+          Whether to report metrics marked as optional, on both the telemetry port and the main port.
+        '';
+        type = lib.types.bool;
+        default = true;
+      };
+      activationProperties = lib.mkOption {
+        description = ''
+          Resource limits for the transient unit that activates new configurations, so a heavy activation can't take the whole host down with it.
+          Accepted keys are `MemoryMax` (bytes, with an optional `K`, `M`, `G` or `T` suffix, or `infinity`), `CPUQuota` (a percentage of a single CPU, e.g. `50%`), `OOMScoreAdjust` (-1000 to 1000) and `TimeoutStartSec` (whole seconds).
+        '';
+        type = lib.types.attrsOf (lib.types.either lib.types.str lib.types.int);
+        default = { };
+        example = {
+          MemoryMax = "2G";
+          CPUQuota = "50%";
+        };
+      };
       cacheUrl = lib.mkOption {
         description = ''
           The URL of the binary cache to use when downloading a system configuration.
@@ -60,11 +96,45 @@ in
         '';
         type = lib.types.str;
       };
+      cacheProxy = lib.mkOption {
+        description = ''
+          The URL of an HTTP(S) proxy to reach the binary cache through. Hosts in `NO_PROXY` are still reached directly.
+          If null, the proxy in the `HTTPS_PROXY`, `HTTP_PROXY` or `ALL_PROXY` environment variables of the service is used, if there's one.
+          Credentials for the proxy should be set with `NIXLESS_AGENT_CACHE_PROXY_CREDENTIALS` in the extra environment file, so they don't end up in the Nix store.
+        '';
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        example = "http://proxy.example.com:3128";
+      };
+      cacheHttpVersion = lib.mkOption {
+        description = ''
+          Which HTTP version the agent uses with the binary cache.
+          `auto` negotiates HTTP/2 with caches over HTTPS and falls back to HTTP/1.1 if the cache doesn't support it. `http1` never uses HTTP/2. `http2-prior-knowledge` uses HTTP/2 without negotiating it, which also works for caches over plain HTTP, but has no fallback to HTTP/1.1.
+        '';
+        type = lib.types.enum [ "auto" "http1" "http2-prior-knowledge" ];
+        default = "auto";
+      };
+      cacheCaCert = lib.mkOption {
+        description = ''
+          Path to a PEM file with CA certificates the agent trusts when talking to the binary cache, on top of the usual ones.
+          Needed for caches whose certificate comes from a private CA.
+        '';
+        type = lib.types.nullOr lib.types.path;
+        default = null;
+      };
+      cacheMinTlsVersion = lib.mkOption {
+        description = ''
+          The oldest TLS version the agent accepts when talking to the binary cache over HTTPS. If null, TLS 1.2 and 1.3 are both accepted.
+        '';
+        type = lib.types.nullOr (lib.types.enum [ "1.2" "1.3" ]);
+        default = null;
+      };
       updatePublicKey = lib.mkOption {
         description = ''
           The public key to use when verifying requests made to update the system.
+          Can also be a list of public keys, in which case requests signed by any of them will be accepted. This is useful when rotating signing keys.
         '';
-        type = lib.types.str;
+        type = lib.types.either lib.types.str (lib.types.nonEmptyListOf lib.types.str);
       };
       maxSystemHistoryCount = lib.mkOption {
         description = ''
@@ -73,6 +143,101 @@ in
         type = lib.types.ints.positive;
         default = 3;
       };
+      storeSyncMode = lib.mkOption {
+        description = ''
+          How aggressively the agent fsyncs new packages in the Nix store and the system profile links before going on with a switch.
+          `none` leaves it up to the OS, `files` syncs every file and directory in new packages and the directories the agent adds entries to, and `full` also syncs the agent's state file.
+        '';
+        type = lib.types.enum [ "none" "files" "full" ];
+        default = "files";
+      };
+      logTarget = lib.mkOption {
+        description = ''
+          Where the agent sends its logs.
+          `stderr` logs plain messages, which systemd still puts in the journal, and `journald` logs straight to the journal, keeping the fields of every log as journal fields so they can be queried with e.g. `journalctl SYSTEM_PACKAGE_ID=<system package id>`, or `journalctl SWITCH_ID=<switch id>` for the logs of a single switch.
+        '';
+        type = lib.types.enum [ "stderr" "journald" ];
+        default = "stderr";
+      };
+      logFormat = lib.mkOption {
+        description = ''
+          How the agent formats logs sent to stderr (only used when `logTarget` is `stderr`).
+          `json` logs one JSON object per line, including the fields of the spans each log happened in, which is easier for log pipelines to parse.
+        '';
+        type = lib.types.enum [ "text" "json" ];
+        default = "text";
+      };
+      logLevel = lib.mkOption {
+        description = ''
+          Which logs the agent shows, using the same directives as `RUST_LOG` (e.g. `info` or `info,nixless_agent=debug`).
+          Changes to this option only take effect when the agent restarts, since systemd only passes the new value to a new process. To change the level of a running agent, set `NIXLESS_AGENT_LOG_LEVEL` in the extra environment file and run `systemctl reload nixless-agent`, since the agent re-reads that file on reload.
+        '';
+        type = lib.types.str;
+        default = "info";
+      };
+      foreignPackagesPolicy = lib.mkOption {
+        description = ''
+          What the agent does with packages in the Nix store that aren't part of any configuration it's tracking. The agent looks for them after every successful switch.
+          `ignore` leaves them alone, `warn` logs them, and `gc` deletes them together with the packages from old configurations.
+        '';
+        type = lib.types.enum [ "ignore" "warn" "gc" ];
+        default = "ignore";
+      };
+      retainedCapabilities = lib.mkOption {
+        description = ''
+          The capabilities nixless-agent keeps after it finishes starting up. Every other capability is dropped.
+        '';
+        type = lib.types.nonEmptyListOf lib.types.str;
+        default = [ "CAP_CHOWN" ];
+      };
+      watchdogSec = lib.mkOption {
+        description = ''
+          If set, systemd will consider nixless-agent stuck (and stop it) if the agent doesn't ping the systemd watchdog for this many seconds. The agent stops pinging the watchdog when its main loop stops responding.
+          Configuration switches run in the background, so the main loop keeps responding while they happen and this doesn't need to account for how long a switch takes.
+        '';
+        type = lib.types.nullOr lib.types.ints.positive;
+        default = null;
+      };
+      allowNixDaemon = lib.mkOption {
+        description = ''
+          Whether the agent should only warn instead of refusing to start when the nix daemon seems to be running, or when it can't check for it.
+          This is risky: the agent assumes nothing else changes the Nix store or the Nix database while it runs, so a daemon that garbage collects, builds or substitutes packages at the same time can make the agent delete packages still in use, or leave the store inconsistent with the configurations the agent tracks.
+          Only enable this when the daemon is known to stay dormant, e.g. when only its socket is kept around.
+        '';
+        type = lib.types.bool;
+        default = false;
+      };
+      autoReboot = lib.mkOption {
+        description = ''
+          Whether the agent should reboot the machine automatically when a new configuration requires a reboot to be fully applied.
+        '';
+        type = lib.types.bool;
+        default = false;
+      };
+      polkitActionId = lib.mkOption {
+        description = ''
+          A polkit action the agent checks at startup to find out whether it can be authorised to switch systems.
+          systemd checks `org.freedesktop.systemd1.manage-units` itself whenever the agent manages units, so the agent always checks that action as well, and the rule this module adds for it is kept. A custom action needs its own polkit rule.
+        '';
+        type = lib.types.strMatching "[a-z0-9-]+(\\.[a-z0-9-]+)+";
+        default = "org.freedesktop.systemd1.manage-units";
+      };
+      requireNonInteractiveAuth = lib.mkOption {
+        description = ''
+          Whether the agent should refuse to start when polkit would only authorise it after interactive authentication, instead of only warning about it.
+          Nobody is around to authenticate for the agent, so configuration switches would fail later anyway.
+        '';
+        type = lib.types.bool;
+        default = false;
+      };
+      observerMode = lib.mkOption {
+        description = ''
+          Whether the agent should only observe the system. It still serves the summary, history, configurations and metrics, but refuses to switch, roll back, prefetch or repair.
+          In this mode, the agent runs without any capabilities, without write access to /nix, and without the polkit rule that lets it manage systemd units.
+        '';
+        type = lib.types.bool;
+        default = false;
+      };
     };
   };
 
@@ -80,7 +245,7 @@ in
     {
       assertions = [ ];
 
-      security.polkit = {
+      security.polkit = lib.mkIf (!cfg.observerMode) {
         enable = true;
         extraConfig = ''
           polkit.addRule(function(action, subject) {
@@ -88,7 +253,15 @@ in
               if (action.lookup("unit") === undefined && action.lookup("verb") === undefined) {
                 return polkit.Result.YES;
               }
-            }
+              // Lets the agent clean up the unit that activates new configurations if it loses track of it.
+              if (action.lookup("unit") == "nixless-agent-system-switch.service" && (action.lookup("verb") == "stop" || action.lookup("verb") == "reset-failed")) {
+                return polkit.Result.YES;
+              }
+${lib.optionalString cfg.autoReboot ''
+              if (action.lookup("unit") == "reboot.target" && action.lookup("verb") == "start") {
+                return polkit.Result.YES;
+              }
+''}            }
           });
         '';
       };
@@ -113,19 +286,40 @@ in
           NIXLESS_AGENT_TELEMETRY_LISTEN_PORT = builtins.toString cfg.telemetryPort;
           NIXLESS_AGENT_TEMP_DOWNLOAD_PATH = "/var/lib/nixless-agent/downloads";
           NIXLESS_AGENT_CACHE_URL = cfg.cacheUrl;
+          NIXLESS_AGENT_CACHE_PROXY = lib.mkIf (cfg.cacheProxy != null) cfg.cacheProxy;
+          NIXLESS_AGENT_CACHE_HTTP_VERSION = cfg.cacheHttpVersion;
+          NIXLESS_AGENT_CACHE_CA_CERT = lib.mkIf (cfg.cacheCaCert != null) "${cfg.cacheCaCert}";
+          NIXLESS_AGENT_CACHE_MIN_TLS_VERSION = lib.mkIf (cfg.cacheMinTlsVersion != null) cfg.cacheMinTlsVersion;
           NIXLESS_AGENT_ABSOLUTE_ACTIVATION_TRACKER_COMMAND = lib.getExe system-switch-tracker;
+          NIXLESS_AGENT_ACTIVATION_PROPERTY = lib.mkIf (cfg.activationProperties != { }) (lib.concatStringsSep "," (lib.mapAttrsToList (key: value: "${key}=${builtins.toString value}") cfg.activationProperties));
           NIXLESS_AGENT_CACHE_PUBLIC_KEY = cfg.cachePublicKey;
-          NIXLESS_AGENT_UPDATE_PUBLIC_KEY = cfg.updatePublicKey;
+          NIXLESS_AGENT_UPDATE_PUBLIC_KEY = lib.concatStringsSep "," (lib.toList cfg.updatePublicKey);
           NIXLESS_MAX_SYSTEM_HISTORY_COUNT = builtins.toString cfg.maxSystemHistoryCount;
+          NIXLESS_AGENT_AUTO_REBOOT = lib.boolToString cfg.autoReboot;
+          NIXLESS_AGENT_ALLOW_NIX_DAEMON = lib.boolToString cfg.allowNixDaemon;
+          NIXLESS_AGENT_POLKIT_ACTION_ID = cfg.polkitActionId;
+          NIXLESS_AGENT_REQUIRE_NON_INTERACTIVE_AUTH = lib.boolToString cfg.requireNonInteractiveAuth;
+          NIXLESS_AGENT_OBSERVER_MODE = lib.boolToString cfg.observerMode;
+          NIXLESS_AGENT_DISABLE_CONTROL_METRICS = lib.boolToString (!cfg.controlMetrics);
+          NIXLESS_AGENT_ENABLE_MEMORY_PROFILER = lib.boolToString cfg.memoryProfiler;
+          NIXLESS_AGENT_SKIP_OPTIONAL_METRICS = lib.boolToString (!cfg.reportOptionalMetrics);
+          NIXLESS_AGENT_STORE_SYNC_MODE = cfg.storeSyncMode;
+          NIXLESS_AGENT_FOREIGN_PACKAGES_POLICY = cfg.foreignPackagesPolicy;
+          NIXLESS_AGENT_LOG_TARGET = cfg.logTarget;
+          NIXLESS_AGENT_LOG_FORMAT = cfg.logFormat;
+          NIXLESS_AGENT_LOG_LEVEL = cfg.logLevel;
+          NIXLESS_AGENT_RETAINED_CAPABILITIES = lib.concatStringsSep "," cfg.retainedCapabilities;
           RUST_BACKTRACE = "full";
         };
 
         serviceConfig = {
-          Type = "notify";
+          # systemd sends SIGHUP on `systemctl reload`, which makes the agent reload its keys, cache credentials, cache proxy and log level.
+          Type = "notify-reload";
           NotifyAccess = "main";
+          WatchdogSec = lib.mkIf (cfg.watchdogSec != null) cfg.watchdogSec;
           ExecStart = lib.getExe cfg.package;
-          CapabilityBoundingSet = "CAP_SYS_ADMIN CAP_CHOWN CAP_SETPCAP CAP_FOWNER";
-          AmbientCapabilities = "CAP_SYS_ADMIN CAP_CHOWN CAP_SETPCAP CAP_FOWNER";
+          CapabilityBoundingSet = if cfg.observerMode then "" else capabilities;
+          AmbientCapabilities = if cfg.observerMode then "" else capabilities;
           StateDirectory = "nixless-agent";
           DynamicUser = false;
           User = cfg.user;
@@ -138,11 +332,11 @@ in
           ProtectProc = "default"; # Required so nixless-agent can check whether the nix daemon is running.
           ProcSubset = "pid";
           ProtectSystem = "strict";
-          ReadWritePaths = "/nix";
+          ReadWritePaths = lib.mkIf (!cfg.observerMode) "/nix";
           # Restart = "on-failure";
           Restart = "no";
           RestartSec = 10;
-          RestrictAddressFamilies = [ "AF_INET" "AF_INET6" "AF_UNIX" ]; # AF_UNIX is used by D-Bus.
+          RestrictAddressFamilies = [ "AF_INET" "AF_INET6" "AF_UNIX" ]; # AF_UNIX is used by D-Bus and by journald logging.
           RestrictNamespaces = "mnt";
           RestrictRealtime = true;
           RestrictSUIDSGID = true;
@@ -2,14 +2,19 @@ use std::{
     env,
     ffi::CString,
     fs::File,
-    io::Write,
+    io::{ErrorKind, Write},
     os::unix::fs::{chown, OpenOptionsExt},
     path::PathBuf,
     process::exit,
     str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use libc::getpwnam;
+use libc::{clock_gettime, getpwnam, timespec, CLOCK_BOOTTIME};
+use serde_json::json;
+
+/// Written instead when the tracking file we should create already exists, which usually means it was left over from an earlier switch that crashed. The activation can exit with any status, so the agent finds out about this through a file instead of our exit status. The agent looks for this exact name, so it must be kept in sync with the agent.
+const TRACKING_FILE_CONFLICT_FILE_NAME: &str = "tracking_file_conflict";
 
 fn get_user_group_id(user_name: &str) -> std::io::Result<(u32, u32)> {
     let cname = CString::new(user_name)
@@ -24,6 +29,26 @@ fn get_user_group_id(user_name: &str) -> std::io::Result<(u32, u32)> {
     Ok((pwd.pw_uid, pwd.pw_gid))
 }
 
+/// Returns `None` if the clock can't be read, since the agent can do without it.
+fn read_boottime_ns() -> Option<u64> {
+    let mut ts = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let res = unsafe { clock_gettime(CLOCK_BOOTTIME, &mut ts) };
+    if res != 0 {
+        return None;
+    }
+
+    Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
+
+fn read_boot_id() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .ok()
+        .map(|boot_id| boot_id.trim().to_string())
+}
+
 fn main() {
     let args: Vec<_> = env::args().collect();
 
@@ -47,6 +72,9 @@ fn main() {
     let service_result: Option<String> = env::var("SERVICE_RESULT").ok();
     let exit_code: Option<String> = env::var("EXIT_CODE").ok();
     let exit_status: Option<String> = env::var("EXIT_STATUS").ok();
+    // Systemd sets the invocation id for every command of the unit, and the agent sets the switch id when it creates the unit.
+    let invocation_id: Option<String> = env::var("INVOCATION_ID").ok();
+    let switch_id: Option<String> = env::var("NIXLESS_AGENT_SWITCH_ID").ok();
 
     let track_file_name = match track_mode.as_str() {
         "pre-switch" => "pre_switch",
@@ -64,30 +92,85 @@ fn main() {
     let track_directory_path = PathBuf::from_str(&track_directory_path)
         .expect("the directory to keep the tracking files can't be read as a path");
 
-    // TODO: check that `track_directory_path` is actually a directory.
+    match track_directory_path.metadata() {
+        Ok(metadata) if metadata.is_dir() => (),
+        Ok(_) => {
+            eprintln!(
+                "The path to keep the tracking files at '{}' isn't a directory.",
+                track_directory_path.display()
+            );
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!(
+                "Couldn't access the directory to keep the tracking files at '{}': {}.",
+                track_directory_path.display(),
+                err
+            );
+            exit(1);
+        }
+    }
 
     let (user_id, group_id) = get_user_group_id(agent_user)
         .expect("failed to retrieve id of user associated with given user name");
 
+    let written_at_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    // The agent uses everything other than the status codes to tell whether a tracking file was left over from a different switch.
+    let mut contents = json!({
+        "written_at_ms": written_at_ms,
+        "boottime_ns": read_boottime_ns(),
+        "boot_id": read_boot_id(),
+        "invocation_id": invocation_id,
+        "switch_id": switch_id,
+    });
+
     let file_path = track_directory_path.join(track_file_name);
-    let mut file = File::options()
+    let mut file = match File::options()
         .mode(0o600)
         .write(true)
         .create_new(true)
         .open(&file_path)
-        .expect("couldn't create a new tracking file");
-
+    {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            eprintln!(
+                "The tracking file '{}' already exists, probably left over from an earlier switch.",
+                file_path.display()
+            );
+            contents["file_name"] = json!(track_file_name);
+            let conflict_file_path = track_directory_path.join(TRACKING_FILE_CONFLICT_FILE_NAME);
+            File::options()
+                .mode(0o600)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&conflict_file_path)
+                .and_then(|mut file| file.write_all(contents.to_string().as_bytes()))
+                .expect("failed to write the tracking file conflict file");
+            chown(conflict_file_path, Some(user_id), Some(group_id))
+                .expect("failed to set proper owner for the tracking file conflict file");
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!(
+                "Couldn't create the tracking file '{}': {}.",
+                file_path.display(),
+                err
+            );
+            exit(1);
+        }
+    };
     if track_mode == "post-switch" {
-        let contents = format!(
-            "{}\n{}\n{}",
-            service_result.unwrap(),
-            exit_code.unwrap(),
-            exit_status.unwrap()
-        );
-        file.write_all(contents.as_bytes())
-            .expect("failed to write contents to tracking file");
-        _ = file.flush();
+        contents["service_result"] = json!(service_result.unwrap());
+        contents["exit_code"] = json!(exit_code.unwrap());
+        contents["exit_status"] = json!(exit_status.unwrap());
     }
+    file.write_all(contents.to_string().as_bytes())
+        .expect("failed to write contents to tracking file");
+    _ = file.flush();
 
     drop(file);
 
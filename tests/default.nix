@@ -36,6 +36,56 @@ let
       ${lib.getExe nixless-request-signer} sign --private-key-encoded '${testPrivateKey}' --file-path $out >> $out
     '';
 
+  cleanupStateRequest = pkgs.runCommand "cleanup-state-request" { } ''
+    echo cleanup-state >> $out
+    ${lib.getExe nixless-request-signer} sign --private-key-encoded '${testPrivateKey}' --file-path $out >> $out
+  '';
+
+  drainRequest = pkgs.runCommand "drain-request" { } ''
+    echo drain >> $out
+    ${lib.getExe nixless-request-signer} sign --private-key-encoded '${testPrivateKey}' --file-path $out >> $out
+  '';
+
+  repairRequest = pkgs.runCommand "repair-request" { } ''
+    echo repair >> $out
+    ${lib.getExe nixless-request-signer} sign --private-key-encoded '${testPrivateKey}' --file-path $out >> $out
+  '';
+
+  configRequest = pkgs.runCommand "config-request" { } ''
+    echo config >> $out
+    ${lib.getExe nixless-request-signer} sign --private-key-encoded '${testPrivateKey}' --file-path $out >> $out
+  '';
+
+  # A private CA and a certificate for the binary cache issued by it, so the cache can only be trusted through the CA we give to the agent.
+  testCacheTls = pkgs.runCommand "test-cache-tls" { nativeBuildInputs = [ pkgs.openssl ]; } ''
+    cat > ca.cnf <<EOF
+    [req]
+    distinguished_name = dn
+    x509_extensions = ca_ext
+    prompt = no
+    [dn]
+    CN = nixless-agent test CA
+    [ca_ext]
+    basicConstraints = critical, CA:TRUE
+    keyUsage = critical, keyCertSign, cRLSign
+    subjectKeyIdentifier = hash
+    EOF
+
+    cat > cache.ext <<EOF
+    basicConstraints = critical, CA:FALSE
+    keyUsage = critical, digitalSignature, keyEncipherment
+    extendedKeyUsage = serverAuth
+    subjectAltName = DNS:binary_cache
+    EOF
+
+    openssl req -x509 -newkey rsa:2048 -nodes -days 36500 -config ca.cnf -keyout ca.key -out ca.pem
+    openssl req -newkey rsa:2048 -nodes -config ca.cnf -subj /CN=binary_cache -keyout cache.key -out cache.csr
+    openssl x509 -req -in cache.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 36500 -extfile cache.ext -out cache.pem
+
+    mkdir $out
+    cp ca.pem cache.pem cache.key $out/
+  '';
+
   getSystemPackageId = machine:
     let
       machineTopLevel = machine.system.build.toplevel;
@@ -48,7 +98,7 @@ let
       (import ./binary_cache_machine.nix { inherit nixServeNgModule testPrivateKey; })
     ];
 
-    virtualisation.additionalPaths = [ "${pkgs.jq}" "${newTestMachineRequest}" "${secondNewTestMachineRequest}" "${thirdNewTestMachineRequest}" ];
+    virtualisation.additionalPaths = [ "${pkgs.jq}" "${cleanupStateRequest}" "${drainRequest}" "${configRequest}" "${repairRequest}" "${newTestMachineRequest}" "${secondNewTestMachineRequest}" "${thirdNewTestMachineRequest}" ];
   };
 
   testMachineNode = import ./test_machine.nix { inherit nixless-agent-module testPublicKey; };
@@ -109,6 +159,9 @@ in
       binary_cache.wait_until_succeeds("curl -N http://test_machine:56321/summary | ${lib.getExe pkgs.jq} -e '.status == \"standby\"'", 20000)
       binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_system_version 0'")
 
+      status_code = binary_cache.succeed("curl -s -o /dev/null -w '%{http_code}' -X POST --data-binary @${newTestMachineRequest} 'http://test_machine:56321/new-configuration?action=dry-activate'")
+      assert status_code == "400", f"expected an unknown switch action to be rejected, got status {status_code}"
+
       binary_cache.succeed("curl -i --fail-with-body -X POST --data-binary @${newTestMachineRequest} http://test_machine:56321/new-configuration")
       test_machine.wait_for_file("/etc/new-test-machine-tracker", 20000)
       file_contents = test_machine.succeed("cat /etc/new-test-machine-tracker")
@@ -121,6 +174,21 @@ in
       binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_system_configuration_download_duration_count{system_package_id=\"${getSystemPackageId newTestMachine}\"} 1' -")
       binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_system_configuration_setup_duration_count{system_package_id=\"${getSystemPackageId newTestMachine}\"} 1' -")
       binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_system_configuration_switch_duration_count{system_package_id=\"${getSystemPackageId newTestMachine}\"} 1' -")
+      binary_cache.succeed("curl -N http://test_machine:56321/history | ${lib.getExe pkgs.jq} -e 'length == 1 and .[0].outcome == \"successful\" and .[0].version_number == 1 and .[0].system_package_id == \"${getSystemPackageId newTestMachine}\" and .[0].started_at_ms <= .[0].finished_at_ms'")
+
+      binary_cache.succeed("curl -N -X GET --fail-with-body --data-binary @${configRequest} http://test_machine:56321/config | ${lib.getExe pkgs.jq} -e '.nix_store_dir == \"/nix/store\" and .max_system_history_count == 1 and (.update_public_keys | length == 1)'")
+      binary_cache.fail("curl -N -X GET --fail-with-body --data-binary @${drainRequest} http://test_machine:56321/config")
+
+      binary_cache.succeed("curl -i --fail-with-body -X POST --data-binary @${cleanupStateRequest} http://test_machine:56321/cleanup-state")
+      binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_requests_cleanup_state 1' -")
+
+      binary_cache.succeed("curl -i --fail-with-body -X POST --data-binary @${drainRequest} http://test_machine:56321/drain")
+      binary_cache.wait_until_succeeds("curl -N http://test_machine:56321/summary | ${lib.getExe pkgs.jq} -e '.draining and .idle'", 20000)
+      binary_cache.succeed("curl -N http://test_machine:56321/pending-tasks | ${lib.getExe pkgs.jq} -e '.clean_up == null and .system_switch == null and .package_delete == null'")
+      binary_cache.succeed("curl -N http://test_machine:56321/consistency | ${lib.getExe pkgs.jq} -e '.consistent and (.issues | length == 0)'")
+      binary_cache.succeed("curl -N --fail-with-body -X POST --data-binary @${repairRequest} http://test_machine:56321/repair | ${lib.getExe pkgs.jq} -e '(.repairs | length == 0) and (.unrepaired | length == 0)'")
+      status_code = binary_cache.succeed("curl -s -o /dev/null -w '%{http_code}' -X POST --data-binary @${secondNewTestMachineRequest} http://test_machine:56321/new-configuration")
+      assert status_code == "503", f"expected a new configuration to be refused while draining, got status {status_code}"
     '';
   };
 
@@ -193,15 +261,111 @@ in
 
       binary_cache.wait_until_succeeds("curl -N http://test_machine:56321/summary | ${lib.getExe pkgs.jq} -e '.status == \"standby\"'", 20000)
 
+      # There aren't that many configurations to go back to.
+      binary_cache.fail("curl -i --fail-with-body -X POST --data-binary '-99' http://test_machine:56321/rollback-configuration")
+
       binary_cache.succeed("curl -i --fail-with-body -X POST http://test_machine:56321/rollback-configuration")
       test_machine.wait_for_file("/etc/new-test-machine-tracker", 20000)
 
       binary_cache.wait_until_succeeds("curl -N http://test_machine:56321/summary | ${lib.getExe pkgs.jq} -e '.status == \"standby\" and .current_config.system_package_id == \"${getSystemPackageId newTestMachine}\"'", 20000)
-      binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_system_version 3' -")
-      binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_requests_rollback 1' -")
+      binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_system_version 1' -")
+      binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_requests_rollback 2' -")
 
       # Should've been cleaned up.
       test_machine.fail("ls -l /etc/third-new-test-machine-tracker")
+
+      # Rolling back to an explicit version brings back the third configuration with its original version number.
+      binary_cache.succeed("curl -i --fail-with-body -X POST --data-binary '2' http://test_machine:56321/rollback-configuration")
+      test_machine.wait_for_file("/etc/third-new-test-machine-tracker", 20000)
+
+      binary_cache.wait_until_succeeds("curl -N http://test_machine:56321/summary | ${lib.getExe pkgs.jq} -e '.status == \"standby\" and .current_config.system_package_id == \"${getSystemPackageId thirdNewTestMachine}\"'", 20000)
+      binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_system_version 2' -")
+    '';
+  };
+
+  prefetchBeforeSwitch = nixosLib.runTest {
+    name = "prefetch-before-switch";
+    hostPkgs = pkgs;
+    globalTimeout = 120;
+
+    nodes = {
+      binary_cache = binaryCacheNode;
+      test_machine = testMachineNode;
+    };
+
+    includeTestScriptReferences = false; # If this is left at the default of `true`, the test machine will end up with a local copy of the new configuration already, because it uses its own Nix store and the testing infrastructure will put the closure of the test script inside that Nix store.
+    testScript = ''
+      binary_cache.start()
+      binary_cache.wait_for_unit("nix-serve.service")
+
+      test_machine.start(True)
+      test_machine.wait_for_unit("nixless-agent.service")
+
+      binary_cache.wait_until_succeeds("curl -N http://test_machine:56321/summary | ${lib.getExe pkgs.jq} -e '.status == \"standby\"'", 20000)
+
+      binary_cache.succeed("curl -i --fail-with-body -X POST --data-binary @${newTestMachineRequest} http://test_machine:56321/prefetch")
+      binary_cache.wait_until_succeeds("curl -N http://test_machine:56321/summary | ${lib.getExe pkgs.jq} -e '.status == \"standby\" and .prefetched_config.ready and .prefetched_config.system_package_id == \"${getSystemPackageId newTestMachine}\"'", 20000)
+
+      # The configuration is in the Nix store, but nothing got activated.
+      test_machine.succeed("test -d /nix/store/${getSystemPackageId newTestMachine}")
+      test_machine.fail("ls -l /etc/new-test-machine-tracker")
+      binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_system_version 0'")
+
+      binary_cache.succeed("curl -i --fail-with-body -X POST --data-binary @${newTestMachineRequest} http://test_machine:56321/new-configuration")
+      test_machine.wait_for_file("/etc/new-test-machine-tracker", 20000)
+
+      binary_cache.wait_until_succeeds("curl -N http://test_machine:56321/summary | ${lib.getExe pkgs.jq} -e '.status == \"standby\" and .current_config.system_package_id == \"${getSystemPackageId newTestMachine}\" and .prefetched_config == null and .progress == null'", 20000)
+      binary_cache.succeed("curl -N http://test_machine:56432/metrics | grep -q 'nixless_agent_requests_prefetch 1' -")
+    '';
+  };
+
+  privateCaCache = nixosLib.runTest {
+    name = "private-ca-cache";
+    hostPkgs = pkgs;
+    globalTimeout = 120;
+
+    nodes = {
+      binary_cache = {
+        imports = [ binaryCacheNode ];
+
+        services.nginx = {
+          enable = true;
+          virtualHosts.binary_cache = {
+            onlySSL = true;
+            sslCertificate = "${testCacheTls}/cache.pem";
+            sslCertificateKey = "${testCacheTls}/cache.key";
+            locations."/".proxyPass = "http://127.0.0.1:8090";
+          };
+        };
+        networking.firewall.allowedTCPPorts = [ 443 ];
+      };
+      test_machine = {
+        imports = [ testMachineNode ];
+
+        services.nixless-agent = {
+          cacheUrl = lib.mkForce "https://binary_cache";
+          cacheCaCert = "${testCacheTls}/ca.pem";
+          cacheMinTlsVersion = "1.2";
+        };
+      };
+    };
+
+    includeTestScriptReferences = false; # If this is left at the default of `true`, the test machine will end up with a local copy of the new configuration already, because it uses its own Nix store and the testing infrastructure will put the closure of the test script inside that Nix store.
+    testScript = ''
+      binary_cache.start()
+      binary_cache.wait_for_unit("nix-serve.service")
+      binary_cache.wait_for_unit("nginx.service")
+
+      test_machine.start(True)
+      test_machine.wait_for_unit("nixless-agent.service")
+
+      binary_cache.wait_until_succeeds("curl -N http://test_machine:56321/summary | ${lib.getExe pkgs.jq} -e '.status == \"standby\"'", 20000)
+
+      # The cache is only reachable over HTTPS here, so both the nix-cache-info check and the NAR downloads need the agent to trust the private CA.
+      binary_cache.succeed("curl -i --fail-with-body -X POST --data-binary @${newTestMachineRequest} http://test_machine:56321/new-configuration")
+      test_machine.wait_for_file("/etc/new-test-machine-tracker", 20000)
+
+      binary_cache.wait_until_succeeds("curl -N http://test_machine:56321/summary | ${lib.getExe pkgs.jq} -e '.status == \"standby\" and .current_config.system_package_id == \"${getSystemPackageId newTestMachine}\"'", 20000)
     '';
   };
 
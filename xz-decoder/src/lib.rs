@@ -35,6 +35,8 @@ pin_project! {
         buffer_len: usize,
         // This is how much of the buffer we have written so far. Only matters when `buffer_len` > 0.
         written_len: usize,
+        // Whether xz2 already told us it reached the end of the xz stream, in which case there's no more output left inside it.
+        stream_ended: bool,
         dec_stream: Stream,
     }
 }
@@ -47,6 +49,7 @@ impl<W: AsyncWrite> XZDecoder<W> {
             buffer: vec![0u8; 1 << 17].into_boxed_slice(),
             buffer_len: 0,
             written_len: 0,
+            stream_ended: false,
         })
     }
 
@@ -136,7 +139,8 @@ impl<W: AsyncWrite> AsyncWrite for XZDecoder<W> {
                 // println!("    xz2 stream gave us an error");
                 return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::Other, err)));
             }
-            Ok(xz2::stream::Status::Ok | xz2::stream::Status::StreamEnd) => (),
+            Ok(xz2::stream::Status::Ok) => (),
+            Ok(xz2::stream::Status::StreamEnd) => *this.stream_ended = true,
             Ok(status) => {
                 // println!("    xz2 stream gave us an unexpected status");
                 return Poll::Ready(Err(std::io::Error::new(
@@ -184,14 +188,48 @@ impl<W: AsyncWrite> AsyncWrite for XZDecoder<W> {
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
         // println!("Got called to shutdown!");
-        match self.as_mut().flush_buffer(cx) {
-            Poll::Pending => return Poll::Pending,
-            Poll::Ready(Ok(_)) => (),
-            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
-        }
-        // Assumption: if we're here, there's no data in `self.buffer` to flush anymore, so we'll just delegate to the inner writer.
-        if self.buffer_len != 0 {
-            unreachable!("broken assumption");
+        // `poll_write()` only ever asks xz2 to run with the input it has, so xz2 may still be holding some decompressed output (e.g. if our buffer got full). We'll tell it to finish and keep emptying our buffer into the inner writer until the xz stream ends.
+        loop {
+            match self.as_mut().flush_buffer(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            }
+            // Assumption: if we're here, there's no data in `self.buffer`, so we can use it completely.
+            if self.buffer_len != 0 {
+                unreachable!("broken assumption");
+            }
+
+            if self.stream_ended {
+                break;
+            }
+
+            let this = self.as_mut().project();
+            let total_out = this.dec_stream.total_out();
+            let process_result =
+                this.dec_stream
+                    .process(&[], this.buffer, xz2::stream::Action::Finish);
+            *this.buffer_len = (this.dec_stream.total_out() - total_out) as usize;
+
+            match process_result {
+                Err(err) => {
+                    return Poll::Ready(Err(std::io::Error::other(err)));
+                }
+                Ok(xz2::stream::Status::Ok) => (),
+                Ok(xz2::stream::Status::StreamEnd) => *this.stream_ended = true,
+                // xz2 reports that it can't make any more progress this way, which means the input ended before the xz stream was complete.
+                Ok(xz2::stream::Status::MemNeeded) => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "the xz stream ended before all of its data was written",
+                    )));
+                }
+                Ok(status) => {
+                    return Poll::Ready(Err(std::io::Error::other(
+                        XZDecoderError::DecompressionError(status),
+                    )));
+                }
+            }
         }
 
         // println!("    We finished flushing our own buffer, so delegating to the inner writer now.");
@@ -200,3 +238,155 @@ impl<W: AsyncWrite> AsyncWrite for XZDecoder<W> {
         this.inner_writer.poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    // An inner writer that alternates between returning `Pending` and accepting only a few bytes at a time, so the decoder has to deal with both cases on every path.
+    #[derive(Default)]
+    struct StubbornWriter {
+        data: Vec<u8>,
+        pending_next: bool,
+        flushed: bool,
+        shut_down: bool,
+    }
+
+    const STUBBORN_WRITE_LEN: usize = 3;
+
+    impl AsyncWrite for StubbornWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, io::Error>> {
+            if self.pending_next {
+                self.pending_next = false;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            self.pending_next = true;
+            let n = buf.len().min(STUBBORN_WRITE_LEN);
+            self.data.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.flushed = true;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.shut_down = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn sample_data(len: usize) -> Vec<u8> {
+        // Not very compressible, so that the compressed stream isn't trivially small.
+        let mut state = 0x2545f491u32;
+        (0..len)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                if i % 4 == 0 {
+                    b'a'
+                } else {
+                    state as u8
+                }
+            })
+            .collect()
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn decodes_into_pending_partial_writer() {
+        let data = sample_data(1 << 12);
+        let compressed = compress(&data);
+
+        let mut decoder = XZDecoder::new(StubbornWriter::default()).unwrap();
+        decoder.write_all(&compressed).await.unwrap();
+        decoder.flush().await.unwrap();
+        decoder.shutdown().await.unwrap();
+
+        assert!(decoder.inner_writer.flushed);
+        assert!(decoder.inner_writer.shut_down);
+        assert_eq!(decoder.inner_writer.data, data);
+    }
+
+    #[tokio::test]
+    async fn decodes_tiny_chunks() {
+        let data = sample_data(1 << 12);
+        let compressed = compress(&data);
+
+        let mut decoder = XZDecoder::new(StubbornWriter::default()).unwrap();
+        for chunk in compressed.chunks(1) {
+            decoder.write_all(chunk).await.unwrap();
+        }
+        decoder.shutdown().await.unwrap();
+
+        assert_eq!(decoder.inner_writer.data, data);
+    }
+
+    #[tokio::test]
+    async fn decodes_random_chunk_splits() {
+        let data = sample_data(1 << 16);
+        let compressed = compress(&data);
+
+        for seed in 0..16 {
+            // Seeded so that a failing split can be reproduced.
+            let mut rng = fastrand::Rng::with_seed(seed);
+            let mut decoder = XZDecoder::new(Vec::new()).unwrap();
+
+            let mut remaining = &compressed[..];
+            while !remaining.is_empty() {
+                let chunk_len = rng.usize(1..=remaining.len().min(1 << 10));
+                let (chunk, rest) = remaining.split_at(chunk_len);
+                decoder.write_all(chunk).await.unwrap();
+                remaining = rest;
+            }
+            decoder.shutdown().await.unwrap();
+
+            assert_eq!(decoder.inner_writer, data, "mismatch with seed {seed}");
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_fails_on_truncated_stream() {
+        let data = sample_data(1 << 12);
+        let compressed = compress(&data);
+
+        let mut decoder = XZDecoder::new(Vec::new()).unwrap();
+        decoder
+            .write_all(&compressed[..compressed.len() / 2])
+            .await
+            .unwrap();
+        let err = decoder.shutdown().await.unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_output_larger_than_the_buffer() {
+        // Highly compressible data decompresses to much more than our internal buffer, so shutdown has to keep emptying it.
+        let data = vec![b'x'; 1 << 20];
+        let compressed = compress(&data);
+
+        let mut decoder = XZDecoder::new(Vec::new()).unwrap();
+        decoder.write_all(&compressed).await.unwrap();
+        decoder.shutdown().await.unwrap();
+
+        assert_eq!(decoder.inner_writer, data);
+    }
+}